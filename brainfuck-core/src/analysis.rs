@@ -0,0 +1,147 @@
+use std::{collections::BTreeMap, ops::Range};
+
+use crate::{data::BfInstruction, run::RunningProgramInfo};
+
+/// What a piece of a program does to a cell, see [`NetEffect`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CellEffect {
+    /// Adds to the cell, a negative amount subtracts.
+    Add(i64),
+    /// Sets the cell, like a loop that always ends on a 0 cell and the additions after it. The value is before the cell wraps.
+    Set(i64),
+    /// Changes the cell in a way that depends on the tape or the input.
+    Unknown,
+}
+
+/// What a piece of a program does wherever on the tape it runs, made by [`RunningProgramInfo::net_effect`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetEffect {
+    /// How far the head moves, to the right for a positive distance.
+    pub movement: isize,
+    /// The cells it changes, by their offset from the cell the head starts on.
+    pub cells: BTreeMap<isize, CellEffect>,
+}
+
+impl NetEffect {
+    fn add(&mut self, amount: i64) {
+        let cell = self
+            .cells
+            .entry(self.movement)
+            .or_insert(CellEffect::Add(0));
+        *cell = match *cell {
+            CellEffect::Add(value) => CellEffect::Add(value + amount),
+            CellEffect::Set(value) => CellEffect::Set(value + amount),
+            CellEffect::Unknown => CellEffect::Unknown,
+        };
+        if *cell == CellEffect::Add(0) {
+            self.cells.remove(&self.movement);
+        }
+    }
+}
+
+impl<C> RunningProgramInfo<C> {
+    /// Index of the bracket matching the `[` or `]` at `pc`, or `None` for other instructions.
+    pub fn matching_bracket(&self, pc: usize) -> Option<usize> {
+        match self.code.get(pc)? {
+            BfInstruction::LoopStart | BfInstruction::LoopEnd if self.jump_table[pc] > 0 => {
+                Some(self.jump_table[pc] as usize - 1)
+            }
+            _ => None,
+        }
+    }
+
+    /// What the instructions in `instructions` do, counting every loop among them as run until its cell is 0.
+    ///
+    /// `None` when that depends on the tape, which is when `instructions` splits a loop or has a loop that moves the head.
+    pub fn net_effect(&self, instructions: Range<usize>) -> Option<NetEffect> {
+        let mut effect = NetEffect {
+            movement: 0,
+            cells: BTreeMap::new(),
+        };
+        let mut pc = instructions.start;
+        while pc < instructions.end {
+            match self.code.get(pc)? {
+                BfInstruction::Inc => effect.add(1),
+                BfInstruction::Dec => effect.add(-1),
+                BfInstruction::Left => effect.movement -= 1,
+                BfInstruction::Right => effect.movement += 1,
+                BfInstruction::Input => {
+                    effect.cells.insert(effect.movement, CellEffect::Unknown);
+                }
+                BfInstruction::Output => {}
+                BfInstruction::LoopStart => {
+                    let end = self
+                        .matching_bracket(pc)
+                        .filter(|&end| end < instructions.end)?;
+                    let body = self.net_effect(pc + 1..end)?;
+                    if body.movement != 0 {
+                        return None;
+                    }
+                    for offset in body.cells.keys() {
+                        effect
+                            .cells
+                            .insert(effect.movement + offset, CellEffect::Unknown);
+                    }
+                    effect.cells.insert(effect.movement, CellEffect::Set(0));
+                    pc = end;
+                }
+                //the loop started before `instructions`
+                BfInstruction::LoopEnd => return None,
+            }
+            pc += 1;
+        }
+        Some(effect)
+    }
+}
+
+// --- UNIT TESTS ---
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::preprocess_input;
+
+    #[test]
+    fn test_matching_bracket() {
+        let program = preprocess_input("+[>[-]<]", 1).unwrap();
+        assert_eq!(program.matching_bracket(1), Some(7));
+        assert_eq!(program.matching_bracket(7), Some(1));
+        assert_eq!(program.matching_bracket(3), Some(5));
+        assert_eq!(program.matching_bracket(5), Some(3));
+        assert_eq!(program.matching_bracket(0), None);
+        assert_eq!(program.matching_bracket(8), None);
+    }
+
+    /// Additions and moves add up, loops that end where they started clear their cell and change the others in them.
+    #[test]
+    fn test_net_effect() {
+        let program = preprocess_input("++>-<+->>,<[->+<]+.", 1).unwrap();
+        let effect = program.net_effect(0..program.code.size()).unwrap();
+        assert_eq!(effect.movement, 1);
+        assert_eq!(
+            effect.cells.into_iter().collect::<Vec<_>>(),
+            [
+                (0, CellEffect::Add(2)),
+                (1, CellEffect::Set(1)),
+                (2, CellEffect::Unknown),
+            ]
+        );
+
+        // `+-` cancels out
+        let effect = program.net_effect(4..7).unwrap();
+        assert_eq!(effect.movement, -1);
+        assert!(effect.cells.is_empty());
+    }
+
+    /// Loops that move the head and loops cut by the selection are not known.
+    #[test]
+    fn test_unknown_net_effect() {
+        let program = preprocess_input("+[>]+[-]", 1).unwrap();
+        assert_eq!(program.net_effect(0..8), None);
+        assert_eq!(
+            program.net_effect(4..8).map(|effect| effect.movement),
+            Some(0)
+        );
+        assert_eq!(program.net_effect(5..7), None);
+        assert_eq!(program.net_effect(6..8), None);
+    }
+}
@@ -0,0 +1,188 @@
+use std::fmt::Debug;
+use std::hash::Hash;
+
+/// What `+` and `-` do when a cell would go past its largest or smallest value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowMode {
+    /// Wrap around, so `-` on 0 gives the largest value.
+    #[default]
+    Wrap,
+    /// Stay at the largest or smallest value.
+    Saturate,
+    /// Stop the run with a `CellOverflowError`.
+    Error,
+}
+
+/// Which [`Cell`] type a run or search uses, for choosing it at runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CellWidth {
+    #[default]
+    U8,
+    U16,
+    U32,
+}
+
+impl CellWidth {
+    pub fn bits(self) -> u32 {
+        match self {
+            CellWidth::U8 => 8,
+            CellWidth::U16 => 16,
+            CellWidth::U32 => 32,
+        }
+    }
+}
+
+/// A tape cell. Implemented for `u8`, `u16` and `u32`.
+///
+/// Output always writes the low byte of the cell and input stores the byte zero extended.
+pub trait Cell: Copy + Eq + Hash + Debug + Default + Send + Sync + 'static {
+    const ZERO: Self;
+    const MAX: Self;
+    /// Size of the cell in bytes when it is stored in a seed file.
+    const BYTES: usize;
+
+    /// `None` when `overflow` is [`OverflowMode::Error`] and the cell is already at its largest value.
+    fn increment(self, overflow: OverflowMode) -> Option<Self>;
+    /// `None` when `overflow` is [`OverflowMode::Error`] and the cell is already 0.
+    fn decrement(self, overflow: OverflowMode) -> Option<Self>;
+    /// Adds `amount` one at a time, subtracting for a negative amount. `None` when `overflow` is [`OverflowMode::Error`] and one of the steps would overflow.
+    fn add_n(self, amount: i64, overflow: OverflowMode) -> Option<Self>;
+    fn from_byte(byte: u8) -> Self;
+    fn to_byte(self) -> u8;
+    /// The value of the cell zero extended, for fingerprinting tapes.
+    fn to_u64(self) -> u64;
+    fn write_ne_bytes(self, out: &mut Vec<u8>);
+    fn read_ne_bytes(bytes: &[u8]) -> Self;
+}
+
+macro_rules! impl_cell {
+    ($($t:ty),*) => {
+        $(
+            impl Cell for $t {
+                const ZERO: Self = 0;
+                const MAX: Self = <$t>::MAX;
+                const BYTES: usize = std::mem::size_of::<$t>();
+
+                #[inline(always)]
+                fn increment(self, overflow: OverflowMode) -> Option<Self> {
+                    match overflow {
+                        OverflowMode::Wrap => Some(self.wrapping_add(1)),
+                        OverflowMode::Saturate => Some(self.saturating_add(1)),
+                        OverflowMode::Error => self.checked_add(1),
+                    }
+                }
+
+                #[inline(always)]
+                fn decrement(self, overflow: OverflowMode) -> Option<Self> {
+                    match overflow {
+                        OverflowMode::Wrap => Some(self.wrapping_sub(1)),
+                        OverflowMode::Saturate => Some(self.saturating_sub(1)),
+                        OverflowMode::Error => self.checked_sub(1),
+                    }
+                }
+
+                #[inline(always)]
+                fn add_n(self, amount: i64, overflow: OverflowMode) -> Option<Self> {
+                    // a step count that does not fit in the cell overflows it
+                    let steps = <$t>::try_from(amount.unsigned_abs());
+                    match (overflow, amount >= 0) {
+                        (OverflowMode::Wrap, _) => {
+                            Some(u64::from(self).wrapping_add(amount as u64) as $t)
+                        }
+                        (OverflowMode::Saturate, true) => {
+                            Some(steps.map_or(<$t>::MAX, |steps| self.saturating_add(steps)))
+                        }
+                        (OverflowMode::Saturate, false) => {
+                            Some(steps.map_or(0, |steps| self.saturating_sub(steps)))
+                        }
+                        (OverflowMode::Error, true) => steps.ok().and_then(|steps| self.checked_add(steps)),
+                        (OverflowMode::Error, false) => steps.ok().and_then(|steps| self.checked_sub(steps)),
+                    }
+                }
+
+                #[inline(always)]
+                fn from_byte(byte: u8) -> Self {
+                    <$t>::from(byte)
+                }
+
+                #[inline(always)]
+                fn to_byte(self) -> u8 {
+                    self.to_le_bytes()[0]
+                }
+
+                #[inline(always)]
+                fn to_u64(self) -> u64 {
+                    u64::from(self)
+                }
+
+                fn write_ne_bytes(self, out: &mut Vec<u8>) {
+                    out.extend_from_slice(&self.to_ne_bytes());
+                }
+
+                fn read_ne_bytes(bytes: &[u8]) -> Self {
+                    <$t>::from_ne_bytes(bytes.try_into().unwrap())
+                }
+            }
+        )*
+    };
+}
+
+impl_cell!(u8, u16, u32);
+
+// --- UNIT TESTS ---
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Each overflow mode handles the top of the range differently.
+    #[test]
+    fn test_increment_overflow() {
+        assert_eq!(u8::MAX.increment(OverflowMode::Wrap), Some(0));
+        assert_eq!(u8::MAX.increment(OverflowMode::Saturate), Some(u8::MAX));
+        assert_eq!(u8::MAX.increment(OverflowMode::Error), None);
+        assert_eq!(255u16.increment(OverflowMode::Error), Some(256));
+    }
+
+    /// Adding many at once matches adding one at a time in every overflow mode.
+    #[test]
+    fn test_add_n() {
+        for overflow in [
+            OverflowMode::Wrap,
+            OverflowMode::Saturate,
+            OverflowMode::Error,
+        ] {
+            for start in [0u8, 1, 100, 254, 255] {
+                for amount in [-300i64, -256, -3, 0, 2, 255, 256, 300] {
+                    let mut expected = Some(start);
+                    for _ in 0..amount.unsigned_abs() {
+                        expected = expected.and_then(|cell| {
+                            if amount > 0 {
+                                cell.increment(overflow)
+                            } else {
+                                cell.decrement(overflow)
+                            }
+                        });
+                    }
+                    assert_eq!(start.add_n(amount, overflow), expected);
+                }
+            }
+        }
+        assert_eq!(5u32.add_n(-6, OverflowMode::Wrap), Some(u32::MAX));
+    }
+
+    /// Each overflow mode handles the bottom of the range differently.
+    #[test]
+    fn test_decrement_underflow() {
+        assert_eq!(0u32.decrement(OverflowMode::Wrap), Some(u32::MAX));
+        assert_eq!(0u32.decrement(OverflowMode::Saturate), Some(0));
+        assert_eq!(0u32.decrement(OverflowMode::Error), None);
+    }
+
+    /// Wide cells output their low byte.
+    #[test]
+    fn test_to_byte() {
+        assert_eq!(0x1241u16.to_byte(), 0x41);
+        assert_eq!(0xFFFF_FF0Au32.to_byte(), 0x0A);
+        assert_eq!(u16::from_byte(0xFF), 0xFF);
+    }
+}
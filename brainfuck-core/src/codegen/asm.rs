@@ -0,0 +1,71 @@
+use super::{CodegenOptions, SourceWriter, describe, elf::assemble};
+use crate::{data::CompressedBF, error::ParseError, x86::Assembler};
+
+/// Translates `program` into annotated x86-64 assembly for the GNU assembler, in Intel syntax, for a Linux program that behaves like `brainfuck-main run` with the same options.
+///
+/// The instructions are the ones [`generate_elf`](crate::generate_elf) emits, written out as they are emitted: every op of the [`CompiledProgram`](crate::CompiledProgram) starts with a comment giving the range of instructions it was compiled from, and the runtime routines explain what they do.
+/// It builds with `as -o program.o program.s && ld -o program program.o` and needs no C library.
+pub fn generate_asm(
+    program: &CompressedBF,
+    options: &CodegenOptions,
+) -> Result<String, ParseError> {
+    let width = options.cell_width.bits() as usize / 8;
+    let listing = assemble(program, options, Assembler::with_listing(width))?.into_listing();
+
+    let mut out = SourceWriter::new();
+    out.line(&format!(
+        "# Generated from a Brainfuck program of {} instructions.",
+        program.size()
+    ));
+    out.line(&format!("# {}.", describe(options)));
+    out.block(
+        "#
+# Build with `as -o program.o program.s && ld -o program program.o`; the program runs on Linux x86-64 without a C library.
+# rbx holds the tape, r12 the head, r13 the number of cells and r14 the cell the program started on, all counted in cells.
+# rbp points at the output buffer, the input buffer, and the read position and end of the input buffer, and r15 counts the bytes waiting in the output buffer.
+
+    .intel_syntax noprefix
+    .text
+    .globl _start",
+    );
+    out.text.push_str(&listing);
+    Ok(out.text)
+}
+
+// --- UNIT TESTS ---
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cell::CellWidth;
+
+    /// Every op is labeled with the instructions it came from, and only the routines and messages the program needs are written out.
+    #[test]
+    fn test_generate_asm() {
+        let program = CompressedBF::from_string("+++[->++<]>[-]");
+        let asm = generate_asm(&program, &CodegenOptions::default()).unwrap();
+        assert!(asm.contains(".intel_syntax noprefix"));
+        assert!(asm.contains("# 0..3: +++\n    add byte ptr [rbx + r12], 3\n"));
+        assert!(asm.contains("# 3..10: [->++<]\n"));
+        assert!(asm.contains("# 11..14: [-]\n    mov byte ptr [rbx + r12], 0\n"));
+        assert!(asm.contains("\nmove_right:\n"));
+        assert!(
+            asm.contains("\nout_of_memory:\n    .ascii \"tape head moved past the last cell\"\n")
+        );
+        assert!(!asm.contains("\ninput:\n"));
+        assert!(!asm.contains("input_exhausted"));
+        assert!(!asm.contains("grow_right"));
+
+        let options = CodegenOptions {
+            cell_width: CellWidth::U16,
+            ..CodegenOptions::default()
+        };
+        let asm = generate_asm(&CompressedBF::from_string(">+"), &options).unwrap();
+        assert!(asm.contains("    add word ptr [rbx + r12*2], 1\n"));
+
+        let unbalanced = CompressedBF::from_string("+]");
+        assert!(matches!(
+            generate_asm(&unbalanced, &CodegenOptions::default()),
+            Err(ParseError::UnmatchedLoopEnd { instruction: 1, .. })
+        ));
+    }
+}
@@ -0,0 +1,410 @@
+use super::{CodegenOptions, Helpers, SourceWriter, compile, describe, runs, wrapped_amount};
+use crate::{
+    cell::OverflowMode,
+    data::CompressedBF,
+    error::{ParseError, RunErrorKind},
+    ir::{CompiledOp, Op},
+    run::{EofBehavior, TapePolicy},
+};
+
+/// Translates `program` into a self-contained C file that behaves like `brainfuck-main run` with the same options.
+///
+/// The C program prints the same bytes and stops with the same message and exit code when the run fails, but it does not detect infinite loops.
+/// Folded instructions become a single statement and the loops [`CompiledProgram`](crate::CompiledProgram) recognizes become the C they stand for.
+pub fn generate_c(program: &CompressedBF, options: &CodegenOptions) -> Result<String, ParseError> {
+    let compiled = compile(program, options)?;
+    let helpers = Helpers::of(&compiled);
+    let source = program.to_string();
+    let bits = options.cell_width.bits();
+    let mut out = SourceWriter::new();
+
+    out.line("/*");
+    out.line(&format!(
+        " * Generated from a Brainfuck program of {} instructions.",
+        program.size()
+    ));
+    out.line(&format!(" * {}.", describe(options)));
+    out.line(" */");
+    out.block(
+        "#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+",
+    );
+    out.line(&format!("typedef uint{}_t cell;", bits));
+    out.line(&format!("#define CELL_MAX UINT{}_MAX", bits));
+    if let TapePolicy::Growable { max_cells, .. } = options.tape {
+        out.line(&format!("#define MAX_CELLS ((size_t){})", max_cells));
+    }
+    out.line("");
+    out.line("static cell *tape;");
+    out.line(&format!("static size_t len = {};", options.tape_size));
+    if !compiled.ops().is_empty() {
+        out.line("static size_t head;");
+    }
+    if helpers.can_fail(options) {
+        out.line(
+            "/* the cell the program started on, which moves when the tape grows to the left */",
+        );
+        out.line("static size_t origin;");
+    }
+    out.line("");
+
+    if helpers.can_fail(options) {
+        out.block(
+            r#"/* Prints the error `brainfuck-main run` would print and exits with its exit code. */
+static void fail(const char *message, int code, size_t pc) {
+    fflush(stdout);
+    fprintf(stderr, "Error: %s at instruction %zu, tape head %lld, cell value %llu\n", message, pc,
+            (long long)head - (long long)origin, (unsigned long long)tape[head]);
+    exit(code);
+}
+"#,
+        );
+    }
+    if let TapePolicy::Growable { grow_left, .. } = options.tape {
+        if helpers.move_right {
+            out.block(
+                r#"/* Grows the tape to the right, doubling it up to MAX_CELLS cells. */
+static int grow_right(void) {
+    if (len >= MAX_CELLS) {
+        return 0;
+    }
+    size_t new_len = len * 2 < MAX_CELLS ? len * 2 : MAX_CELLS;
+    cell *grown = realloc(tape, new_len * sizeof(cell));
+    if (!grown) {
+        perror("realloc");
+        exit(EXIT_FAILURE);
+    }
+    memset(grown + len, 0, (new_len - len) * sizeof(cell));
+    tape = grown;
+    len = new_len;
+    return 1;
+}
+"#,
+            );
+        }
+        if grow_left && helpers.move_left {
+            out.block(
+                r#"/* Grows the tape to the left by as many cells as it has, up to MAX_CELLS cells. */
+static int grow_left(void) {
+    if (len >= MAX_CELLS) {
+        return 0;
+    }
+    size_t extra = MAX_CELLS - len < len ? MAX_CELLS - len : len;
+    cell *grown = realloc(tape, (len + extra) * sizeof(cell));
+    if (!grown) {
+        perror("realloc");
+        exit(EXIT_FAILURE);
+    }
+    memmove(grown + extra, grown, len * sizeof(cell));
+    memset(grown, 0, extra * sizeof(cell));
+    tape = grown;
+    len += extra;
+    head += extra;
+    origin += extra;
+    return 1;
+}
+"#,
+            );
+        }
+    }
+    if helpers.move_right {
+        let out_of_memory = fail_call(RunErrorKind::OutOfMemory);
+        let check = match options.tape {
+            TapePolicy::Fixed => "    if (len - 1 - head < distance) {\n".to_string(),
+            TapePolicy::Growable { .. } => {
+                "    while (len - 1 - head < distance) {\n        if (grow_right()) {\n            continue;\n        }\n".to_string()
+            }
+        };
+        out.block(&format!(
+            "/* Moves the head right for the `>` run starting at instruction `pc`, failing at the `>` that leaves the tape. */
+static void move_right(size_t distance, size_t pc) {{
+{check}        pc += len - 1 - head;
+        head = len - 1;
+        {out_of_memory};
+    }}
+    head += distance;
+}}
+"
+        ));
+    }
+    if helpers.move_left {
+        let (check, kind) = match options.tape {
+            TapePolicy::Growable {
+                grow_left: true, ..
+            } => (
+                "    while (head < distance) {\n        if (grow_left()) {\n            continue;\n        }\n",
+                RunErrorKind::OutOfMemory,
+            ),
+            _ => ("    if (head < distance) {\n", RunErrorKind::TapeHeadBound),
+        };
+        let failure = fail_call(kind);
+        out.block(&format!(
+            "/* Moves the head left for the `<` run starting at instruction `pc`, failing at the `<` that leaves the tape. */
+static void move_left(size_t distance, size_t pc) {{
+{check}        pc += head;
+        head = 0;
+        {failure};
+    }}
+    head -= distance;
+}}
+"
+        ));
+    }
+    match options.overflow {
+        OverflowMode::Wrap => {}
+        OverflowMode::Saturate => {
+            if helpers.add {
+                out.block(
+                    "static void add_saturating(uint64_t amount) {
+    tape[head] = CELL_MAX - tape[head] < amount ? CELL_MAX : tape[head] + amount;
+}
+",
+                );
+            }
+            if helpers.sub {
+                out.block(
+                    "static void sub_saturating(uint64_t amount) {
+    tape[head] = tape[head] < amount ? 0 : tape[head] - amount;
+}
+",
+                );
+            }
+        }
+        OverflowMode::Error => {
+            let overflow = fail_call(RunErrorKind::CellOverflow);
+            if helpers.add {
+                out.block(&format!(
+                    "/* Adds for the `+` run starting at instruction `pc`, failing at the `+` that overflows. */
+static void add_checked(uint64_t amount, size_t pc) {{
+    if (CELL_MAX - tape[head] < amount) {{
+        pc += CELL_MAX - tape[head];
+        tape[head] = CELL_MAX;
+        {overflow};
+    }}
+    tape[head] += amount;
+}}
+"
+                ));
+            }
+            if helpers.sub {
+                out.block(&format!(
+                    "/* Subtracts for the `-` run starting at instruction `pc`, failing at the `-` that overflows. */
+static void sub_checked(uint64_t amount, size_t pc) {{
+    if (tape[head] < amount) {{
+        pc += tape[head];
+        tape[head] = 0;
+        {overflow};
+    }}
+    tape[head] -= amount;
+}}
+"
+                ));
+            }
+        }
+    }
+    if helpers.input {
+        let (signature, on_eof) = match options.eof {
+            EofBehavior::Error => (
+                "size_t pc",
+                format!(
+                    "    }} else {{\n        {};\n",
+                    fail_call(RunErrorKind::InputExhausted)
+                ),
+            ),
+            EofBehavior::Zero => (
+                "void",
+                "    } else {\n        tape[head] = 0;\n".to_string(),
+            ),
+            EofBehavior::Max => (
+                "void",
+                "    } else {\n        tape[head] = CELL_MAX;\n".to_string(),
+            ),
+            EofBehavior::Unchanged => ("void", String::new()),
+        };
+        out.block(&format!(
+            "/* `,`, flushing the output first so that prompts show up before the program waits. */
+static void input({signature}) {{
+    fflush(stdout);
+    int c = getchar();
+    if (c != EOF) {{
+        tape[head] = (cell)c;
+{on_eof}    }}
+}}
+"
+        ));
+    }
+
+    out.line("int main(void) {");
+    out.indent += 1;
+    out.block(
+        "tape = calloc(len, sizeof(cell));
+if (!tape) {
+    perror(\"calloc\");
+    return EXIT_FAILURE;
+}",
+    );
+    for compiled_op in compiled.ops() {
+        emit_op(&mut out, compiled_op, &source, options, bits);
+    }
+    out.line("return 0;");
+    out.indent -= 1;
+    out.line("}");
+    Ok(out.text)
+}
+
+fn fail_call(kind: RunErrorKind) -> String {
+    format!("fail(\"{}\", {}, pc)", kind, kind.exit_code())
+}
+
+fn emit_op(
+    out: &mut SourceWriter,
+    compiled_op: &CompiledOp,
+    source: &str,
+    options: &CodegenOptions,
+    bits: u32,
+) {
+    let CompiledOp { op, pc, len } = compiled_op;
+    let overflow = options.overflow;
+    let text = &source[*pc..*pc + *len];
+    match op {
+        Op::Add(amount) => emit_add(out, *amount, *pc, overflow, bits),
+        Op::Move(distance) => emit_move(out, *distance, *pc),
+        Op::SetZero => out.line(&format!("tape[head] = 0; /* {} */", text)),
+        Op::MulMove { targets, reach } => {
+            out.line(&format!("if (tape[head]) {{ /* {} */", text));
+            out.indent += 1;
+            let mut room = Vec::new();
+            if reach.0 < 0 {
+                room.push(format!("head >= {}", reach.0.unsigned_abs()));
+            }
+            if reach.1 > 0 {
+                room.push(format!("len - 1 - head >= {}", reach.1));
+            }
+            if !room.is_empty() {
+                out.line(&format!("if ({}) {{", room.join(" && ")));
+                out.indent += 1;
+            }
+            for &(offset, factor) in targets {
+                let factor = factor.rem_euclid(1i64 << bits);
+                let index = match offset {
+                    offset if offset < 0 => format!("head - {}", offset.unsigned_abs()),
+                    offset => format!("head + {}", offset),
+                };
+                if factor == 1 {
+                    out.line(&format!("tape[{}] += tape[head];", index));
+                } else {
+                    out.line(&format!("tape[{}] += tape[head] * {}u;", index, factor));
+                }
+            }
+            out.line("tape[head] = 0;");
+            if !room.is_empty() {
+                // the tape has to grow or the head leaves it, so run the loop as written
+                out.indent -= 1;
+                out.line("} else {");
+                out.indent += 1;
+                out.line("while (tape[head]) {");
+                out.indent += 1;
+                emit_straight(out, &text[1..text.len() - 1], pc + 1, overflow, bits);
+                out.indent -= 1;
+                out.line("}");
+                out.indent -= 1;
+                out.line("}");
+            }
+            out.indent -= 1;
+            out.line("}");
+        }
+        Op::Scan(stride) => {
+            let call = if *stride > 0 {
+                format!("move_right({}, {})", stride, pc + 1)
+            } else {
+                format!("move_left({}, {})", stride.unsigned_abs(), pc + 1)
+            };
+            out.line(&format!("while (tape[head]) {{ /* {} */", text));
+            out.line(&format!("    {};", call));
+            out.line("}");
+        }
+        Op::Output => out.line("putchar(tape[head]);"),
+        Op::Input if options.eof == EofBehavior::Error => out.line(&format!("input({});", pc)),
+        Op::Input => out.line("input();"),
+        Op::JumpIfZero(Some(_)) => {
+            out.line("while (tape[head]) {");
+            out.indent += 1;
+        }
+        Op::JumpIfZero(None) => out.line(&format!(
+            "if (!tape[head]) {{ fail(\"{}\", {}, {}); }}",
+            RunErrorKind::UnclosedLoop,
+            RunErrorKind::UnclosedLoop.exit_code(),
+            pc
+        )),
+        Op::JumpIfNonZero(_) => {
+            out.indent -= 1;
+            out.line("}");
+        }
+    }
+}
+
+fn emit_add(out: &mut SourceWriter, amount: i64, pc: usize, overflow: OverflowMode, bits: u32) {
+    match overflow {
+        OverflowMode::Wrap => match wrapped_amount(amount, bits) {
+            0 => {}
+            amount if amount > 0 => out.line(&format!("tape[head] += {};", amount)),
+            amount => out.line(&format!("tape[head] -= {};", amount.unsigned_abs())),
+        },
+        OverflowMode::Saturate if amount > 0 => out.line(&format!("add_saturating({});", amount)),
+        OverflowMode::Saturate => out.line(&format!("sub_saturating({});", amount.unsigned_abs())),
+        OverflowMode::Error if amount > 0 => out.line(&format!("add_checked({}, {});", amount, pc)),
+        OverflowMode::Error => {
+            out.line(&format!("sub_checked({}, {});", amount.unsigned_abs(), pc))
+        }
+    }
+}
+
+fn emit_move(out: &mut SourceWriter, distance: isize, pc: usize) {
+    if distance > 0 {
+        out.line(&format!("move_right({}, {});", distance, pc));
+    } else {
+        out.line(&format!("move_left({}, {});", distance.unsigned_abs(), pc));
+    }
+}
+
+//the `+-<>` instructions in `text`, which start at instruction `pc`, one statement per run of the same instruction
+fn emit_straight(out: &mut SourceWriter, text: &str, pc: usize, overflow: OverflowMode, bits: u32) {
+    for (instruction, start, count) in runs(text) {
+        let count = count as isize;
+        match instruction {
+            b'+' => emit_add(out, count as i64, pc + start, overflow, bits),
+            b'-' => emit_add(out, -count as i64, pc + start, overflow, bits),
+            b'>' => emit_move(out, count, pc + start),
+            b'<' => emit_move(out, -count, pc + start),
+            other => unreachable!("{} in the body of a loop idiom", other as char),
+        }
+    }
+}
+
+// --- UNIT TESTS ---
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Folds and loop idioms come out as single statements, and only the helpers the program needs are emitted.
+    #[test]
+    fn test_generate_c() {
+        let program = CompressedBF::from_string("+++[->++<]>[-].");
+        let c = generate_c(&program, &CodegenOptions::default()).unwrap();
+        assert!(c.contains("tape[head] += 3;"));
+        assert!(c.contains("tape[head + 1] += tape[head] * 2u;"));
+        assert!(c.contains("tape[head] = 0; /* [-] */"));
+        assert!(c.contains("move_right(1, 10);"));
+        assert!(!c.contains("fail(\"cell overflowed\""));
+        assert!(!c.contains("input("));
+
+        let unbalanced = CompressedBF::from_string("+]");
+        assert!(matches!(
+            generate_c(&unbalanced, &CodegenOptions::default()),
+            Err(ParseError::UnmatchedLoopEnd { instruction: 1, .. })
+        ));
+    }
+}
@@ -0,0 +1,1040 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+};
+
+use super::{CodegenOptions, Helpers, compile, runs};
+use crate::{
+    cell::OverflowMode,
+    data::CompressedBF,
+    error::{ParseError, RunErrorKind},
+    ir::{CompiledOp, CompiledProgram, Op},
+    run::{EofBehavior, TapePolicy},
+    x86::{Assembler, Forward, Label, Mark},
+};
+
+// where the file is loaded, the first address of the usual executable layout
+const BASE: u64 = 0x40_0000;
+// the ELF header and the two program headers, after which the code starts
+pub(super) const HEADERS: usize = 64 + 2 * 56;
+
+// the stack frame the program keeps in rbp: the output buffer, the input buffer, and the read position and end of the input buffer
+const BUFFER_SIZE: i32 = 4096;
+const INPUT_BUFFER: i32 = BUFFER_SIZE;
+const INPUT_POS: i32 = 2 * BUFFER_SIZE;
+const INPUT_END: i32 = 2 * BUFFER_SIZE + 8;
+const FRAME_SIZE: i32 = 2 * BUFFER_SIZE + 16;
+
+// Linux system call numbers
+const SYS_READ: u8 = 0;
+const SYS_WRITE: u8 = 1;
+const SYS_MMAP: u8 = 9;
+const SYS_MREMAP: u8 = 25;
+const SYS_EXIT_GROUP: u8 = 231;
+
+/// Translates `program` into a static Linux x86-64 executable that behaves like `brainfuck-main run` with the same options.
+///
+/// The executable talks to the kernel with raw system calls and needs no C library, compiler or assembler, on this machine or the one it runs on.
+/// Like the other code generators it prints the same bytes and fails with the same message and exit code, but it does not detect infinite loops.
+/// The tape is kept in memory mapped with `mmap` and grown with `mremap`, and output is buffered until the program reads input or stops.
+///
+/// # Panics
+/// If the program has more than `i32::MAX` instructions.
+pub fn generate_elf(
+    program: &CompressedBF,
+    options: &CodegenOptions,
+) -> Result<Vec<u8>, ParseError> {
+    let width = options.cell_width.bits() as usize / 8;
+    let asm = assemble(program, options, Assembler::new(width))?;
+    Ok(executable(&asm.code))
+}
+
+//emits the code of the executable into `asm`, starting with `_start`, then the out of line paths of the ops, the routines and the data they use
+pub(super) fn assemble(
+    program: &CompressedBF,
+    options: &CodegenOptions,
+    mut asm: Assembler,
+) -> Result<Assembler, ParseError> {
+    let compiled = compile(program, options)?;
+    let helpers = Helpers::of(&compiled);
+    let source = program.to_string();
+    let width = asm.width();
+
+    asm.label(Label::Named("_start"));
+    asm.comment(format_args!(
+        "the buffers: {} bytes of output, {} bytes of input, and the read position and end of the input",
+        BUFFER_SIZE, BUFFER_SIZE
+    ));
+    asm.ins(format_args!("sub rsp, {}", FRAME_SIZE), &[0x48, 0x81, 0xEC]);
+    asm.emit_i32(FRAME_SIZE);
+    asm.ins(format_args!("mov rbp, rsp"), &[0x48, 0x89, 0xE5]);
+    asm.ins(format_args!("xor r15d, r15d"), &[0x45, 0x31, 0xFF]);
+    for field in [INPUT_POS, INPUT_END] {
+        asm.ins(
+            format_args!("mov qword ptr [rbp + {}], 0", field),
+            &[0x48, 0xC7, 0x85],
+        );
+        asm.emit_i32(field);
+        asm.emit_i32(0);
+    }
+    let bytes = options.tape_size as u64 * width as u64;
+    asm.comment(format_args!(
+        "the tape, mmap(NULL, {}, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0)",
+        bytes
+    ));
+    asm.ins(
+        format_args!("mov eax, {}", SYS_MMAP),
+        &[0xB8, SYS_MMAP, 0, 0, 0],
+    );
+    asm.ins(format_args!("xor edi, edi"), &[0x31, 0xFF]);
+    asm.ins(format_args!("movabs rsi, {}", bytes), &[0x48, 0xBE]);
+    asm.emit(&bytes.to_le_bytes());
+    asm.ins(format_args!("mov edx, 3"), &[0xBA, 3, 0, 0, 0]);
+    asm.ins(format_args!("mov r10d, 0x22"), &[0x41, 0xBA, 0x22, 0, 0, 0]);
+    asm.ins(
+        format_args!("mov r8, -1"),
+        &[0x49, 0xC7, 0xC0, 0xFF, 0xFF, 0xFF, 0xFF],
+    );
+    asm.ins(format_args!("xor r9d, r9d"), &[0x45, 0x31, 0xC9]);
+    asm.ins(format_args!("syscall"), &[0x0F, 0x05]);
+    asm.ins(
+        format_args!("cmp rax, -4096"),
+        &[0x48, 0x3D, 0x00, 0xF0, 0xFF, 0xFF],
+    );
+    asm.jump(&[0x0F, 0x87], Label::Named("no_tape"));
+    asm.ins(format_args!("mov rbx, rax"), &[0x48, 0x89, 0xC3]);
+    asm.ins(format_args!("xor r12d, r12d"), &[0x45, 0x31, 0xE4]);
+    asm.ins(
+        format_args!("movabs r13, {}", options.tape_size),
+        &[0x49, 0xBD],
+    );
+    asm.emit(&(options.tape_size as u64).to_le_bytes());
+    asm.ins(format_args!("xor r14d, r14d"), &[0x45, 0x31, 0xF6]);
+
+    let ops = compiled.ops();
+    // the ops something jumps to, which get a label in the listing
+    let mut targets = BTreeSet::new();
+    for (ip, compiled_op) in ops.iter().enumerate() {
+        match compiled_op.op {
+            Op::JumpIfZero(Some(target)) | Op::JumpIfNonZero(target) => {
+                targets.insert(target);
+            }
+            Op::JumpIfZero(None) | Op::Move(_) | Op::MulMove { .. } => {
+                targets.insert(ip + 1);
+            }
+            Op::Scan(_) => {
+                targets.insert(ip);
+                targets.insert(ip + 1);
+            }
+            _ => {}
+        }
+    }
+    let mut op_starts = Vec::with_capacity(ops.len() + 1);
+    for (ip, compiled_op) in ops.iter().enumerate() {
+        op_starts.push(asm.code.len());
+        if targets.contains(&ip) {
+            asm.label(Label::Op(ip));
+        }
+        let CompiledOp { pc, len, .. } = *compiled_op;
+        asm.comment(format_args!(
+            "{}..{}: {}",
+            pc,
+            pc + len,
+            &source[pc..pc + len]
+        ));
+        match &compiled_op.op {
+            Op::Add(amount) => asm.add_run(*amount, pc, options.overflow),
+            Op::Move(distance) => asm.move_head(imm32(*distance), Label::Bail(ip)),
+            Op::SetZero => asm.set_zero(),
+            Op::MulMove { targets, reach } => {
+                let targets: Vec<(i32, i32)> = targets
+                    .iter()
+                    .map(|&(offset, factor)| (imm32(offset), factor as i32))
+                    .collect();
+                asm.mul_move(&targets, (imm32(reach.0), imm32(reach.1)), ip);
+            }
+            Op::Scan(stride) => asm.scan(imm32(*stride), ip),
+            Op::Output => asm.jump(&[0xE8], Label::Named("output")),
+            Op::Input => {
+                asm.mov_esi(pc);
+                asm.jump(&[0xE8], Label::Named("input"));
+            }
+            Op::JumpIfZero(target) => {
+                asm.compare_zero();
+                match target {
+                    Some(target) => asm.jump(&[0x0F, 0x84], Label::Op(*target)),
+                    None => {
+                        asm.jump(&[0x0F, 0x85], Label::Op(ip + 1));
+                        asm.mov_esi(pc);
+                        asm.jump(&[0xE9], Label::Named("fail_unclosed_loop"));
+                    }
+                }
+            }
+            Op::JumpIfNonZero(target) => {
+                asm.compare_zero();
+                asm.jump(&[0x0F, 0x85], Label::Op(*target));
+            }
+        }
+    }
+    op_starts.push(asm.code.len());
+    if targets.contains(&ops.len()) {
+        asm.label(Label::Op(ops.len()));
+    }
+    asm.comment(format_args!("the end of the program"));
+    if helpers.output {
+        asm.jump(&[0xE8], Label::Named("flush"));
+    }
+    asm.ins(format_args!("xor edi, edi"), &[0x31, 0xFF]);
+    asm.jump(&[0xE9], Label::Named("exit"));
+
+    let mut stubs = HashMap::new();
+    for (_, label) in asm.fixups.clone() {
+        let Label::Bail(ip) = label else {
+            continue;
+        };
+        if stubs.contains_key(&label) {
+            continue;
+        }
+        stubs.insert(label, asm.code.len());
+        emit_slow_path(&mut asm, &compiled, &source, ip, options.overflow);
+    }
+
+    // emit the routines the code calls, which can call more of them, and then the data they all use
+    let mut named = HashMap::new();
+    while let Some(name) = asm.fixups.iter().find_map(|&(_, label)| match label {
+        Label::Named(name) if data(name).is_none() && !named.contains_key(name) => Some(name),
+        _ => None,
+    }) {
+        named.insert(name, asm.code.len());
+        asm.routine(name, options, helpers.output);
+    }
+    let mut messages = Vec::new();
+    for &(_, label) in &asm.fixups {
+        if let Label::Named(name) = label
+            && !named.contains_key(name)
+            && !messages.contains(&name)
+        {
+            messages.push(name);
+        }
+    }
+    if !messages.is_empty() {
+        asm.line(format_args!(""));
+        asm.line(format_args!("    .section .rodata"));
+    }
+    for name in messages {
+        named.insert(name, asm.code.len());
+        asm.data(name);
+    }
+
+    asm.resolve(|label| match label {
+        Label::Op(ip) => op_starts.get(ip).copied(),
+        Label::Bail(_) => stubs.get(&label).copied(),
+        Label::Named(name) => named.get(name).copied(),
+        Label::Status(_) => None,
+    })
+    .expect("the code of programs shorter than i32::MAX instructions is in reach of rel32 jumps");
+    Ok(asm)
+}
+
+fn imm32(value: isize) -> i32 {
+    i32::try_from(value).expect("programs are shorter than i32::MAX instructions")
+}
+
+//the routine that reports `kind`, and the data holding its message
+fn failure(kind: RunErrorKind) -> (&'static str, &'static str) {
+    match kind {
+        RunErrorKind::TapeHeadBound => ("fail_tape_head_bound", "tape_head_bound"),
+        RunErrorKind::OutOfMemory => ("fail_out_of_memory", "out_of_memory"),
+        RunErrorKind::InputExhausted => ("fail_input_exhausted", "input_exhausted"),
+        RunErrorKind::CellOverflow => ("fail_cell_overflow", "cell_overflow"),
+        RunErrorKind::UnclosedLoop => ("fail_unclosed_loop", "unclosed_loop"),
+        kind => unreachable!("generated programs do not stop with {:?}", kind),
+    }
+}
+
+//the bytes of the data named `name`, `None` for a routine
+fn data(name: &str) -> Option<Vec<u8>> {
+    let text = match name {
+        "error_prefix" => "Error: ".to_string(),
+        "at_instruction" => " at instruction ".to_string(),
+        "tape_head" => ", tape head ".to_string(),
+        "cell_value" => ", cell value ".to_string(),
+        "minus" => "-".to_string(),
+        "newline" => "\n".to_string(),
+        "no_tape_message" => "Error: could not allocate the tape\n".to_string(),
+        "tape_head_bound" => RunErrorKind::TapeHeadBound.to_string(),
+        "out_of_memory" => RunErrorKind::OutOfMemory.to_string(),
+        "input_exhausted" => RunErrorKind::InputExhausted.to_string(),
+        "cell_overflow" => RunErrorKind::CellOverflow.to_string(),
+        "unclosed_loop" => RunErrorKind::UnclosedLoop.to_string(),
+        _ => return None,
+    };
+    Some(text.into_bytes())
+}
+
+//the code the op with index `ip` jumps to when the tape is too small for its fast path
+fn emit_slow_path(
+    asm: &mut Assembler,
+    compiled: &CompiledProgram,
+    source: &str,
+    ip: usize,
+    overflow: OverflowMode,
+) {
+    let compiled_op = &compiled.ops()[ip];
+    asm.label(Label::Bail(ip));
+    match compiled_op.op {
+        Op::Move(distance) => {
+            asm.call_move(distance, compiled_op.pc);
+            asm.jump(&[0xE9], Label::Op(ip + 1));
+        }
+        Op::Scan(stride) => {
+            asm.call_move(stride, compiled_op.pc + 1);
+            asm.jump(&[0xE9], Label::Op(ip));
+        }
+        // the tape has to grow or the head leaves it, so run the loop as written
+        Op::MulMove { .. } => {
+            let CompiledOp { pc, len, .. } = *compiled_op;
+            let text = &source[pc..pc + len];
+            asm.comment(format_args!("{}..{}: {}, as written", pc, pc + len, text));
+            let top = asm.mark();
+            asm.compare_zero();
+            asm.jump(&[0x0F, 0x84], Label::Op(ip + 1));
+            for (instruction, start, count) in runs(&text[1..text.len() - 1]) {
+                let pc = pc + 1 + start;
+                let count = count as isize;
+                match instruction {
+                    b'+' => asm.add_run(count as i64, pc, overflow),
+                    b'-' => asm.add_run(-count as i64, pc, overflow),
+                    b'>' => asm.call_move(count, pc),
+                    b'<' => asm.call_move(-count, pc),
+                    other => unreachable!("{} in the body of a loop idiom", other as char),
+                }
+            }
+            asm.jump_back(&[0xE9], top);
+        }
+        ref op => unreachable!("{:?} has no slow path", op),
+    }
+}
+
+impl Assembler {
+    fn mov_esi(&mut self, value: usize) {
+        let value = imm32(value as isize);
+        self.ins(format_args!("mov esi, {}", value), &[0xBE]);
+        self.emit_i32(value);
+    }
+
+    fn mov_rdx(&mut self, value: u64) {
+        match u32::try_from(value) {
+            Ok(value) => {
+                self.ins(format_args!("mov edx, {}", value), &[0xBA]);
+                self.emit(&value.to_le_bytes());
+            }
+            Err(_) => {
+                self.ins(format_args!("movabs rdx, {}", value), &[0x48, 0xBA]);
+                self.emit(&value.to_le_bytes());
+            }
+        }
+    }
+
+    //lea rsi, [data]; mov edx, its length
+    fn load_data(&mut self, name: &'static str) {
+        let len = data(name).expect("a name of data").len();
+        self.jump(&[0x48, 0x8D, 0x35], Label::Named(name));
+        self.mov_rdx(len as u64);
+    }
+
+    //the data named `name`, as an `.ascii` directive in the listing
+    fn data(&mut self, name: &'static str) {
+        let bytes = data(name).expect("a name of data");
+        let text = String::from_utf8(bytes).expect("messages are ASCII");
+        self.label(Label::Named(name));
+        self.line(format_args!("    .ascii \"{}\"", text.escape_default()));
+        self.emit(text.as_bytes());
+    }
+
+    fn store_max(&mut self) {
+        let cell = self.cell_operand(0);
+        self.line(format_args!("    mov {}, {}", cell, cell_max(self.width())));
+        match self.width() {
+            1 => {
+                self.cell(false, 0, &[0xC6], 0, 0);
+                self.emit(&[0xFF]);
+            }
+            2 => {
+                self.cell(true, 0, &[0xC7], 0, 0);
+                self.emit(&[0xFF; 2]);
+            }
+            _ => {
+                self.cell(false, 0, &[0xC7], 0, 0);
+                self.emit(&[0xFF; 4]);
+            }
+        }
+    }
+
+    //emits `mnemonic` on the current cell and the low part of edx, `opcode8` for byte cells and `opcode` for wider ones
+    fn cell_edx(&mut self, mnemonic: &str, opcode8: u8, opcode: u8) {
+        let cell = self.cell_operand(0);
+        let edx = ["dl", "dx", "edx"][self.width().trailing_zeros() as usize];
+        self.line(format_args!("    {} {}, {}", mnemonic, cell, edx));
+        match self.width() {
+            1 => self.cell(false, 0, &[opcode8], 2, 0),
+            2 => self.cell(true, 0, &[opcode], 2, 0),
+            _ => self.cell(false, 0, &[opcode], 2, 0),
+        }
+    }
+
+    //`+` or `-` run starting at instruction `pc` that changes the cell by `amount`
+    fn add_run(&mut self, amount: i64, pc: usize, overflow: OverflowMode) {
+        let routine = match (overflow, amount > 0) {
+            (OverflowMode::Wrap, _) => return self.add(amount),
+            (OverflowMode::Saturate, true) => "add_saturating",
+            (OverflowMode::Saturate, false) => "sub_saturating",
+            (OverflowMode::Error, true) => "add_checked",
+            (OverflowMode::Error, false) => "sub_checked",
+        };
+        self.mov_esi(pc);
+        self.mov_rdx(amount.unsigned_abs());
+        self.jump(&[0xE8], Label::Named(routine));
+    }
+
+    //calls the routine that moves the head `distance` cells for the run starting at instruction `pc`
+    fn call_move(&mut self, distance: isize, pc: usize) {
+        self.mov_esi(pc);
+        self.mov_rdx(distance.unsigned_abs() as u64);
+        let routine = if distance > 0 {
+            "move_right"
+        } else {
+            "move_left"
+        };
+        self.jump(&[0xE8], Label::Named(routine));
+    }
+
+    //shl `register`, by the log2 of the cell width, to turn cells into bytes
+    fn shift_cells(&mut self, register: &str, modrm: u8) {
+        match self.width().trailing_zeros() as u8 {
+            0 => {}
+            1 => self.ins(format_args!("shl {}, 1", register), &[0x48, 0xD1, modrm]),
+            shift => self.ins(
+                format_args!("shl {}, {}", register, shift),
+                &[0x48, 0xC1, modrm, shift],
+            ),
+        }
+    }
+
+    //mremap(rbx, r13 cells, rdx cells, MREMAP_MAYMOVE), leaving the new address in rax
+    fn remap(&mut self) {
+        self.ins(format_args!("mov rdi, rbx"), &[0x48, 0x89, 0xDF]);
+        self.ins(format_args!("mov rsi, r13"), &[0x4C, 0x89, 0xEE]);
+        self.shift_cells("rsi", 0xE6);
+        self.shift_cells("rdx", 0xE2);
+        self.ins(
+            format_args!("mov r10d, 1 # MREMAP_MAYMOVE"),
+            &[0x41, 0xBA, 1, 0, 0, 0],
+        );
+        self.ins(
+            format_args!("mov eax, {} # mremap", SYS_MREMAP),
+            &[0xB8, SYS_MREMAP, 0, 0, 0],
+        );
+        self.ins(format_args!("syscall"), &[0x0F, 0x05]);
+    }
+
+    //the blank line, the comment saying what the routine `name` does and its label
+    fn start_routine(&mut self, name: &'static str, doc: fmt::Arguments) {
+        self.line(format_args!(""));
+        self.line(format_args!("# {}", doc));
+        self.label(Label::Named(name));
+    }
+
+    //push rsi; push rdx; call `grow`; pop rdx; pop rsi; test eax, eax; jnz `top`
+    fn call_grow(&mut self, grow: &'static str, top: Mark) {
+        self.ins(format_args!("push rsi"), &[0x56]);
+        self.ins(format_args!("push rdx"), &[0x52]);
+        self.jump(&[0xE8], Label::Named(grow));
+        self.ins(format_args!("pop rdx"), &[0x5A]);
+        self.ins(format_args!("pop rsi"), &[0x5E]);
+        self.ins(format_args!("test eax, eax"), &[0x85, 0xC0]);
+        self.jump_back(&[0x0F, 0x85], top);
+    }
+
+    //cmp rax, -4096, the largest error a system call returns, and ja to the returned jump
+    fn check_syscall(&mut self) -> Forward {
+        self.ins(
+            format_args!("cmp rax, -4096"),
+            &[0x48, 0x3D, 0x00, 0xF0, 0xFF, 0xFF],
+        );
+        self.short(0x77)
+    }
+
+    /// Emits the routine `name`.
+    ///
+    /// Routines take the instruction they run for in `rsi` and an amount in `rdx`, and may change every register but the ones the [`Assembler`] keeps state in, `rbp` (the buffers), `r14` (the cell the program started on) and `r15` (the bytes in the output buffer).
+    fn routine(&mut self, name: &'static str, options: &CodegenOptions, flushes: bool) {
+        let max_cells = match options.tape {
+            TapePolicy::Growable { max_cells, .. } => max_cells as u64,
+            TapePolicy::Fixed => options.tape_size as u64,
+        };
+        let ret = |asm: &mut Assembler| asm.ins(format_args!("ret"), &[0xC3]);
+        match name {
+            "exit" => {
+                self.start_routine(
+                    name,
+                    format_args!("Stops the program with the exit code in edi."),
+                );
+                self.ins(
+                    format_args!("mov eax, {} # exit_group", SYS_EXIT_GROUP),
+                    &[0xB8, SYS_EXIT_GROUP, 0, 0, 0],
+                );
+                self.ins(format_args!("syscall"), &[0x0F, 0x05]);
+            }
+            "no_tape" => {
+                self.start_routine(
+                    name,
+                    format_args!("Reports that there is no memory for the tape."),
+                );
+                self.load_data("no_tape_message");
+                self.jump(&[0xE8], Label::Named("write_error"));
+                self.ins(format_args!("mov edi, 1"), &[0xBF, 1, 0, 0, 0]);
+                self.jump(&[0xE9], Label::Named("exit"));
+            }
+            "flush" => {
+                self.start_routine(
+                    name,
+                    format_args!(
+                        "Writes the output buffer to stdout, stopping with exit code 1 when stdout is gone."
+                    ),
+                );
+                self.ins(format_args!("mov rsi, rbp"), &[0x48, 0x89, 0xEE]);
+                self.ins(format_args!("mov rdx, r15"), &[0x4C, 0x89, 0xFA]);
+                let top = self.mark();
+                self.ins(format_args!("test rdx, rdx"), &[0x48, 0x85, 0xD2]);
+                let done = self.short(0x74);
+                self.ins(
+                    format_args!("mov eax, {} # write", SYS_WRITE),
+                    &[0xB8, SYS_WRITE, 0, 0, 0],
+                );
+                self.ins(format_args!("mov edi, 1"), &[0xBF, 1, 0, 0, 0]);
+                self.ins(format_args!("syscall"), &[0x0F, 0x05]);
+                self.ins(format_args!("test rax, rax"), &[0x48, 0x85, 0xC0]);
+                let broken = self.short(0x7E);
+                self.ins(format_args!("add rsi, rax"), &[0x48, 0x01, 0xC6]);
+                self.ins(format_args!("sub rdx, rax"), &[0x48, 0x29, 0xC2]);
+                self.jump_back(&[0xEB], top);
+                self.land(done);
+                self.ins(format_args!("xor r15d, r15d"), &[0x45, 0x31, 0xFF]);
+                ret(self);
+                self.land(broken);
+                self.ins(format_args!("mov edi, 1"), &[0xBF, 1, 0, 0, 0]);
+                self.jump(&[0xE9], Label::Named("exit"));
+            }
+            "output" => {
+                self.start_routine(
+                    name,
+                    format_args!(
+                        "`.`: appends the current cell to the output buffer, writing the buffer out when it is full."
+                    ),
+                );
+                let cell = self.cell_address(0);
+                self.line(format_args!("    mov al, byte ptr {}", cell));
+                self.cell(false, 0, &[0x8A], 0, 0);
+                self.ins(
+                    format_args!("mov byte ptr [rbp + r15], al"),
+                    &[0x42, 0x88, 0x44, 0x3D, 0x00],
+                );
+                self.ins(format_args!("inc r15"), &[0x49, 0xFF, 0xC7]);
+                self.ins(
+                    format_args!("cmp r15, {}", BUFFER_SIZE),
+                    &[0x49, 0x81, 0xFF],
+                );
+                self.emit_i32(BUFFER_SIZE);
+                self.jump(&[0x0F, 0x83], Label::Named("flush"));
+                ret(self);
+            }
+            "input" => {
+                self.start_routine(
+                    name,
+                    format_args!(
+                        "`,` for the instruction in esi: stores the next byte of stdin, writing out the output first so that prompts show up before the program waits."
+                    ),
+                );
+                self.ins(format_args!("push rsi"), &[0x56]);
+                if flushes {
+                    self.ins(format_args!("test r15, r15"), &[0x4D, 0x85, 0xFF]);
+                    let flushed = self.short(0x74);
+                    self.jump(&[0xE8], Label::Named("flush"));
+                    self.land(flushed);
+                }
+                self.ins(
+                    format_args!("mov rax, qword ptr [rbp + {}]", INPUT_POS),
+                    &[0x48, 0x8B, 0x85],
+                );
+                self.emit_i32(INPUT_POS);
+                self.ins(
+                    format_args!("cmp rax, qword ptr [rbp + {}]", INPUT_END),
+                    &[0x48, 0x3B, 0x85],
+                );
+                self.emit_i32(INPUT_END);
+                let have = self.short(0x72);
+                self.ins(
+                    format_args!("mov eax, {} # read", SYS_READ),
+                    &[0xB8, SYS_READ, 0, 0, 0],
+                );
+                self.ins(format_args!("xor edi, edi"), &[0x31, 0xFF]);
+                self.ins(
+                    format_args!("lea rsi, [rbp + {}]", INPUT_BUFFER),
+                    &[0x48, 0x8D, 0xB5],
+                );
+                self.emit_i32(INPUT_BUFFER);
+                self.ins(format_args!("mov edx, {}", BUFFER_SIZE), &[0xBA]);
+                self.emit_i32(BUFFER_SIZE);
+                self.ins(format_args!("syscall"), &[0x0F, 0x05]);
+                self.ins(format_args!("test rax, rax"), &[0x48, 0x85, 0xC0]);
+                let eof = self.short(0x7E);
+                self.ins(
+                    format_args!("mov qword ptr [rbp + {}], rax", INPUT_END),
+                    &[0x48, 0x89, 0x85],
+                );
+                self.emit_i32(INPUT_END);
+                self.ins(format_args!("xor eax, eax"), &[0x31, 0xC0]);
+                self.land(have);
+                self.ins(
+                    format_args!("movzx ecx, byte ptr [rbp + rax + {}]", INPUT_BUFFER),
+                    &[0x0F, 0xB6, 0x8C, 0x05],
+                );
+                self.emit_i32(INPUT_BUFFER);
+                self.ins(format_args!("inc rax"), &[0x48, 0xFF, 0xC0]);
+                self.ins(
+                    format_args!("mov qword ptr [rbp + {}], rax", INPUT_POS),
+                    &[0x48, 0x89, 0x85],
+                );
+                self.emit_i32(INPUT_POS);
+                let cell = self.cell_operand(0);
+                let ecx = ["cl", "cx", "ecx"][self.width().trailing_zeros() as usize];
+                self.line(format_args!("    mov {}, {}", cell, ecx));
+                match self.width() {
+                    1 => self.cell(false, 0, &[0x88], 1, 0),
+                    2 => self.cell(true, 0, &[0x89], 1, 0),
+                    _ => self.cell(false, 0, &[0x89], 1, 0),
+                }
+                self.ins(format_args!("pop rsi"), &[0x5E]);
+                ret(self);
+                self.land(eof);
+                self.ins(format_args!("pop rsi"), &[0x5E]);
+                match options.eof {
+                    EofBehavior::Error => {
+                        let (routine, _) = failure(RunErrorKind::InputExhausted);
+                        self.jump(&[0xE9], Label::Named(routine));
+                    }
+                    EofBehavior::Zero => {
+                        self.set_zero();
+                        ret(self);
+                    }
+                    EofBehavior::Max => {
+                        self.store_max();
+                        ret(self);
+                    }
+                    EofBehavior::Unchanged => ret(self),
+                }
+            }
+            "move_right" => {
+                self.start_routine(
+                    name,
+                    format_args!(
+                        "Moves the head right by rdx cells for the `>` run starting at the instruction in esi, failing at the `>` that leaves the tape."
+                    ),
+                );
+                let top = self.mark();
+                let room = |asm: &mut Assembler| {
+                    asm.ins(format_args!("mov rax, r13"), &[0x4C, 0x89, 0xE8]);
+                    asm.ins(format_args!("sub rax, r12"), &[0x4C, 0x29, 0xE0]);
+                    asm.ins(format_args!("dec rax"), &[0x48, 0xFF, 0xC8]);
+                };
+                room(self);
+                self.ins(format_args!("cmp rax, rdx"), &[0x48, 0x39, 0xD0]);
+                let ok = self.short(0x73);
+                if let TapePolicy::Growable { .. } = options.tape {
+                    self.call_grow("grow_right", top);
+                    // the tape could have moved, so recompute the room left
+                    room(self);
+                }
+                self.ins(format_args!("add rsi, rax"), &[0x48, 0x01, 0xC6]);
+                self.ins(
+                    format_args!("lea r12, [r13 - 1]"),
+                    &[0x4D, 0x8D, 0x65, 0xFF],
+                );
+                let (routine, _) = failure(RunErrorKind::OutOfMemory);
+                self.jump(&[0xE9], Label::Named(routine));
+                self.land(ok);
+                self.ins(format_args!("add r12, rdx"), &[0x49, 0x01, 0xD4]);
+                ret(self);
+            }
+            "move_left" => {
+                self.start_routine(
+                    name,
+                    format_args!(
+                        "Moves the head left by rdx cells for the `<` run starting at the instruction in esi, failing at the `<` that leaves the tape."
+                    ),
+                );
+                let top = self.mark();
+                self.ins(format_args!("cmp r12, rdx"), &[0x49, 0x39, 0xD4]);
+                let ok = self.short(0x73);
+                let kind = match options.tape {
+                    TapePolicy::Growable {
+                        grow_left: true, ..
+                    } => {
+                        self.call_grow("grow_left", top);
+                        RunErrorKind::OutOfMemory
+                    }
+                    _ => RunErrorKind::TapeHeadBound,
+                };
+                self.ins(format_args!("add rsi, r12"), &[0x4C, 0x01, 0xE6]);
+                self.ins(format_args!("xor r12d, r12d"), &[0x45, 0x31, 0xE4]);
+                let (routine, _) = failure(kind);
+                self.jump(&[0xE9], Label::Named(routine));
+                self.land(ok);
+                self.ins(format_args!("sub r12, rdx"), &[0x49, 0x29, 0xD4]);
+                ret(self);
+            }
+            "grow_right" => {
+                self.start_routine(
+                    name,
+                    format_args!(
+                        "Grows the tape to the right, doubling it up to {} cells; eax is 1 when it grew.",
+                        max_cells
+                    ),
+                );
+                self.ins(format_args!("movabs rcx, {}", max_cells), &[0x48, 0xB9]);
+                self.emit(&max_cells.to_le_bytes());
+                self.ins(format_args!("cmp r13, rcx"), &[0x49, 0x39, 0xCD]);
+                let full = self.short(0x73);
+                self.ins(
+                    format_args!("lea rdx, [r13 + r13]"),
+                    &[0x4B, 0x8D, 0x54, 0x2D, 0x00],
+                );
+                self.ins(format_args!("cmp rdx, rcx"), &[0x48, 0x39, 0xCA]);
+                let doubled = self.short(0x76);
+                self.ins(format_args!("mov rdx, rcx"), &[0x48, 0x89, 0xCA]);
+                self.land(doubled);
+                self.ins(format_args!("push rdx"), &[0x52]);
+                self.remap();
+                self.ins(format_args!("pop rdx"), &[0x5A]);
+                let failed = self.check_syscall();
+                self.ins(format_args!("mov rbx, rax"), &[0x48, 0x89, 0xC3]);
+                self.ins(format_args!("mov r13, rdx"), &[0x49, 0x89, 0xD5]);
+                self.ins(format_args!("mov eax, 1"), &[0xB8, 1, 0, 0, 0]);
+                ret(self);
+                self.land(full);
+                self.land(failed);
+                self.ins(format_args!("xor eax, eax"), &[0x31, 0xC0]);
+                ret(self);
+            }
+            "grow_left" => {
+                self.start_routine(
+                    name,
+                    format_args!(
+                        "Grows the tape to the left by as many cells as it has, up to {} cells; eax is 1 when it grew.",
+                        max_cells
+                    ),
+                );
+                self.ins(format_args!("movabs rcx, {}", max_cells), &[0x48, 0xB9]);
+                self.emit(&max_cells.to_le_bytes());
+                self.ins(format_args!("cmp r13, rcx"), &[0x49, 0x39, 0xCD]);
+                let full = self.short(0x73);
+                self.comment(format_args!("the cells to add"));
+                self.ins(format_args!("sub rcx, r13"), &[0x4C, 0x29, 0xE9]);
+                self.ins(format_args!("cmp rcx, r13"), &[0x4C, 0x39, 0xE9]);
+                let limited = self.short(0x76);
+                self.ins(format_args!("mov rcx, r13"), &[0x4C, 0x89, 0xE9]);
+                self.land(limited);
+                self.ins(format_args!("push rcx"), &[0x51]);
+                self.ins(
+                    format_args!("lea rdx, [r13 + rcx]"),
+                    &[0x49, 0x8D, 0x54, 0x0D, 0x00],
+                );
+                self.remap();
+                self.ins(format_args!("pop rcx"), &[0x59]);
+                let failed = self.check_syscall();
+                self.ins(format_args!("mov rbx, rax"), &[0x48, 0x89, 0xC3]);
+                self.comment(format_args!("copy the cells up, from the last byte down"));
+                self.ins(format_args!("std"), &[0xFD]);
+                self.ins(format_args!("mov rsi, r13"), &[0x4C, 0x89, 0xEE]);
+                self.shift_cells("rsi", 0xE6);
+                self.ins(
+                    format_args!("lea rsi, [rbx + rsi - 1]"),
+                    &[0x48, 0x8D, 0x74, 0x33, 0xFF],
+                );
+                self.ins(format_args!("mov rdi, rcx"), &[0x48, 0x89, 0xCF]);
+                self.shift_cells("rdi", 0xE7);
+                self.ins(format_args!("add rdi, rsi"), &[0x48, 0x01, 0xF7]);
+                self.ins(format_args!("push rcx"), &[0x51]);
+                self.ins(format_args!("mov rcx, r13"), &[0x4C, 0x89, 0xE9]);
+                self.shift_cells("rcx", 0xE1);
+                self.ins(format_args!("rep movsb"), &[0xF3, 0xA4]);
+                self.ins(format_args!("cld"), &[0xFC]);
+                self.ins(format_args!("pop rcx"), &[0x59]);
+                self.comment(format_args!("clear the new cells"));
+                self.ins(format_args!("push rcx"), &[0x51]);
+                self.ins(format_args!("mov rdi, rbx"), &[0x48, 0x89, 0xDF]);
+                self.shift_cells("rcx", 0xE1);
+                self.ins(format_args!("xor eax, eax"), &[0x31, 0xC0]);
+                self.ins(format_args!("rep stosb"), &[0xF3, 0xAA]);
+                self.ins(format_args!("pop rcx"), &[0x59]);
+                self.ins(format_args!("add r13, rcx"), &[0x49, 0x01, 0xCD]);
+                self.ins(format_args!("add r12, rcx"), &[0x49, 0x01, 0xCC]);
+                self.ins(format_args!("add r14, rcx"), &[0x49, 0x01, 0xCE]);
+                self.ins(format_args!("mov eax, 1"), &[0xB8, 1, 0, 0, 0]);
+                ret(self);
+                self.land(full);
+                self.land(failed);
+                self.ins(format_args!("xor eax, eax"), &[0x31, 0xC0]);
+                ret(self);
+            }
+            "add_saturating" | "add_checked" => {
+                let what = if name == "add_checked" {
+                    "failing at the `+` that overflows"
+                } else {
+                    "stopping at the largest value"
+                };
+                self.start_routine(
+                    name,
+                    format_args!(
+                        "Adds rdx to the current cell for the `+` run starting at the instruction in esi, {}.",
+                        what
+                    ),
+                );
+                let max = cell_max(self.width());
+                self.load_cell();
+                self.ins(format_args!("mov ecx, {}", max), &[0xB9]);
+                self.emit(&(max as u32).to_le_bytes());
+                self.ins(format_args!("sub rcx, rax"), &[0x48, 0x29, 0xC1]);
+                self.ins(format_args!("cmp rcx, rdx"), &[0x48, 0x39, 0xD1]);
+                let overflow = self.short(0x72);
+                self.cell_edx("add", 0x00, 0x01);
+                ret(self);
+                self.land(overflow);
+                self.store_max();
+                if name == "add_checked" {
+                    self.ins(format_args!("add rsi, rcx"), &[0x48, 0x01, 0xCE]);
+                    let (routine, _) = failure(RunErrorKind::CellOverflow);
+                    self.jump(&[0xE9], Label::Named(routine));
+                } else {
+                    ret(self);
+                }
+            }
+            "sub_saturating" | "sub_checked" => {
+                let what = if name == "sub_checked" {
+                    "failing at the `-` that overflows"
+                } else {
+                    "stopping at 0"
+                };
+                self.start_routine(
+                    name,
+                    format_args!(
+                        "Subtracts rdx from the current cell for the `-` run starting at the instruction in esi, {}.",
+                        what
+                    ),
+                );
+                self.load_cell();
+                self.ins(format_args!("cmp rax, rdx"), &[0x48, 0x39, 0xD0]);
+                let overflow = self.short(0x72);
+                self.cell_edx("sub", 0x28, 0x29);
+                ret(self);
+                self.land(overflow);
+                self.set_zero();
+                if name == "sub_checked" {
+                    self.ins(format_args!("add rsi, rax"), &[0x48, 0x01, 0xC6]);
+                    let (routine, _) = failure(RunErrorKind::CellOverflow);
+                    self.jump(&[0xE9], Label::Named(routine));
+                } else {
+                    ret(self);
+                }
+            }
+            "write_error" => {
+                self.start_routine(name, format_args!("Writes rdx bytes at rsi to stderr."));
+                self.ins(
+                    format_args!("mov eax, {} # write", SYS_WRITE),
+                    &[0xB8, SYS_WRITE, 0, 0, 0],
+                );
+                self.ins(format_args!("mov edi, 2"), &[0xBF, 2, 0, 0, 0]);
+                self.ins(format_args!("syscall"), &[0x0F, 0x05]);
+                ret(self);
+            }
+            "write_signed" => {
+                self.start_routine(
+                    name,
+                    format_args!("Writes rax as a signed number in decimal to stderr."),
+                );
+                self.ins(format_args!("test rax, rax"), &[0x48, 0x85, 0xC0]);
+                self.jump(&[0x0F, 0x89], Label::Named("write_unsigned"));
+                self.ins(format_args!("push rax"), &[0x50]);
+                self.load_data("minus");
+                self.jump(&[0xE8], Label::Named("write_error"));
+                self.ins(format_args!("pop rax"), &[0x58]);
+                self.ins(format_args!("neg rax"), &[0x48, 0xF7, 0xD8]);
+                self.jump(&[0xE9], Label::Named("write_unsigned"));
+            }
+            "write_unsigned" => {
+                self.start_routine(
+                    name,
+                    format_args!(
+                        "Writes rax in decimal to stderr, building the digits backwards at the end of the input buffer, which is not read any more."
+                    ),
+                );
+                self.ins(
+                    format_args!("lea rsi, [rbp + {}]", INPUT_POS),
+                    &[0x48, 0x8D, 0xB5],
+                );
+                self.emit_i32(INPUT_POS);
+                self.ins(format_args!("mov ecx, 10"), &[0xB9, 10, 0, 0, 0]);
+                let top = self.mark();
+                self.ins(format_args!("xor edx, edx"), &[0x31, 0xD2]);
+                self.ins(format_args!("div rcx"), &[0x48, 0xF7, 0xF1]);
+                self.ins(format_args!("add dl, '0'"), &[0x80, 0xC2, b'0']);
+                self.ins(format_args!("dec rsi"), &[0x48, 0xFF, 0xCE]);
+                self.ins(format_args!("mov byte ptr [rsi], dl"), &[0x88, 0x16]);
+                self.ins(format_args!("test rax, rax"), &[0x48, 0x85, 0xC0]);
+                self.jump_back(&[0x75], top);
+                self.ins(
+                    format_args!("lea rdx, [rbp + {}]", INPUT_POS),
+                    &[0x48, 0x8D, 0x95],
+                );
+                self.emit_i32(INPUT_POS);
+                self.ins(format_args!("sub rdx, rsi"), &[0x48, 0x29, 0xF2]);
+                self.jump(&[0xE9], Label::Named("write_error"));
+            }
+            "fail" => {
+                self.start_routine(
+                    name,
+                    format_args!(
+                        "Prints the error `brainfuck-main run` would print for the message of rdx bytes at rdi and the instruction in esi, and stops with the exit code in ecx."
+                    ),
+                );
+                self.ins(format_args!("push rcx"), &[0x51]);
+                self.ins(format_args!("push rsi"), &[0x56]);
+                self.ins(format_args!("push rdi"), &[0x57]);
+                self.ins(format_args!("push rdx"), &[0x52]);
+                if flushes {
+                    self.jump(&[0xE8], Label::Named("flush"));
+                }
+                self.load_data("error_prefix");
+                self.jump(&[0xE8], Label::Named("write_error"));
+                self.ins(format_args!("pop rdx"), &[0x5A]);
+                self.ins(format_args!("pop rsi"), &[0x5E]);
+                self.jump(&[0xE8], Label::Named("write_error"));
+                self.load_data("at_instruction");
+                self.jump(&[0xE8], Label::Named("write_error"));
+                self.ins(format_args!("pop rax"), &[0x58]);
+                self.jump(&[0xE8], Label::Named("write_unsigned"));
+                self.load_data("tape_head");
+                self.jump(&[0xE8], Label::Named("write_error"));
+                self.ins(format_args!("mov rax, r12"), &[0x4C, 0x89, 0xE0]);
+                self.ins(format_args!("sub rax, r14"), &[0x4C, 0x29, 0xF0]);
+                self.jump(&[0xE8], Label::Named("write_signed"));
+                self.load_data("cell_value");
+                self.jump(&[0xE8], Label::Named("write_error"));
+                self.load_cell();
+                self.jump(&[0xE8], Label::Named("write_unsigned"));
+                self.load_data("newline");
+                self.jump(&[0xE8], Label::Named("write_error"));
+                self.ins(format_args!("pop rdi"), &[0x5F]);
+                self.jump(&[0xE9], Label::Named("exit"));
+            }
+            name => {
+                let kind = [
+                    RunErrorKind::TapeHeadBound,
+                    RunErrorKind::OutOfMemory,
+                    RunErrorKind::InputExhausted,
+                    RunErrorKind::CellOverflow,
+                    RunErrorKind::UnclosedLoop,
+                ]
+                .into_iter()
+                .find(|&kind| failure(kind).0 == name)
+                .unwrap_or_else(|| unreachable!("no routine named {}", name));
+                let code = u32::from(kind.exit_code());
+                self.start_routine(
+                    name,
+                    format_args!("Fails with \"{}\" and exit code {}.", kind, code),
+                );
+                let message = failure(kind).1;
+                self.jump(&[0x48, 0x8D, 0x3D], Label::Named(message));
+                self.mov_rdx(data(message).expect("a message").len() as u64);
+                self.ins(format_args!("mov ecx, {}", code), &[0xB9]);
+                self.emit(&code.to_le_bytes());
+                self.jump(&[0xE9], Label::Named("fail"));
+            }
+        }
+    }
+}
+
+fn cell_max(width: usize) -> u64 {
+    u64::MAX >> (64 - 8 * width)
+}
+
+//the ELF file that loads `code` and starts at its first byte
+fn executable(code: &[u8]) -> Vec<u8> {
+    let size = (HEADERS + code.len()) as u64;
+    let mut file = Vec::with_capacity(size as usize);
+    // e_ident: 64 bits, little endian, version 1, System V
+    file.extend_from_slice(&[0x7F, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    // e_type executable, e_machine x86-64, e_version
+    file.extend_from_slice(&2u16.to_le_bytes());
+    file.extend_from_slice(&0x3Eu16.to_le_bytes());
+    file.extend_from_slice(&1u32.to_le_bytes());
+    // e_entry, e_phoff, e_shoff, e_flags
+    file.extend_from_slice(&(BASE + HEADERS as u64).to_le_bytes());
+    file.extend_from_slice(&64u64.to_le_bytes());
+    file.extend_from_slice(&0u64.to_le_bytes());
+    file.extend_from_slice(&0u32.to_le_bytes());
+    // e_ehsize, e_phentsize, e_phnum, e_shentsize, e_shnum, e_shstrndx
+    for field in [64u16, 56, 2, 64, 0, 0] {
+        file.extend_from_slice(&field.to_le_bytes());
+    }
+
+    // PT_LOAD of the whole file, readable and executable
+    file.extend_from_slice(&1u32.to_le_bytes());
+    file.extend_from_slice(&5u32.to_le_bytes());
+    for field in [0, BASE, BASE, size, size, 0x1000] {
+        file.extend_from_slice(&field.to_le_bytes());
+    }
+    // PT_GNU_STACK, so that the stack is not executable
+    file.extend_from_slice(&0x6474_E551u32.to_le_bytes());
+    file.extend_from_slice(&6u32.to_le_bytes());
+    file.extend_from_slice(&[0; 48]);
+
+    file.extend_from_slice(code);
+    file
+}
+
+// --- UNIT TESTS ---
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The file is one loadable segment that starts running right after the headers, and only holds the routines the program needs.
+    #[test]
+    fn test_generate_elf() {
+        let program = CompressedBF::from_string("+++[->++<]>[-]");
+        let elf = generate_elf(&program, &CodegenOptions::default()).unwrap();
+        assert_eq!(&elf[..4], b"\x7FELF");
+        assert_eq!(elf[18], 0x3E);
+        assert_eq!(
+            u64::from_le_bytes(elf[24..32].try_into().unwrap()),
+            BASE + HEADERS as u64
+        );
+        // p_filesz of the segment is the whole file
+        assert_eq!(
+            u64::from_le_bytes(elf[96..104].try_into().unwrap()),
+            elf.len() as u64
+        );
+        let text = |name| data(name).unwrap();
+        let contains = |needle: &[u8]| elf.windows(needle.len()).any(|window| window == needle);
+        assert!(contains(&text("out_of_memory")));
+        assert!(!contains(&text("input_exhausted")));
+        assert!(elf.len() < 1024);
+
+        let unbalanced = CompressedBF::from_string("+]");
+        assert!(matches!(
+            generate_elf(&unbalanced, &CodegenOptions::default()),
+            Err(ParseError::UnmatchedLoopEnd { instruction: 1, .. })
+        ));
+    }
+}
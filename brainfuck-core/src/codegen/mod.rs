@@ -0,0 +1,657 @@
+mod asm;
+mod c;
+mod elf;
+mod rust;
+
+pub use asm::generate_asm;
+pub use c::generate_c;
+pub use elf::generate_elf;
+pub use rust::{generate_rust, generate_rust_module};
+
+use crate::{
+    cell::{CellWidth, OverflowMode},
+    data::CompressedBF,
+    error::ParseError,
+    ir::{CompiledProgram, Op},
+    run::{EofBehavior, TapePolicy},
+    source::SourceSpan,
+    util::jump_table,
+};
+
+/// The machine a generated program runs on, the same as the options `brainfuck-main run` takes for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodegenOptions {
+    /// Number of cells on the tape, or the initial number of cells for a growable tape.
+    pub tape_size: usize,
+    pub cell_width: CellWidth,
+    pub tape: TapePolicy,
+    pub overflow: OverflowMode,
+    pub eof: EofBehavior,
+}
+
+impl Default for CodegenOptions {
+    fn default() -> Self {
+        CodegenOptions {
+            tape_size: 30_000,
+            cell_width: CellWidth::U8,
+            tape: TapePolicy::Fixed,
+            overflow: OverflowMode::Wrap,
+            eof: EofBehavior::Error,
+        }
+    }
+}
+
+//compiles `program` for a code generator, rejecting unbalanced loops the way `run` does
+fn compile(
+    program: &CompressedBF,
+    options: &CodegenOptions,
+) -> Result<CompiledProgram, ParseError> {
+    //`program` has no comments or line breaks, so instruction `i` is in column `i + 1`
+    let jump_table = jump_table(program, |i| SourceSpan {
+        offset: i,
+        line: 1,
+        column: i + 1,
+    })?;
+    Ok(CompiledProgram::compile_code(
+        program,
+        &jump_table,
+        0,
+        options.overflow,
+    ))
+}
+
+/// `amount` reduced to the range of a wrapping cell of `bits` bits, as the nearest positive or negative number.
+fn wrapped_amount(amount: i64, bits: u32) -> i64 {
+    let modulus = 1i64 << bits;
+    let amount = amount.rem_euclid(modulus);
+    if amount > modulus / 2 {
+        amount - modulus
+    } else {
+        amount
+    }
+}
+
+//one line summary of the machine the program runs on
+fn describe(options: &CodegenOptions) -> String {
+    let tape = match options.tape {
+        TapePolicy::Fixed => "that never resize".to_string(),
+        TapePolicy::Growable {
+            grow_left: false,
+            max_cells,
+        } => format!("that grow to the right up to {} cells", max_cells),
+        TapePolicy::Growable {
+            grow_left: true,
+            max_cells,
+        } => format!("that grow in both directions up to {} cells", max_cells),
+    };
+    let overflow = match options.overflow {
+        OverflowMode::Wrap => "cells wrap",
+        OverflowMode::Saturate => "cells saturate",
+        OverflowMode::Error => "cells that overflow stop the program",
+    };
+    let eof = match options.eof {
+        EofBehavior::Error => "`,` after the input ran out stops the program",
+        EofBehavior::Zero => "`,` after the input ran out stores 0",
+        EofBehavior::Max => "`,` after the input ran out stores the largest cell value",
+        EofBehavior::Unchanged => "`,` after the input ran out leaves the cell as it is",
+    };
+    format!(
+        "{} cells of {} bits {}, {}, {}",
+        options.tape_size,
+        options.cell_width.bits(),
+        tape,
+        overflow,
+        eof
+    )
+}
+
+/// The runs of the same instruction in `text`, as the instruction, the index of its first byte and its length.
+fn runs(text: &str) -> Vec<(u8, usize, usize)> {
+    let bytes = text.as_bytes();
+    let mut runs = Vec::new();
+    let mut start = 0;
+    while start < bytes.len() {
+        let mut end = start;
+        while end < bytes.len() && bytes[end] == bytes[start] {
+            end += 1;
+        }
+        runs.push((bytes[start], start, end - start));
+        start = end;
+    }
+    runs
+}
+
+/// The texts of the `candidates`, named helpers, that `body` calls directly or through other helpers, in the order of `candidates`.
+///
+/// `calls(text, name)` tells whether `text` calls the helper `name`.
+fn used<'a>(
+    body: &str,
+    candidates: &'a [(&str, String)],
+    calls: impl Fn(&str, &str) -> bool,
+) -> Vec<&'a str> {
+    // a helper can call one listed before it, so go over the list until nothing changes
+    let mut used = vec![false; candidates.len()];
+    loop {
+        let mut changed = false;
+        for (index, (name, _)) in candidates.iter().enumerate() {
+            let called = calls(body, name)
+                || candidates
+                    .iter()
+                    .zip(&used)
+                    .any(|((_, text), used)| *used && calls(text, name));
+            if called && !used[index] {
+                used[index] = true;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    candidates
+        .iter()
+        .zip(&used)
+        .filter(|(_, used)| **used)
+        .map(|((_, text), _)| text.as_str())
+        .collect()
+}
+
+/// Which helpers the code for a program calls, so that generators only emit those.
+#[derive(Debug, Default)]
+struct Helpers {
+    move_left: bool,
+    move_right: bool,
+    add: bool,
+    sub: bool,
+    input: bool,
+    output: bool,
+    unclosed_loop: bool,
+}
+
+impl Helpers {
+    fn of(compiled: &CompiledProgram) -> Helpers {
+        let mut helpers = Helpers::default();
+        for op in compiled.ops() {
+            match &op.op {
+                Op::Move(distance) | Op::Scan(distance) => helpers.moves(*distance),
+                Op::Add(amount) => {
+                    helpers.add |= *amount > 0;
+                    helpers.sub |= *amount < 0;
+                }
+                // the loop the op stands for is run as it is when the tape is too small, and it comes back to where it started
+                Op::MulMove { reach, .. } if *reach != (0, 0) => {
+                    helpers.moves(-1);
+                    helpers.moves(1);
+                }
+                Op::Input => helpers.input = true,
+                Op::Output => helpers.output = true,
+                Op::JumpIfZero(None) => helpers.unclosed_loop = true,
+                _ => {}
+            }
+        }
+        helpers
+    }
+
+    fn moves(&mut self, distance: isize) {
+        self.move_left |= distance < 0;
+        self.move_right |= distance > 0;
+    }
+
+    /// Whether the program can fail, and so needs the code that reports errors.
+    fn can_fail(&self, options: &CodegenOptions) -> bool {
+        self.move_left
+            || self.move_right
+            || (options.overflow == OverflowMode::Error && (self.add || self.sub))
+            || (options.eof == EofBehavior::Error && self.input)
+            || self.unclosed_loop
+    }
+}
+
+/// Builds indented source text line by line.
+struct SourceWriter {
+    text: String,
+    indent: usize,
+}
+
+impl SourceWriter {
+    fn new() -> Self {
+        SourceWriter {
+            text: String::new(),
+            indent: 0,
+        }
+    }
+
+    fn line(&mut self, line: &str) {
+        if !line.is_empty() {
+            for _ in 0..self.indent {
+                self.text.push_str("    ");
+            }
+        }
+        self.text.push_str(line);
+        self.text.push('\n');
+    }
+
+    //writes `block`, a piece of source with its own line breaks and indentation, at the current indentation; a trailing line break leaves an empty line after it
+    fn block(&mut self, block: &str) {
+        for line in block.split('\n') {
+            self.line(line);
+        }
+    }
+}
+
+// --- UNIT TESTS ---
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cell::Cell,
+        error::{RunError, RunErrorKind},
+        run::{RunOptions, run_program_fragment_no_target_with_stop},
+        util::preprocess_input_with_cells,
+    };
+    use std::{
+        io::Write,
+        path::Path,
+        process::{Command, Stdio},
+    };
+
+    const INPUT: &[u8] = b"ab";
+
+    //a program and the machine it is generated for
+    type Case = (&'static str, CodegenOptions);
+
+    /// Programs that fail in every way a generated program can, each on small fixed and growing tapes with every overflow mode.
+    fn cases() -> Vec<Case> {
+        let programs = [
+            "",
+            "++++++++[>++++++++<-]>+.+.+.",
+            "-[->+>++<<]>.>.",
+            "+[>+]",
+            "<+",
+            ">>>+[<]<.",
+            ",[.,]",
+            ",+[-.,+]",
+            "++[->>>>>>+<<<<<<]>>>>>>.",
+            "+++++[-]---.",
+        ];
+        let tapes = [
+            TapePolicy::Fixed,
+            TapePolicy::Growable {
+                grow_left: true,
+                max_cells: 16,
+            },
+        ];
+        let mut cases = Vec::new();
+        for program in programs {
+            for tape in tapes {
+                for overflow in [
+                    OverflowMode::Wrap,
+                    OverflowMode::Saturate,
+                    OverflowMode::Error,
+                ] {
+                    for (eof, cell_width) in [
+                        (EofBehavior::Error, CellWidth::U8),
+                        (EofBehavior::Max, CellWidth::U16),
+                    ] {
+                        let options = CodegenOptions {
+                            tape_size: 4,
+                            cell_width,
+                            tape,
+                            overflow,
+                            eof,
+                        };
+                        cases.push((program, options));
+                    }
+                }
+            }
+        }
+        cases
+    }
+
+    /// What `brainfuck-main run` does with a case given [`INPUT`].
+    #[derive(Debug)]
+    struct Expected {
+        output: Vec<u8>,
+        exit_code: u8,
+        stderr: String,
+    }
+
+    //`None` for an infinite loop, which generated programs do not detect
+    fn interpret(program: &str, options: &CodegenOptions) -> Option<Expected> {
+        match options.cell_width {
+            CellWidth::U8 => interpret_with::<u8>(program, options),
+            CellWidth::U16 => interpret_with::<u16>(program, options),
+            CellWidth::U32 => interpret_with::<u32>(program, options),
+        }
+    }
+
+    fn interpret_with<C: Cell>(program: &str, options: &CodegenOptions) -> Option<Expected> {
+        let info = preprocess_input_with_cells::<C>(program, options.tape_size).unwrap();
+        let run_options = RunOptions {
+            tape: options.tape,
+            overflow: options.overflow,
+            eof: options.eof,
+            ..RunOptions::default()
+        };
+        let mut input = INPUT.iter().copied();
+        let mut output = Vec::new();
+        let (result, stop) = run_program_fragment_no_target_with_stop(
+            &info,
+            &run_options,
+            || input.next(),
+            |byte| output.push(byte),
+        );
+        match result.error_kind() {
+            Some(RunErrorKind::InfiniteLoop) => None,
+            Some(kind) => Some(Expected {
+                output,
+                exit_code: kind.exit_code(),
+                stderr: format!("Error: {}\n", RunError { kind, stop }),
+            }),
+            None => Some(Expected {
+                output,
+                exit_code: 0,
+                stderr: String::new(),
+            }),
+        }
+    }
+
+    /// A code generator and how to turn what it generates into programs that run.
+    struct Backend {
+        name: &'static str,
+        /// The programs `build` calls, the backend is skipped when one of them is missing.
+        tools: &'static [&'static str],
+        /// Builds every case in the directory, giving the command that runs each.
+        build: fn(&Path, &[Case]) -> Vec<Command>,
+    }
+
+    fn build_c(dir: &Path, cases: &[Case]) -> Vec<Command> {
+        let mut commands = Vec::new();
+        for (index, (program, options)) in cases.iter().enumerate() {
+            let source = dir.join(format!("program-{}.c", index));
+            let binary = dir.join(format!("program-{}", index));
+            let c = generate_c(&CompressedBF::from_string(program), options).unwrap();
+            std::fs::write(&source, c).unwrap();
+            let status = Command::new("cc")
+                .args(["-O0", "-Wall", "-Werror", "-o"])
+                .arg(&binary)
+                .arg(&source)
+                .status()
+                .unwrap();
+            assert!(status.success(), "{} {:?}", program, options);
+            commands.push(Command::new(binary));
+        }
+        commands
+    }
+
+    //the modules go into one program, as rustc is too slow to build one per case, which runs the case its argument names
+    fn build_rust(dir: &Path, cases: &[Case]) -> Vec<Command> {
+        let mut modules = String::new();
+        let mut main = String::from(
+            "fn main() -> std::process::ExitCode {\n    let case: usize = std::env::args().nth(1).unwrap().parse().unwrap();\n    let result = match case {\n",
+        );
+        for (index, (program, options)) in cases.iter().enumerate() {
+            let rust = generate_rust_module(&CompressedBF::from_string(program), options).unwrap();
+            modules.push_str(&format!("mod case_{} {{\n{}}}\n\n", index, rust));
+            main.push_str(&format!(
+                "        {0} => case_{0}::run(std::io::stdin().lock(), std::io::stdout().lock())\n            .map_err(|error| (error.to_string(), error.exit_code())),\n",
+                index
+            ));
+        }
+        main.push_str(
+            "        _ => unreachable!(),\n    };\n    match result {\n        Ok(()) => std::process::ExitCode::SUCCESS,\n        Err((message, exit_code)) => {\n            eprintln!(\"Error: {}\", message);\n            std::process::ExitCode::from(exit_code)\n        }\n    }\n}\n",
+        );
+        let source = dir.join("cases.rs");
+        let binary = dir.join("cases");
+        std::fs::write(&source, modules + &main).unwrap();
+        let status = Command::new("rustc")
+            .args(["--edition", "2024", "-D", "warnings", "-o"])
+            .arg(&binary)
+            .arg(&source)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        (0..cases.len())
+            .map(|index| {
+                let mut command = Command::new(&binary);
+                command.arg(index.to_string());
+                command
+            })
+            .collect()
+    }
+
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    fn build_asm(dir: &Path, cases: &[Case]) -> Vec<Command> {
+        let mut commands = Vec::new();
+        for (index, (program, options)) in cases.iter().enumerate() {
+            let source = dir.join(format!("program-{}.s", index));
+            let object = dir.join(format!("program-{}.o", index));
+            let binary = dir.join(format!("program-{}", index));
+            let asm = generate_asm(&CompressedBF::from_string(program), options).unwrap();
+            std::fs::write(&source, asm).unwrap();
+            let assembled = Command::new("as")
+                .arg("-o")
+                .arg(&object)
+                .arg(&source)
+                .status()
+                .unwrap();
+            assert!(assembled.success(), "{} {:?}", program, options);
+            let linked = Command::new("ld")
+                .arg("-o")
+                .arg(&binary)
+                .arg(&object)
+                .status()
+                .unwrap();
+            assert!(linked.success(), "{} {:?}", program, options);
+            commands.push(Command::new(binary));
+        }
+        commands
+    }
+
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    fn build_elf(dir: &Path, cases: &[Case]) -> Vec<Command> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut commands = Vec::new();
+        for (index, (program, options)) in cases.iter().enumerate() {
+            let binary = dir.join(format!("program-{}", index));
+            let elf = generate_elf(&CompressedBF::from_string(program), options).unwrap();
+            std::fs::write(&binary, elf).unwrap();
+            std::fs::set_permissions(&binary, std::fs::Permissions::from_mode(0o755)).unwrap();
+            commands.push(Command::new(binary));
+        }
+        commands
+    }
+
+    //builds the cases with `backend` in a directory of its own and checks what every one of them does
+    fn check_backend(backend: &Backend, cases: &[(Case, Expected)]) {
+        let dir = std::env::temp_dir().join(format!(
+            "bf-codegen-{}-{}",
+            backend.name,
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let programs: Vec<Case> = cases.iter().map(|(case, _)| *case).collect();
+        let commands = (backend.build)(&dir, &programs);
+        assert_eq!(commands.len(), cases.len());
+        for (mut command, ((program, options), expected)) in commands.into_iter().zip(cases) {
+            command
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped());
+            // another backend can fork while a file is still open for writing, so wait for the child to exec
+            let mut child = loop {
+                match command.spawn() {
+                    Err(e) if e.kind() == std::io::ErrorKind::ExecutableFileBusy => {
+                        std::thread::sleep(std::time::Duration::from_millis(10))
+                    }
+                    spawned => break spawned.unwrap(),
+                }
+            };
+            // programs that fail before reading close the pipe early
+            let _ = child.stdin.take().unwrap().write_all(INPUT);
+            let ran = child.wait_with_output().unwrap();
+            let case = format!("{} {} {:?}", backend.name, program, options);
+            assert_eq!(ran.stdout, expected.output, "{}", case);
+            assert_eq!(
+                ran.status.code(),
+                Some(expected.exit_code as i32),
+                "{}",
+                case
+            );
+            assert_eq!(
+                String::from_utf8(ran.stderr).unwrap(),
+                expected.stderr,
+                "{}",
+                case
+            );
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Built on this machine, the programs of every code generator print what the interpreter prints and fail with the same message and exit code.
+    ///
+    /// A generator is skipped when a program it needs, like `cc` or `rustc`, is missing.
+    #[test]
+    fn test_generated_programs_match_interpreter() {
+        #[allow(unused_mut)]
+        let mut backends = vec![
+            Backend {
+                name: "c",
+                tools: &["cc"],
+                build: build_c,
+            },
+            Backend {
+                name: "rust",
+                tools: &["rustc"],
+                build: build_rust,
+            },
+        ];
+        #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+        backends.extend([
+            Backend {
+                name: "asm",
+                tools: &["as", "ld"],
+                build: build_asm,
+            },
+            Backend {
+                name: "elf",
+                tools: &[],
+                build: build_elf,
+            },
+        ]);
+        let cases: Vec<_> = cases()
+            .into_iter()
+            .filter_map(|(program, options)| {
+                Some(((program, options), interpret(program, &options)?))
+            })
+            .collect();
+        assert!(cases.len() > 80);
+
+        // the backends build and run their programs side by side
+        std::thread::scope(|scope| {
+            for backend in &backends {
+                let available = backend
+                    .tools
+                    .iter()
+                    .all(|tool| Command::new(tool).arg("--version").output().is_ok());
+                if available {
+                    scope.spawn(|| check_backend(backend, &cases));
+                }
+            }
+        });
+    }
+
+    //the instructions objdump finds in the raw x86-64 code in `file`, with the targets of jumps and calls as instruction indices and rip-relative addresses left out, so that code laid out with shorter encodings compares equal
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    fn disassemble(file: &Path) -> Vec<String> {
+        let dumped = Command::new("objdump")
+            .args(["-D", "-b", "binary", "-m", "i386:x86-64", "-M", "intel"])
+            .arg("--no-show-raw-insn")
+            .arg(file)
+            .output()
+            .unwrap();
+        assert!(dumped.status.success());
+        let text = String::from_utf8(dumped.stdout).unwrap();
+        let instructions: Vec<(u64, String)> = text
+            .lines()
+            .filter_map(|line| {
+                let (address, instruction) = line.trim_start().split_once(":\t")?;
+                let address = u64::from_str_radix(address, 16).ok()?;
+                let instruction = instruction.split(" #").next().unwrap().trim_end();
+                Some((
+                    address,
+                    instruction.split_whitespace().collect::<Vec<_>>().join(" "),
+                ))
+            })
+            .collect();
+        let index = |address: &str| {
+            let address = u64::from_str_radix(address.trim_start_matches("0x"), 16).ok()?;
+            instructions.iter().position(|&(start, _)| start == address)
+        };
+        instructions
+            .iter()
+            .map(|(_, instruction)| {
+                let (mnemonic, operands) = instruction.split_once(' ').unwrap_or((instruction, ""));
+                if mnemonic.starts_with('j') || mnemonic == "call" {
+                    match index(operands) {
+                        Some(target) => format!("{} @{}", mnemonic, target),
+                        None => instruction.clone(),
+                    }
+                } else if let Some((before, after)) = operands.split_once("[rip+") {
+                    let after = &after[after.find(']').unwrap()..];
+                    format!("{} {}[rip{}", mnemonic, before, after)
+                } else {
+                    instruction.clone()
+                }
+            })
+            .collect()
+    }
+
+    /// Assembled, the listing of [`generate_asm`] is the code of [`generate_elf`] instruction for instruction.
+    ///
+    /// Skipped when `as`, `objcopy` or `objdump` is missing.
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    #[test]
+    fn test_asm_is_the_elf_code() {
+        let available = ["as", "objcopy", "objdump"]
+            .iter()
+            .all(|tool| Command::new(tool).arg("--version").output().is_ok());
+        if !available {
+            return;
+        }
+        let dir = std::env::temp_dir().join(format!("bf-codegen-listing-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("program.s");
+        let object = dir.join("program.o");
+        let assembled_code = dir.join("assembled.bin");
+        let elf_code = dir.join("elf.bin");
+        for (program, options) in cases() {
+            let program_code = CompressedBF::from_string(program);
+            std::fs::write(&source, generate_asm(&program_code, &options).unwrap()).unwrap();
+            let assembled = Command::new("as")
+                .arg("-o")
+                .arg(&object)
+                .arg(&source)
+                .status()
+                .unwrap();
+            assert!(assembled.success(), "{} {:?}", program, options);
+            let copied = Command::new("objcopy")
+                .args(["-O", "binary", "-j", ".text"])
+                .arg(&object)
+                .arg(&assembled_code)
+                .status()
+                .unwrap();
+            assert!(copied.success());
+            let elf = generate_elf(&program_code, &options).unwrap();
+            std::fs::write(&elf_code, &elf[elf::HEADERS..]).unwrap();
+
+            // the ELF code goes on with the messages the listing keeps in .rodata
+            let listing = disassemble(&assembled_code);
+            let code = disassemble(&elf_code);
+            assert!(code.len() >= listing.len());
+            assert_eq!(listing, code[..listing.len()], "{} {:?}", program, options);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,699 @@
+use super::{CodegenOptions, SourceWriter, compile, describe, runs, used, wrapped_amount};
+use crate::{
+    cell::OverflowMode,
+    data::CompressedBF,
+    error::{ParseError, RunErrorKind},
+    ir::{CompiledOp, Op},
+    run::{EofBehavior, TapePolicy},
+};
+
+/// Translates `program` into a self-contained `main.rs` that behaves like `brainfuck-main run` with the same options.
+///
+/// The file holds the function [`generate_rust_module`] emits and a `main` that runs it on stdin and a buffered stdout, printing the same error and stopping with the same exit code when the run fails.
+pub fn generate_rust(
+    program: &CompressedBF,
+    options: &CodegenOptions,
+) -> Result<String, ParseError> {
+    generate(program, options, true)
+}
+
+/// Translates `program` into a Rust module with a `pub fn run<R: Read, W: Write>(input: R, output: W) -> Result<(), Error>`.
+///
+/// `run` writes the same bytes as [`run_program_fragment_no_target`](crate::run_program_fragment_no_target) and fails on the same instruction with the same kind of error, but it does not detect infinite loops.
+/// The module depends on nothing but `std`, so it can be dropped into any crate.
+pub fn generate_rust_module(
+    program: &CompressedBF,
+    options: &CodegenOptions,
+) -> Result<String, ParseError> {
+    generate(program, options, false)
+}
+
+fn generate(
+    program: &CompressedBF,
+    options: &CodegenOptions,
+    with_main: bool,
+) -> Result<String, ParseError> {
+    let compiled = compile(program, options)?;
+    let source = program.to_string();
+    let bits = options.cell_width.bits();
+
+    let mut body = SourceWriter::new();
+    body.indent = 1;
+    for compiled_op in compiled.ops() {
+        emit_op(&mut body, compiled_op, &source, options, bits);
+    }
+    let methods = tape_methods(&body.text, options);
+    let can_fail = methods.contains("fn fail(");
+
+    let mut out = SourceWriter::new();
+    out.line(&format!(
+        "//! Generated from a Brainfuck program of {} instructions.",
+        program.size()
+    ));
+    out.line(&format!("//! {}.", describe(options)));
+    out.line("");
+    out.line("use std::fmt;");
+    out.line("use std::io::{self, Read, Write};");
+    if with_main {
+        out.line("use std::process::ExitCode;");
+    }
+    out.line("");
+    if !compiled.ops().is_empty() {
+        out.line(&format!("type Cell = u{};", bits));
+        if let TapePolicy::Growable { max_cells, .. } = options.tape
+            && methods.contains("fn grow_")
+        {
+            out.line(&format!("const MAX_CELLS: usize = {};", max_cells));
+        }
+        out.line("");
+    }
+
+    out.line("/// Why [`run`] stopped before the end of the program.");
+    out.line("#[derive(Debug)]");
+    out.line("pub enum Error {");
+    if can_fail {
+        out.block(
+            "    /// The run failed the way `brainfuck-main run` reports it, at instruction `pc`.
+    Run {
+        message: &'static str,
+        exit_code: u8,
+        pc: usize,
+        tape_head: isize,
+        cell: Cell,
+    },",
+        );
+    }
+    out.block(
+        "    /// Reading the input or writing the output failed.
+    Io(io::Error),
+}
+
+impl Error {
+    /// The exit code `brainfuck-main run` stops with for this error.
+    pub fn exit_code(&self) -> u8 {
+        match self {",
+    );
+    if can_fail {
+        out.line("            Error::Run { exit_code, .. } => *exit_code,");
+    }
+    out.block(
+        "            Error::Io(_) => 1,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {",
+    );
+    if can_fail {
+        out.block(
+            "            Error::Run {
+                message,
+                pc,
+                tape_head,
+                cell,
+                ..
+            } => write!(
+                f,
+                \"{} at instruction {}, tape head {}, cell value {}\",
+                message, pc, tape_head, cell
+            ),",
+        );
+    }
+    out.block(
+        "            Error::Io(error) => write!(f, \"{}\", error),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+",
+    );
+
+    if compiled.ops().is_empty() {
+        out.block(
+            "/// Runs the program, which does nothing.
+pub fn run<R: Read, W: Write>(_input: R, mut output: W) -> Result<(), Error> {
+    output.flush()?;
+    Ok(())
+}",
+        );
+    } else {
+        out.line("/// The cells of the machine and where the head is.");
+        out.line("struct Tape {");
+        out.line("    cells: Vec<Cell>,");
+        out.line("    head: usize,");
+        if can_fail {
+            out.line("    /// The cell the program started on, which moves when the tape grows to the left.");
+            out.line("    origin: usize,");
+        }
+        out.line("}");
+        out.line("");
+        out.line("impl Tape {");
+        out.text.push_str(&methods);
+        out.line("}");
+        out.line("");
+
+        let input = if body.text.contains("tape.input(") {
+            "input"
+        } else {
+            "_input"
+        };
+        let output = if body.text.contains("output.") {
+            "output"
+        } else {
+            "_output"
+        };
+        out.line(&format!(
+            "fn execute(tape: &mut Tape, {}: &mut impl Read, {}: &mut impl Write) -> Result<(), Error> {{",
+            input, output
+        ));
+        out.text.push_str(&body.text);
+        out.line("    Ok(())");
+        out.line("}");
+        out.line("");
+        out.block(&format!(
+            "/// Runs the program, reading the bytes of `,` from `input` and writing the bytes of `.` to `output`.
+///
+/// `output` is flushed before every `,` and when the program stops.
+pub fn run<R: Read, W: Write>(mut input: R, mut output: W) -> Result<(), Error> {{
+    let mut tape = Tape {{
+        cells: vec![0; {}],
+        head: 0,{}
+    }};
+    let result = execute(&mut tape, &mut input, &mut output);
+    let flushed = output.flush();
+    result?;
+    Ok(flushed?)
+}}",
+            options.tape_size,
+            if can_fail { "\n        origin: 0," } else { "" }
+        ));
+    }
+
+    if with_main {
+        out.line("");
+        out.block(
+            "fn main() -> ExitCode {
+    // `run` flushes the buffer before every `,` and when it stops
+    match run(io::stdin().lock(), io::BufWriter::new(io::stdout().lock())) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!(\"Error: {}\", error);
+            ExitCode::from(error.exit_code())
+        }
+    }
+}",
+        );
+    }
+    Ok(out.text)
+}
+
+//the methods of `Tape` that `body` calls, directly or through another method, in a fixed order
+fn tape_methods(body: &str, options: &CodegenOptions) -> String {
+    let mut candidates: Vec<(&str, String)> = Vec::new();
+    candidates.push((
+        "fail",
+        "    /// The error `brainfuck-main run` reports when instruction `pc` fails with `message`.
+    fn fail(&self, message: &'static str, exit_code: u8, pc: usize) -> Error {
+        Error::Run {
+            message,
+            exit_code,
+            pc,
+            tape_head: self.head as isize - self.origin as isize,
+            cell: self.cells[self.head],
+        }
+    }
+"
+        .to_string(),
+    ));
+    if let TapePolicy::Growable { grow_left, .. } = options.tape {
+        candidates.push((
+            "grow_right",
+            "    /// Grows the tape to the right, doubling it up to `MAX_CELLS` cells.
+    fn grow_right(&mut self) -> bool {
+        if self.cells.len() >= MAX_CELLS {
+            return false;
+        }
+        let len = (self.cells.len() * 2).min(MAX_CELLS);
+        self.cells.resize(len, 0);
+        true
+    }
+"
+            .to_string(),
+        ));
+        if grow_left {
+            candidates.push((
+                "grow_left",
+                "    /// Grows the tape to the left by as many cells as it has, up to `MAX_CELLS` cells.
+    fn grow_left(&mut self) -> bool {
+        if self.cells.len() >= MAX_CELLS {
+            return false;
+        }
+        let extra = self.cells.len().min(MAX_CELLS - self.cells.len());
+        self.cells.splice(0..0, std::iter::repeat_n(0, extra));
+        self.head += extra;
+        self.origin += extra;
+        true
+    }
+"
+                .to_string(),
+            ));
+        }
+    }
+    candidates.push((
+        "cell",
+        "    fn cell(&self) -> Cell {
+        self.cells[self.head]
+    }
+"
+        .to_string(),
+    ));
+    candidates.push((
+        "set",
+        "    fn set(&mut self, value: Cell) {
+        self.cells[self.head] = value;
+    }
+"
+        .to_string(),
+    ));
+    candidates.push((
+        "add_at",
+        "    /// Adds `amount` to the cell `offset` cells away from the head, which the caller made sure is on the tape.
+    fn add_at(&mut self, offset: isize, amount: Cell) {
+        let index = self.head.wrapping_add_signed(offset);
+        self.cells[index] = self.cells[index].wrapping_add(amount);
+    }
+"
+        .to_string(),
+    ));
+
+    let check = match options.tape {
+        TapePolicy::Fixed => "        if self.cells.len() - 1 - self.head < distance {\n".to_string(),
+        TapePolicy::Growable { .. } => "        while self.cells.len() - 1 - self.head < distance {\n            if self.grow_right() {\n                continue;\n            }\n".to_string(),
+    };
+    candidates.push((
+        "move_right",
+        format!(
+            "    /// Moves the head right for the `>` run starting at instruction `pc`, failing at the `>` that leaves the tape.
+    fn move_right(&mut self, distance: usize, pc: usize) -> Result<(), Error> {{
+{check}            let pc = pc + self.cells.len() - 1 - self.head;
+            self.head = self.cells.len() - 1;
+            return Err(self.{});
+        }}
+        self.head += distance;
+        Ok(())
+    }}
+",
+            fail_call(RunErrorKind::OutOfMemory)
+        ),
+    ));
+    let (check, kind) = match options.tape {
+        TapePolicy::Growable {
+            grow_left: true, ..
+        } => (
+            "        while self.head < distance {\n            if self.grow_left() {\n                continue;\n            }\n",
+            RunErrorKind::OutOfMemory,
+        ),
+        _ => (
+            "        if self.head < distance {\n",
+            RunErrorKind::TapeHeadBound,
+        ),
+    };
+    candidates.push((
+        "move_left",
+        format!(
+            "    /// Moves the head left for the `<` run starting at instruction `pc`, failing at the `<` that leaves the tape.
+    fn move_left(&mut self, distance: usize, pc: usize) -> Result<(), Error> {{
+{check}            let pc = pc + self.head;
+            self.head = 0;
+            return Err(self.{});
+        }}
+        self.head -= distance;
+        Ok(())
+    }}
+",
+            fail_call(kind)
+        ),
+    ));
+
+    match options.overflow {
+        OverflowMode::Wrap => {
+            candidates.push((
+                "add",
+                "    fn add(&mut self, amount: Cell) {
+        self.cells[self.head] = self.cells[self.head].wrapping_add(amount);
+    }
+"
+                .to_string(),
+            ));
+            candidates.push((
+                "sub",
+                "    fn sub(&mut self, amount: Cell) {
+        self.cells[self.head] = self.cells[self.head].wrapping_sub(amount);
+    }
+"
+                .to_string(),
+            ));
+        }
+        OverflowMode::Saturate => {
+            candidates.push((
+                "add_saturating",
+                "    fn add_saturating(&mut self, amount: u64) {
+        let cell = &mut self.cells[self.head];
+        *cell = if ((Cell::MAX - *cell) as u64) < amount {
+            Cell::MAX
+        } else {
+            *cell + amount as Cell
+        };
+    }
+"
+                .to_string(),
+            ));
+            candidates.push((
+                "sub_saturating",
+                "    fn sub_saturating(&mut self, amount: u64) {
+        let cell = &mut self.cells[self.head];
+        *cell = if (*cell as u64) < amount {
+            0
+        } else {
+            *cell - amount as Cell
+        };
+    }
+"
+                .to_string(),
+            ));
+        }
+        OverflowMode::Error => {
+            let overflow = fail_call(RunErrorKind::CellOverflow);
+            candidates.push((
+                "add_checked",
+                format!(
+                    "    /// Adds for the `+` run starting at instruction `pc`, failing at the `+` that overflows.
+    fn add_checked(&mut self, amount: u64, pc: usize) -> Result<(), Error> {{
+        let room = (Cell::MAX - self.cells[self.head]) as u64;
+        if room < amount {{
+            let pc = pc + room as usize;
+            self.cells[self.head] = Cell::MAX;
+            return Err(self.{overflow});
+        }}
+        self.cells[self.head] += amount as Cell;
+        Ok(())
+    }}
+"
+                ),
+            ));
+            candidates.push((
+                "sub_checked",
+                format!(
+                    "    /// Subtracts for the `-` run starting at instruction `pc`, failing at the `-` that overflows.
+    fn sub_checked(&mut self, amount: u64, pc: usize) -> Result<(), Error> {{
+        let room = self.cells[self.head] as u64;
+        if room < amount {{
+            let pc = pc + room as usize;
+            self.cells[self.head] = 0;
+            return Err(self.{overflow});
+        }}
+        self.cells[self.head] -= amount as Cell;
+        Ok(())
+    }}
+"
+                ),
+            ));
+        }
+    }
+
+    let (signature, on_eof) = match options.eof {
+        EofBehavior::Error => (
+            ", pc: usize",
+            format!(
+                "{{\n                return Err(self.{});\n            }}",
+                fail_call(RunErrorKind::InputExhausted)
+            ),
+        ),
+        EofBehavior::Zero => (
+            "",
+            "{\n                self.cells[self.head] = 0;\n            }".to_string(),
+        ),
+        EofBehavior::Max => (
+            "",
+            "{\n                self.cells[self.head] = Cell::MAX;\n            }".to_string(),
+        ),
+        EofBehavior::Unchanged => ("", "{}".to_string()),
+    };
+    candidates.push((
+        "input",
+        format!(
+            "    /// `,`, storing the next byte of `input`.
+    fn input(&mut self, input: &mut impl Read{signature}) -> Result<(), Error> {{
+        let mut byte = [0];
+        match input.read_exact(&mut byte) {{
+            Ok(()) => self.cells[self.head] = byte[0] as Cell,
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => {on_eof}
+            Err(error) => return Err(error.into()),
+        }}
+        Ok(())
+    }}
+"
+        ),
+    ));
+
+    let kept = used(body, &candidates, |text, name| {
+        text.contains(&format!("tape.{}(", name)) || text.contains(&format!("self.{}(", name))
+    });
+    kept.join("\n")
+}
+
+fn fail_call(kind: RunErrorKind) -> String {
+    format!("fail(\"{}\", {}, pc)", kind, kind.exit_code())
+}
+
+fn emit_op(
+    out: &mut SourceWriter,
+    compiled_op: &CompiledOp,
+    source: &str,
+    options: &CodegenOptions,
+    bits: u32,
+) {
+    let CompiledOp { op, pc, len } = compiled_op;
+    let overflow = options.overflow;
+    let text = &source[*pc..*pc + *len];
+    match op {
+        Op::Add(amount) => emit_add(out, *amount, *pc, overflow, bits),
+        Op::Move(distance) => emit_move(out, *distance, *pc),
+        Op::SetZero => out.line(&format!("tape.set(0); // {}", text)),
+        Op::MulMove { targets, reach } => {
+            out.line("if tape.cell() != 0 {");
+            out.indent += 1;
+            out.line(&format!("// {}", text));
+            let mut room = Vec::new();
+            if reach.0 < 0 {
+                room.push(format!("tape.head >= {}", reach.0.unsigned_abs()));
+            }
+            if reach.1 > 0 {
+                room.push(format!("tape.cells.len() - 1 - tape.head >= {}", reach.1));
+            }
+            if !room.is_empty() {
+                out.line(&format!("if {} {{", room.join(" && ")));
+                out.indent += 1;
+            }
+            out.line("let value = tape.cell();");
+            for &(offset, factor) in targets {
+                let factor = factor.rem_euclid(1i64 << bits);
+                if factor == 1 {
+                    out.line(&format!("tape.add_at({}, value);", offset));
+                } else {
+                    out.line(&format!(
+                        "tape.add_at({}, value.wrapping_mul({}));",
+                        offset, factor
+                    ));
+                }
+            }
+            out.line("tape.set(0);");
+            if !room.is_empty() {
+                // the tape has to grow or the head leaves it, so run the loop as written
+                out.indent -= 1;
+                out.line("} else {");
+                out.indent += 1;
+                out.line("while tape.cell() != 0 {");
+                out.indent += 1;
+                emit_straight(out, &text[1..text.len() - 1], pc + 1, overflow, bits);
+                out.indent -= 1;
+                out.line("}");
+                out.indent -= 1;
+                out.line("}");
+            }
+            out.indent -= 1;
+            out.line("}");
+        }
+        Op::Scan(stride) => {
+            out.line("while tape.cell() != 0 {");
+            out.indent += 1;
+            out.line(&format!("// {}", text));
+            emit_move(out, *stride, pc + 1);
+            out.indent -= 1;
+            out.line("}");
+        }
+        Op::Output => out.line("output.write_all(&[tape.cell() as u8])?;"),
+        Op::Input => {
+            out.line("output.flush()?;");
+            if options.eof == EofBehavior::Error {
+                out.line(&format!("tape.input(input, {})?;", pc));
+            } else {
+                out.line("tape.input(input)?;");
+            }
+        }
+        Op::JumpIfZero(Some(_)) => {
+            out.line("while tape.cell() != 0 {");
+            out.indent += 1;
+        }
+        Op::JumpIfZero(None) => {
+            out.line("if tape.cell() == 0 {");
+            out.line(&format!("    let pc = {};", pc));
+            out.line(&format!(
+                "    return Err(tape.{});",
+                fail_call(RunErrorKind::UnclosedLoop)
+            ));
+            out.line("}");
+        }
+        Op::JumpIfNonZero(_) => {
+            out.indent -= 1;
+            out.line("}");
+        }
+    }
+}
+
+fn emit_add(out: &mut SourceWriter, amount: i64, pc: usize, overflow: OverflowMode, bits: u32) {
+    match overflow {
+        OverflowMode::Wrap => match wrapped_amount(amount, bits) {
+            0 => {}
+            amount if amount > 0 => out.line(&format!("tape.add({});", amount)),
+            amount => out.line(&format!("tape.sub({});", amount.unsigned_abs())),
+        },
+        OverflowMode::Saturate if amount > 0 => {
+            out.line(&format!("tape.add_saturating({});", amount))
+        }
+        OverflowMode::Saturate => {
+            out.line(&format!("tape.sub_saturating({});", amount.unsigned_abs()))
+        }
+        OverflowMode::Error if amount > 0 => {
+            out.line(&format!("tape.add_checked({}, {})?;", amount, pc))
+        }
+        OverflowMode::Error => out.line(&format!(
+            "tape.sub_checked({}, {})?;",
+            amount.unsigned_abs(),
+            pc
+        )),
+    }
+}
+
+fn emit_move(out: &mut SourceWriter, distance: isize, pc: usize) {
+    if distance > 0 {
+        out.line(&format!("tape.move_right({}, {})?;", distance, pc));
+    } else {
+        out.line(&format!(
+            "tape.move_left({}, {})?;",
+            distance.unsigned_abs(),
+            pc
+        ));
+    }
+}
+
+//the `+-<>` instructions in `text`, which start at instruction `pc`, one statement per run of the same instruction
+fn emit_straight(out: &mut SourceWriter, text: &str, pc: usize, overflow: OverflowMode, bits: u32) {
+    for (instruction, start, count) in runs(text) {
+        let count = count as isize;
+        match instruction {
+            b'+' => emit_add(out, count as i64, pc + start, overflow, bits),
+            b'-' => emit_add(out, -count as i64, pc + start, overflow, bits),
+            b'>' => emit_move(out, count, pc + start),
+            b'<' => emit_move(out, -count, pc + start),
+            other => unreachable!("{} in the body of a loop idiom", other as char),
+        }
+    }
+}
+
+// --- UNIT TESTS ---
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    /// Folds and loop idioms come out as single statements, and only the methods the program calls are emitted.
+    #[test]
+    fn test_generate_rust() {
+        let program = CompressedBF::from_string("+++[->++<]>[-].");
+        let rust = generate_rust_module(&program, &CodegenOptions::default()).unwrap();
+        assert!(rust.contains("tape.add(3);"));
+        assert!(rust.contains("tape.add_at(1, value.wrapping_mul(2));"));
+        assert!(rust.contains("tape.set(0); // [-]"));
+        assert!(rust.contains("tape.move_right(1, 10)?;"));
+        assert!(!rust.contains("fn add_checked("));
+        assert!(!rust.contains("fn input("));
+        assert!(!rust.contains("fn main("));
+
+        let unbalanced = CompressedBF::from_string("+]");
+        assert!(matches!(
+            generate_rust(&unbalanced, &CodegenOptions::default()),
+            Err(ParseError::UnmatchedLoopEnd { instruction: 1, .. })
+        ));
+    }
+
+    fn has_rustc() -> bool {
+        Command::new("rustc").arg("--version").output().is_ok()
+    }
+
+    /// The `main.rs` reads stdin, and prints the error of `brainfuck-main run` and stops with its exit code.
+    ///
+    /// Skipped when there is no `rustc`.
+    #[test]
+    fn test_rust_main() {
+        if !has_rustc() {
+            return;
+        }
+        let dir = std::env::temp_dir().join(format!("bf-codegen-rust-main-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("main.rs");
+        let binary = dir.join("main");
+        let rust = generate_rust(
+            &CompressedBF::from_string(",[.,]<"),
+            &CodegenOptions::default(),
+        )
+        .unwrap();
+        assert!(rust.contains("run(io::stdin().lock(), io::BufWriter::new(io::stdout().lock()))"));
+        std::fs::write(&source, rust).unwrap();
+        let status = Command::new("rustc")
+            .args(["--edition", "2024", "-D", "warnings", "-o"])
+            .arg(&binary)
+            .arg(&source)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let mut child = Command::new(&binary)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(b"hi").unwrap();
+        let compiled = child.wait_with_output().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(compiled.stdout, b"hi");
+        assert_eq!(
+            String::from_utf8(compiled.stderr).unwrap(),
+            "Error: input ran out at instruction 3, tape head 0, cell value 105\n"
+        );
+        assert_eq!(
+            compiled.status.code(),
+            Some(RunErrorKind::InputExhausted.exit_code() as i32)
+        );
+    }
+}
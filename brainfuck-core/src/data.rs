@@ -31,6 +31,20 @@ impl BfInstruction {
     pub fn to_u8(self) -> u8 {
         self as u8
     }
+
+    pub(crate) fn from_char(c: char) -> Option<BfInstruction> {
+        match c {
+            '+' => Some(BfInstruction::Inc),
+            '-' => Some(BfInstruction::Dec),
+            '<' => Some(BfInstruction::Left),
+            '>' => Some(BfInstruction::Right),
+            '[' => Some(BfInstruction::LoopStart),
+            ']' => Some(BfInstruction::LoopEnd),
+            ',' => Some(BfInstruction::Input),
+            '.' => Some(BfInstruction::Output),
+            _ => None,
+        }
+    }
 }
 
 impl Display for BfInstruction {
@@ -62,20 +76,10 @@ impl CompressedBF {
 }
 
 impl CompressedBF {
-    pub(crate) fn from_string<T: AsRef<str>>(p0: T) -> CompressedBF {
+    pub fn from_string<T: AsRef<str>>(p0: T) -> CompressedBF {
         let mut bf = CompressedBF::new(0, 0);
-        for c in p0.as_ref().chars() {
-            let instruction = match c {
-                '+' => BfInstruction::Inc,
-                '-' => BfInstruction::Dec,
-                '<' => BfInstruction::Left,
-                '>' => BfInstruction::Right,
-                '[' => BfInstruction::LoopStart,
-                ']' => BfInstruction::LoopEnd,
-                ',' => BfInstruction::Input,
-                '.' => BfInstruction::Output,
-                _ => continue, // Ignore unknown characters
-            };
+        // Ignore unknown characters
+        for instruction in p0.as_ref().chars().filter_map(BfInstruction::from_char) {
             bf.append(instruction);
         }
         bf
@@ -0,0 +1,1043 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::time::Instant;
+
+use crate::{
+    cell::Cell,
+    data::{BfInstruction, CompressedBF},
+    error::RunErrorKind,
+    run::{
+        Budget, ContinueState, DEADLINE_CHECK_INTERVAL, EofBehavior, ProgramState, RunOptions,
+        RunningProgramInfo, TapePolicy, grow_tape_left, grow_tape_right,
+    },
+};
+
+/// Something a [`Debugger`] keeps an eye on, stopping the program right after the instruction that changed it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Watchpoint {
+    /// The value of the cell at this position, counted from the cell the program started on.
+    Cell(isize),
+    /// The position of the tape head.
+    Head,
+    /// Every byte the program prints.
+    Output,
+}
+
+/// The change a [`Watchpoint`] saw.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchEvent<C = u8> {
+    Cell { position: isize, old: C, new: C },
+    Head { old: isize, new: isize },
+    Output(u8),
+}
+
+/// Why a [`Debugger`] stopped running the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugStop<C = u8> {
+    /// The step that was asked for is done.
+    Stepped,
+    /// The next instruction, at this index, has a breakpoint.
+    Breakpoint(usize),
+    /// The last instruction changed something a watchpoint is on.
+    Watchpoint(WatchEvent<C>),
+    /// The [`Budget`] ran out first.
+    Paused,
+    /// The program ran to its end.
+    Finished,
+    /// The next instruction fails with this error, so the program can not go on.
+    Failed(RunErrorKind),
+    /// Going back reached the state the debugger started in.
+    Start,
+    /// The next instruction is a `,` and there is no input for it yet, see [`Debugger::wait_for_input`].
+    WaitingForInput,
+}
+
+/// Debuggers start out keeping a snapshot of the program every this many steps, so that going back past the undo log only has to run forward from the snapshot before.
+const SNAPSHOT_INTERVAL: u64 = 1 << 16;
+
+/// Most snapshots a debugger keeps. Past that it drops every other one and keeps them twice as far apart.
+const SNAPSHOT_LIMIT: usize = 64;
+
+/// Most steps the undo log goes back, older steps are rebuilt from the snapshots.
+const UNDO_LIMIT: usize = 1 << 20;
+
+/// What an instruction changed, enough to take it back.
+#[derive(Debug, Clone, Copy)]
+enum Change<C> {
+    /// A jump, or a `,` at the end of the input that left the cell as it was.
+    None,
+    /// The current cell had this value.
+    Cell(C),
+    /// `,` read a byte over this value of the current cell.
+    Input(C),
+    Output,
+    Left,
+    Right,
+    /// The tape grew by this many cells on the left before the head moved left.
+    GrewLeft(usize),
+    /// The tape had this many cells before it grew on the right and the head moved right.
+    GrewRight(usize),
+}
+
+/// An entry of the undo log.
+#[derive(Debug, Clone, Copy)]
+struct Undo<C> {
+    /// The instruction that ran.
+    pc: usize,
+    change: Change<C>,
+}
+
+/// The program after `steps` steps, with `resume_output_ind` the length of the output.
+#[derive(Debug, Clone)]
+struct Snapshot<C> {
+    steps: u64,
+    state: ContinueState<C>,
+    input_pos: usize,
+}
+
+/// Runs a program an instruction at a time, with breakpoints and watchpoints, keeping its tape, head and output open to inspection in between.
+///
+/// Instructions do what they do for [`Interpreter::run`](crate::Interpreter::run) with the same tape, overflow and EOF options, but nothing is compiled and loops are not detected, so every way of running takes a [`Budget`].
+///
+/// The debugger can also go back: it logs how to take back the last million or so steps, and keeps up to 64 snapshots of the program to rebuild that log from when going back further, 65536 steps apart at first and further apart as the run gets longer.
+#[derive(Debug)]
+pub struct Debugger<C = u8> {
+    program: RunningProgramInfo<C>,
+    options: RunOptions,
+    state: ProgramState<C>,
+    pc: usize,
+    output: Vec<u8>,
+    input: Vec<u8>,
+    input_pos: usize,
+    steps: u64,
+    error: Option<RunErrorKind>,
+    breakpoints: BTreeSet<usize>,
+    watchpoints: BTreeSet<Watchpoint>,
+    history: VecDeque<Undo<C>>,
+    snapshots: Vec<Snapshot<C>>,
+    // the steps at which `,` found no input, so that running them again does the same
+    eof_steps: Vec<u64>,
+    snapshot_interval: u64,
+    snapshot_limit: usize,
+    undo_limit: usize,
+    wait_for_input: bool,
+}
+
+impl<C: Cell> Debugger<C> {
+    /// Starts debugging `program` from the state it is in, which is the start for a program fresh from [`preprocess_input`](crate::util::preprocess_input).
+    ///
+    /// The budget and engine in `options` are not used.
+    pub fn new(program: RunningProgramInfo<C>, options: &RunOptions) -> Self {
+        let state = program.continue_state.program_state.clone();
+        let pc = program.continue_state.resume_pc;
+        let mut debugger = Debugger {
+            program,
+            options: *options,
+            state,
+            pc,
+            output: Vec::new(),
+            input: Vec::new(),
+            input_pos: 0,
+            steps: 0,
+            error: None,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeSet::new(),
+            history: VecDeque::new(),
+            snapshots: Vec::new(),
+            eof_steps: Vec::new(),
+            snapshot_interval: SNAPSHOT_INTERVAL,
+            snapshot_limit: SNAPSHOT_LIMIT,
+            undo_limit: UNDO_LIMIT,
+            wait_for_input: false,
+        };
+        debugger.snapshots.push(debugger.snapshot());
+        debugger
+    }
+
+    /// Adds `input` to the bytes `,` reads. Once they run out `,` does what the EOF option says, unless the debugger waits for input.
+    pub fn push_input(&mut self, input: &[u8]) {
+        self.input.extend_from_slice(input);
+    }
+
+    /// Makes a `,` that finds no input stop with [`DebugStop::WaitingForInput`] instead of doing what the EOF option says, for input that is typed in while debugging.
+    pub fn wait_for_input(&mut self, wait: bool) {
+        self.wait_for_input = wait;
+    }
+
+    pub fn program(&self) -> &CompressedBF {
+        &self.program.code
+    }
+
+    /// Index of the next instruction to run, the program size once it finished.
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// The whole tape. It grows as the program walks off its ends when the tape is growable.
+    pub fn tape(&self) -> &[C] {
+        &self.state.tape
+    }
+
+    /// Index in [`Debugger::tape`] of the cell under the head.
+    pub fn tape_head(&self) -> usize {
+        self.state.tape_head
+    }
+
+    /// Index in [`Debugger::tape`] of the cell the program started on, which moves when the tape grows to the left.
+    pub fn origin(&self) -> usize {
+        self.state.origin
+    }
+
+    /// Position of the head, counted from the cell the program started on like [`StopPoint::tape_head`](crate::StopPoint::tape_head).
+    pub fn head_position(&self) -> isize {
+        self.state.tape_head as isize - self.state.origin as isize
+    }
+
+    /// Value of the cell under the head.
+    pub fn cell(&self) -> C {
+        self.state.tape[self.state.tape_head]
+    }
+
+    /// Value of the cell at `position`, counted from the cell the program started on, or `None` when the tape does not reach it.
+    pub fn cell_at(&self, position: isize) -> Option<C> {
+        let index = self.state.origin.checked_add_signed(position)?;
+        self.state.tape.get(index).copied()
+    }
+
+    /// Everything the program printed so far.
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    /// Number of instructions run so far, jumps included, less the ones taken back.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Whether the program finished or failed, so that it can not run any further.
+    pub fn is_done(&self) -> bool {
+        self.error.is_some() || self.pc >= self.program.code.size()
+    }
+
+    /// Stops runs before the instruction at `pc`, returning false if there already was a breakpoint.
+    pub fn add_breakpoint(&mut self, pc: usize) -> bool {
+        self.breakpoints.insert(pc)
+    }
+
+    /// Returns false if there was no breakpoint at `pc`.
+    pub fn remove_breakpoint(&mut self, pc: usize) -> bool {
+        self.breakpoints.remove(&pc)
+    }
+
+    /// The instructions with breakpoints, in program order.
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// Returns false if the watchpoint was already set.
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> bool {
+        self.watchpoints.insert(watchpoint)
+    }
+
+    /// Returns false if the watchpoint was not set.
+    pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) -> bool {
+        self.watchpoints.remove(&watchpoint)
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = Watchpoint> + '_ {
+        self.watchpoints.iter().copied()
+    }
+
+    /// Runs the next instruction.
+    pub fn step(&mut self) -> DebugStop<C> {
+        if let Some(kind) = self.error {
+            return DebugStop::Failed(kind);
+        }
+        if self.pc >= self.program.code.size() {
+            return DebugStop::Finished;
+        }
+        if self.wait_for_input
+            && self.program.code.get(self.pc) == Some(BfInstruction::Input)
+            && self.input_pos == self.input.len()
+            && self.eof_steps.binary_search(&self.steps).is_err()
+        {
+            return DebugStop::WaitingForInput;
+        }
+        match self.execute_one() {
+            Err(kind) => {
+                self.error = Some(kind);
+                DebugStop::Failed(kind)
+            }
+            Ok(Some(event)) => DebugStop::Watchpoint(event),
+            Ok(None) if self.pc >= self.program.code.size() => DebugStop::Finished,
+            Ok(None) => DebugStop::Stepped,
+        }
+    }
+
+    /// Runs the next instruction, or the whole loop when it is a `[`, stopping early at breakpoints and watchpoints like [`Debugger::run`].
+    pub fn step_over(&mut self, budget: Budget) -> DebugStop<C> {
+        match self.loop_end(self.pc) {
+            Some(after_loop) => self.run_until(Some(after_loop), budget),
+            None => self.step(),
+        }
+    }
+
+    /// Runs until the innermost loop around the next instruction is done, stopping early at breakpoints and watchpoints like [`Debugger::run`].
+    ///
+    /// Outside of loops it runs like [`Debugger::run`].
+    pub fn step_out(&mut self, budget: Budget) -> DebugStop<C> {
+        let after_loop = self.enclosing_loop().and_then(|start| self.loop_end(start));
+        self.run_until(after_loop, budget)
+    }
+
+    /// Index of the instruction after the loop that starts at `pc`, or `None` when `pc` is no `[`.
+    pub fn loop_end(&self, pc: usize) -> Option<usize> {
+        match self.program.code.get(pc)? {
+            BfInstruction::LoopStart => self.program.matching_bracket(pc).map(|end| end + 1),
+            _ => None,
+        }
+    }
+
+    /// Index of the `[` of the innermost loop around the next instruction, or `None` outside of loops.
+    pub fn enclosing_loop(&self) -> Option<usize> {
+        let mut pc = self.pc.checked_sub(1)?;
+        loop {
+            match self.program.code.get(pc)? {
+                BfInstruction::LoopStart => return Some(pc),
+                // the whole loop ends before the next instruction
+                BfInstruction::LoopEnd => pc = self.program.matching_bracket(pc)?,
+                _ => {}
+            }
+            pc = pc.checked_sub(1)?;
+        }
+    }
+
+    /// Runs until the next breakpoint or watchpoint, the end of the program, a failure or the end of `budget`, whichever comes first.
+    ///
+    /// The next instruction always runs, so that a run can go on from a breakpoint.
+    pub fn run(&mut self, budget: Budget) -> DebugStop<C> {
+        self.run_until(None, budget)
+    }
+
+    /// Runs until the next instruction is the one at `pc`, stopping early at breakpoints and watchpoints like [`Debugger::run`].
+    pub fn run_to(&mut self, pc: usize, budget: Budget) -> DebugStop<C> {
+        self.run_until(Some(pc), budget)
+    }
+
+    /// Takes back the last instruction that ran. A program that failed can run again after that.
+    pub fn step_back(&mut self) -> DebugStop<C> {
+        match self.back_one(&BTreeSet::new()) {
+            Some(_) => DebugStop::Stepped,
+            None => DebugStop::Start,
+        }
+    }
+
+    /// Runs backwards until it takes back an instruction with a breakpoint or one that changed something a watchpoint is on, stopping before that instruction, or until the start or the end of `budget`.
+    pub fn run_back(&mut self, budget: Budget) -> DebugStop<C> {
+        let watchpoints = self.watchpoints.clone();
+        self.run_back_until(&watchpoints, true, budget)
+    }
+
+    /// Runs backwards to the last instruction that changed the cell at `position`, counted from the cell the program started on, stopping before it.
+    ///
+    /// Stops with a [`DebugStop::Watchpoint`] for the change whether or not the cell is watched, and ignores breakpoints.
+    pub fn back_to_write(&mut self, position: isize, budget: Budget) -> DebugStop<C> {
+        let watchpoints = BTreeSet::from([Watchpoint::Cell(position)]);
+        self.run_back_until(&watchpoints, false, budget)
+    }
+
+    /// Goes to the state after `step` instructions ran, ignoring breakpoints and watchpoints.
+    ///
+    /// Going forward stops early when the program finishes or fails or `budget` runs out. Going back is never longer than running forward from the last snapshot, so it ignores `budget`.
+    pub fn goto_step(&mut self, step: u64, budget: Budget) -> DebugStop<C> {
+        if step < self.steps {
+            if self.steps - step <= self.history.len() as u64 {
+                while self.steps > step {
+                    self.back_one(&BTreeSet::new());
+                }
+            } else {
+                let index = self
+                    .snapshots
+                    .partition_point(|snapshot| snapshot.steps <= step);
+                self.restore(index - 1);
+                self.replay(step);
+            }
+            return DebugStop::Stepped;
+        }
+        let mut steps = 0;
+        while self.steps < step {
+            if spent(&budget, steps) {
+                return DebugStop::Paused;
+            }
+            steps += 1;
+            match self.step() {
+                DebugStop::Stepped | DebugStop::Watchpoint(_) => {}
+                DebugStop::Finished if self.steps == step => {}
+                stop => return stop,
+            }
+        }
+        DebugStop::Stepped
+    }
+
+    //runs until the next instruction is `target`, or something else stops the run
+    fn run_until(&mut self, target: Option<usize>, budget: Budget) -> DebugStop<C> {
+        let mut steps = 0;
+        loop {
+            if spent(&budget, steps) {
+                return DebugStop::Paused;
+            }
+            steps += 1;
+            match self.step() {
+                DebugStop::Stepped if Some(self.pc) == target => return DebugStop::Stepped,
+                DebugStop::Stepped if self.breakpoints.contains(&self.pc) => {
+                    return DebugStop::Breakpoint(self.pc);
+                }
+                DebugStop::Stepped => {}
+                stop => return stop,
+            }
+        }
+    }
+
+    //runs backwards until an instruction that changed something one of `watchpoints` is on, or one with a breakpoint when `breakpoints` is set
+    fn run_back_until(
+        &mut self,
+        watchpoints: &BTreeSet<Watchpoint>,
+        breakpoints: bool,
+        budget: Budget,
+    ) -> DebugStop<C> {
+        let mut steps = 0;
+        loop {
+            if spent(&budget, steps) {
+                return DebugStop::Paused;
+            }
+            steps += 1;
+            match self.back_one(watchpoints) {
+                None => return DebugStop::Start,
+                Some(Some(event)) => return DebugStop::Watchpoint(event),
+                Some(None) if breakpoints && self.breakpoints.contains(&self.pc) => {
+                    return DebugStop::Breakpoint(self.pc);
+                }
+                Some(None) => {}
+            }
+        }
+    }
+
+    //runs the instruction at `pc`, returning the change it made if a watchpoint is on it, and leaving everything as it was when it fails
+    fn execute_one(&mut self) -> Result<Option<WatchEvent<C>>, RunErrorKind> {
+        let overflow = self.options.overflow;
+        let head = self.head_position();
+        let pc = self.pc;
+        let mut next = pc + 1;
+        let change = match self.program.code.get(pc) {
+            None => unreachable!("pc {} is inside the program", pc),
+            Some(BfInstruction::Inc) => {
+                let value = self
+                    .cell()
+                    .increment(overflow)
+                    .ok_or(RunErrorKind::CellOverflow)?;
+                Change::Cell(self.set_cell(value))
+            }
+            Some(BfInstruction::Dec) => {
+                let value = self
+                    .cell()
+                    .decrement(overflow)
+                    .ok_or(RunErrorKind::CellOverflow)?;
+                Change::Cell(self.set_cell(value))
+            }
+            Some(BfInstruction::Left) => {
+                let state = &mut self.state;
+                let mut change = Change::Left;
+                if state.tape_head == 0 {
+                    match self.options.tape {
+                        TapePolicy::Growable {
+                            grow_left: true,
+                            max_cells,
+                        } => {
+                            let len = state.tape.len();
+                            if !grow_tape_left(
+                                &mut state.tape,
+                                &mut state.tape_head,
+                                &mut state.origin,
+                                max_cells,
+                            ) {
+                                return Err(RunErrorKind::OutOfMemory);
+                            }
+                            change = Change::GrewLeft(state.tape.len() - len);
+                        }
+                        _ => return Err(RunErrorKind::TapeHeadBound),
+                    }
+                }
+                state.tape_head -= 1;
+                change
+            }
+            Some(BfInstruction::Right) => {
+                let state = &mut self.state;
+                let mut change = Change::Right;
+                if state.tape_head + 1 == state.tape.len() {
+                    let len = state.tape.len();
+                    let grew = match self.options.tape {
+                        TapePolicy::Growable { max_cells, .. } => {
+                            grow_tape_right(&mut state.tape, max_cells)
+                        }
+                        TapePolicy::Fixed => false,
+                    };
+                    if !grew {
+                        return Err(RunErrorKind::OutOfMemory);
+                    }
+                    change = Change::GrewRight(len);
+                }
+                state.tape_head += 1;
+                change
+            }
+            Some(BfInstruction::LoopStart) => {
+                if self.cell() == C::ZERO {
+                    match self.program.jump_table[pc] {
+                        -2 => return Err(RunErrorKind::UnclosedLoop),
+                        after_loop => next = after_loop as usize,
+                    }
+                }
+                Change::None
+            }
+            Some(BfInstruction::LoopEnd) => {
+                if self.cell() != C::ZERO {
+                    next = self.program.jump_table[pc] as usize;
+                }
+                Change::None
+            }
+            Some(BfInstruction::Output) => {
+                self.output.push(self.cell().to_byte());
+                Change::Output
+            }
+            Some(BfInstruction::Input) => {
+                // a `,` that found no input the first time finds none when the step is run again, even if there is more input now
+                let was_eof = self.eof_steps.binary_search(&self.steps).is_ok();
+                let byte = if was_eof {
+                    None
+                } else {
+                    self.input.get(self.input_pos).copied()
+                };
+                if let Some(byte) = byte {
+                    self.input_pos += 1;
+                    Change::Input(self.set_cell(C::from_byte(byte)))
+                } else {
+                    let change = match self.options.eof {
+                        EofBehavior::Error => return Err(RunErrorKind::InputExhausted),
+                        EofBehavior::Zero => Change::Cell(self.set_cell(C::ZERO)),
+                        EofBehavior::Max => Change::Cell(self.set_cell(C::MAX)),
+                        EofBehavior::Unchanged => Change::None,
+                    };
+                    if !was_eof {
+                        self.eof_steps.push(self.steps);
+                    }
+                    change
+                }
+            }
+        };
+        let event = self.event(change, &self.watchpoints, head);
+        self.history.push_back(Undo { pc, change });
+        if self.history.len() > self.undo_limit {
+            self.history.pop_front();
+        }
+        self.pc = next;
+        self.steps += 1;
+        if self.steps.is_multiple_of(self.snapshot_interval)
+            && self
+                .snapshots
+                .last()
+                .is_some_and(|snapshot| snapshot.steps < self.steps)
+        {
+            self.snapshots.push(self.snapshot());
+            if self.snapshots.len() > self.snapshot_limit {
+                // the snapshots are at every multiple of the interval so far, so this keeps every other one
+                self.snapshot_interval *= 2;
+                let interval = self.snapshot_interval;
+                self.snapshots
+                    .retain(|snapshot| snapshot.steps.is_multiple_of(interval));
+            }
+        }
+        Ok(event)
+    }
+
+    //takes back the last instruction that ran, returning the change it made if one of `watchpoints` is on it, or `None` at the start
+    fn back_one(&mut self, watchpoints: &BTreeSet<Watchpoint>) -> Option<Option<WatchEvent<C>>> {
+        self.error = None;
+        if self.steps == 0 {
+            return None;
+        }
+        if self.history.is_empty() {
+            // the undo log does not go back this far, so rebuild it from the snapshot before
+            let steps = self.steps;
+            let index = self
+                .snapshots
+                .partition_point(|snapshot| snapshot.steps < steps);
+            self.restore(index - 1);
+            self.replay(steps);
+        }
+        let undo = self.history.pop_back()?;
+        let head = self.head_position();
+        let before = match undo.change {
+            Change::Left | Change::GrewLeft(_) => head + 1,
+            Change::Right | Change::GrewRight(_) => head - 1,
+            _ => head,
+        };
+        // seen backwards, the change goes from the state before the step to the current one
+        let event = self.event(undo.change, watchpoints, before);
+        let state = &mut self.state;
+        match undo.change {
+            Change::None => {}
+            Change::Cell(old) => state.tape[state.tape_head] = old,
+            Change::Input(old) => {
+                state.tape[state.tape_head] = old;
+                self.input_pos -= 1;
+            }
+            Change::Output => {
+                self.output.pop();
+            }
+            Change::Left => state.tape_head += 1,
+            Change::Right => state.tape_head -= 1,
+            Change::GrewLeft(extra) => {
+                state.tape.drain(..extra);
+                state.tape_head = state.tape_head + 1 - extra;
+                state.origin -= extra;
+            }
+            Change::GrewRight(len) => {
+                state.tape_head -= 1;
+                state.tape.truncate(len);
+            }
+        }
+        self.pc = undo.pc;
+        self.steps -= 1;
+        Some(event)
+    }
+
+    //the event for `change`, made by the last instruction with the head at position `before` when it started, if one of `watchpoints` is on it
+    fn event(
+        &self,
+        change: Change<C>,
+        watchpoints: &BTreeSet<Watchpoint>,
+        before: isize,
+    ) -> Option<WatchEvent<C>> {
+        let position = self.head_position();
+        match change {
+            Change::None => None,
+            Change::Cell(old) | Change::Input(old) => {
+                let new = self.cell();
+                (old != new && watchpoints.contains(&Watchpoint::Cell(position)))
+                    .then_some(WatchEvent::Cell { position, old, new })
+            }
+            Change::Output => watchpoints
+                .contains(&Watchpoint::Output)
+                .then(|| WatchEvent::Output(*self.output.last().expect("a byte was printed"))),
+            Change::Left | Change::Right | Change::GrewLeft(_) | Change::GrewRight(_) => {
+                watchpoints
+                    .contains(&Watchpoint::Head)
+                    .then_some(WatchEvent::Head {
+                        old: before,
+                        new: position,
+                    })
+            }
+        }
+    }
+
+    //stores `value` in the current cell, returning what was there
+    fn set_cell(&mut self, value: C) -> C {
+        std::mem::replace(&mut self.state.tape[self.state.tape_head], value)
+    }
+
+    fn snapshot(&self) -> Snapshot<C> {
+        Snapshot {
+            steps: self.steps,
+            state: ContinueState {
+                program_state: self.state.clone(),
+                resume_pc: self.pc,
+                resume_output_ind: self.output.len(),
+            },
+            input_pos: self.input_pos,
+        }
+    }
+
+    //goes back to the snapshot at `index`, with an empty undo log
+    fn restore(&mut self, index: usize) {
+        let snapshot = &self.snapshots[index];
+        self.state = snapshot.state.program_state.clone();
+        self.pc = snapshot.state.resume_pc;
+        self.output.truncate(snapshot.state.resume_output_ind);
+        self.input_pos = snapshot.input_pos;
+        self.steps = snapshot.steps;
+        self.error = None;
+        self.history.clear();
+    }
+
+    //runs forward again to `steps` steps after going back to a snapshot
+    fn replay(&mut self, steps: u64) {
+        while self.steps < steps {
+            if let DebugStop::Failed(_) | DebugStop::Finished | DebugStop::WaitingForInput =
+                self.step()
+            {
+                break;
+            }
+        }
+    }
+}
+
+//whether a run that took `steps` steps used up `budget`
+fn spent(budget: &Budget, steps: u64) -> bool {
+    if budget.max_steps.is_some_and(|max_steps| steps >= max_steps) {
+        return true;
+    }
+    budget.deadline.is_some_and(|deadline| {
+        steps.is_multiple_of(DEADLINE_CHECK_INTERVAL) && Instant::now() >= deadline
+    })
+}
+
+// --- UNIT TESTS ---
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cell::OverflowMode;
+    use crate::run::run_program_fragment_no_target;
+    use crate::util::preprocess_input;
+
+    fn start(program: &str, options: &RunOptions) -> Debugger {
+        Debugger::new(preprocess_input(program, 4).unwrap(), options)
+    }
+
+    /// Stepping over the loop runs all of it, stepping into it stops at every instruction.
+    #[test]
+    fn test_step_and_step_over() {
+        let mut debugger = start("++[->+<]>.", &RunOptions::default());
+        assert_eq!(debugger.step(), DebugStop::Stepped);
+        assert_eq!(debugger.step(), DebugStop::Stepped);
+        assert_eq!(debugger.pc(), 2);
+        assert_eq!(debugger.step_over(Budget::default()), DebugStop::Stepped);
+        assert_eq!(debugger.pc(), 8);
+        assert_eq!(debugger.tape(), &[0, 2, 0, 0]);
+        assert_eq!(debugger.steps(), 2 + 1 + 2 * 5);
+        assert_eq!(debugger.step_over(Budget::default()), DebugStop::Stepped);
+        assert_eq!(debugger.head_position(), 1);
+        assert_eq!(debugger.step(), DebugStop::Finished);
+        assert_eq!(debugger.output(), &[2]);
+        assert!(debugger.is_done());
+        assert_eq!(debugger.step(), DebugStop::Finished);
+
+        // a loop that is skipped is stepped over in one step
+        let mut debugger = start("[+].", &RunOptions::default());
+        assert_eq!(debugger.step(), DebugStop::Stepped);
+        assert_eq!(debugger.pc(), 3);
+    }
+
+    /// Stepping out runs to the end of the innermost loop around the next instruction, skipping the loops before it.
+    #[test]
+    fn test_step_out() {
+        let mut debugger = start("++[>+[-]+[->+<]<-]>.", &RunOptions::default());
+        assert_eq!(debugger.enclosing_loop(), None);
+        assert_eq!(debugger.loop_end(2), Some(18));
+        assert_eq!(debugger.loop_end(1), None);
+        assert_eq!(debugger.run_to(11, Budget::default()), DebugStop::Stepped);
+        assert_eq!(debugger.enclosing_loop(), Some(9));
+        assert_eq!(debugger.step_out(Budget::default()), DebugStop::Stepped);
+        assert_eq!(debugger.pc(), 15);
+        assert_eq!(debugger.enclosing_loop(), Some(2));
+        assert_eq!(debugger.step_out(Budget::default()), DebugStop::Stepped);
+        assert_eq!(debugger.pc(), 18);
+        assert_eq!(debugger.tape(), &[0, 0, 2, 0]);
+        assert_eq!(debugger.step_out(Budget::default()), DebugStop::Finished);
+        assert_eq!(debugger.output(), &[0]);
+    }
+
+    /// Runs stop before instructions with a breakpoint and go on from there, and pause when the budget runs out.
+    #[test]
+    fn test_breakpoints_and_budget() {
+        let mut debugger = start("+++[>+<-]", &RunOptions::default());
+        assert!(debugger.add_breakpoint(5));
+        assert!(!debugger.add_breakpoint(5));
+        for cell in 0..3 {
+            assert_eq!(debugger.run(Budget::default()), DebugStop::Breakpoint(5));
+            assert_eq!(debugger.cell(), cell);
+        }
+        assert!(debugger.remove_breakpoint(5));
+        assert_eq!(debugger.breakpoints().count(), 0);
+        assert_eq!(debugger.run(Budget::default()), DebugStop::Finished);
+        assert_eq!(debugger.tape(), &[0, 3, 0, 0]);
+
+        let mut debugger = start("+[]", &RunOptions::default());
+        assert_eq!(debugger.run(Budget::steps(1000)), DebugStop::Paused);
+        assert_eq!(debugger.steps(), 1000);
+
+        let mut debugger = start("+[]", &RunOptions::default());
+        debugger.step();
+        assert_eq!(debugger.step_over(Budget::steps(10)), DebugStop::Paused);
+
+        // running to an instruction stops there, or at a breakpoint on the way
+        let mut debugger = start("+++[>+<-]>.", &RunOptions::default());
+        debugger.add_breakpoint(5);
+        assert_eq!(
+            debugger.run_to(9, Budget::default()),
+            DebugStop::Breakpoint(5)
+        );
+        debugger.remove_breakpoint(5);
+        assert_eq!(debugger.run_to(9, Budget::default()), DebugStop::Stepped);
+        assert_eq!(debugger.pc(), 9);
+        assert_eq!(debugger.tape(), &[0, 3, 0, 0]);
+    }
+
+    /// Watchpoints stop right after a watched cell changes, the head moves or a byte is printed.
+    #[test]
+    fn test_watchpoints() {
+        let mut debugger = start("+>++<+.>", &RunOptions::default());
+        debugger.add_watchpoint(Watchpoint::Cell(1));
+        debugger.add_watchpoint(Watchpoint::Output);
+        assert_eq!(
+            debugger.run(Budget::default()),
+            DebugStop::Watchpoint(WatchEvent::Cell {
+                position: 1,
+                old: 0,
+                new: 1
+            })
+        );
+        assert_eq!(debugger.pc(), 3);
+        assert_eq!(
+            debugger.run(Budget::default()),
+            DebugStop::Watchpoint(WatchEvent::Cell {
+                position: 1,
+                old: 1,
+                new: 2
+            })
+        );
+        assert_eq!(
+            debugger.run(Budget::default()),
+            DebugStop::Watchpoint(WatchEvent::Output(2))
+        );
+        debugger.add_watchpoint(Watchpoint::Head);
+        assert_eq!(
+            debugger.run(Budget::default()),
+            DebugStop::Watchpoint(WatchEvent::Head { old: 0, new: 1 })
+        );
+        assert_eq!(debugger.run(Budget::default()), DebugStop::Finished);
+    }
+
+    /// A program that fails stays on the failing instruction with its tape as it was, like the interpreter reports it, and reads the input it was given.
+    #[test]
+    fn test_failures_and_input() {
+        let options = RunOptions {
+            tape: TapePolicy::Growable {
+                grow_left: true,
+                max_cells: 8,
+            },
+            ..RunOptions::default()
+        };
+        let mut debugger = start(",.<<,.", &options);
+        debugger.push_input(b"a");
+        assert_eq!(
+            debugger.run(Budget::default()),
+            DebugStop::Failed(RunErrorKind::InputExhausted)
+        );
+        assert_eq!(debugger.pc(), 4);
+        assert_eq!(debugger.head_position(), -2);
+        assert_eq!(debugger.cell_at(0), Some(b'a'));
+        assert_eq!(debugger.tape().len(), 8);
+        assert_eq!(debugger.output(), b"a");
+        assert_eq!(
+            debugger.step(),
+            DebugStop::Failed(RunErrorKind::InputExhausted)
+        );
+
+        let mut debugger = start(",,.", &RunOptions::default());
+        debugger.wait_for_input(true);
+        assert_eq!(debugger.run(Budget::default()), DebugStop::WaitingForInput);
+        debugger.push_input(b"ab");
+        assert_eq!(debugger.run(Budget::default()), DebugStop::Finished);
+        assert_eq!(debugger.output(), b"b");
+
+        let mut debugger = start("<", &RunOptions::default());
+        assert_eq!(
+            debugger.step(),
+            DebugStop::Failed(RunErrorKind::TapeHeadBound)
+        );
+        assert_eq!(debugger.cell_at(-1), None);
+    }
+
+    /// Running to the end gives the output and result of the interpreter.
+    #[test]
+    fn test_debugger_matches_interpreter() {
+        let programs = [
+            "++++++++[>++++++++<-]>+.+.+.",
+            "-[->+>++<<]>.>.",
+            "+[>+]",
+            ",[.,]",
+            ",+[-.,+]",
+            "+++++[-]---.",
+        ];
+        for program in programs {
+            for overflow in [
+                OverflowMode::Wrap,
+                OverflowMode::Saturate,
+                OverflowMode::Error,
+            ] {
+                let options = RunOptions {
+                    overflow,
+                    ..RunOptions::default()
+                };
+                let info = preprocess_input(program, 4).unwrap();
+                let mut input = b"ab".iter().copied();
+                let mut output = Vec::new();
+                let result = run_program_fragment_no_target(
+                    &info,
+                    &options,
+                    || input.next(),
+                    |byte| output.push(byte),
+                );
+                let mut debugger = Debugger::new(info, &options);
+                debugger.push_input(b"ab");
+                let stop = match debugger.run(Budget::default()) {
+                    DebugStop::Failed(kind) => Some(kind),
+                    DebugStop::Finished => None,
+                    stop => panic!("{} stopped with {:?}", program, stop),
+                };
+                assert_eq!(stop, result.error_kind(), "{} {:?}", program, overflow);
+                assert_eq!(debugger.output(), output, "{} {:?}", program, overflow);
+            }
+        }
+    }
+
+    //everything about the program a step can change
+    fn state(debugger: &Debugger) -> (usize, Vec<u8>, isize, Vec<u8>) {
+        (
+            debugger.pc(),
+            debugger.tape().to_vec(),
+            debugger.head_position(),
+            debugger.output().to_vec(),
+        )
+    }
+
+    /// Stepping back goes through the same states as stepping forward, on a tape that grows both ways and with an undo log too short for the whole run and fewer snapshots than it takes.
+    #[test]
+    fn test_step_back() {
+        let options = RunOptions {
+            tape: TapePolicy::Growable {
+                grow_left: true,
+                max_cells: 32,
+            },
+            ..RunOptions::default()
+        };
+        let mut debugger = start("++[-<<<<<+>>>>>]<<<<<.>>>>>>>>>>+.[-]", &options);
+        debugger.snapshot_interval = 4;
+        debugger.snapshot_limit = 3;
+        debugger.undo_limit = 8;
+        let mut states = vec![state(&debugger)];
+        while !debugger.is_done() {
+            debugger.step();
+            states.push(state(&debugger));
+        }
+        assert_eq!(debugger.output(), &[2, 1]);
+        assert_eq!(debugger.tape().len(), 32);
+        assert!(debugger.snapshots.len() <= 3);
+        assert!(debugger.snapshot_interval > 4);
+        while let Some(expected) = states.pop() {
+            assert_eq!(state(&debugger), expected, "{}", debugger.steps());
+            let stop = debugger.step_back();
+            assert_eq!(stop == DebugStop::Start, states.is_empty());
+        }
+        assert_eq!(debugger.steps(), 0);
+        assert_eq!(debugger.run(Budget::default()), DebugStop::Finished);
+        assert_eq!(debugger.output(), &[2, 1]);
+
+        // a failed program can be taken back and run again
+        let mut debugger = start("+<", &RunOptions::default());
+        assert_eq!(
+            debugger.run(Budget::default()),
+            DebugStop::Failed(RunErrorKind::TapeHeadBound)
+        );
+        assert_eq!(debugger.step_back(), DebugStop::Stepped);
+        assert_eq!((debugger.pc(), debugger.cell()), (0, 0));
+        assert_eq!(debugger.step(), DebugStop::Stepped);
+        assert_eq!(
+            debugger.step(),
+            DebugStop::Failed(RunErrorKind::TapeHeadBound)
+        );
+    }
+
+    /// Running back stops at the instruction that last changed a cell, at breakpoints and at the start.
+    #[test]
+    fn test_run_back() {
+        let mut debugger = start("+++>++<[-]>[-<+>]", &RunOptions::default());
+        assert_eq!(debugger.run(Budget::default()), DebugStop::Finished);
+        assert_eq!(debugger.tape(), &[2, 0, 0, 0]);
+        // who set cell 1 to 0
+        assert_eq!(
+            debugger.back_to_write(1, Budget::default()),
+            DebugStop::Watchpoint(WatchEvent::Cell {
+                position: 1,
+                old: 1,
+                new: 0
+            })
+        );
+        assert_eq!(debugger.pc(), 12);
+        assert_eq!(
+            debugger.back_to_write(0, Budget::default()),
+            DebugStop::Watchpoint(WatchEvent::Cell {
+                position: 0,
+                old: 0,
+                new: 1
+            })
+        );
+        assert_eq!(debugger.pc(), 14);
+        assert_eq!(
+            debugger.back_to_write(0, Budget::steps(3)),
+            DebugStop::Paused
+        );
+
+        debugger.add_breakpoint(8);
+        assert_eq!(
+            debugger.run_back(Budget::default()),
+            DebugStop::Breakpoint(8)
+        );
+        assert_eq!(debugger.cell(), 1);
+        debugger.remove_breakpoint(8);
+        debugger.add_watchpoint(Watchpoint::Head);
+        assert_eq!(
+            debugger.run_back(Budget::default()),
+            DebugStop::Watchpoint(WatchEvent::Head { old: 1, new: 0 })
+        );
+        assert_eq!(debugger.pc(), 6);
+        debugger.remove_watchpoint(Watchpoint::Head);
+        assert_eq!(debugger.run_back(Budget::default()), DebugStop::Start);
+        assert_eq!(
+            debugger.back_to_write(0, Budget::default()),
+            DebugStop::Start
+        );
+    }
+
+    /// Jumping to a step gives the state of the program after that many steps, and a `,` that found no input finds none when it runs again, even after more input came.
+    #[test]
+    fn test_goto_step() {
+        let options = RunOptions {
+            eof: EofBehavior::Zero,
+            ..RunOptions::default()
+        };
+        let mut debugger = start(",.,.>>>,.", &options);
+        debugger.snapshot_interval = 2;
+        debugger.undo_limit = 2;
+        debugger.push_input(b"a");
+        let mut states = vec![state(&debugger)];
+        while debugger.steps() < 7 {
+            debugger.step();
+            states.push(state(&debugger));
+        }
+        assert_eq!(debugger.output(), b"a\0");
+        debugger.push_input(b"b");
+        for step in [3, 7, 1, 4, 0, 6, 5, 5, 2] {
+            assert_eq!(
+                debugger.goto_step(step, Budget::default()),
+                DebugStop::Stepped
+            );
+            assert_eq!(debugger.steps(), step);
+            assert_eq!(state(&debugger), states[step as usize], "{}", step);
+        }
+        assert_eq!(debugger.goto_step(5, Budget::steps(2)), DebugStop::Paused);
+        assert_eq!(
+            debugger.goto_step(100, Budget::default()),
+            DebugStop::Finished
+        );
+        assert_eq!(debugger.output(), b"a\0b");
+    }
+}
@@ -0,0 +1,281 @@
+use std::{error::Error, fmt, io, path::PathBuf};
+
+use crate::{run::StopPoint, source::SourceSpan};
+
+/// A program could not be turned into something runnable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// The `[` at this instruction index is never closed.
+    UnmatchedLoopStart {
+        instruction: usize,
+        span: SourceSpan,
+    },
+    /// The `]` at this instruction index has no `[` before it.
+    UnmatchedLoopEnd {
+        instruction: usize,
+        span: SourceSpan,
+    },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnmatchedLoopStart { span, .. } => {
+                write!(f, "loop start at {} is never closed", span)
+            }
+            ParseError::UnmatchedLoopEnd { span, .. } => {
+                write!(f, "loop end at {} has no matching loop start", span)
+            }
+        }
+    }
+}
+
+impl ParseError {
+    /// Where in the source the unmatched bracket is.
+    pub fn span(&self) -> SourceSpan {
+        match self {
+            ParseError::UnmatchedLoopStart { span, .. }
+            | ParseError::UnmatchedLoopEnd { span, .. } => *span,
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+/// A program could not be set up to run, see [`preprocess_input`](crate::util::preprocess_input).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PreprocessError {
+    Parse(ParseError),
+    /// The tape to run the program on has no cells.
+    ZeroTapeSize,
+}
+
+impl fmt::Display for PreprocessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PreprocessError::Parse(err) => err.fmt(f),
+            PreprocessError::ZeroTapeSize => write!(f, "tape size must be at least one cell"),
+        }
+    }
+}
+
+impl PreprocessError {
+    /// Where in the source the error is, if it is about a particular instruction.
+    pub fn span(&self) -> Option<SourceSpan> {
+        match self {
+            PreprocessError::Parse(err) => Some(err.span()),
+            PreprocessError::ZeroTapeSize => None,
+        }
+    }
+}
+
+impl Error for PreprocessError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PreprocessError::Parse(err) => Some(err),
+            PreprocessError::ZeroTapeSize => None,
+        }
+    }
+}
+
+impl From<ParseError> for PreprocessError {
+    fn from(err: ParseError) -> Self {
+        PreprocessError::Parse(err)
+    }
+}
+
+/// Why a run stopped without finishing, see [`RunError`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunErrorKind {
+    /// The head moved left of the first cell.
+    TapeHeadBound,
+    /// The head moved right of the last cell, or a growable tape hit its cell limit.
+    OutOfMemory,
+    /// The run came back to a state it had already been in, so it would never finish.
+    InfiniteLoop,
+    /// `,` ran out of input.
+    InputExhausted,
+    /// A cell went past its bounds with overflow set to error.
+    CellOverflow,
+    /// The run reached a `[` that is never closed.
+    UnclosedLoop,
+    /// The run printed something other than the target output.
+    TargetMismatch,
+}
+
+impl RunErrorKind {
+    /// The exit code `brainfuck-main run` and the programs it compiles stop with for this error.
+    pub fn exit_code(self) -> u8 {
+        match self {
+            RunErrorKind::TapeHeadBound => 3,
+            RunErrorKind::OutOfMemory => 4,
+            RunErrorKind::InfiniteLoop => 5,
+            RunErrorKind::InputExhausted => 6,
+            RunErrorKind::CellOverflow => 7,
+            RunErrorKind::UnclosedLoop => 8,
+            RunErrorKind::TargetMismatch => 9,
+        }
+    }
+}
+
+impl fmt::Display for RunErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            RunErrorKind::TapeHeadBound => "tape head moved left of the first cell",
+            RunErrorKind::OutOfMemory => "tape head moved past the last cell",
+            RunErrorKind::InfiniteLoop => "infinite loop detected",
+            RunErrorKind::InputExhausted => "input ran out",
+            RunErrorKind::CellOverflow => "cell overflowed",
+            RunErrorKind::UnclosedLoop => "unclosed loop",
+            RunErrorKind::TargetMismatch => "output does not match the target",
+        };
+        f.write_str(message)
+    }
+}
+
+/// A run that failed, with the point it failed at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunError<C = u8> {
+    pub kind: RunErrorKind,
+    pub stop: StopPoint<C>,
+}
+
+impl<C: fmt::Debug> fmt::Display for RunError<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at instruction {}, tape head {}, cell value {:?}",
+            self.kind, self.stop.pc, self.stop.tape_head, self.stop.cell
+        )
+    }
+}
+
+impl<C: fmt::Debug> Error for RunError<C> {}
+
+/// Reading or writing one of the seed files the search spills its layers to failed.
+#[derive(Debug)]
+pub enum SeedError {
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// The file was written for programs of a different size.
+    ProgramSizeMismatch {
+        path: PathBuf,
+        expected: usize,
+        found: usize,
+    },
+    /// The file ends in the middle of a seed or holds something that is not an instruction.
+    Corrupt {
+        path: PathBuf,
+    },
+}
+
+impl fmt::Display for SeedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SeedError::Io { path, source } => {
+                write!(f, "seed file {}: {}", path.display(), source)
+            }
+            SeedError::ProgramSizeMismatch {
+                path,
+                expected,
+                found,
+            } => write!(
+                f,
+                "seed file {} holds programs of size {} instead of {}",
+                path.display(),
+                found,
+                expected
+            ),
+            SeedError::Corrupt { path } => write!(f, "seed file {} is corrupt", path.display()),
+        }
+    }
+}
+
+impl Error for SeedError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SeedError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// [`find_program`](crate::find_program) gave up.
+#[derive(Debug)]
+pub enum SearchError {
+    /// The tape of the candidates has no cells.
+    ZeroTapeSize,
+    StartingProgramTooLarge {
+        size: usize,
+        max_program_size: usize,
+    },
+    /// The starting program has unbalanced loops.
+    StartingProgram(ParseError),
+    /// Every program up to the maximum size was tried.
+    MaxSizeReached {
+        max_program_size: usize,
+    },
+    /// The scratch directory for the seed files could not be created.
+    SeedDirectory {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Seed(SeedError),
+}
+
+impl fmt::Display for SearchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SearchError::ZeroTapeSize => write!(f, "tape size must be at least one cell"),
+            SearchError::StartingProgramTooLarge {
+                size,
+                max_program_size,
+            } => write!(
+                f,
+                "starting program has {} instructions, more than the maximum program size of {}",
+                size, max_program_size
+            ),
+            SearchError::StartingProgram(err) => write!(f, "starting program: {}", err),
+            SearchError::MaxSizeReached { max_program_size } => write!(
+                f,
+                "no program of at most {} instructions prints the target",
+                max_program_size
+            ),
+            SearchError::SeedDirectory { path, source } => write!(
+                f,
+                "could not create the seed directory {}: {}",
+                path.display(),
+                source
+            ),
+            SearchError::Seed(err) => err.fmt(f),
+        }
+    }
+}
+
+impl Error for SearchError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SearchError::StartingProgram(err) => Some(err),
+            SearchError::SeedDirectory { source, .. } => Some(source),
+            SearchError::Seed(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<SeedError> for SearchError {
+    fn from(err: SeedError) -> Self {
+        SearchError::Seed(err)
+    }
+}
+
+impl From<PreprocessError> for SearchError {
+    fn from(err: PreprocessError) -> Self {
+        match err {
+            PreprocessError::Parse(err) => SearchError::StartingProgram(err),
+            PreprocessError::ZeroTapeSize => SearchError::ZeroTapeSize,
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use crate::{error::ParseError, util::parse_program};
+
+/// Indents every line of `source` with `indent` once for each loop it is in, and once less for every `]` it starts with.
+///
+/// Whitespace at the ends of lines goes away, runs of blank lines become one and the result ends with one line break. Everything else, comments included, stays as it is.
+/// Fails like [`parse_program`] when the loops do not match, as then how deep a line is in them is not known.
+pub fn format_source(source: &str, indent: &str) -> Result<String, ParseError> {
+    parse_program(source)?;
+    let mut formatted = String::with_capacity(source.len());
+    let mut depth = 0;
+    let mut blank = false;
+    for line in source.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            blank = !formatted.is_empty();
+            continue;
+        }
+        if blank {
+            formatted.push('\n');
+            blank = false;
+        }
+        let closing = line
+            .chars()
+            .take_while(|&c| c == ']' || c.is_whitespace())
+            .filter(|&c| c == ']')
+            .count();
+        for _ in closing..depth {
+            formatted.push_str(indent);
+        }
+        formatted.push_str(line);
+        formatted.push('\n');
+        depth += line.matches('[').count();
+        depth -= line.matches(']').count();
+    }
+    Ok(formatted)
+}
+
+// --- UNIT TESTS ---
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_source() {
+        let source = "\n\nset up\n++[   \n>++[\n- move it\n\t]<-\n\n\n  ] done\n\n";
+        let formatted = format_source(source, "  ").unwrap();
+        assert_eq!(
+            formatted,
+            "set up\n++[\n  >++[\n    - move it\n  ]<-\n\n] done\n"
+        );
+        assert_eq!(format_source(&formatted, "  ").unwrap(), formatted);
+        assert_eq!(format_source("", "\t").unwrap(), "");
+    }
+
+    /// Lines are not moved around when the loops do not match.
+    #[test]
+    fn test_format_unmatched() {
+        assert!(matches!(
+            format_source("[\n+", "  "),
+            Err(ParseError::UnmatchedLoopStart { .. })
+        ));
+        assert!(matches!(
+            format_source("]", "  "),
+            Err(ParseError::UnmatchedLoopEnd { .. })
+        ));
+    }
+}
@@ -0,0 +1,1106 @@
+use std::ops::Range;
+use std::time::Instant;
+
+#[cfg(all(target_arch = "x86_64", unix))]
+use crate::jit::NativeCode;
+use crate::{
+    cell::{Cell, OverflowMode},
+    data::{BfInstruction, CompressedBF},
+    loop_detector::{CycleFinder, LoopDetector},
+    run::{
+        BfRunResult, Budget, Budgeted, ContinueState, DEADLINE_CHECK_INTERVAL, EofBehavior,
+        OutputPolicy, ProgramState, ReadFailed, RunOptions, RunningProgramInfo, StepCheck,
+        StopPoint, Tape, TapePolicy, TerminationPolicy, Unchecked, execute, execute_from,
+        finish_run, grow_tape_left, grow_tape_right,
+    },
+};
+
+/// An operation of a [`CompiledProgram`], standing for one or more Brainfuck instructions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    /// Adds to the current cell, a negative amount subtracts.
+    Add(i64),
+    /// Moves the head, to the right for a positive distance.
+    Move(isize),
+    /// `[-]`, clears the current cell.
+    SetZero,
+    /// A loop like `[->++>+++<<]`, which decrements the current cell once per iteration and only adds to the cells around it.
+    /// Adds the current cell times `factor` to the cell `offset` cells away for every target, then clears the current cell.
+    MulMove {
+        targets: Vec<(isize, i64)>,
+        /// Offsets of the leftmost and rightmost cells the loop body visits.
+        reach: (isize, isize),
+    },
+    /// A loop like `[>]` or `[<<]`, which moves `stride` cells at a time until the current cell is 0.
+    Scan(isize),
+    Output,
+    Input,
+    /// `[`, jumps to the op after the matching [`Op::JumpIfNonZero`] when the current cell is 0. `None` when the loop is never closed.
+    JumpIfZero(Option<usize>),
+    /// `]`, jumps to the op after the matching [`Op::JumpIfZero`] when the current cell is not 0.
+    JumpIfNonZero(usize),
+}
+
+/// An [`Op`] together with the instructions it was compiled from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompiledOp {
+    pub op: Op,
+    /// Index of the first instruction.
+    pub pc: usize,
+    /// Number of instructions.
+    pub len: usize,
+}
+
+/// A program compiled for [`Engine::Optimized`](crate::Engine::Optimized).
+///
+/// Runs of `+`/`-` and `<`/`>` are folded and common loops are replaced by a single op, but only where that can not change the outcome of the run: folds never mix `+` and `-` unless cells wrap, never mix `<` and `>`, and the loops that stand for many additions at once are only used when cells wrap.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompiledProgram {
+    ops: Vec<CompiledOp>,
+    // index of the op at the instruction the program resumes from
+    pub(crate) start: usize,
+}
+
+impl CompiledProgram {
+    /// Compiles `program_fragment` for cells that overflow according to `overflow`.
+    ///
+    /// No op spans the instruction `program_fragment` resumes from, so the run can start there.
+    pub fn compile<C: Cell>(
+        program_fragment: &RunningProgramInfo<C>,
+        overflow: OverflowMode,
+    ) -> CompiledProgram {
+        Self::compile_code(
+            &program_fragment.code,
+            &program_fragment.jump_table,
+            program_fragment.continue_state.resume_pc,
+            overflow,
+        )
+    }
+
+    //compiles `code` with the jump table of its loops for a run that starts at instruction `resume_pc`
+    pub(crate) fn compile_code(
+        code: &CompressedBF,
+        jump_table: &[i64],
+        resume_pc: usize,
+        overflow: OverflowMode,
+    ) -> CompiledProgram {
+        let instructions: Vec<BfInstruction> = code.iter().collect();
+        let wrap = overflow == OverflowMode::Wrap;
+        let mut ops: Vec<CompiledOp> = Vec::new();
+        let mut open_loops = Vec::new();
+        let mut start = None;
+
+        let mut pc = 0;
+        while pc < instructions.len() {
+            if pc == resume_pc {
+                start = Some(ops.len());
+            }
+            // a fold must not swallow the instruction the run starts at
+            let limit = if resume_pc > pc {
+                resume_pc.min(instructions.len())
+            } else {
+                instructions.len()
+            };
+            let instruction = instructions[pc];
+            let (op, len) = match instruction {
+                BfInstruction::Inc | BfInstruction::Dec => {
+                    let mut amount = 0;
+                    let mut end = pc;
+                    while end < limit
+                        && matches!(instructions[end], BfInstruction::Inc | BfInstruction::Dec)
+                        && (wrap || instructions[end] == instruction)
+                    {
+                        amount += if instructions[end] == BfInstruction::Inc {
+                            1
+                        } else {
+                            -1
+                        };
+                        end += 1;
+                    }
+                    (Op::Add(amount), end - pc)
+                }
+                BfInstruction::Left | BfInstruction::Right => {
+                    let mut end = pc;
+                    while end < limit && instructions[end] == instruction {
+                        end += 1;
+                    }
+                    let distance = (end - pc) as isize;
+                    if instruction == BfInstruction::Right {
+                        (Op::Move(distance), end - pc)
+                    } else {
+                        (Op::Move(-distance), end - pc)
+                    }
+                }
+                BfInstruction::Output => (Op::Output, 1),
+                BfInstruction::Input => (Op::Input, 1),
+                BfInstruction::LoopStart => {
+                    match loop_idiom(&instructions, pc, jump_table[pc], resume_pc, wrap) {
+                        Some(idiom) => idiom,
+                        None => {
+                            open_loops.push(ops.len());
+                            (Op::JumpIfZero(None), 1)
+                        }
+                    }
+                }
+                BfInstruction::LoopEnd => {
+                    let loop_start = open_loops.pop().unwrap_or_else(|| {
+                        panic!(
+                            "jump table is not initialized correctly, found LoopEnd without LoopStart, pc: {}, program: {:?}",
+                            pc, code
+                        )
+                    });
+                    ops[loop_start].op = Op::JumpIfZero(Some(ops.len() + 1));
+                    (Op::JumpIfNonZero(loop_start + 1), 1)
+                }
+            };
+            ops.push(CompiledOp { op, pc, len });
+            pc += len;
+        }
+
+        let start = start.unwrap_or(ops.len());
+        CompiledProgram { ops, start }
+    }
+
+    pub fn ops(&self) -> &[CompiledOp] {
+        &self.ops
+    }
+
+    //index of the op that starts at instruction `pc`, the end of the ops for the end of the program
+    fn op_at(&self, pc: usize) -> Option<usize> {
+        match self.ops.last() {
+            Some(last) if pc >= last.pc + last.len => Some(self.ops.len()),
+            None => Some(0),
+            _ => self.ops.binary_search_by_key(&pc, |op| op.pc).ok(),
+        }
+    }
+}
+
+/// The program an [`Interpreter`](crate::Interpreter) last compiled, kept for the next runs of the same program.
+#[derive(Debug)]
+pub(crate) struct CompiledCache {
+    code: CompressedBF,
+    overflow: OverflowMode,
+    pub(crate) compiled: CompiledProgram,
+    /// The native code for `compiled` once the JIT translated it, `None` inside when it could not.
+    #[cfg(all(target_arch = "x86_64", unix))]
+    pub(crate) native: Option<Option<NativeCode>>,
+}
+
+impl CompiledCache {
+    /// The compiled form of `program_fragment`, which is only compiled again when `cache` holds another program or has no op starting where `program_fragment` resumes.
+    pub(crate) fn get<'a, C: Cell>(
+        cache: &'a mut Option<CompiledCache>,
+        program_fragment: &RunningProgramInfo<C>,
+        overflow: OverflowMode,
+    ) -> &'a mut CompiledCache {
+        let resume_pc = program_fragment.continue_state.resume_pc;
+        let start = cache
+            .as_ref()
+            .filter(|cached| cached.overflow == overflow && cached.code == program_fragment.code)
+            .and_then(|cached| cached.compiled.op_at(resume_pc));
+        match (cache, start) {
+            (Some(cached), Some(start)) => {
+                cached.compiled.start = start;
+                cached
+            }
+            (cache, _) => cache.insert(CompiledCache {
+                code: program_fragment.code.clone(),
+                overflow,
+                compiled: CompiledProgram::compile(program_fragment, overflow),
+                #[cfg(all(target_arch = "x86_64", unix))]
+                native: None,
+            }),
+        }
+    }
+}
+
+//the single op a loop starting at `loop_start` can be replaced by, with the number of instructions it spans
+fn loop_idiom(
+    instructions: &[BfInstruction],
+    loop_start: usize,
+    jump: i64,
+    resume_pc: usize,
+    wrap: bool,
+) -> Option<(Op, usize)> {
+    // -2 marks a loop that is never closed
+    if jump < 0 {
+        return None;
+    }
+    let loop_end = jump as usize - 1;
+    if loop_start < resume_pc && resume_pc <= loop_end {
+        return None;
+    }
+    let body = &instructions[loop_start + 1..loop_end];
+    let len = loop_end + 1 - loop_start;
+
+    if body == [BfInstruction::Dec] {
+        return Some((Op::SetZero, len));
+    }
+    if !body.is_empty() && body.iter().all(|i| *i == BfInstruction::Right) {
+        return Some((Op::Scan(body.len() as isize), len));
+    }
+    if !body.is_empty() && body.iter().all(|i| *i == BfInstruction::Left) {
+        return Some((Op::Scan(-(body.len() as isize)), len));
+    }
+    if !wrap {
+        return None;
+    }
+
+    let mut offset = 0isize;
+    let mut reach = (0isize, 0isize);
+    let mut deltas: Vec<(isize, i64)> = Vec::new();
+    for instruction in body {
+        let delta = match instruction {
+            BfInstruction::Inc => 1,
+            BfInstruction::Dec => -1,
+            BfInstruction::Left => {
+                offset -= 1;
+                reach.0 = reach.0.min(offset);
+                continue;
+            }
+            BfInstruction::Right => {
+                offset += 1;
+                reach.1 = reach.1.max(offset);
+                continue;
+            }
+            _ => return None,
+        };
+        match deltas.iter_mut().find(|(o, _)| *o == offset) {
+            Some((_, factor)) => *factor += delta,
+            None => deltas.push((offset, delta)),
+        }
+    }
+    let origin_delta = deltas
+        .iter()
+        .find(|(o, _)| *o == 0)
+        .map_or(0, |(_, factor)| *factor);
+    if offset != 0 || origin_delta != -1 {
+        return None;
+    }
+    let targets = deltas
+        .into_iter()
+        .filter(|&(o, factor)| o != 0 && factor != 0)
+        .collect();
+    Some((Op::MulMove { targets, reach }, len))
+}
+
+/// Where a compiled run last started looking for loops, kept so that the run can end exactly where the reference engine would.
+///
+/// [`CycleFinder`] only looks at the states that jump back into a loop and finds a loop after it came around a few times, when the reference engine would have stopped on the first repeated state.
+/// So the outputs of a compiled run are held back until it is certain that the reference engine prints them too: when the run ends, reads input, or jumped back into loops often enough that any loop before them would have been found.
+/// A run that finds a loop, or whose budget runs out before it is certain that it is not in one, runs again from the checkpoint on the reference engine, which stops where it would have stopped and prints what was held back up to there.
+#[derive(Debug)]
+pub(crate) struct Checkpoint<C> {
+    state: ContinueState<C>,
+    cycles: CycleFinder<C>,
+    // steps and jumps back into a loop since `state`
+    steps: u64,
+    jumps: u64,
+    // an EOF was read since `state`, which the run may be reading again in a loop
+    eof: bool,
+    // outputs since `state` that were passed on
+    released: usize,
+    // outputs held back, and where each run of them starts: the jumps back into a loop before it at most, its `output_ind` and the number of outputs since `state` before it
+    held: Vec<u8>,
+    marks: Vec<(u64, usize, usize)>,
+}
+
+impl<C: Cell> Checkpoint<C> {
+    pub(crate) fn new() -> Self {
+        Checkpoint {
+            state: ContinueState {
+                program_state: ProgramState::new(0),
+                resume_pc: 0,
+                resume_output_ind: 0,
+            },
+            cycles: CycleFinder::new(),
+            steps: 0,
+            jumps: 0,
+            eof: false,
+            released: 0,
+            held: Vec::new(),
+            marks: Vec::new(),
+        }
+    }
+
+    /// Starts over from `state`, the state a run that does not resume a paused one starts in.
+    pub(crate) fn start(&mut self, state: &ContinueState<C>) {
+        let program_state = &state.program_state;
+        self.restart(
+            &program_state.tape,
+            program_state.tape_head,
+            program_state.origin,
+            state.resume_pc,
+            state.resume_output_ind,
+        );
+    }
+
+    /// Starts over from the state about to run instruction `pc`, after the run read a byte it can not come back to deterministically.
+    pub(crate) fn restart(
+        &mut self,
+        tape: &Tape<C>,
+        tape_head: usize,
+        origin: usize,
+        pc: usize,
+        output_ind: usize,
+    ) {
+        let state = &mut self.state.program_state;
+        state.tape.clone_from(tape);
+        state.tape_head = tape_head;
+        state.origin = origin;
+        self.state.resume_pc = pc;
+        self.state.resume_output_ind = output_ind;
+        self.cycles.forget();
+        self.steps = 0;
+        self.jumps = 0;
+        self.eof = false;
+        self.released = 0;
+        self.held.clear();
+        self.marks.clear();
+    }
+
+    /// Counts `jumps` more jumps back into a loop.
+    pub(crate) fn jumped_back(&mut self, jumps: u64) {
+        self.jumps += jumps;
+    }
+
+    /// Records the state after jumping back to the instruction at `pc`, returning true if it repeats an earlier one.
+    #[inline(always)]
+    pub(crate) fn repeats(
+        &mut self,
+        pc: usize,
+        tape: &Tape<C>,
+        tape_head: usize,
+        origin: usize,
+    ) -> bool {
+        self.cycles.seen_before(pc, tape, tape_head, origin)
+    }
+
+    /// Holds back the byte printed after `output_ind` earlier outputs, with at most `uncounted` jumps back into a loop since the last ones counted.
+    #[inline(always)]
+    pub(crate) fn hold(&mut self, byte: u8, output_ind: usize, uncounted: u64) {
+        let jumps = self.jumps + uncounted;
+        let outputs = self.released + self.held.len();
+        let follows = self.marks.last().is_some_and(|&(last, first_ind, before)| {
+            last == jumps && first_ind + (outputs - before) == output_ind
+        });
+        if !follows {
+            self.marks.push((jumps, output_ind, outputs));
+        }
+        self.held.push(byte);
+    }
+
+    /// Passes on the held outputs that came before any state that could repeat an earlier one without the cycle finder having found it, false if writing one failed.
+    ///
+    /// A state a whole loop after the first state of the loop has at least as many jumps back into a loop before it as the loop has states that are checked for repeats up to the repeat, so outputs with no more jumps before them than that come before the repeat.
+    #[inline(always)]
+    pub(crate) fn release<O: OutputPolicy>(&mut self, output: &mut O) -> bool {
+        let ruled_out = self.cycles.ruled_out();
+        match self.marks.first() {
+            Some(&(jumps, ..)) if jumps <= ruled_out => {
+                let marks = self
+                    .marks
+                    .partition_point(|&(jumps, ..)| jumps <= ruled_out);
+                self.pass_on(marks, output)
+            }
+            _ => true,
+        }
+    }
+
+    //passes on every held output, once the run reached a point that it can not have got to in a loop
+    fn flush<O: OutputPolicy>(&mut self, output: &mut O) -> bool {
+        self.pass_on(self.marks.len(), output)
+    }
+
+    //passes on the held outputs before mark `marks`
+    fn pass_on<O: OutputPolicy>(&mut self, marks: usize, output: &mut O) -> bool {
+        let end = self
+            .marks
+            .get(marks)
+            .map_or(self.held.len(), |&(.., before)| before - self.released);
+        for (index, &(_, output_ind, before)) in self.marks[..marks].iter().enumerate() {
+            let next = self
+                .marks
+                .get(index + 1)
+                .map_or(self.held.len(), |&(.., before)| before - self.released);
+            let first = before - self.released;
+            for (offset, &byte) in self.held[first..next].iter().enumerate() {
+                if !output.write(byte, output_ind + offset) {
+                    return false;
+                }
+            }
+        }
+        self.released += end;
+        self.held.drain(..end);
+        self.marks.drain(..marks);
+        true
+    }
+
+    /// Runs `,` on `cell`, returning whether it read a byte, after which the run has to [`restart`](Checkpoint::restart) the checkpoint, or how the run ended.
+    ///
+    /// Held outputs are passed on first, so that a prompt shows before the run waits for input, unless the run read an EOF since the checkpoint, which it may be reading again in a loop.
+    pub(crate) fn read<O, FInput>(
+        &mut self,
+        cell: &mut C,
+        eof: EofBehavior,
+        output: &mut O,
+        read_input: &mut FInput,
+    ) -> Result<bool, BfRunResult<C>>
+    where
+        O: OutputPolicy,
+        FInput: FnMut() -> Result<Option<u8>, ReadFailed>,
+    {
+        if !self.eof && !self.flush(output) {
+            return Err(BfRunResult::TargetMismatchError);
+        }
+        match (read_input(), eof) {
+            (Ok(Some(input)), _) => {
+                *cell = C::from_byte(input);
+                if !self.flush(output) {
+                    return Err(BfRunResult::TargetMismatchError);
+                }
+                Ok(true)
+            }
+            (Err(ReadFailed), _) | (Ok(None), EofBehavior::Error) => {
+                Err(BfRunResult::InputTokenError)
+            }
+            (Ok(None), behavior) => {
+                self.eof = true;
+                match behavior {
+                    EofBehavior::Zero => *cell = C::ZERO,
+                    EofBehavior::Max => *cell = C::MAX,
+                    _ => {}
+                }
+                Ok(false)
+            }
+        }
+    }
+
+    /// Turns the way a compiled run ended into its result like [`finish_run`], with `state` the state it ended in and `None` standing for a budget that ran out.
+    ///
+    /// A run that found a loop, or ran out of budget while a state before could still have repeated an earlier one, runs again from the checkpoint on the reference engine, which finds loops with `loops` and stops where the reference engine stops.
+    pub(crate) fn finish<O: OutputPolicy>(
+        &mut self,
+        program_fragment: &RunningProgramInfo<C>,
+        options: &RunOptions,
+        loops: &mut LoopDetector<C>,
+        output: &mut O,
+        result: Option<BfRunResult<C>>,
+        state: ContinueState<C>,
+    ) -> (BfRunResult<C>, StopPoint<C>) {
+        let result = match result {
+            Some(BfRunResult::InfiniteLoopError) => {
+                return self.replay(program_fragment, options, Budget::default(), loops, output);
+            }
+            None if self.jumps > self.cycles.ruled_out() => {
+                let budget = Budget::steps(self.steps);
+                return self.replay(program_fragment, options, budget, loops, output);
+            }
+            // only a failing writer stops a compiled run this way, and its error is what the caller gets
+            Some(BfRunResult::TargetMismatchError) => result,
+            _ if !self.flush(output) => Some(BfRunResult::TargetMismatchError),
+            _ => result,
+        };
+        finish_run(program_fragment, output, result, state)
+    }
+
+    //runs the program again from the checkpoint on the reference engine with `budget`, passing on the outputs that were not passed on yet
+    fn replay<O: OutputPolicy>(
+        &mut self,
+        program_fragment: &RunningProgramInfo<C>,
+        options: &RunOptions,
+        budget: Budget,
+        loops: &mut LoopDetector<C>,
+        output: &mut O,
+    ) -> (BfRunResult<C>, StopPoint<C>) {
+        let mut rest = program_fragment.clone();
+        rest.continue_state = self.state.clone();
+        let mut output = Skip {
+            output,
+            skip: self.released,
+        };
+        self.released += self.held.len();
+        self.held.clear();
+        self.marks.clear();
+        let state = &rest.continue_state.program_state;
+        loops.start(&state.tape, state.origin);
+        // the run read no byte since the checkpoint, only EOFs
+        let read_input = || Ok(None);
+        if budget.is_unlimited() {
+            execute(&rest, options, loops, &mut output, read_input)
+        } else {
+            execute(
+                &rest,
+                options,
+                &mut Budgeted::new(loops, budget),
+                &mut output,
+                read_input,
+            )
+        }
+    }
+}
+
+/// Passes on the outputs of a run after the first `skip`, which were passed on before.
+struct Skip<'a, O> {
+    output: &'a mut O,
+    skip: usize,
+}
+
+impl<O: OutputPolicy> OutputPolicy for Skip<'_, O> {
+    fn write(&mut self, byte: u8, output_ind: usize) -> bool {
+        if self.skip > 0 {
+            self.skip -= 1;
+            true
+        } else {
+            self.output.write(byte, output_ind)
+        }
+    }
+
+    fn complete(&self, output_ind: usize) -> bool {
+        self.output.complete(output_ind)
+    }
+}
+
+/// Stops a run once it leaves the instructions `span`, leaving everything else to `inner`.
+struct Within<'a, T> {
+    span: Range<usize>,
+    inner: &'a mut T,
+}
+
+impl<C: Cell, T: TerminationPolicy<C>> TerminationPolicy<C> for Within<'_, T> {
+    #[inline(always)]
+    fn check(&mut self, pc: usize, tape: &Tape<C>, tape_head: usize, origin: usize) -> StepCheck {
+        if self.span.contains(&pc) {
+            self.inner.check(pc, tape, tape_head, origin)
+        } else {
+            StepCheck::OutOfBudget
+        }
+    }
+
+    #[inline(always)]
+    fn write(&mut self, tape: &mut Tape<C>, index: usize, origin: usize, value: C) {
+        self.inner.write(tape, index, origin, value);
+    }
+
+    #[inline(always)]
+    fn input_read(&mut self) {
+        self.inner.input_read();
+    }
+}
+
+/// Runs the instructions of `op` from `state` on the reference engine with `budget`, for an op that the compiled code can not run exactly as they would: a cell overflowing, the head leaving the tape or the budget running out in the middle of it.
+///
+/// Returns how the run ended, `None` once the op is done or the budget ran out, the state it ended in and the steps it took. These instructions never loop forever, so no loops are looked for.
+pub(crate) fn run_op<C, O, FInput>(
+    program_fragment: &RunningProgramInfo<C>,
+    op: &CompiledOp,
+    state: ContinueState<C>,
+    options: &RunOptions,
+    budget: Budget,
+    output: &mut O,
+    read_input: FInput,
+) -> (Option<BfRunResult<C>>, ContinueState<C>, u64)
+where
+    C: Cell,
+    O: OutputPolicy,
+    FInput: FnMut() -> Result<Option<u8>, ReadFailed>,
+{
+    let mut unchecked = Unchecked;
+    let mut budgeted = Budgeted::new(&mut unchecked, budget);
+    let mut within = Within {
+        span: op.pc..op.pc + op.len,
+        inner: &mut budgeted,
+    };
+    let (result, state) = execute_from(
+        program_fragment,
+        state,
+        options,
+        &mut within,
+        output,
+        read_input,
+    );
+    (result, state, budgeted.steps())
+}
+
+//how the op loop of a compiled run ended
+enum Exit<C> {
+    Done(BfRunResult<C>),
+    Paused,
+    // the next op could fail or go past the step budget, which the reference engine pins down to the exact instruction
+    Fallback,
+}
+
+/// Runs `compiled`, the compiled form of `program_fragment`, looking for loops with `checkpoint` every time it jumps back into a loop.
+///
+/// Ops the compiled code can not finish exactly as the reference engine would, a cell overflowing, the head leaving the tape or a step budget running out in the middle of an op, run on the reference engine with [`run_op`], after which the compiled code carries on.
+pub(crate) fn execute_compiled<C, O, FInput>(
+    compiled: &CompiledProgram,
+    program_fragment: &RunningProgramInfo<C>,
+    options: &RunOptions,
+    loops: &mut LoopDetector<C>,
+    checkpoint: &mut Checkpoint<C>,
+    output: &mut O,
+    mut read_input: FInput,
+) -> (BfRunResult<C>, StopPoint<C>)
+where
+    C: Cell,
+    O: OutputPolicy,
+    FInput: FnMut() -> Result<Option<u8>, ReadFailed>,
+{
+    let overflow = options.overflow;
+    let ops = &compiled.ops;
+    let mut tape = program_fragment.continue_state.program_state.tape.clone();
+    let mut tape_head = program_fragment.continue_state.program_state.tape_head;
+    let mut origin = program_fragment.continue_state.program_state.origin;
+    let mut output_ind = program_fragment.continue_state.resume_output_ind;
+    let mut ip = compiled.start;
+    // the instruction an op run on the reference engine stopped at
+    let mut stopped_at = None;
+    // steps the reference engine would have taken so far, and when the checkpoint was last taken
+    let mut steps: u64 = 0;
+    let mut checkpoint_steps: u64 = 0;
+    let max_steps = options.budget.max_steps.unwrap_or(u64::MAX);
+    let mut ops_run: u64 = 0;
+
+    let exit = loop {
+        let exit = 'run: {
+            while ip < ops.len() {
+                let CompiledOp { op, pc, len } = &ops[ip];
+                if let Some(deadline) = options.budget.deadline
+                    && ops_run.is_multiple_of(DEADLINE_CHECK_INTERVAL)
+                    && Instant::now() >= deadline
+                {
+                    break 'run Exit::Paused;
+                }
+                ops_run += 1;
+
+                // a loop op runs its `[` once and the rest of the loop once per iteration
+                let loop_steps = |iterations: u64| 1 + iterations.saturating_mul(*len as u64 - 1);
+                let cost = match op {
+                    Op::SetZero => loop_steps(tape[tape_head].to_u64()),
+                    Op::MulMove { .. } => loop_steps(tape[tape_head].to_u64()),
+                    Op::Scan(_) => 1,
+                    _ => *len as u64,
+                };
+                if cost > max_steps - steps {
+                    break 'run Exit::Fallback;
+                }
+
+                match op {
+                    Op::Add(amount) => match tape[tape_head].add_n(*amount, overflow) {
+                        Some(value) => tape[tape_head] = value,
+                        None => break 'run Exit::Fallback,
+                    },
+                    Op::Move(distance) => {
+                        if *distance > 0 {
+                            let target = tape_head + *distance as usize;
+                            if target >= tape.len() {
+                                let TapePolicy::Growable { max_cells, .. } = options.tape else {
+                                    break 'run Exit::Fallback;
+                                };
+                                while target >= tape.len() {
+                                    if !grow_tape_right(&mut tape, max_cells) {
+                                        break 'run Exit::Fallback;
+                                    }
+                                }
+                            }
+                            tape_head = target;
+                        } else {
+                            let distance = distance.unsigned_abs();
+                            if tape_head < distance {
+                                let TapePolicy::Growable {
+                                    grow_left: true,
+                                    max_cells,
+                                } = options.tape
+                                else {
+                                    break 'run Exit::Fallback;
+                                };
+                                while tape_head < distance {
+                                    if !grow_tape_left(
+                                        &mut tape,
+                                        &mut tape_head,
+                                        &mut origin,
+                                        max_cells,
+                                    ) {
+                                        break 'run Exit::Fallback;
+                                    }
+                                }
+                            }
+                            tape_head -= distance;
+                        }
+                    }
+                    Op::SetZero => tape[tape_head] = C::ZERO,
+                    Op::MulMove { targets, reach } => {
+                        let value = tape[tape_head].to_u64();
+                        if value != 0 {
+                            let head = tape_head as isize;
+                            if head + reach.0 < 0 || head + reach.1 >= tape.len() as isize {
+                                break 'run Exit::Fallback;
+                            }
+                            for &(offset, factor) in targets {
+                                let index = (head + offset) as usize;
+                                let amount = (factor as u64).wrapping_mul(value) as i64;
+                                tape[index] = tape[index]
+                                    .add_n(amount, OverflowMode::Wrap)
+                                    .expect("wrapping never fails");
+                            }
+                            tape[tape_head] = C::ZERO;
+                        }
+                    }
+                    Op::Scan(stride) => {
+                        let mut position = tape_head;
+                        let mut moves = 0;
+                        while tape[position] != C::ZERO {
+                            let next = position as isize + stride;
+                            if next < 0 || next >= tape.len() as isize {
+                                break 'run Exit::Fallback;
+                            }
+                            position = next as usize;
+                            moves += 1;
+                        }
+                        let cost = loop_steps(moves);
+                        if cost > max_steps - steps {
+                            break 'run Exit::Fallback;
+                        }
+                        steps += cost - 1;
+                        tape_head = position;
+                    }
+                    Op::Output => {
+                        checkpoint.hold(tape[tape_head].to_byte(), output_ind, 0);
+                        output_ind += 1;
+                    }
+                    Op::Input => {
+                        output_ind += 1;
+                        match checkpoint.read(
+                            &mut tape[tape_head],
+                            options.eof,
+                            output,
+                            &mut read_input,
+                        ) {
+                            Ok(true) => {
+                                steps += 1;
+                                ip += 1;
+                                checkpoint.restart(&tape, tape_head, origin, pc + 1, output_ind);
+                                checkpoint_steps = steps;
+                                continue;
+                            }
+                            Ok(false) => {}
+                            Err(result) => break 'run Exit::Done(result),
+                        }
+                    }
+                    Op::JumpIfZero(target) => {
+                        if tape[tape_head] == C::ZERO {
+                            steps += 1;
+                            match target {
+                                Some(target) => {
+                                    ip = *target;
+                                    continue;
+                                }
+                                None => break 'run Exit::Done(BfRunResult::NOOPError),
+                            }
+                        }
+                    }
+                    Op::JumpIfNonZero(target) => {
+                        if tape[tape_head] != C::ZERO {
+                            steps += 1;
+                            ip = *target;
+                            checkpoint.jumped_back(1);
+                            if checkpoint.repeats(ops[ip].pc, &tape, tape_head, origin) {
+                                break 'run Exit::Done(BfRunResult::InfiniteLoopError);
+                            }
+                            if !checkpoint.release(output) {
+                                break 'run Exit::Done(BfRunResult::TargetMismatchError);
+                            }
+                            continue;
+                        }
+                    }
+                }
+                steps += cost;
+                ip += 1;
+            }
+            Exit::Done(BfRunResult::Success)
+        };
+        let Exit::Fallback = exit else {
+            break exit;
+        };
+
+        let op = &ops[ip];
+        let budget = Budget {
+            max_steps: options.budget.max_steps.map(|max_steps| max_steps - steps),
+            deadline: options.budget.deadline,
+        };
+        let state = ContinueState {
+            program_state: ProgramState {
+                tape: std::mem::take(&mut tape),
+                tape_head,
+                origin,
+            },
+            resume_pc: op.pc,
+            resume_output_ind: output_ind,
+        };
+        let (result, state, taken) = run_op(
+            program_fragment,
+            op,
+            state,
+            options,
+            budget,
+            output,
+            &mut read_input,
+        );
+        steps += taken;
+        ProgramState {
+            tape,
+            tape_head,
+            origin,
+        } = state.program_state;
+        match result {
+            None if state.resume_pc == op.pc + op.len => ip += 1,
+            None => {
+                stopped_at = Some(state.resume_pc);
+                break Exit::Paused;
+            }
+            Some(result) => {
+                stopped_at = Some(state.resume_pc);
+                break Exit::Done(result);
+            }
+        }
+    };
+
+    checkpoint.steps += steps - checkpoint_steps;
+    let pc =
+        stopped_at.unwrap_or_else(|| ops.get(ip).map_or(program_fragment.code.size(), |op| op.pc));
+    let state = ContinueState {
+        program_state: ProgramState {
+            tape,
+            tape_head,
+            origin,
+        },
+        resume_pc: pc,
+        resume_output_ind: output_ind,
+    };
+    let result = match exit {
+        Exit::Done(result) => Some(result),
+        _ => None,
+    };
+    checkpoint.finish(program_fragment, options, loops, output, result, state)
+}
+
+// --- UNIT TESTS ---
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run::{Engine, Interpreter};
+    use crate::util::{preprocess_input, preprocess_input_with_cells};
+
+    fn compile(program: &str, overflow: OverflowMode) -> Vec<Op> {
+        let info = preprocess_input(program, 8).unwrap();
+        CompiledProgram::compile(&info, overflow)
+            .ops()
+            .iter()
+            .map(|op| op.op.clone())
+            .collect()
+    }
+
+    //runs `program` with every engine, the reference engine first
+    fn run_engines<C: Cell>(
+        program: &str,
+        input: &[u8],
+        options: RunOptions,
+    ) -> Vec<(BfRunResult<C>, StopPoint<C>, Vec<u8>)> {
+        let info = preprocess_input_with_cells::<C>(program, 4).unwrap();
+        let mut runs = Vec::new();
+        for engine in [Engine::Reference, Engine::Optimized, Engine::Jit] {
+            let options = RunOptions { engine, ..options };
+            let mut input = input.iter().copied();
+            let mut output = Vec::new();
+            let (result, stop) = Interpreter::new().run_with_stop(
+                &info,
+                &options,
+                || input.next(),
+                |byte| output.push(byte),
+            );
+            runs.push((result, stop, output));
+        }
+        runs
+    }
+
+    //the other engines end exactly like the reference engine
+    fn assert_engines_agree<C: Cell>(
+        runs: &[(BfRunResult<C>, StopPoint<C>, Vec<u8>)],
+        program: &str,
+        options: RunOptions,
+    ) {
+        let (reference, others) = runs.split_first().unwrap();
+        for run in others {
+            assert_eq!(reference, run, "{} {:?}", program, options);
+        }
+    }
+
+    /// Folds and loop idioms are only used where they behave like the instructions they replace.
+    #[test]
+    fn test_compile() {
+        assert_eq!(
+            compile("+++--[-]>>[->++>+++<<]<<[>]", OverflowMode::Wrap),
+            vec![
+                Op::Add(1),
+                Op::SetZero,
+                Op::Move(2),
+                Op::MulMove {
+                    targets: vec![(1, 2), (2, 3)],
+                    reach: (0, 2)
+                },
+                Op::Move(-2),
+                Op::Scan(1),
+            ]
+        );
+        assert_eq!(
+            compile("+++--[-]>><[->+<]", OverflowMode::Saturate),
+            vec![
+                Op::Add(3),
+                Op::Add(-2),
+                Op::SetZero,
+                Op::Move(2),
+                Op::Move(-1),
+                Op::JumpIfZero(Some(11)),
+                Op::Add(-1),
+                Op::Move(1),
+                Op::Add(1),
+                Op::Move(-1),
+                Op::JumpIfNonZero(6),
+            ]
+        );
+    }
+
+    /// Compiling a program that resumes in the middle of a fold or loop idiom splits it there.
+    #[test]
+    fn test_compile_resume_point() {
+        let mut info = preprocess_input("+++[-]", 8).unwrap();
+        info.continue_state.resume_pc = 4;
+        let compiled = CompiledProgram::compile(&info, OverflowMode::Wrap);
+        let ops: Vec<_> = compiled
+            .ops()
+            .iter()
+            .map(|op| (op.op.clone(), op.pc))
+            .collect();
+        assert_eq!(
+            ops,
+            vec![
+                (Op::Add(3), 0),
+                (Op::JumpIfZero(Some(4)), 3),
+                (Op::Add(-1), 4),
+                (Op::JumpIfNonZero(2), 5),
+            ]
+        );
+        assert_eq!(compiled.start, 2);
+    }
+
+    /// All engines agree on every program, for every kind of tape, cell, overflow and EOF handling, down to where a run fails, pauses or finds an infinite loop.
+    #[test]
+    fn test_engines_agree() {
+        let mut programs: Vec<String> = [
+            "++++++++[>++++++++<-]>+.+.+.",
+            "+[>+]",
+            "+[<+]",
+            ">+>+>+<<<[>]<.",
+            ">>>+[<]",
+            "-[->+>++<<]>.>.",
+            "++[->-->+++<<]",
+            "+++[-<+>]",
+            "++[->>>>>+<<<<<]",
+            ",[.,]",
+            ",+[-.,+]",
+            ">,[>,]<[.<]",
+            "+++++[-]+++.",
+            "++>>>>>",
+            "<+",
+            "+[-]-[-]",
+            "+[]",
+            "+[.]",
+            "+[>+<]",
+            "+[[-]+]",
+            "+[>>+[<]>]",
+        ]
+        .iter()
+        .map(|program| program.to_string())
+        .collect();
+
+        // random programs with a bias towards the loops that compile to idioms
+        let pieces = [
+            "+", "-", ">", "<", ".", ",", "[-]", "[>]", "[<]", "[->+<]", "[-<++>]", "+++", "--",
+        ];
+        let mut seed = 0x2545f4914f6cdd1du64;
+        for _ in 0..100 {
+            let mut program = String::new();
+            let mut depth = 0;
+            for _ in 0..12 {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                match seed % 16 {
+                    13 => {
+                        program.push('[');
+                        depth += 1;
+                    }
+                    14 | 15 if depth > 0 => {
+                        program.push(']');
+                        depth -= 1;
+                    }
+                    n => program.push_str(pieces[n as usize % pieces.len()]),
+                }
+            }
+            program.push_str(&"]".repeat(depth));
+            programs.push(program);
+        }
+
+        let tapes = [
+            TapePolicy::Fixed,
+            TapePolicy::Growable {
+                grow_left: false,
+                max_cells: 16,
+            },
+            TapePolicy::Growable {
+                grow_left: true,
+                max_cells: 16,
+            },
+        ];
+        let mut compared = 0;
+        let mut looping = 0;
+        for program in &programs {
+            for tape in tapes {
+                for overflow in [
+                    OverflowMode::Wrap,
+                    OverflowMode::Saturate,
+                    OverflowMode::Error,
+                ] {
+                    for eof in [EofBehavior::Error, EofBehavior::Zero, EofBehavior::Max] {
+                        for budget in [
+                            Budget::steps(5),
+                            Budget::steps(40),
+                            Budget::steps(2_000),
+                            Budget::default(),
+                        ] {
+                            let options = RunOptions {
+                                tape,
+                                overflow,
+                                eof,
+                                budget,
+                                ..RunOptions::default()
+                            };
+                            let runs = run_engines::<u8>(program, b"ab", options);
+                            assert_engines_agree(&runs, program, options);
+                            looping += usize::from(runs[0].0 == BfRunResult::InfiniteLoopError);
+                            // without a budget the reference engine takes too long to rule out loops of 16 bit cells
+                            if !budget.is_unlimited() {
+                                let runs = run_engines::<u16>(program, b"ab", options);
+                                assert_engines_agree(&runs, program, options);
+                            }
+                            compared += 1;
+                        }
+                    }
+                }
+            }
+        }
+        assert!(compared > 4_000);
+        assert!(looping > 500);
+    }
+}
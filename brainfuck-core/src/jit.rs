@@ -0,0 +1,513 @@
+use std::collections::HashMap;
+use std::ffi::c_void;
+
+use crate::{
+    cell::Cell,
+    ir::{Checkpoint, CompiledCache, CompiledProgram, Op, execute_compiled, run_op},
+    loop_detector::LoopDetector,
+    run::{
+        BfRunResult, Budget, ContinueState, OutputPolicy, ProgramState, ReadFailed, RunOptions,
+        RunningProgramInfo, StopPoint, Tape, TapePolicy, grow_tape_left, grow_tape_right,
+    },
+    x86::{Assembler, Label},
+};
+
+// what the native code returns
+const FINISHED: u32 = 0;
+// the op at `Context::ip` needs the tape to grow or could fail, so Rust has to take over
+const BAIL: u32 = 1;
+// `Context::countdown` ran out on a jump back into the loop starting at op `Context::ip`, so Rust has to look for a repeated state
+const CHECK: u32 = 2;
+// the op at `Context::ip` reads input, which Rust does
+const INPUT: u32 = 3;
+
+/// Jumps back into a loop between two looks for a repeated state, fewer make loops show up sooner and more make the run faster.
+const CHECK_INTERVAL: u64 = 1024;
+
+/// Everything the native code reads and writes outside of its registers. The field offsets are baked into the code.
+#[repr(C)]
+struct Context {
+    tape: *mut u8,
+    // in cells
+    head: usize,
+    len: usize,
+    ip: usize,
+    output: unsafe extern "C" fn(*mut Context, *mut u8),
+    io: *mut c_void,
+    // jumps back into a loop left before the next check
+    countdown: u64,
+}
+
+const TAPE_OFFSET: u8 = 0;
+const HEAD_OFFSET: u8 = 8;
+const LEN_OFFSET: u8 = 16;
+const IP_OFFSET: u8 = 24;
+const OUTPUT_OFFSET: u8 = 32;
+const COUNTDOWN_OFFSET: u8 = 48;
+
+/// What the native code reaches through [`write_trampoline`].
+struct Io<'a, C> {
+    checkpoint: &'a mut Checkpoint<C>,
+    output_ind: usize,
+}
+
+//holds back the output of the native code, which Rust passes on once it is certain of it, so no callback of the run is ever called from native code
+unsafe extern "C" fn write_trampoline<C: Cell>(context: *mut Context, cell: *mut u8) {
+    // SAFETY: the native code passes the context it was started with, whose `io` is an `Io<C>`, and a pointer to the current cell
+    let (io, value, countdown) = unsafe {
+        (
+            &mut *((*context).io as *mut Io<C>),
+            *cell.cast::<C>(),
+            (*context).countdown,
+        )
+    };
+    io.checkpoint
+        .hold(value.to_byte(), io.output_ind, CHECK_INTERVAL - countdown);
+    io.output_ind += 1;
+}
+
+impl Assembler {
+    fn call(&mut self, callback: u8) {
+        // mov rdi, r14; lea rsi, [current cell]; call [r14 + callback]
+        self.emit(&[0x4C, 0x89, 0xF7]);
+        self.cell(false, 0x08, &[0x8D], 6, 0);
+        self.emit(&[0x41, 0xFF, 0x56, callback]);
+    }
+}
+
+/// Native code for a [`CompiledProgram`], in memory that is executable but not writable.
+#[derive(Debug)]
+pub(crate) struct NativeCode {
+    memory: *mut c_void,
+    size: usize,
+    // offset of the function that runs the program
+    entry: usize,
+}
+
+impl NativeCode {
+    /// Translates `compiled` for cells of type `C` that wrap, `None` if it has ops too large to encode or no executable memory could be mapped.
+    fn compile<C: Cell>(compiled: &CompiledProgram) -> Option<NativeCode> {
+        let ops = compiled.ops();
+        let mut asm = Assembler::new(C::BYTES);
+        let mut op_starts = Vec::with_capacity(ops.len() + 1);
+        for (ip, compiled_op) in ops.iter().enumerate() {
+            op_starts.push(asm.code.len());
+            match &compiled_op.op {
+                Op::Add(amount) => asm.add(*amount),
+                Op::Move(distance) => {
+                    asm.move_head(i32::try_from(*distance).ok()?, Label::Bail(ip))
+                }
+                Op::SetZero => asm.set_zero(),
+                Op::MulMove { targets, reach } => {
+                    let targets = targets
+                        .iter()
+                        .map(|&(offset, factor)| Some((i32::try_from(offset).ok()?, factor as i32)))
+                        .collect::<Option<Vec<_>>>()?;
+                    let reach = (i32::try_from(reach.0).ok()?, i32::try_from(reach.1).ok()?);
+                    asm.mul_move(&targets, reach, ip);
+                }
+                Op::Scan(stride) => asm.scan(i32::try_from(*stride).ok()?, ip),
+                Op::Output => asm.call(OUTPUT_OFFSET),
+                Op::Input => {
+                    // mov eax, INPUT; jmp status
+                    asm.emit(&[0xB8]);
+                    asm.emit(&INPUT.to_le_bytes());
+                    asm.jump(&[0xE9], Label::Status(ip));
+                }
+                Op::JumpIfZero(target) => {
+                    asm.compare_zero();
+                    let label = target.map_or(Label::Bail(ip), Label::Op);
+                    asm.jump(&[0x0F, 0x84], label);
+                }
+                Op::JumpIfNonZero(target) => {
+                    asm.compare_zero();
+                    asm.jump(&[0x0F, 0x84], Label::Op(ip + 1));
+                    // sub qword [r14 + countdown], 1; mov eax, CHECK; jz status; jmp target
+                    asm.emit(&[0x49, 0x83, 0x6E, COUNTDOWN_OFFSET, 0x01, 0xB8]);
+                    asm.emit(&CHECK.to_le_bytes());
+                    asm.jump(&[0x0F, 0x84], Label::Status(*target));
+                    asm.jump(&[0xE9], Label::Op(*target));
+                }
+            }
+        }
+        op_starts.push(asm.code.len());
+        // xor eax, eax
+        asm.emit(&[0x31, 0xC0]);
+
+        // stores the head, restores the callee saved registers and returns eax
+        let exit = asm.code.len();
+        asm.emit(&[0x4D, 0x89, 0x66, HEAD_OFFSET]);
+        asm.emit(&[0x41, 0x5F, 0x41, 0x5E, 0x41, 0x5D, 0x41, 0x5C, 0x5B, 0xC3]);
+
+        let mut stubs = HashMap::new();
+        for (_, label) in asm.fixups.clone() {
+            let (Label::Bail(ip) | Label::Status(ip)) = label else {
+                continue;
+            };
+            if stubs.contains_key(&label) {
+                continue;
+            }
+            stubs.insert(label, asm.code.len());
+            // mov qword [r14 + ip], ip
+            asm.emit(&[0x49, 0xC7, 0x46, IP_OFFSET]);
+            asm.emit_i32(i32::try_from(ip).ok()?);
+            if matches!(label, Label::Bail(_)) {
+                // mov eax, BAIL
+                asm.emit(&[0xB8]);
+                asm.emit(&BAIL.to_le_bytes());
+            }
+            asm.emit(&[0xE9]);
+            let back = exit as i64 - (asm.code.len() as i64 + 4);
+            asm.emit_i32(back as i32);
+        }
+
+        // push the callee saved registers, r15 only to keep the stack aligned for calls
+        let entry = asm.code.len();
+        asm.emit(&[0x53, 0x41, 0x54, 0x41, 0x55, 0x41, 0x56, 0x41, 0x57]);
+        // mov r14, rdi; mov rbx, [r14]; mov r12, [r14 + head]; mov r13, [r14 + len]
+        asm.emit(&[0x49, 0x89, 0xFE, 0x49, 0x8B, 0x5E, TAPE_OFFSET]);
+        asm.emit(&[0x4D, 0x8B, 0x66, HEAD_OFFSET, 0x4D, 0x8B, 0x6E, LEN_OFFSET]);
+        // jumps to the op with index rsi through the table: lea rcx, [rip + table]; movsxd rax, [rcx + rsi * 4]; add rax, rcx; jmp rax
+        asm.emit(&[0x48, 0x8D, 0x0D]);
+        let table_field = asm.code.len();
+        asm.emit_i32(0);
+        asm.emit(&[0x48, 0x63, 0x04, 0xB1, 0x48, 0x01, 0xC8, 0xFF, 0xE0]);
+
+        while !asm.code.len().is_multiple_of(4) {
+            asm.emit(&[0xCC]);
+        }
+        let table = asm.code.len();
+        let table_rel = (table - (table_field + 4)) as i32;
+        asm.code[table_field..table_field + 4].copy_from_slice(&table_rel.to_le_bytes());
+        for &start in &op_starts {
+            let offset = start as i64 - table as i64;
+            asm.emit_i32(i32::try_from(offset).ok()?);
+        }
+
+        asm.resolve(|label| match label {
+            Label::Op(ip) => Some(op_starts[ip]),
+            label => stubs.get(&label).copied(),
+        })?;
+
+        NativeCode::map(&asm.code, entry)
+    }
+
+    fn map(code: &[u8], entry: usize) -> Option<NativeCode> {
+        let size = code.len();
+        // SAFETY: a fresh private mapping, written before it is made executable and never written again
+        unsafe {
+            let memory = libc::mmap(
+                std::ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if memory == libc::MAP_FAILED {
+                return None;
+            }
+            std::ptr::copy_nonoverlapping(code.as_ptr(), memory.cast::<u8>(), size);
+            let native = NativeCode {
+                memory,
+                size,
+                entry,
+            };
+            if libc::mprotect(memory, size, libc::PROT_READ | libc::PROT_EXEC) != 0 {
+                return None;
+            }
+            Some(native)
+        }
+    }
+
+    /// Runs the program from the op with index `ip`.
+    ///
+    /// # Safety
+    /// `context` must describe a tape of the cell type the code was compiled for, and its callbacks must match `io`.
+    unsafe fn run(&self, context: &mut Context, ip: usize) -> u32 {
+        // SAFETY: the entry follows the System V calling convention of this signature
+        let function: unsafe extern "C" fn(*mut Context, usize) -> u32 =
+            unsafe { std::mem::transmute(self.memory.cast::<u8>().add(self.entry)) };
+        unsafe { function(context, ip) }
+    }
+}
+
+// SAFETY: the mapping is only ever read and executed, and is owned by this value alone
+unsafe impl Send for NativeCode {}
+
+impl Drop for NativeCode {
+    fn drop(&mut self) {
+        // SAFETY: the mapping was created in `map` with this size and nothing points into it any more
+        unsafe {
+            libc::munmap(self.memory, self.size);
+        }
+    }
+}
+
+//grows the tape so that the move of the op at which the native code stopped stays on it, false when only the reference engine can tell how the run goes on
+fn make_room<C: Cell>(
+    op: &Op,
+    tape: &mut Tape<C>,
+    tape_head: &mut usize,
+    origin: &mut usize,
+    policy: TapePolicy,
+) -> bool {
+    let TapePolicy::Growable {
+        grow_left,
+        max_cells,
+    } = policy
+    else {
+        return false;
+    };
+    let (Op::Move(distance) | Op::Scan(distance)) = op else {
+        return false;
+    };
+    if *distance > 0 {
+        let target = *tape_head + *distance as usize;
+        while target >= tape.len() {
+            if !grow_tape_right(tape, max_cells) {
+                return false;
+            }
+        }
+    } else {
+        while *tape_head < distance.unsigned_abs() {
+            if !grow_left || !grow_tape_left(tape, tape_head, origin, max_cells) {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// Runs `cache`, the compiled form of `program_fragment`, as native code, translating it on the first run that needs it.
+///
+/// Only runs that [run natively](RunOptions::runs_natively) are translated, anything else runs on [`execute_compiled`]. The native code checks the head against the tape before every move; when the tape has to grow it stops so that Rust can grow it and start it again, and an op that could fail runs on the reference engine with [`run_op`].
+/// Input is read by Rust, and every [`CHECK_INTERVAL`] jumps back into a loop the native code also stops so that `checkpoint` can look for a repeated state and pass on the output it held back.
+pub(crate) fn execute_jit<C, O, FInput>(
+    cache: &mut CompiledCache,
+    program_fragment: &RunningProgramInfo<C>,
+    options: &RunOptions,
+    loops: &mut LoopDetector<C>,
+    checkpoint: &mut Checkpoint<C>,
+    output: &mut O,
+    mut read_input: FInput,
+) -> (BfRunResult<C>, StopPoint<C>)
+where
+    C: Cell,
+    O: OutputPolicy,
+    FInput: FnMut() -> Result<Option<u8>, ReadFailed>,
+{
+    let compiled = &cache.compiled;
+    let native = options
+        .runs_natively()
+        .then(|| {
+            cache
+                .native
+                .get_or_insert_with(|| NativeCode::compile::<C>(compiled))
+                .as_ref()
+        })
+        .flatten();
+    let Some(native) = native else {
+        return execute_compiled(
+            compiled,
+            program_fragment,
+            options,
+            loops,
+            checkpoint,
+            output,
+            read_input,
+        );
+    };
+
+    let ops = compiled.ops();
+    let mut tape = program_fragment.continue_state.program_state.tape.clone();
+    let mut tape_head = program_fragment.continue_state.program_state.tape_head;
+    let mut origin = program_fragment.continue_state.program_state.origin;
+    let mut io = Io {
+        checkpoint,
+        output_ind: program_fragment.continue_state.resume_output_ind,
+    };
+    let mut ip = compiled.start;
+    // the instruction an op run on the reference engine stopped at
+    let mut stopped_at = None;
+    let mut countdown = CHECK_INTERVAL;
+
+    let result = loop {
+        let mut context = Context {
+            tape: tape.as_mut_ptr().cast(),
+            head: tape_head,
+            len: tape.len(),
+            ip,
+            output: write_trampoline::<C>,
+            io: std::ptr::from_mut(&mut io).cast(),
+            countdown,
+        };
+        // SAFETY: the code was compiled for cells of type `C`, and the context points at the tape and at `io` with the trampoline for its type
+        let status = unsafe { native.run(&mut context, ip) };
+        tape_head = context.head;
+        io.checkpoint.jumped_back(countdown - context.countdown);
+        countdown = context.countdown;
+        if status == FINISHED {
+            ip = ops.len();
+            break BfRunResult::Success;
+        }
+        ip = context.ip;
+        match status {
+            CHECK => {
+                countdown = CHECK_INTERVAL;
+                if io.checkpoint.repeats(ops[ip].pc, &tape, tape_head, origin) {
+                    break BfRunResult::InfiniteLoopError;
+                }
+                if !io.checkpoint.release(output) {
+                    break BfRunResult::TargetMismatchError;
+                }
+            }
+            INPUT => {
+                io.output_ind += 1;
+                let cell = &mut tape[tape_head];
+                match io
+                    .checkpoint
+                    .read(cell, options.eof, output, &mut read_input)
+                {
+                    Ok(read) => {
+                        let pc = ops[ip].pc + 1;
+                        ip += 1;
+                        if read {
+                            io.checkpoint
+                                .restart(&tape, tape_head, origin, pc, io.output_ind);
+                        }
+                    }
+                    Err(result) => break result,
+                }
+            }
+            _ => {
+                let op = &ops[ip];
+                if make_room(&op.op, &mut tape, &mut tape_head, &mut origin, options.tape) {
+                    continue;
+                }
+                let state = ContinueState {
+                    program_state: ProgramState {
+                        tape: std::mem::take(&mut tape),
+                        tape_head,
+                        origin,
+                    },
+                    resume_pc: op.pc,
+                    resume_output_ind: io.output_ind,
+                };
+                let (result, state, _) = run_op(
+                    program_fragment,
+                    op,
+                    state,
+                    options,
+                    Budget::default(),
+                    output,
+                    &mut read_input,
+                );
+                ProgramState {
+                    tape,
+                    tape_head,
+                    origin,
+                } = state.program_state;
+                match result {
+                    None => ip += 1,
+                    Some(result) => {
+                        stopped_at = Some(state.resume_pc);
+                        break result;
+                    }
+                }
+            }
+        }
+    };
+
+    let Io {
+        checkpoint,
+        output_ind,
+    } = io;
+    let pc =
+        stopped_at.unwrap_or_else(|| ops.get(ip).map_or(program_fragment.code.size(), |op| op.pc));
+    let state = ContinueState {
+        program_state: ProgramState {
+            tape,
+            tape_head,
+            origin,
+        },
+        resume_pc: pc,
+        resume_output_ind: output_ind,
+    };
+    checkpoint.finish(
+        program_fragment,
+        options,
+        loops,
+        output,
+        Some(result),
+        state,
+    )
+}
+
+// --- UNIT TESTS ---
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run::{Engine, Interpreter};
+    use std::panic::catch_unwind;
+    use crate::util::preprocess_input_with_cells;
+
+    fn run_jit<C: Cell>(
+        program: &str,
+        tape: TapePolicy,
+    ) -> (BfRunResult<C>, StopPoint<C>, Vec<u8>) {
+        let info = preprocess_input_with_cells::<C>(program, 4).unwrap();
+        let options = RunOptions {
+            tape,
+            engine: Engine::Jit,
+            ..RunOptions::default()
+        };
+        let mut output = Vec::new();
+        let (result, stop) =
+            Interpreter::new().run_with_stop(&info, &options, || None, |byte| output.push(byte));
+        (result, stop, output)
+    }
+
+    /// The native code wraps cells of every width and stops at the edge of a fixed tape.
+    #[test]
+    fn test_cells_and_bounds() {
+        let (result, _, output) = run_jit::<u8>("-.>-[->+>+++<<]>.>.", TapePolicy::Fixed);
+        assert_eq!(result, BfRunResult::Success);
+        assert_eq!(output, vec![255, 255, 253]);
+        let (result, _, output) = run_jit::<u16>("-[->+<]>.", TapePolicy::Fixed);
+        assert_eq!(result, BfRunResult::Success);
+        assert_eq!(output, vec![255]);
+        let (result, stop, _) = run_jit::<u32>("+[>+]", TapePolicy::Fixed);
+        assert_eq!(result, BfRunResult::OOMError);
+        assert_eq!((stop.pc, stop.tape_head), (2, 3));
+    }
+
+    /// A tape that grows is grown by Rust and the native code carries on on the new tape.
+    #[test]
+    fn test_growable_tape() {
+        let tape = TapePolicy::Growable {
+            grow_left: true,
+            max_cells: 64,
+        };
+        let (result, stop, _) = run_jit::<u8>("+[<<+]", tape);
+        assert_eq!(result, BfRunResult::OOMError);
+        assert_eq!(stop.pc, 2);
+        let (result, _, output) = run_jit::<u8>("+>>>>>>>>>>+[<]>.", tape);
+        assert_eq!(result, BfRunResult::Success);
+        assert_eq!(output, vec![1]);
+    }
+
+    /// A panic in a callback unwinds out of the run instead of through the native code.
+    #[test]
+    fn test_callback_panic() {
+        let info = preprocess_input_with_cells::<u8>("+.", 4).unwrap();
+        let options = RunOptions {
+            engine: Engine::Jit,
+            ..RunOptions::default()
+        };
+        let outcome = catch_unwind(|| {
+            Interpreter::new().run(&info, &options, || None, |_| panic!("output closed"))
+        });
+        assert!(outcome.is_err());
+    }
+}
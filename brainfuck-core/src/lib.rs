@@ -1,5 +1,33 @@
+mod analysis;
+mod cell;
+mod codegen;
 mod data;
+mod debugger;
+mod error;
+mod format;
+mod ir;
+#[cfg(all(target_arch = "x86_64", unix))]
+mod jit;
+mod loop_detector;
 mod run;
 mod search;
+mod source;
 pub mod util;
-pub use run::run_program_fragment_no_target;
+mod x86;
+pub use analysis::{CellEffect, NetEffect};
+pub use cell::{Cell, CellWidth, OverflowMode};
+pub use codegen::{
+    CodegenOptions, generate_asm, generate_c, generate_elf, generate_rust, generate_rust_module,
+};
+pub use data::{BfInstruction, CompressedBF};
+pub use debugger::{DebugStop, Debugger, WatchEvent, Watchpoint};
+pub use error::{ParseError, PreprocessError, RunError, RunErrorKind, SearchError, SeedError};
+pub use format::format_source;
+pub use ir::{CompiledOp, CompiledProgram, Op};
+pub use run::{
+    BfRunResult, Budget, ContinueState, Engine, EofBehavior, Interpreter, RunOptions,
+    RunningProgramInfo, StopPoint, TapePolicy, run_program_fragment_no_target,
+    run_program_fragment_no_target_with_stop,
+};
+pub use search::{SearchOptions, find_program};
+pub use source::{SourceMap, SourceSpan};
@@ -0,0 +1,308 @@
+use std::collections::hash_map::Entry;
+
+use ahash::{HashMap, HashMapExt, HashSet, HashSetExt};
+
+use crate::{cell::Cell, run::Tape};
+
+/// Weight of the cell `index - origin` places from the cell the run started on.
+///
+/// Counting from the origin keeps the fingerprint unchanged when the tape grows to the left. The weight is always odd, so changing a single cell always changes the fingerprint.
+#[inline(always)]
+fn cell_weight(index: usize, origin: usize) -> u64 {
+    // splitmix64
+    let mut z = (index as u64)
+        .wrapping_sub(origin as u64)
+        .wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    (z ^ (z >> 31)) | 1
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct StateKey {
+    pc: usize,
+    fingerprint: u64,
+    tape_head: usize,
+    origin: usize,
+    tape_len: usize,
+}
+
+/// Finds the first state of a run that repeats an earlier one without storing the tape of every state.
+///
+/// The tape is summarized by a fingerprint, the sum of every cell times the weight of its position, which every write updates in O(1).
+/// Equal fingerprints are only a hint: a state counts as a repeat once the cells written since the earlier state all hold the value they had back then.
+#[derive(Debug)]
+pub(crate) struct LoopDetector<C> {
+    fingerprint: u64,
+    // length of `writes` when each state was first seen
+    seen: HashMap<StateKey, usize>,
+    // states that share their key with the one in `seen` but have a different tape
+    collisions: HashMap<StateKey, Vec<usize>>,
+    // index and previous value of every cell written since the detector was last cleared
+    writes: Vec<(usize, C)>,
+}
+
+impl<C: Cell> LoopDetector<C> {
+    pub(crate) fn new() -> Self {
+        LoopDetector {
+            fingerprint: 0,
+            seen: HashMap::new(),
+            collisions: HashMap::new(),
+            writes: Vec::new(),
+        }
+    }
+
+    /// Forgets the previous run and fingerprints the tape the next one starts on.
+    pub(crate) fn start(&mut self, tape: &Tape<C>, origin: usize) {
+        self.forget();
+        self.fingerprint = tape.iter().enumerate().fold(0u64, |sum, (index, cell)| {
+            sum.wrapping_add(cell_weight(index, origin).wrapping_mul(cell.to_u64()))
+        });
+    }
+
+    /// Forgets every state seen so far, for when the run can no longer come back to them deterministically.
+    pub(crate) fn forget(&mut self) {
+        self.seen.clear();
+        self.collisions.clear();
+        self.writes.clear();
+    }
+
+    /// Stores `value` in the cell at `index`, which every write to the tape must go through.
+    #[inline(always)]
+    pub(crate) fn write(&mut self, tape: &mut Tape<C>, index: usize, origin: usize, value: C) {
+        let previous = tape[index];
+        self.fingerprint = self.fingerprint.wrapping_add(
+            cell_weight(index, origin).wrapping_mul(value.to_u64().wrapping_sub(previous.to_u64())),
+        );
+        self.writes.push((index, previous));
+        tape[index] = value;
+    }
+
+    /// Records the state about to run the instruction at `pc`, returning true if the run was already in exactly this state.
+    #[inline(always)]
+    pub(crate) fn seen_before(
+        &mut self,
+        pc: usize,
+        tape: &Tape<C>,
+        tape_head: usize,
+        origin: usize,
+    ) -> bool {
+        let key = StateKey {
+            pc,
+            fingerprint: self.fingerprint,
+            tape_head,
+            origin,
+            tape_len: tape.len(),
+        };
+        let position = self.writes.len();
+        let first = match self.seen.entry(key) {
+            Entry::Vacant(entry) => {
+                entry.insert(position);
+                return false;
+            }
+            Entry::Occupied(entry) => *entry.get(),
+        };
+        if unchanged_since(&self.writes[first..], tape) {
+            return true;
+        }
+        let others = self.collisions.entry(key).or_default();
+        if others
+            .iter()
+            .any(|&other| unchanged_since(&self.writes[other..], tape))
+        {
+            return true;
+        }
+        others.push(position);
+        false
+    }
+}
+
+//the key includes the tape length and origin, so the tape has not grown since and the indices in `writes` still point at the same cells
+fn unchanged_since<C: Cell>(writes: &[(usize, C)], tape: &Tape<C>) -> bool {
+    let mut checked = HashSet::new();
+    writes
+        .iter()
+        .all(|&(index, previous)| !checked.insert(index) || tape[index] == previous)
+}
+
+/// A state of a run that [`CycleFinder`] compares later states with.
+#[derive(Debug)]
+struct SavedState<C> {
+    // index of the state among the ones checked since the finder was last cleared
+    index: u64,
+    pc: usize,
+    tape: Tape<C>,
+    tape_head: usize,
+    origin: usize,
+}
+
+/// Finds runs of the optimized engines that come back to a state, looking only at the states they jump back into a loop in.
+///
+/// A run that never ends jumps back into loops forever, and as each of those states follows from the one before, they repeat as well.
+/// Brent's algorithm finds the repeat while keeping a single earlier state, which it takes again every time the number of states since doubles.
+/// That finds a loop later than [`LoopDetector`] does, once it came around between two and four times, but takes no work on writes to the tape.
+#[derive(Debug)]
+pub(crate) struct CycleFinder<C> {
+    saved: Option<SavedState<C>>,
+    // states checked since the finder was last cleared
+    checked: u64,
+    // states checked since `saved` was taken, and after how many it is taken again
+    since: u64,
+    period: u64,
+    // see `ruled_out`
+    ruled_out: u64,
+}
+
+impl<C: Cell> CycleFinder<C> {
+    pub(crate) fn new() -> Self {
+        CycleFinder {
+            saved: None,
+            checked: 0,
+            since: 0,
+            period: 1,
+            ruled_out: 0,
+        }
+    }
+
+    /// Forgets every state seen so far, for when the run can no longer come back to them deterministically.
+    pub(crate) fn forget(&mut self) {
+        self.saved = None;
+        self.checked = 0;
+        self.since = 0;
+        self.period = 1;
+        self.ruled_out = 0;
+    }
+
+    /// The index, counted from 0 since the finder was last cleared, of the last checked state known not to repeat an earlier one unnoticed.
+    ///
+    /// The states between two saved ones are compared with the first, which finds every loop that started by then and comes around within as many states.
+    pub(crate) fn ruled_out(&self) -> u64 {
+        self.ruled_out
+    }
+
+    /// Records the state after jumping back to the instruction at `pc`, returning true if it is the same as the saved one.
+    #[inline(always)]
+    pub(crate) fn seen_before(
+        &mut self,
+        pc: usize,
+        tape: &Tape<C>,
+        tape_head: usize,
+        origin: usize,
+    ) -> bool {
+        if let Some(saved) = &self.saved
+            && saved.pc == pc
+            && saved.tape_head == tape_head
+            && saved.origin == origin
+            && saved.tape.get(tape_head) == tape.get(tape_head)
+            && saved.tape == *tape
+        {
+            return true;
+        }
+        let index = self.checked;
+        self.checked += 1;
+        self.since += 1;
+        if self.since >= self.period {
+            match &mut self.saved {
+                Some(saved) => {
+                    // the saved state was compared with the `period` states after it, at least as many as came before it
+                    self.ruled_out = saved.index;
+                    saved.index = index;
+                    saved.pc = pc;
+                    saved.tape.clone_from(tape);
+                    saved.tape_head = tape_head;
+                    saved.origin = origin;
+                }
+                None => {
+                    self.saved = Some(SavedState {
+                        index,
+                        pc,
+                        tape: tape.clone(),
+                        tape_head,
+                        origin,
+                    })
+                }
+            }
+            self.since = 0;
+            self.period = self.period.saturating_mul(2);
+        }
+        false
+    }
+}
+
+// --- UNIT TESTS ---
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smallvec::smallvec;
+
+    /// Writing a cell back to its old value restores the fingerprint, wherever the origin is.
+    #[test]
+    fn test_fingerprint_is_incremental() {
+        let mut tape: Tape<u8> = smallvec![0, 3, 0, 7];
+        let mut detector = LoopDetector::new();
+        detector.start(&tape, 1);
+        let start = detector.fingerprint;
+        detector.write(&mut tape, 2, 1, 200);
+        assert_ne!(detector.fingerprint, start);
+        detector.write(&mut tape, 2, 1, 0);
+        assert_eq!(detector.fingerprint, start);
+
+        let mut grown: Tape<u8> = smallvec![0, 0, 0, 3, 0, 7];
+        let mut other = LoopDetector::new();
+        other.start(&grown, 3);
+        assert_eq!(other.fingerprint, start);
+        other.write(&mut grown, 4, 3, 200);
+        detector.write(&mut tape, 2, 1, 200);
+        assert_eq!(other.fingerprint, detector.fingerprint);
+    }
+
+    /// A state whose fingerprint collides with an earlier one is only a repeat if the tape is the same.
+    #[test]
+    fn test_collision_is_confirmed() {
+        let mut tape: Tape<u8> = smallvec![0; 4];
+        let mut detector = LoopDetector::new();
+        detector.start(&tape, 0);
+        let start = detector.fingerprint;
+        assert!(!detector.seen_before(0, &tape, 0, 0));
+
+        detector.write(&mut tape, 1, 0, 5);
+        detector.fingerprint = start;
+        assert!(!detector.seen_before(0, &tape, 0, 0));
+        assert!(detector.seen_before(0, &tape, 0, 0));
+
+        detector.write(&mut tape, 1, 0, 0);
+        detector.fingerprint = start;
+        assert!(detector.seen_before(0, &tape, 0, 0));
+        assert!(!detector.seen_before(1, &tape, 0, 0));
+    }
+
+    /// A cycle of states is found whatever its length and however late it starts, and states before a reset never count.
+    #[test]
+    fn test_cycle_finder() {
+        for (lead, length) in [(0, 1), (3, 1), (0, 7), (5, 12), (100, 33)] {
+            let mut finder = CycleFinder::new();
+            let mut tape: Tape<u8> = smallvec![0; 2];
+            let found = (0..4 * (lead + length)).position(|step| {
+                tape[1] = if step < lead {
+                    100 + step as u8
+                } else {
+                    (step % length) as u8
+                };
+                let found = finder.seen_before(3, &tape, 1, 0);
+                assert!(found || finder.ruled_out() < (lead + length) as u64);
+                found
+            });
+            let found = found.expect("the cycle is found");
+            assert!(found >= lead + length, "{lead} {length}");
+        }
+
+        let mut finder = CycleFinder::new();
+        let tape: Tape<u8> = smallvec![1; 2];
+        assert!(!finder.seen_before(0, &tape, 0, 0));
+        assert!(!finder.seen_before(1, &tape, 0, 0));
+        finder.forget();
+        assert!(!finder.seen_before(1, &tape, 0, 0));
+        assert!(finder.seen_before(1, &tape, 0, 0));
+        assert!(!finder.seen_before(1, &tape, 1, 0));
+    }
+}
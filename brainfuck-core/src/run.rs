@@ -1,41 +1,80 @@
+use crate::cell::{Cell, OverflowMode};
 use crate::data::{BfInstruction, CompressedBF};
-use ahash::{HashMap, HashMapExt, HashSet, HashSetExt};
-use lazy_static::lazy_static;
-use std::any::Any;
-use std::sync::atomic::AtomicUsize;
-use std::sync::{Arc, Mutex};
-use std::thread::ThreadId;
+use crate::error::RunErrorKind;
+use crate::ir::{Checkpoint, CompiledCache, execute_compiled};
+#[cfg(all(target_arch = "x86_64", unix))]
+use crate::jit::execute_jit;
+use crate::loop_detector::LoopDetector;
+use smallvec::SmallVec;
+use std::cell::RefCell;
+use std::convert::Infallible;
+use std::time::Instant;
 
 #[derive(Debug, Eq, PartialEq)]
-pub enum BfRunResult<const MAX_TAPE_SIZE: usize> {
+pub enum BfRunResult<C = u8> {
     NOOPError,
     TargetMismatchError,
     TapeHeadBoundError,
     OOMError,
     InfiniteLoopError,
     InputTokenError,
-    IncompleteLoopSuccess(ContinueState<MAX_TAPE_SIZE>),
-    IncompleteOutputSuccess(ContinueState<MAX_TAPE_SIZE>),
+    CellOverflowError,
+    IncompleteLoopSuccess(ContinueState<C>),
+    IncompleteOutputSuccess(ContinueState<C>),
+    /// The [`Budget`] ran out before the program finished, resume from the state with [`RunningProgramInfo::resume`].
+    Paused(ContinueState<C>),
     Success,
 }
 
+impl<C> BfRunResult<C> {
+    /// The error this result stands for, or `None` for the success variants.
+    pub fn error_kind(&self) -> Option<RunErrorKind> {
+        match self {
+            BfRunResult::NOOPError => Some(RunErrorKind::UnclosedLoop),
+            BfRunResult::TargetMismatchError => Some(RunErrorKind::TargetMismatch),
+            BfRunResult::TapeHeadBoundError => Some(RunErrorKind::TapeHeadBound),
+            BfRunResult::OOMError => Some(RunErrorKind::OutOfMemory),
+            BfRunResult::InfiniteLoopError => Some(RunErrorKind::InfiniteLoop),
+            BfRunResult::InputTokenError => Some(RunErrorKind::InputExhausted),
+            BfRunResult::CellOverflowError => Some(RunErrorKind::CellOverflow),
+            BfRunResult::IncompleteLoopSuccess(_)
+            | BfRunResult::IncompleteOutputSuccess(_)
+            | BfRunResult::Paused(_)
+            | BfRunResult::Success => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
-pub struct ContinueState<const MAX_TAPE_SIZE: usize> {
-    pub(crate) program_state: ProgramState<MAX_TAPE_SIZE>,
+pub struct ContinueState<C = u8> {
+    pub(crate) program_state: ProgramState<C>,
     pub(crate) resume_pc: usize,
     pub(crate) resume_output_ind: usize,
 }
 
+/// A parsed program with its jump table and the state the next run starts from, made by [`preprocess_input`](crate::util::preprocess_input).
 #[derive(Debug)]
-pub struct RunningProgramInfo<const MAX_TAPE_SIZE: usize> {
+pub struct RunningProgramInfo<C = u8> {
     pub(crate) code: CompressedBF,
     pub(crate) current_paren_count: usize,
     pub(crate) jump_table: Vec<i64>,
-    pub(crate) continue_state: ContinueState<MAX_TAPE_SIZE>,
+    pub(crate) continue_state: ContinueState<C>,
+}
+
+impl<C> RunningProgramInfo<C> {
+    /// The instructions of the program, without the comments of its source.
+    pub fn code(&self) -> &CompressedBF {
+        &self.code
+    }
+
+    /// Makes the next run continue from `state`, the state a previous run of this program paused in.
+    pub fn resume(&mut self, state: ContinueState<C>) {
+        self.continue_state = state;
+    }
 }
 
 //make sure to keep same vector capacity for Vec in order to save a lot of time on memory operations
-impl<const MAX_TAPE_SIZE: usize> Clone for RunningProgramInfo<MAX_TAPE_SIZE> {
+impl<C: Cell> Clone for RunningProgramInfo<C> {
     fn clone(&self) -> Self {
         let mut new_jump_table = Vec::with_capacity(self.jump_table.capacity());
         new_jump_table.extend_from_slice(&self.jump_table);
@@ -48,208 +87,392 @@ impl<const MAX_TAPE_SIZE: usize> Clone for RunningProgramInfo<MAX_TAPE_SIZE> {
     }
 }
 
+/// Tapes up to this many cells are stored inline, so cloning the small tapes used by the search never allocates.
+pub(crate) const SMALL_TAPE_SIZE: usize = 32;
+
+pub(crate) type Tape<C = u8> = SmallVec<[C; SMALL_TAPE_SIZE]>;
+
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
-pub struct ProgramState<const MAX_TAPE_SIZE: usize> {
-    pub(crate) tape: [u8; MAX_TAPE_SIZE],
-    pub(crate) tape_head: u8,
+pub struct ProgramState<C = u8> {
+    pub(crate) tape: Tape<C>,
+    pub(crate) tape_head: usize,
+    // index in `tape` of the cell the program started on, only moves when the tape grows to the left
+    pub(crate) origin: usize,
 }
 
-lazy_static! {
-    static ref GLOBAL: Mutex<HashMap<usize, Box<dyn Any + Send + Sync>>> =
-        Mutex::new(HashMap::new());
+impl<C: Cell> ProgramState<C> {
+    /// A zeroed tape of `tape_size` cells with the head on the first cell.
+    pub(crate) fn new(tape_size: usize) -> ProgramState<C> {
+        ProgramState {
+            tape: SmallVec::from_elem(C::ZERO, tape_size),
+            tape_head: 0,
+            origin: 0,
+        }
+    }
 }
 
-fn get_state_tracker<const MAX_TAPE_SIZE: usize>()
--> Arc<Mutex<Vec<HashSet<ProgramState<MAX_TAPE_SIZE>>>>> {
-    let mut global = GLOBAL.lock().unwrap();
-    let entry = global.entry(MAX_TAPE_SIZE).or_insert_with(|| {
-        Box::new(HashMap::<
-            ThreadId,
-            Arc<Mutex<Vec<HashSet<ProgramState<MAX_TAPE_SIZE>>>>>,
-        >::new()) as Box<dyn Any + Send + Sync>
-    });
+/// What happens when the head moves off the end of the tape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TapePolicy {
+    /// The tape never changes size, moving left of the first cell is a `TapeHeadBoundError` and moving right of the last cell is an `OOMError`.
+    Fixed,
+    /// The tape grows on demand to the right, and also to the left when `grow_left` is set.
+    /// Holding more than `max_cells` cells is an `OOMError`, moving left of the first cell without `grow_left` is a `TapeHeadBoundError`.
+    Growable { grow_left: bool, max_cells: usize },
+}
 
-    // Downcast to the correct type
-    let map = entry
-        .downcast_mut::<HashMap<ThreadId, Arc<Mutex<Vec<HashSet<ProgramState<MAX_TAPE_SIZE>>>>>>>()
-        .unwrap();
+/// What `,` does once `read_input` has run out of input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EofBehavior {
+    /// Stop the run with an `InputTokenError`.
+    #[default]
+    Error,
+    /// Set the cell to 0.
+    Zero,
+    /// Set the cell to its largest value, which is 255 (-1) for byte cells.
+    Max,
+    /// Leave the cell as it is.
+    Unchanged,
+}
 
-    let thread_id = std::thread::current().id();
-    map.entry(thread_id)
-        .or_insert_with(|| Arc::new(Mutex::new(Vec::new())))
-        .clone()
+/// The clock is only read once every this many steps, so a run can go slightly past its deadline.
+pub(crate) const DEADLINE_CHECK_INTERVAL: u64 = 1024;
+
+/// How far a run may go before it stops with [`BfRunResult::Paused`]. The default is unlimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Budget {
+    /// Most instructions to run, every executed instruction including jumps is one step.
+    pub max_steps: Option<u64>,
+    /// Point in time to pause at.
+    pub deadline: Option<Instant>,
 }
 
-const SHRINK_TO_SIZE: usize = 2147483649;
+impl Budget {
+    pub fn steps(max_steps: u64) -> Self {
+        Budget {
+            max_steps: Some(max_steps),
+            deadline: None,
+        }
+    }
 
-pub fn run_program_fragment<const MAX_TAPE_SIZE: usize>(
-    program_fragment: &RunningProgramInfo<MAX_TAPE_SIZE>,
-    target_output: &[u8],
-) -> BfRunResult<MAX_TAPE_SIZE> {
-    let state_tracker_arc_mutex = get_state_tracker::<MAX_TAPE_SIZE>();
-    let mut state_tracker = state_tracker_arc_mutex.lock().unwrap();
-    {
-        //clear the state tracker for this thread
-        for state in state_tracker.iter_mut() {
-            state.clear();
-            state.shrink_to(SHRINK_TO_SIZE);
+    pub fn until(deadline: Instant) -> Self {
+        Budget {
+            max_steps: None,
+            deadline: Some(deadline),
         }
-        // Ensure the state tracker has enough elements
-        if state_tracker.len() < program_fragment.code.size() {
-            state_tracker.resize_with(program_fragment.code.size(), || {
-                HashSet::with_capacity(256 * 4)
-            });
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.max_steps.is_none() && self.deadline.is_none()
+    }
+}
+
+/// How [`Interpreter::run`] executes a program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Engine {
+    /// Runs one instruction at a time and stops programs that come back to a state they were in before with an `InfiniteLoopError`.
+    #[default]
+    Reference,
+    /// Compiles the program to a [`CompiledProgram`](crate::CompiledProgram) first, which is many times faster for long running programs.
+    /// Output and results are the same as with `Reference`. Output is passed on once it is certain that the program is not in an infinite loop, and a loop, or a budget that runs out before that is certain, makes the run go back to where it last read input and run again on `Reference` from there.
+    Optimized,
+    /// Translates the [`CompiledProgram`](crate::CompiledProgram) to native x86-64 code and runs that, with the same results as `Optimized`. It looks for loops less often, so output is held back for longer.
+    /// Only runs without a budget on cells that wrap run as native code on x86-64 Unix, see [`RunOptions::runs_natively`], all other runs use `Optimized` instead.
+    Jit,
+}
+
+/// Options for [`run_program_fragment_no_target`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RunOptions {
+    pub tape: TapePolicy,
+    pub overflow: OverflowMode,
+    pub eof: EofBehavior,
+    pub budget: Budget,
+    pub engine: Engine,
+}
+
+impl RunOptions {
+    /// Whether a run with these options on [`Engine::Jit`] runs as native code instead of on [`Engine::Optimized`].
+    pub fn runs_natively(&self) -> bool {
+        cfg!(all(target_arch = "x86_64", unix))
+            && self.overflow == OverflowMode::Wrap
+            && self.budget.is_unlimited()
+    }
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        RunOptions {
+            tape: TapePolicy::Fixed,
+            overflow: OverflowMode::Wrap,
+            eof: EofBehavior::Error,
+            budget: Budget::default(),
+            engine: Engine::Reference,
         }
+    }
+}
 
-        let mut tape = program_fragment.continue_state.program_state.tape;
-        let mut tape_head = program_fragment.continue_state.program_state.tape_head;
-        let mut pc = program_fragment.continue_state.resume_pc; // Start from the last instruction
-        let mut output_ind = program_fragment.continue_state.resume_output_ind; // Resume from the last output index
+//grows the tape geometrically so that walking along an unbounded tape stays amortized O(1) per step
+pub(crate) fn grow_tape_right<C: Cell>(tape: &mut Tape<C>, max_cells: usize) -> bool {
+    if tape.len() >= max_cells {
+        return false;
+    }
+    let new_len = tape
+        .len()
+        .saturating_mul(2)
+        .clamp(tape.len() + 1, max_cells);
+    tape.resize(new_len, C::ZERO);
+    true
+}
 
-        while pc < program_fragment.code.size() {
-            let current_state = ProgramState { tape, tape_head };
-            if state_tracker[pc].contains(&current_state) {
-                return collect_and_return(BfRunResult::InfiniteLoopError, &state_tracker);
-            } else {
-                state_tracker[pc].insert(current_state);
-            }
+pub(crate) fn grow_tape_left<C: Cell>(
+    tape: &mut Tape<C>,
+    tape_head: &mut usize,
+    origin: &mut usize,
+    max_cells: usize,
+) -> bool {
+    if tape.len() >= max_cells {
+        return false;
+    }
+    let extra = tape.len().min(max_cells - tape.len()).max(1);
+    tape.insert_many(0, std::iter::repeat_n(C::ZERO, extra));
+    *tape_head += extra;
+    *origin += extra;
+    true
+}
 
-            match program_fragment.code.get(pc) {
-                None => {
-                    panic!(
-                        "could not read current BF instruction, pc: {}, program: {:?}",
-                        pc, program_fragment.code
-                    );
-                }
-                Some(BfInstruction::Inc) => {
-                    tape[tape_head as usize] = tape[tape_head as usize].wrapping_add(1);
-                }
-                Some(BfInstruction::Dec) => {
-                    tape[tape_head as usize] = tape[tape_head as usize].wrapping_sub(1);
-                }
-                Some(BfInstruction::Left) => {
-                    if tape_head == 0 {
-                        return collect_and_return(BfRunResult::TapeHeadBoundError, &state_tracker);
-                    }
-                    tape_head -= 1;
-                }
-                Some(BfInstruction::Right) => {
-                    if tape_head as usize + 1 == MAX_TAPE_SIZE {
-                        return collect_and_return(BfRunResult::OOMError, &state_tracker);
-                    }
-                    tape_head += 1;
-                }
-                Some(BfInstruction::LoopStart) => {
-                    if tape[tape_head as usize] == 0 {
-                        if program_fragment.jump_table[pc] == -1 {
-                            panic!(
-                                "jump table is not initialized correctly, found -1 at LoopStart, pc: {}, program: {:?}, jump_table: {:?}",
-                                pc, program_fragment.code, program_fragment.jump_table
-                            );
-                        }
-                        if program_fragment.jump_table[pc] == -2 {
-                            return collect_and_return(BfRunResult::NOOPError, &state_tracker);
-                        }
-                        pc = program_fragment.jump_table[pc] as usize;
-                        continue;
-                    }
-                }
-                Some(BfInstruction::LoopEnd) => {
-                    if tape[tape_head as usize] != 0 {
-                        if program_fragment.jump_table[pc] == -1 {
-                            panic!(
-                                "jump table is not initialized correctly, found -1 at LoopEnd, pc: {}, program: {:?}, jump_table: {:?}",
-                                pc, program_fragment.code, program_fragment.jump_table
-                            );
-                        }
-                        pc = program_fragment.jump_table[pc] as usize;
-                        continue;
-                    }
-                }
-                Some(BfInstruction::Output) => {
-                    if output_ind == target_output.len() {
-                        return collect_and_return(
-                            BfRunResult::TargetMismatchError,
-                            &state_tracker,
-                        );
-                    }
-                    if target_output[output_ind] != tape[tape_head as usize] {
-                        return collect_and_return(
-                            BfRunResult::TargetMismatchError,
-                            &state_tracker,
-                        );
-                    }
-                    output_ind += 1;
-                }
-                Some(BfInstruction::Input) => {
-                    return collect_and_return(BfRunResult::InputTokenError, &state_tracker);
-                }
-            }
-            pc += 1;
+/// Where a run of [`Interpreter::run_with_stop`] stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StopPoint<C = u8> {
+    /// Index of the instruction that failed, or the program size when the run finished.
+    pub pc: usize,
+    /// Position of the tape head, counted from the cell the run started on.
+    pub tape_head: isize,
+    /// Value of the cell under the tape head.
+    pub cell: C,
+}
+
+const MAX_STEPS: usize = 131066;
+
+/// Where `.` sends the bytes of a run.
+pub(crate) trait OutputPolicy {
+    /// Handles the byte printed after `output_ind` earlier ones, returning false if the run should stop with a `TargetMismatchError`.
+    fn write(&mut self, byte: u8, output_ind: usize) -> bool;
+    /// Whether a run that reached the end of the program after `output_ind` outputs printed everything it had to.
+    fn complete(&self, output_ind: usize) -> bool;
+}
+
+/// Compares the output of a search candidate with the target.
+struct MatchTarget<'a>(&'a [u8]);
+
+impl OutputPolicy for MatchTarget<'_> {
+    #[inline(always)]
+    fn write(&mut self, byte: u8, output_ind: usize) -> bool {
+        self.0.get(output_ind) == Some(&byte)
+    }
+
+    #[inline(always)]
+    fn complete(&self, output_ind: usize) -> bool {
+        output_ind == self.0.len()
+    }
+}
+
+/// Passes every byte to a callback, which returns false when writing it failed.
+struct WriteOutput<F>(F);
+
+impl<F: FnMut(u8) -> bool> OutputPolicy for WriteOutput<F> {
+    #[inline(always)]
+    fn write(&mut self, byte: u8, _output_ind: usize) -> bool {
+        (self.0)(byte)
+    }
+
+    #[inline(always)]
+    fn complete(&self, _output_ind: usize) -> bool {
+        true
+    }
+}
+
+/// Returned by the input callback of an engine when reading failed, which stops the run like running out of input with [`EofBehavior::Error`].
+pub(crate) struct ReadFailed;
+
+/// What a [`TerminationPolicy`] makes of the state before an instruction.
+pub(crate) enum StepCheck {
+    Continue,
+    /// The run was in this state before, so it never ends.
+    Repeated,
+    /// The run used up its [`Budget`].
+    OutOfBudget,
+}
+
+/// Decides when a run that has not failed yet stops anyway.
+///
+/// Every write to the tape goes through the policy so that it can keep track of the tape without looking at all of it.
+pub(crate) trait TerminationPolicy<C: Cell> {
+    /// Called before every instruction.
+    fn check(&mut self, pc: usize, tape: &Tape<C>, tape_head: usize, origin: usize) -> StepCheck;
+    fn write(&mut self, tape: &mut Tape<C>, index: usize, origin: usize, value: C);
+    /// Called after `,` read a byte, which the run may not be able to get again.
+    fn input_read(&mut self);
+}
+
+impl<C: Cell> TerminationPolicy<C> for LoopDetector<C> {
+    #[inline(always)]
+    fn check(&mut self, pc: usize, tape: &Tape<C>, tape_head: usize, origin: usize) -> StepCheck {
+        if self.seen_before(pc, tape, tape_head, origin) {
+            StepCheck::Repeated
+        } else {
+            StepCheck::Continue
         }
+    }
 
-        if program_fragment.current_paren_count != 0 {
-            return collect_and_return(
-                BfRunResult::IncompleteLoopSuccess(ContinueState {
-                    program_state: ProgramState { tape, tape_head },
-                    resume_pc: pc,
-                    resume_output_ind: output_ind,
-                }),
-                &state_tracker,
-            );
+    #[inline(always)]
+    fn write(&mut self, tape: &mut Tape<C>, index: usize, origin: usize, value: C) {
+        LoopDetector::write(self, tape, index, origin, value);
+    }
+
+    #[inline(always)]
+    fn input_read(&mut self) {
+        // what happens next depends on the byte that was read, so earlier states no longer prove a loop
+        self.forget();
+    }
+}
+
+/// Runs without looking for loops.
+pub(crate) struct Unchecked;
+
+impl<C: Cell> TerminationPolicy<C> for Unchecked {
+    #[inline(always)]
+    fn check(
+        &mut self,
+        _pc: usize,
+        _tape: &Tape<C>,
+        _tape_head: usize,
+        _origin: usize,
+    ) -> StepCheck {
+        StepCheck::Continue
+    }
+
+    #[inline(always)]
+    fn write(&mut self, tape: &mut Tape<C>, index: usize, _origin: usize, value: C) {
+        tape[index] = value;
+    }
+
+    #[inline(always)]
+    fn input_read(&mut self) {}
+}
+
+/// Counts the steps of a run and pauses it once `budget` runs out, leaving everything else to `inner`.
+pub(crate) struct Budgeted<'a, T> {
+    inner: &'a mut T,
+    budget: Budget,
+    steps: u64,
+}
+
+impl<'a, T> Budgeted<'a, T> {
+    pub(crate) fn new(inner: &'a mut T, budget: Budget) -> Self {
+        Budgeted {
+            inner,
+            budget,
+            steps: 0,
         }
+    }
 
-        if output_ind != target_output.len() {
-            collect_and_return(
-                BfRunResult::IncompleteOutputSuccess(ContinueState {
-                    program_state: ProgramState { tape, tape_head },
-                    resume_pc: pc,
-                    resume_output_ind: output_ind,
-                }),
-                &state_tracker,
-            )
-        } else {
-            collect_and_return(BfRunResult::Success, &state_tracker)
+    /// Steps counted so far.
+    pub(crate) fn steps(&self) -> u64 {
+        self.steps
+    }
+}
+
+impl<C: Cell, T: TerminationPolicy<C>> TerminationPolicy<C> for Budgeted<'_, T> {
+    #[inline(always)]
+    fn check(&mut self, pc: usize, tape: &Tape<C>, tape_head: usize, origin: usize) -> StepCheck {
+        if self
+            .budget
+            .max_steps
+            .is_some_and(|max_steps| self.steps >= max_steps)
+        {
+            return StepCheck::OutOfBudget;
+        }
+        if let Some(deadline) = self.budget.deadline
+            && self.steps.is_multiple_of(DEADLINE_CHECK_INTERVAL)
+            && Instant::now() >= deadline
+        {
+            return StepCheck::OutOfBudget;
         }
+        self.steps += 1;
+        self.inner.check(pc, tape, tape_head, origin)
+    }
+
+    #[inline(always)]
+    fn write(&mut self, tape: &mut Tape<C>, index: usize, origin: usize, value: C) {
+        self.inner.write(tape, index, origin, value);
+    }
+
+    #[inline(always)]
+    fn input_read(&mut self) {
+        self.inner.input_read();
     }
 }
 
-//output_ind for this method is actually used to make sure that outputs and inputs in a loop don't trigger a infinite loop error.
-pub fn run_program_fragment_no_target<const MAX_TAPE_SIZE: usize, FInput, FOutput>(
-    program_fragment: &RunningProgramInfo<MAX_TAPE_SIZE>,
-    mut read_input: FInput,
-    mut write_output: FOutput,
-) -> BfRunResult<MAX_TAPE_SIZE>
+/// The interpreter loop behind every way of running a program.
+///
+/// `termination` has to be ready for the tape in `program_fragment`.
+#[inline(always)]
+pub(crate) fn execute<C, T, O, FInput>(
+    program_fragment: &RunningProgramInfo<C>,
+    options: &RunOptions,
+    termination: &mut T,
+    output: &mut O,
+    read_input: FInput,
+) -> (BfRunResult<C>, StopPoint<C>)
 where
-    FInput: FnMut() -> Option<u8>,
-    FOutput: FnMut(u8),
+    C: Cell,
+    T: TerminationPolicy<C>,
+    O: OutputPolicy,
+    FInput: FnMut() -> Result<Option<u8>, ReadFailed>,
 {
-    let state_tracker_arc_mutex = get_state_tracker::<MAX_TAPE_SIZE>();
-    let mut state_tracker = state_tracker_arc_mutex.lock().unwrap();
-    {
-        //clear the state tracker for this thread
-        for state in state_tracker.iter_mut() {
-            state.clear();
-            state.shrink_to(SHRINK_TO_SIZE);
-        }
-        // Ensure the state tracker has enough elements
-        if state_tracker.len() < program_fragment.code.size() {
-            state_tracker.resize_with(program_fragment.code.size(), || {
-                HashSet::with_capacity(256 * 4)
-            });
-        }
+    let (result, state) = execute_from(
+        program_fragment,
+        program_fragment.continue_state.clone(),
+        options,
+        termination,
+        output,
+        read_input,
+    );
+    finish_run(program_fragment, output, result, state)
+}
 
-        let mut tape = program_fragment.continue_state.program_state.tape;
-        let mut tape_head = program_fragment.continue_state.program_state.tape_head;
-        let mut pc = program_fragment.continue_state.resume_pc; // Start from the last instruction
-        let mut output_ind = program_fragment.continue_state.resume_output_ind; // Resume from the last output index
+/// Runs `program_fragment` from `state` instead of the state it resumes from, returning how the run ended, `None` when `termination` paused it, and the state it ended in.
+#[inline(always)]
+pub(crate) fn execute_from<C, T, O, FInput>(
+    program_fragment: &RunningProgramInfo<C>,
+    state: ContinueState<C>,
+    options: &RunOptions,
+    termination: &mut T,
+    output: &mut O,
+    mut read_input: FInput,
+) -> (Option<BfRunResult<C>>, ContinueState<C>)
+where
+    C: Cell,
+    T: TerminationPolicy<C>,
+    O: OutputPolicy,
+    FInput: FnMut() -> Result<Option<u8>, ReadFailed>,
+{
+    let overflow = options.overflow;
+    let ProgramState {
+        mut tape,
+        mut tape_head,
+        mut origin,
+    } = state.program_state;
+    let mut pc = state.resume_pc; // Start from the last instruction
+    let mut output_ind = state.resume_output_ind; // Resume from the last output index
 
+    let result = 'run: {
         while pc < program_fragment.code.size() {
-            let current_state = ProgramState { tape, tape_head };
-            if state_tracker[pc].contains(&current_state) {
-                return collect_and_return(BfRunResult::InfiniteLoopError, &state_tracker);
-            } else {
-                state_tracker[pc].insert(current_state);
+            match termination.check(pc, &tape, tape_head, origin) {
+                StepCheck::Continue => {}
+                StepCheck::Repeated => break 'run Some(BfRunResult::InfiniteLoopError),
+                StepCheck::OutOfBudget => break 'run None,
             }
 
             match program_fragment.code.get(pc) {
@@ -259,26 +482,58 @@ where
                         pc, program_fragment.code
                     );
                 }
-                Some(BfInstruction::Inc) => {
-                    tape[tape_head as usize] = tape[tape_head as usize].wrapping_add(1);
-                }
-                Some(BfInstruction::Dec) => {
-                    tape[tape_head as usize] = tape[tape_head as usize].wrapping_sub(1);
-                }
+                Some(BfInstruction::Inc) => match tape[tape_head].increment(overflow) {
+                    Some(value) => termination.write(&mut tape, tape_head, origin, value),
+                    None => {
+                        break 'run Some(BfRunResult::CellOverflowError);
+                    }
+                },
+                Some(BfInstruction::Dec) => match tape[tape_head].decrement(overflow) {
+                    Some(value) => termination.write(&mut tape, tape_head, origin, value),
+                    None => {
+                        break 'run Some(BfRunResult::CellOverflowError);
+                    }
+                },
                 Some(BfInstruction::Left) => {
                     if tape_head == 0 {
-                        return collect_and_return(BfRunResult::TapeHeadBoundError, &state_tracker);
+                        match options.tape {
+                            TapePolicy::Growable {
+                                grow_left: true,
+                                max_cells,
+                            } => {
+                                if !grow_tape_left(
+                                    &mut tape,
+                                    &mut tape_head,
+                                    &mut origin,
+                                    max_cells,
+                                ) {
+                                    break 'run Some(BfRunResult::OOMError);
+                                }
+                            }
+                            _ => {
+                                break 'run Some(BfRunResult::TapeHeadBoundError);
+                            }
+                        }
                     }
                     tape_head -= 1;
                 }
                 Some(BfInstruction::Right) => {
-                    if tape_head as usize + 1 == MAX_TAPE_SIZE {
-                        return collect_and_return(BfRunResult::OOMError, &state_tracker);
+                    if tape_head + 1 == tape.len() {
+                        match options.tape {
+                            TapePolicy::Growable { max_cells, .. } => {
+                                if !grow_tape_right(&mut tape, max_cells) {
+                                    break 'run Some(BfRunResult::OOMError);
+                                }
+                            }
+                            TapePolicy::Fixed => {
+                                break 'run Some(BfRunResult::OOMError);
+                            }
+                        }
                     }
                     tape_head += 1;
                 }
                 Some(BfInstruction::LoopStart) => {
-                    if tape[tape_head as usize] == 0 {
+                    if tape[tape_head] == C::ZERO {
                         if program_fragment.jump_table[pc] == -1 {
                             panic!(
                                 "jump table is not initialized correctly, found -1 at LoopStart, pc: {}, program: {:?}, jump_table: {:?}",
@@ -286,14 +541,14 @@ where
                             );
                         }
                         if program_fragment.jump_table[pc] == -2 {
-                            return collect_and_return(BfRunResult::NOOPError, &state_tracker);
+                            break 'run Some(BfRunResult::NOOPError);
                         }
                         pc = program_fragment.jump_table[pc] as usize;
                         continue;
                     }
                 }
                 Some(BfInstruction::LoopEnd) => {
-                    if tape[tape_head as usize] != 0 {
+                    if tape[tape_head] != C::ZERO {
                         if program_fragment.jump_table[pc] == -1 {
                             panic!(
                                 "jump table is not initialized correctly, found -1 at LoopEnd, pc: {}, program: {:?}, jump_table: {:?}",
@@ -305,179 +560,781 @@ where
                     }
                 }
                 Some(BfInstruction::Output) => {
-                    write_output(tape[tape_head as usize]);
+                    if !output.write(tape[tape_head].to_byte(), output_ind) {
+                        break 'run Some(BfRunResult::TargetMismatchError);
+                    }
                     output_ind += 1;
                 }
                 Some(BfInstruction::Input) => {
+                    //output_ind also counts inputs so that outputs and inputs in a loop don't trigger a infinite loop error
                     output_ind += 1;
-                    if let Some(input) = read_input() {
-                        tape[tape_head as usize] = input;
-                    } else {
-                        return collect_and_return(BfRunResult::InputTokenError, &state_tracker);
+                    match (read_input(), options.eof) {
+                        (Ok(Some(input)), _) => {
+                            termination.write(&mut tape, tape_head, origin, C::from_byte(input));
+                            termination.input_read();
+                        }
+                        (Err(ReadFailed), _) | (Ok(None), EofBehavior::Error) => {
+                            break 'run Some(BfRunResult::InputTokenError);
+                        }
+                        (Ok(None), EofBehavior::Zero) => {
+                            termination.write(&mut tape, tape_head, origin, C::ZERO)
+                        }
+                        (Ok(None), EofBehavior::Max) => {
+                            termination.write(&mut tape, tape_head, origin, C::MAX)
+                        }
+                        (Ok(None), EofBehavior::Unchanged) => {}
                     }
                 }
             }
             pc += 1;
         }
+        Some(BfRunResult::Success)
+    };
 
-        if program_fragment.current_paren_count != 0 {
-            return collect_and_return(
-                BfRunResult::IncompleteLoopSuccess(ContinueState {
-                    program_state: ProgramState { tape, tape_head },
-                    resume_pc: pc,
-                    resume_output_ind: output_ind,
-                }),
-                &state_tracker,
-            );
+    let state = ContinueState {
+        program_state: ProgramState {
+            tape,
+            tape_head,
+            origin,
+        },
+        resume_pc: pc,
+        resume_output_ind: output_ind,
+    };
+    (result, state)
+}
+
+/// Turns the way the loop of a run ended into its result, with `state` the state the run ended in and `None` standing for a budget that ran out.
+pub(crate) fn finish_run<C: Cell, O: OutputPolicy>(
+    program_fragment: &RunningProgramInfo<C>,
+    output: &O,
+    result: Option<BfRunResult<C>>,
+    state: ContinueState<C>,
+) -> (BfRunResult<C>, StopPoint<C>) {
+    let program_state = &state.program_state;
+    let stop = StopPoint {
+        pc: state.resume_pc,
+        tape_head: program_state.tape_head as isize - program_state.origin as isize,
+        cell: program_state.tape[program_state.tape_head],
+    };
+
+    let result = match result {
+        None => BfRunResult::Paused(state),
+        Some(BfRunResult::Success) => {
+            if program_fragment.current_paren_count != 0 {
+                BfRunResult::IncompleteLoopSuccess(state)
+            } else if !output.complete(state.resume_output_ind) {
+                BfRunResult::IncompleteOutputSuccess(state)
+            } else {
+                BfRunResult::Success
+            }
         }
+        Some(result) => result,
+    };
 
-        collect_and_return(BfRunResult::Success, &state_tracker)
-    }
+    (result, stop)
 }
 
-const MAX_STEPS: usize = 131066;
+/// Owns the loop detection storage that runs use.
+///
+/// The storage is kept between runs so that it only has to grow once; reuse one interpreter per thread for many runs, and drop it to free the memory.
+/// When the next run resumes a program from the exact state its last run paused in on the same engine, the states seen before the pause still count towards finding loops.
+/// The optimized engines keep the last program they compiled, so that running it again or resuming it does not compile it again.
+#[derive(Debug)]
+pub struct Interpreter<C = u8> {
+    loops: LoopDetector<C>,
+    // where the optimized engines last started looking for loops
+    checkpoint: Checkpoint<C>,
+    // the program, state and engine the last run paused in
+    paused: Option<(CompressedBF, ContinueState<C>, Engine)>,
+    // the program the optimized engines ran last
+    compiled: Option<CompiledCache>,
+}
 
-static MAX_STEPS_REACHED: AtomicUsize = AtomicUsize::new(0);
+impl<C: Cell> Default for Interpreter<C> {
+    fn default() -> Self {
+        Interpreter::new()
+    }
+}
 
-pub fn run_program_fragment_without_states<const MAX_TAPE_SIZE: usize>(
-    program_fragment: &RunningProgramInfo<MAX_TAPE_SIZE>,
-    target_output: &[u8],
-) -> BfRunResult<MAX_TAPE_SIZE> {
-    let mut steps = 0;
+impl<C: Cell> Interpreter<C> {
+    pub fn new() -> Self {
+        Interpreter {
+            loops: LoopDetector::new(),
+            checkpoint: Checkpoint::new(),
+            paused: None,
+            compiled: None,
+        }
+    }
 
-    let mut tape = program_fragment.continue_state.program_state.tape;
-    let mut tape_head = program_fragment.continue_state.program_state.tape_head;
-    let mut pc = program_fragment.continue_state.resume_pc; // Start from the last instruction
-    let mut output_ind = program_fragment.continue_state.resume_output_ind; // Resume from the last output index
+    //starts loop detection for `engine` over unless `program_fragment` continues where the last run on it paused
+    fn prepare_loops(&mut self, program_fragment: &RunningProgramInfo<C>, engine: Engine) {
+        let resuming = self.paused.take().is_some_and(|(code, state, paused_on)| {
+            paused_on == engine
+                && code == program_fragment.code
+                && state == program_fragment.continue_state
+        });
+        if resuming {
+            return;
+        }
+        if engine == Engine::Reference {
+            let state = &program_fragment.continue_state.program_state;
+            self.loops.start(&state.tape, state.origin);
+        } else {
+            self.checkpoint.start(&program_fragment.continue_state);
+        }
+    }
 
-    while pc < program_fragment.code.size() {
-        steps += 1;
+    /// Runs a search candidate against `target_output`, checking every state for loops.
+    pub(crate) fn run_target(
+        &mut self,
+        program_fragment: &RunningProgramInfo<C>,
+        target_output: &[u8],
+        overflow: OverflowMode,
+    ) -> BfRunResult<C> {
+        self.prepare_loops(program_fragment, Engine::Reference);
+        let (result, _) = execute(
+            program_fragment,
+            &target_options(overflow),
+            &mut self.loops,
+            &mut MatchTarget(target_output),
+            || Ok(None),
+        );
+        result
+    }
 
-        if steps > MAX_STEPS {
-            // Do not update MAX_STEPS_REACHED here, as this is the fallback to run_program_fragment
-            return run_program_fragment(program_fragment, target_output);
+    /// Runs `program_fragment` to the end, or until `options.budget` runs out, reading `,` from `read_input` and passing every `.` to `write_output`.
+    pub fn run<FInput, FOutput>(
+        &mut self,
+        program_fragment: &RunningProgramInfo<C>,
+        options: &RunOptions,
+        read_input: FInput,
+        write_output: FOutput,
+    ) -> BfRunResult<C>
+    where
+        FInput: FnMut() -> Option<u8>,
+        FOutput: FnMut(u8),
+    {
+        self.run_with_stop(program_fragment, options, read_input, write_output)
+            .0
+    }
+
+    /// Same as [`Interpreter::run`], also reporting where the run stopped so that failures can be diagnosed.
+    pub fn run_with_stop<FInput, FOutput>(
+        &mut self,
+        program_fragment: &RunningProgramInfo<C>,
+        options: &RunOptions,
+        mut read_input: FInput,
+        mut write_output: FOutput,
+    ) -> (BfRunResult<C>, StopPoint<C>)
+    where
+        FInput: FnMut() -> Option<u8>,
+        FOutput: FnMut(u8),
+    {
+        let result = self.try_run_with_stop(
+            program_fragment,
+            options,
+            || Ok::<_, Infallible>(read_input()),
+            |byte| {
+                write_output(byte);
+                Ok(())
+            },
+        );
+        match result {
+            Ok(result) => result,
+            Err(never) => match never {},
         }
+    }
 
-        match program_fragment.code.get(pc) {
-            None => {
-                MAX_STEPS_REACHED.fetch_max(steps, std::sync::atomic::Ordering::Relaxed);
-                panic!(
-                    "could not read current BF instruction, pc: {}, program: {:?}",
-                    pc, program_fragment.code
-                );
-            }
-            Some(BfInstruction::Inc) => {
-                tape[tape_head as usize] = tape[tape_head as usize].wrapping_add(1);
-            }
-            Some(BfInstruction::Dec) => {
-                tape[tape_head as usize] = tape[tape_head as usize].wrapping_sub(1);
-            }
-            Some(BfInstruction::Left) => {
-                if tape_head == 0 {
-                    MAX_STEPS_REACHED.fetch_max(steps, std::sync::atomic::Ordering::Relaxed);
-                    return BfRunResult::TapeHeadBoundError;
-                }
-                tape_head -= 1;
+    /// Same as [`Interpreter::run_with_stop`] with callbacks that can fail, the first error stops the run and is returned instead of its result.
+    pub fn try_run_with_stop<FInput, FOutput, E>(
+        &mut self,
+        program_fragment: &RunningProgramInfo<C>,
+        options: &RunOptions,
+        mut read_input: FInput,
+        mut write_output: FOutput,
+    ) -> Result<(BfRunResult<C>, StopPoint<C>), E>
+    where
+        FInput: FnMut() -> Result<Option<u8>, E>,
+        FOutput: FnMut(u8) -> Result<(), E>,
+    {
+        let failure = RefCell::new(None);
+        let read_input = || {
+            read_input().map_err(|error| {
+                *failure.borrow_mut() = Some(error);
+                ReadFailed
+            })
+        };
+        let mut output = WriteOutput(|byte| match write_output(byte) {
+            Ok(()) => true,
+            Err(error) => {
+                *failure.borrow_mut() = Some(error);
+                false
             }
-            Some(BfInstruction::Right) => {
-                if tape_head as usize + 1 == MAX_TAPE_SIZE {
-                    MAX_STEPS_REACHED.fetch_max(steps, std::sync::atomic::Ordering::Relaxed);
-                    return BfRunResult::OOMError;
-                }
-                tape_head += 1;
+        });
+        let result = self.run_engine(program_fragment, options, &mut output, read_input);
+        match failure.into_inner() {
+            Some(error) => Err(error),
+            None => Ok(result),
+        }
+    }
+
+    //runs on the engine `options` picks, with `read_input` and `output` ready for the engines
+    fn run_engine<O, FInput>(
+        &mut self,
+        program_fragment: &RunningProgramInfo<C>,
+        options: &RunOptions,
+        output: &mut O,
+        read_input: FInput,
+    ) -> (BfRunResult<C>, StopPoint<C>)
+    where
+        O: OutputPolicy,
+        FInput: FnMut() -> Result<Option<u8>, ReadFailed>,
+    {
+        self.prepare_loops(program_fragment, options.engine);
+        let (result, stop) = if options.engine != Engine::Reference {
+            let cache = CompiledCache::get(&mut self.compiled, program_fragment, options.overflow);
+            match options.engine {
+                #[cfg(all(target_arch = "x86_64", unix))]
+                Engine::Jit => execute_jit(
+                    cache,
+                    program_fragment,
+                    options,
+                    &mut self.loops,
+                    &mut self.checkpoint,
+                    output,
+                    read_input,
+                ),
+                _ => execute_compiled(
+                    &cache.compiled,
+                    program_fragment,
+                    options,
+                    &mut self.loops,
+                    &mut self.checkpoint,
+                    output,
+                    read_input,
+                ),
             }
-            Some(BfInstruction::LoopStart) => {
-                if tape[tape_head as usize] == 0 {
-                    if program_fragment.jump_table[pc] == -1 {
-                        MAX_STEPS_REACHED.fetch_max(steps, std::sync::atomic::Ordering::Relaxed);
-                        panic!(
-                            "jump table is not initialized correctly, found -1 at LoopStart, pc: {}, program: {:?}, jump_table: {:?}",
-                            pc, program_fragment.code, program_fragment.jump_table
-                        );
-                    }
-                    if program_fragment.jump_table[pc] == -2 {
-                        MAX_STEPS_REACHED.fetch_max(steps, std::sync::atomic::Ordering::Relaxed);
-                        return BfRunResult::NOOPError;
-                    }
-                    pc = program_fragment.jump_table[pc] as usize;
-                    continue;
-                }
+        } else if options.budget.is_unlimited() {
+            execute(
+                program_fragment,
+                options,
+                &mut self.loops,
+                output,
+                read_input,
+            )
+        } else {
+            execute(
+                program_fragment,
+                options,
+                &mut Budgeted::new(&mut self.loops, options.budget),
+                output,
+                read_input,
+            )
+        };
+        if let BfRunResult::Paused(state) = &result {
+            self.paused = Some((program_fragment.code.clone(), state.clone(), options.engine));
+        }
+        (result, stop)
+    }
+
+    /// Runs a search candidate without loop detection for up to `MAX_STEPS` steps, then falls back to [`Interpreter::run_target`].
+    pub(crate) fn run_target_without_states(
+        &mut self,
+        program_fragment: &RunningProgramInfo<C>,
+        target_output: &[u8],
+        overflow: OverflowMode,
+    ) -> BfRunResult<C> {
+        let mut unchecked = Unchecked;
+        let mut limit = Budgeted::new(&mut unchecked, Budget::steps(MAX_STEPS as u64));
+        let (result, _) = execute(
+            program_fragment,
+            &target_options(overflow),
+            &mut limit,
+            &mut MatchTarget(target_output),
+            || Ok(None),
+        );
+        match result {
+            BfRunResult::Paused(_) => self.run_target(program_fragment, target_output, overflow),
+            result => result,
+        }
+    }
+}
+
+//search candidates run on a fixed tape and can not read input
+fn target_options(overflow: OverflowMode) -> RunOptions {
+    RunOptions {
+        overflow,
+        ..RunOptions::default()
+    }
+}
+
+/// Runs `program_fragment` on a fresh [`Interpreter`], see [`Interpreter::run`].
+pub fn run_program_fragment_no_target<C: Cell, FInput, FOutput>(
+    program_fragment: &RunningProgramInfo<C>,
+    options: &RunOptions,
+    read_input: FInput,
+    write_output: FOutput,
+) -> BfRunResult<C>
+where
+    FInput: FnMut() -> Option<u8>,
+    FOutput: FnMut(u8),
+{
+    Interpreter::new().run(program_fragment, options, read_input, write_output)
+}
+
+/// Runs `program_fragment` on a fresh [`Interpreter`], see [`Interpreter::run_with_stop`].
+pub fn run_program_fragment_no_target_with_stop<C: Cell, FInput, FOutput>(
+    program_fragment: &RunningProgramInfo<C>,
+    options: &RunOptions,
+    read_input: FInput,
+    write_output: FOutput,
+) -> (BfRunResult<C>, StopPoint<C>)
+where
+    FInput: FnMut() -> Option<u8>,
+    FOutput: FnMut(u8),
+{
+    Interpreter::new().run_with_stop(program_fragment, options, read_input, write_output)
+}
+
+// --- UNIT TESTS ---
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::{preprocess_input, preprocess_input_with_cells};
+
+    fn run_collecting(program: &str, tape_size: usize) -> (BfRunResult, Vec<u8>) {
+        run_with_options(program, tape_size, &RunOptions::default())
+    }
+
+    fn run_with_options(
+        program: &str,
+        tape_size: usize,
+        options: &RunOptions,
+    ) -> (BfRunResult, Vec<u8>) {
+        let info = preprocess_input(program, tape_size).unwrap();
+        let mut output = Vec::new();
+        let result =
+            run_program_fragment_no_target(&info, options, || None, |byte| output.push(byte));
+        (result, output)
+    }
+
+    fn run_cells<C: Cell>(program: &str, options: &RunOptions) -> (BfRunResult<C>, Vec<u8>) {
+        let info = preprocess_input_with_cells::<C>(program, 8).unwrap();
+        let mut output = Vec::new();
+        let result =
+            run_program_fragment_no_target(&info, options, || None, |byte| output.push(byte));
+        (result, output)
+    }
+
+    fn run_with_input(program: &str, input: &[u8], eof: EofBehavior) -> (BfRunResult, Vec<u8>) {
+        let info = preprocess_input(program, 8).unwrap();
+        let options = RunOptions {
+            eof,
+            ..RunOptions::default()
+        };
+        let mut input = input.iter().copied();
+        let mut output = Vec::new();
+        let result = run_program_fragment_no_target(
+            &info,
+            &options,
+            || input.next(),
+            |byte| output.push(byte),
+        );
+        (result, output)
+    }
+
+    fn growable(grow_left: bool, max_cells: usize) -> RunOptions {
+        RunOptions {
+            tape: TapePolicy::Growable {
+                grow_left,
+                max_cells,
+            },
+            ..RunOptions::default()
+        }
+    }
+
+    /// The hello world program from the jump table tests prints correctly on the default tape.
+    #[test]
+    fn test_hello_world_large_tape() {
+        let program = ">++++++++[<+++++++++>-]<.>++++[<+++++++>-]<+.+++++++..+++.>>++++++[<+++++++>-]<++.------------.>++++++[<+++++++++>-]<+.<.+++.------.--------.>>>++++[<++++++++>-]<+.";
+        let (result, output) = run_collecting(program, 30_000);
+        assert_eq!(result, BfRunResult::Success);
+        assert_eq!(output, b"Hello, World!");
+    }
+
+    /// The head can move past cell 255 without wrapping back to the start of the tape.
+    #[test]
+    fn test_head_past_255() {
+        let program = format!("{}+.{}.", ">".repeat(300), "<".repeat(300));
+        let (result, output) = run_collecting(&program, 301);
+        assert_eq!(result, BfRunResult::Success);
+        assert_eq!(output, vec![1, 0]);
+    }
+
+    /// Moving off either end of a large tape still reports the bound errors.
+    #[test]
+    fn test_large_tape_bounds() {
+        let (result, _) = run_collecting(&">".repeat(300), 300);
+        assert_eq!(result, BfRunResult::OOMError);
+        let (result, _) = run_collecting(&format!("{}{}", ">".repeat(299), "<".repeat(300)), 300);
+        assert_eq!(result, BfRunResult::TapeHeadBoundError);
+    }
+
+    /// A growable tape extends to the right as far as the program walks.
+    #[test]
+    fn test_growable_right() {
+        let program = format!("{}+.", ">".repeat(100));
+        let (result, output) = run_with_options(&program, 1, &growable(false, 1000));
+        assert_eq!(result, BfRunResult::Success);
+        assert_eq!(output, vec![1]);
+    }
+
+    /// The memory cap of a growable tape is reported as an `OOMError`.
+    #[test]
+    fn test_growable_cap() {
+        let (result, _) = run_with_options(&">".repeat(10), 1, &growable(true, 10));
+        assert_eq!(result, BfRunResult::OOMError);
+        let (result, _) = run_with_options(&"<".repeat(10), 1, &growable(true, 10));
+        assert_eq!(result, BfRunResult::OOMError);
+    }
+
+    /// Only a bidirectional tape lets the head move left of the starting cell.
+    #[test]
+    fn test_growable_left() {
+        let program = "<<<+.>>>.";
+        let (result, output) = run_with_options(program, 1, &growable(true, 1000));
+        assert_eq!(result, BfRunResult::Success);
+        assert_eq!(output, vec![1, 0]);
+        let (result, _) = run_with_options(program, 1, &growable(false, 1000));
+        assert_eq!(result, BfRunResult::TapeHeadBoundError);
+    }
+
+    /// Wider cells count past 255 and only print their low byte.
+    #[test]
+    fn test_wide_cells() {
+        let program = format!("{}[.[-]]", "+".repeat(257));
+        let (result, output) = run_cells::<u8>(&program, &RunOptions::default());
+        assert_eq!(result, BfRunResult::Success);
+        assert_eq!(output, vec![1]);
+        let (result, output) = run_cells::<u16>(&program, &RunOptions::default());
+        assert_eq!(result, BfRunResult::Success);
+        assert_eq!(output, vec![1]);
+
+        let program = format!("{}[.[-]]", "+".repeat(256));
+        let (_, output) = run_cells::<u8>(&program, &RunOptions::default());
+        assert_eq!(output, vec![]);
+        let (_, output) = run_cells::<u32>(&program, &RunOptions::default());
+        assert_eq!(output, vec![0]);
+    }
+
+    /// Saturating cells stop at their bounds and erroring cells end the run there.
+    #[test]
+    fn test_overflow_modes() {
+        let saturate = RunOptions {
+            overflow: OverflowMode::Saturate,
+            ..RunOptions::default()
+        };
+        let (result, output) = run_cells::<u8>("-.+.", &saturate);
+        assert_eq!(result, BfRunResult::Success);
+        assert_eq!(output, vec![0, 1]);
+
+        let error = RunOptions {
+            overflow: OverflowMode::Error,
+            ..RunOptions::default()
+        };
+        let (result, _) = run_cells::<u16>("-", &error);
+        assert_eq!(result, BfRunResult::CellOverflowError);
+        let (result, output) = run_cells::<u8>(&format!("{}.+", "+".repeat(255)), &error);
+        assert_eq!(result, BfRunResult::CellOverflowError);
+        assert_eq!(output, vec![255]);
+        let (result, _) = run_cells::<u16>(&"+".repeat(256), &error);
+        assert_eq!(result, BfRunResult::Success);
+    }
+
+    /// `cat` and `rev` finish once input runs out when EOF stores 0, and fail when EOF is an error.
+    #[test]
+    fn test_eof_zero() {
+        let (result, output) = run_with_input(",[.,]", b"aab", EofBehavior::Zero);
+        assert_eq!(result, BfRunResult::Success);
+        assert_eq!(output, b"aab");
+        let (result, output) = run_with_input(">,[>,]<[.<]", b"abc", EofBehavior::Zero);
+        assert_eq!(result, BfRunResult::Success);
+        assert_eq!(output, b"cba");
+        let (result, output) = run_with_input(",[.,]", b"ab", EofBehavior::Error);
+        assert_eq!(result, BfRunResult::InputTokenError);
+        assert_eq!(output, b"ab");
+    }
+
+    /// The other EOF conventions store the largest cell value or keep the cell.
+    #[test]
+    fn test_eof_max_and_unchanged() {
+        let (result, output) = run_with_input(",+[-.,+]", b"ab", EofBehavior::Max);
+        assert_eq!(result, BfRunResult::Success);
+        assert_eq!(output, b"ab");
+        let (result, output) = run_with_input(",[.[-],]", b"ab", EofBehavior::Unchanged);
+        assert_eq!(result, BfRunResult::Success);
+        assert_eq!(output, b"ab");
+        let (result, output) = run_with_input("+++,.", b"", EofBehavior::Unchanged);
+        assert_eq!(result, BfRunResult::Success);
+        assert_eq!(output, vec![3]);
+    }
+
+    /// A failing run reports the instruction it stopped on, the head position and the cell under it.
+    #[test]
+    fn test_stop_point() {
+        let info = preprocess_input("+++>--<<", 8).unwrap();
+        let (result, stop) = run_program_fragment_no_target_with_stop(
+            &info,
+            &RunOptions::default(),
+            || None,
+            |_| {},
+        );
+        assert_eq!(result, BfRunResult::TapeHeadBoundError);
+        assert_eq!(
+            stop,
+            StopPoint {
+                pc: 7,
+                tape_head: 0,
+                cell: 3
             }
-            Some(BfInstruction::LoopEnd) => {
-                if tape[tape_head as usize] != 0 {
-                    if program_fragment.jump_table[pc] == -1 {
-                        MAX_STEPS_REACHED.fetch_max(steps, std::sync::atomic::Ordering::Relaxed);
-                        panic!(
-                            "jump table is not initialized correctly, found -1 at LoopEnd, pc: {}, program: {:?}, jump_table: {:?}",
-                            pc, program_fragment.code, program_fragment.jump_table
-                        );
-                    }
-                    pc = program_fragment.jump_table[pc] as usize;
-                    continue;
+        );
+
+        let (result, stop) =
+            run_program_fragment_no_target_with_stop(&info, &growable(true, 64), || None, |_| {});
+        assert_eq!(result, BfRunResult::Success);
+        assert_eq!(stop.pc, 8);
+        assert_eq!(stop.tape_head, -1);
+        assert_eq!(stop.cell, 0);
+    }
+
+    /// A callback that fails stops the run on every engine, even one that would print or read forever.
+    #[test]
+    fn test_failing_callbacks() {
+        let info = preprocess_input("+[.>+]", 8).unwrap();
+        let read = preprocess_input("+[,>+]", 8).unwrap();
+        for engine in [Engine::Reference, Engine::Optimized, Engine::Jit] {
+            let options = RunOptions {
+                engine,
+                eof: EofBehavior::Zero,
+                ..growable(false, usize::MAX)
+            };
+            let mut written = 0;
+            let result = Interpreter::new().try_run_with_stop(
+                &info,
+                &options,
+                || Ok(None),
+                |_| {
+                    written += 1;
+                    if written < 3 { Ok(()) } else { Err("closed") }
+                },
+            );
+            assert_eq!(result, Err("closed"), "{engine:?}");
+            assert_eq!(written, 3, "{engine:?}");
+
+            let result = Interpreter::new().try_run_with_stop(
+                &read,
+                &options,
+                || Err("unreadable"),
+                |_| Ok(()),
+            );
+            assert_eq!(result, Err("unreadable"), "{engine:?}");
+        }
+    }
+
+    /// Loops are found on a full size tape, including ones that only come back to a state after changing cells in between.
+    #[test]
+    fn test_loops_on_large_tape() {
+        let (result, _) = run_collecting("+[]", 30_000);
+        assert_eq!(result, BfRunResult::InfiniteLoopError);
+        let (result, _) = run_collecting("+[>+>-<<]", 30_000);
+        assert_eq!(result, BfRunResult::InfiniteLoopError);
+        let (result, _) = run_collecting("+[>+<[-]+>-<]", 30_000);
+        assert_eq!(result, BfRunResult::InfiniteLoopError);
+        let (result, output) = run_collecting("+[>+[>+<-]>[<+>-]<<+]>.", 30_000);
+        assert_eq!(result, BfRunResult::Success);
+        assert_eq!(output, vec![255]);
+    }
+
+    /// Loops are still found after the tape grows to the left.
+    #[test]
+    fn test_loops_on_growable_tape() {
+        let (result, _) = run_with_options("<<+[>+<]", 1, &growable(true, 1000));
+        assert_eq!(result, BfRunResult::InfiniteLoopError);
+        let (result, _) = run_with_options("+[<+]", 1, &growable(true, 1000));
+        assert_eq!(result, BfRunResult::OOMError);
+    }
+
+    /// Search candidates get the same results with and without loop detection, except for loops that only loop detection can catch.
+    #[test]
+    fn test_target_runs_agree() {
+        let mut interpreter = Interpreter::<u8>::new();
+        let open_loop = RunningProgramInfo {
+            code: CompressedBF::from_string("+[."),
+            current_paren_count: 1,
+            jump_table: vec![-1, -2, -1],
+            continue_state: preprocess_input("", 8).unwrap().continue_state,
+        };
+        let cases = [
+            (preprocess_input("+.+.", 8).unwrap(), &[1, 2][..]),
+            (preprocess_input("+.", 8).unwrap(), &[1, 2][..]),
+            (open_loop, &[1][..]),
+            (preprocess_input("++.", 8).unwrap(), &[1][..]),
+            (preprocess_input(",", 8).unwrap(), &[][..]),
+            (preprocess_input("<", 8).unwrap(), &[][..]),
+        ];
+        let mut results = Vec::new();
+        for (info, target) in &cases {
+            let with_states = interpreter.run_target(info, target, OverflowMode::Wrap);
+            let without_states =
+                interpreter.run_target_without_states(info, target, OverflowMode::Wrap);
+            assert_eq!(with_states, without_states);
+            results.push(with_states);
+        }
+        assert_eq!(results[0], BfRunResult::Success);
+        assert!(matches!(
+            results[1],
+            BfRunResult::IncompleteOutputSuccess(_)
+        ));
+        assert!(matches!(results[2], BfRunResult::IncompleteLoopSuccess(_)));
+        assert_eq!(results[3], BfRunResult::TargetMismatchError);
+        assert_eq!(results[4], BfRunResult::InputTokenError);
+        assert_eq!(results[5], BfRunResult::TapeHeadBoundError);
+
+        let looping = preprocess_input("+[]", 8).unwrap();
+        assert_eq!(
+            interpreter.run_target_without_states(&looping, &[], OverflowMode::Wrap),
+            BfRunResult::InfiniteLoopError
+        );
+    }
+
+    //runs `program` in slices of `max_steps` steps until it stops for another reason, returning the result, the output and the number of slices
+    fn run_in_slices(program: &str, max_steps: u64) -> (BfRunResult, Vec<u8>, usize) {
+        let mut info = preprocess_input(program, 8).unwrap();
+        let options = RunOptions {
+            budget: Budget::steps(max_steps),
+            ..RunOptions::default()
+        };
+        let mut interpreter = Interpreter::new();
+        let mut output = Vec::new();
+        let mut slices = 1;
+        loop {
+            match interpreter.run(&info, &options, || None, |byte| output.push(byte)) {
+                BfRunResult::Paused(state) => {
+                    info.resume(state);
+                    slices += 1;
                 }
+                result => return (result, output, slices),
             }
-            Some(BfInstruction::Output) => {
-                if output_ind == target_output.len() {
-                    MAX_STEPS_REACHED.fetch_max(steps, std::sync::atomic::Ordering::Relaxed);
-                    return BfRunResult::TargetMismatchError;
-                }
-                if target_output[output_ind] != tape[tape_head as usize] {
-                    MAX_STEPS_REACHED.fetch_max(steps, std::sync::atomic::Ordering::Relaxed);
-                    return BfRunResult::TargetMismatchError;
+        }
+    }
+
+    /// A run paused by its step budget picks up where it left off, and ends the same way as a run without a budget.
+    #[test]
+    fn test_step_budget_resume() {
+        let program = "++++++++[>++++++++<-]>+.+.+.";
+        let (result, output, slices) = run_in_slices(program, 10);
+        assert_eq!(result, BfRunResult::Success);
+        assert_eq!(output, b"ABC");
+        assert!(slices > 10);
+
+        let info = preprocess_input(program, 8).unwrap();
+        let options = RunOptions {
+            budget: Budget::steps(3),
+            ..RunOptions::default()
+        };
+        let (result, stop) =
+            run_program_fragment_no_target_with_stop(&info, &options, || None, |_| {});
+        assert!(matches!(result, BfRunResult::Paused(_)));
+        assert_eq!(stop.pc, 3);
+        assert_eq!(stop.cell, 3);
+    }
+
+    /// Loops that take longer than one slice to repeat are still found when the run is resumed.
+    #[test]
+    fn test_loop_across_slices() {
+        let (result, _, slices) = run_in_slices("+[>+>-<<]", 100);
+        assert_eq!(result, BfRunResult::InfiniteLoopError);
+        assert!(slices > 1);
+    }
+
+    /// A deadline in the past pauses the run before its first instruction.
+    #[test]
+    fn test_deadline() {
+        let info = preprocess_input("+[]", 8).unwrap();
+        let options = RunOptions {
+            budget: Budget::until(Instant::now()),
+            ..RunOptions::default()
+        };
+        let result = run_program_fragment_no_target(&info, &options, || None, |_| {});
+        assert_eq!(result, BfRunResult::Paused(info.continue_state.clone()));
+    }
+
+    /// Running a program again, or resuming it, reuses the program compiled for the optimized engines.
+    #[test]
+    fn test_compiled_program_reused() {
+        let compiled_ops = |interpreter: &Interpreter| {
+            interpreter
+                .compiled
+                .as_ref()
+                .unwrap()
+                .compiled
+                .ops()
+                .as_ptr()
+        };
+        let mut info = preprocess_input("+>+<[.-]", 8).unwrap();
+        let options = RunOptions {
+            engine: Engine::Optimized,
+            budget: Budget::steps(3),
+            ..RunOptions::default()
+        };
+        let mut interpreter = Interpreter::new();
+        let mut first = None;
+        let mut slices = 0;
+        loop {
+            let result = interpreter.run(&info, &options, || None, |_| {});
+            let ops = compiled_ops(&interpreter);
+            assert_eq!(*first.get_or_insert(ops), ops);
+            slices += 1;
+            match result {
+                BfRunResult::Paused(state) => info.resume(state),
+                result => {
+                    assert_eq!(result, BfRunResult::Success);
+                    break;
                 }
-                output_ind += 1;
-            }
-            Some(BfInstruction::Input) => {
-                MAX_STEPS_REACHED.fetch_max(steps, std::sync::atomic::Ordering::Relaxed);
-                return BfRunResult::InputTokenError;
             }
         }
-        pc += 1;
+        assert!(slices > 1);
+
+        let info = preprocess_input("+>+<[.-]", 8).unwrap();
+        let options = RunOptions {
+            engine: Engine::Jit,
+            ..RunOptions::default()
+        };
+        interpreter.run(&info, &options, || None, |_| {});
+        let ops = compiled_ops(&interpreter);
+        interpreter.run(&info, &options, || None, |_| {});
+        assert_eq!(compiled_ops(&interpreter), ops);
     }
 
-    if program_fragment.current_paren_count != 0 {
-        MAX_STEPS_REACHED.fetch_max(steps, std::sync::atomic::Ordering::Relaxed);
-        return BfRunResult::IncompleteLoopSuccess(ContinueState {
-            program_state: ProgramState { tape, tape_head },
-            resume_pc: pc,
-            resume_output_ind: output_ind,
+    /// One interpreter can be reused for many runs, and moved to other threads, without earlier runs leaking into later ones.
+    #[test]
+    fn test_interpreter_reuse() {
+        let looping = preprocess_input("+[]", 8).unwrap();
+        let counting = preprocess_input("+[.+]", 8).unwrap();
+        let mut interpreter = Interpreter::new();
+        let options = RunOptions::default();
+        for _ in 0..2 {
+            let result = interpreter.run(&looping, &options, || None, |_| {});
+            assert_eq!(result, BfRunResult::InfiniteLoopError);
+            let mut output = Vec::new();
+            let result = interpreter.run(&counting, &options, || None, |byte| output.push(byte));
+            assert_eq!(result, BfRunResult::Success);
+            assert_eq!(output.len(), 255);
+        }
+
+        let handle = std::thread::spawn(move || {
+            interpreter.run(&looping, &RunOptions::default(), || None, |_| {})
         });
+        assert_eq!(handle.join().unwrap(), BfRunResult::InfiniteLoopError);
     }
-
-    let result = if output_ind != target_output.len() {
-        BfRunResult::IncompleteOutputSuccess(ContinueState {
-            program_state: ProgramState { tape, tape_head },
-            resume_pc: pc,
-            resume_output_ind: output_ind,
-        })
-    } else {
-        BfRunResult::Success
-    };
-    MAX_STEPS_REACHED.fetch_max(steps, std::sync::atomic::Ordering::Relaxed);
-    result
-}
-
-//this will be used later for potential analysis
-#[allow(dead_code)]
-pub fn get_max_steps_reached() -> usize {
-    MAX_STEPS_REACHED.load(std::sync::atomic::Ordering::Relaxed)
-}
-
-// fn tabulate_hashset_sizes<(state_tracker: &[HashSet<ProgramState>]) {
-//     if let Some(hist) = HASHSET_SIZE_HISTOGRAM.get() {
-//         let mut map = hist.lock().unwrap();
-//         for size in state_tracker.iter().map(|s| s.len()) {
-//             *map.entry(size).or_insert(0) += 1;
-//         }
-//     }
-// }
-
-//this is for later potential analysis
-fn collect_and_return<const MAX_TAPE_SIZE: usize>(
-    result: BfRunResult<MAX_TAPE_SIZE>,
-    state_tracker: &[HashSet<ProgramState<MAX_TAPE_SIZE>>],
-) -> BfRunResult<MAX_TAPE_SIZE> {
-    let _ = state_tracker;
-    // tabulate_hashset_sizes(state_tracker);
-    result
 }
@@ -1,10 +1,11 @@
-#![allow(dead_code)]
-
 use std::{
-    fs::{File, OpenOptions},
-    io::{BufReader, BufWriter, Read, Write},
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    marker::PhantomData,
+    path::{Path, PathBuf},
     sync::{
         Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
         mpsc::{self, Sender},
     },
     thread::{self, JoinHandle},
@@ -13,97 +14,143 @@ use std::{
 use ahash::{HashSet, RandomState};
 
 use crate::{
+    cell::{Cell, CellWidth, OverflowMode},
     data::{BfInstruction, CompressedBF},
-    run::{
-        BfRunResult, ContinueState, ProgramState, RunningProgramInfo, get_max_steps_reached,
-        run_program_fragment, run_program_fragment_without_states,
-    },
+    error::{SearchError, SeedError},
+    run::{BfRunResult, ContinueState, Interpreter, ProgramState, RunningProgramInfo, Tape},
+    util::preprocess_input_with_cells,
 };
 
-fn find_program<const MAX_TAPE_SIZE: usize>(
-    target_output: &[u8],
-    starting_program: String,
-) -> Result<Vec<BfInstruction>, &'static str> {
-    //parse the starting program
-    let starting_program = CompressedBF::from_string(starting_program);
-
-    let mut current_program_size = starting_program.size();
-    let mut current_program_writing_head = DiskSeedWriter::new(current_program_size);
-
-    // calculate and check paren_count
-    let mut paren_count = 0;
-    for instruction in starting_program.iter() {
-        match instruction {
-            BfInstruction::LoopStart => paren_count += 1,
-            BfInstruction::LoopEnd => paren_count -= 1,
-            _ => {}
+static SEARCH_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Settings for [`find_program`].
+#[derive(Debug, Clone, Copy)]
+pub struct SearchOptions {
+    /// Number of cells on the tape of every candidate program.
+    pub tape_size: usize,
+    /// Largest program size, in instructions, to search before giving up.
+    pub max_program_size: usize,
+    /// Wider cells make wrapping loops such as `+[+]` run for up to 2^16 or 2^32 steps, which slows the search down a lot.
+    pub cell_width: CellWidth,
+    pub overflow: OverflowMode,
+    /// Called with the size of the programs in every layer of candidates the search finished, so that callers can show its progress.
+    pub progress: Option<fn(usize)>,
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        SearchOptions {
+            tape_size: 32,
+            max_program_size: 16,
+            cell_width: CellWidth::default(),
+            overflow: OverflowMode::default(),
+            progress: None,
         }
     }
-    if paren_count != 0 {
-        return Err("Starting program has unmatched parentheses.");
+}
+
+/// Breadth-first search for the shortest program (extending `starting_program`) that prints exactly `target_output`.
+///
+/// Every layer of candidate programs is spilled to disk in a scratch directory under the system temp dir, which is removed once the search finishes.
+/// Candidates run on a zeroed tape of `options.tape_size` cells; tapes of up to 32 cells are kept inline, which keeps the search fast.
+/// Gives up with an error once programs would grow past `options.max_program_size` instructions.
+pub fn find_program(
+    target_output: &[u8],
+    starting_program: &str,
+    options: &SearchOptions,
+) -> Result<CompressedBF, SearchError> {
+    if options.tape_size == 0 {
+        return Err(SearchError::ZeroTapeSize);
     }
 
-    // construct the jump table
-    let mut jump_table = Vec::with_capacity(starting_program.size() + 1);
-    for i in 0..starting_program.size() {
-        match starting_program.get(i) {
-            Some(BfInstruction::LoopStart) => jump_table.push(-2), // -2 indicates start of loop
-            Some(BfInstruction::LoopEnd) => {
-                //find the last -2 in the jump table and set it to the current index + 1 and append the index of the loop start + 1
-                if let Some(loop_start_index) = jump_table.iter().rposition(|&x| x == -2) {
-                    jump_table[loop_start_index] = i as i64 + 1; // set the loop start to the current index + 1
-                    jump_table.push((loop_start_index + 1) as i64); // append the index of the loop start + 1
-                } else {
-                    return Err("Loop end without matching loop start.");
-                }
-            }
-            _ => jump_table.push(-1), // -1 indicates non-loop instruction
-        }
+    let seed_dir = std::env::temp_dir().join(format!(
+        "brainfuck_search_{}_{}",
+        std::process::id(),
+        SEARCH_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    if let Err(source) = fs::create_dir_all(&seed_dir) {
+        return Err(SearchError::SeedDirectory {
+            path: seed_dir,
+            source,
+        });
     }
 
-    // construct RunningProgramInfo for the starting program
-    let starting_program_info = RunningProgramInfo {
-        code: starting_program.clone(),
-        current_paren_count: 0,
-        jump_table,
-        continue_state: ContinueState {
-            resume_pc: 0,
-            resume_output_ind: 0,
-            program_state: ProgramState {
-                tape: [0u8; MAX_TAPE_SIZE],
-                tape_head: 0,
-            },
-        },
+    let result = match options.cell_width {
+        CellWidth::U8 => find_program_in::<u8>(target_output, starting_program, options, &seed_dir),
+        CellWidth::U16 => {
+            find_program_in::<u16>(target_output, starting_program, options, &seed_dir)
+        }
+        CellWidth::U32 => {
+            find_program_in::<u32>(target_output, starting_program, options, &seed_dir)
+        }
     };
 
+    let _ = fs::remove_dir_all(&seed_dir);
+    result
+}
+
+fn find_program_in<C: Cell>(
+    target_output: &[u8],
+    starting_program: &str,
+    options: &SearchOptions,
+    seed_dir: &Path,
+) -> Result<CompressedBF, SearchError> {
+    let SearchOptions {
+        tape_size,
+        max_program_size,
+        overflow,
+        progress,
+        ..
+    } = *options;
+
+    //parse the starting program
+    let starting_program_info = preprocess_input_with_cells::<C>(starting_program, tape_size)?;
+
+    let mut current_program_size = starting_program_info.code.size();
+    if current_program_size > max_program_size {
+        return Err(SearchError::StartingProgramTooLarge {
+            size: current_program_size,
+            max_program_size,
+        });
+    }
+
+    let mut current_program_writing_head =
+        DiskSeedWriter::<C>::new(seed_dir, tape_size, current_program_size)?;
+
     //run initial program
-    let initial_program_run_result = run_program_fragment(&starting_program_info, target_output);
-    println!("Program run result: {:?}", initial_program_run_result);
+    let mut interpreter = Interpreter::<C>::new();
+    let initial_program_run_result =
+        interpreter.run_target(&starting_program_info, target_output, overflow);
     let mut found_states = HashSet::with_capacity_and_hasher(5_000_000, RandomState::default());
 
-    handle_run_result(
+    if let Some(working_program) = handle_run_result(
         initial_program_run_result,
         starting_program_info,
         &mut current_program_writing_head,
         &mut found_states,
-    );
+    ) {
+        current_program_writing_head.flush()?;
+        return Ok(working_program);
+    }
 
-    current_program_writing_head.flush();
+    current_program_writing_head.flush()?;
 
     let mut current_program_reading_head;
 
     loop {
-        current_program_writing_head.flush();
-        current_program_writing_head = DiskSeedWriter::new(current_program_size + 1);
-        current_program_reading_head = DiskSeedReader::new(current_program_size);
+        if current_program_size == max_program_size {
+            return Err(SearchError::MaxSizeReached { max_program_size });
+        }
 
-        current_program_size += 1;
+        current_program_writing_head.flush()?;
+        current_program_writing_head =
+            DiskSeedWriter::new(seed_dir, tape_size, current_program_size + 1)?;
+        current_program_reading_head =
+            DiskSeedReader::new(seed_dir, tape_size, current_program_size)?;
 
-        // if current_program_size == 12 {
-        //     return vec![];
-        // }
+        current_program_size += 1;
 
-        while let Some(program_seed) = current_program_reading_head.read_seed() {
+        while let Some(program_seed) = current_program_reading_head.read_seed()? {
             if (program_seed.code.size() == 0
                 || program_seed.code.get(program_seed.code.size() - 1)
                     != Some(BfInstruction::LoopStart))
@@ -123,7 +170,8 @@ fn find_program<const MAX_TAPE_SIZE: usize>(
                 new_program.jump_table.push((loop_start_loc + 1) as i64);
                 new_program.current_paren_count -= 1;
 
-                let run_res = run_program_fragment_without_states(&new_program, target_output);
+                let run_res =
+                    interpreter.run_target_without_states(&new_program, target_output, overflow);
                 if let Some(working_program) = handle_run_result(
                     run_res,
                     new_program,
@@ -140,7 +188,8 @@ fn find_program<const MAX_TAPE_SIZE: usize>(
                 new_program.current_paren_count += 1;
                 //add a -2 to the jump table to mark the start of the loop
                 new_program.jump_table.push(-2);
-                let run_res = run_program_fragment_without_states(&new_program, target_output);
+                let run_res =
+                    interpreter.run_target_without_states(&new_program, target_output, overflow);
                 if let Some(working_program) = handle_run_result(
                     run_res,
                     new_program,
@@ -155,7 +204,8 @@ fn find_program<const MAX_TAPE_SIZE: usize>(
                 let mut new_program = program_seed.clone();
                 new_program.code.append(BfInstruction::Output);
                 new_program.jump_table.push(-1); // -1 indicates non-loop instruction
-                let run_res = run_program_fragment_without_states(&new_program, target_output);
+                let run_res =
+                    interpreter.run_target_without_states(&new_program, target_output, overflow);
                 if let Some(working_program) = handle_run_result(
                     run_res,
                     new_program,
@@ -172,7 +222,8 @@ fn find_program<const MAX_TAPE_SIZE: usize>(
                 let mut new_program = program_seed.clone();
                 new_program.code.append(BfInstruction::Left);
                 new_program.jump_table.push(-1); // -1 indicates non-loop instruction
-                let run_res = run_program_fragment_without_states(&new_program, target_output);
+                let run_res =
+                    interpreter.run_target_without_states(&new_program, target_output, overflow);
                 if let Some(working_program) = handle_run_result(
                     run_res,
                     new_program,
@@ -189,7 +240,8 @@ fn find_program<const MAX_TAPE_SIZE: usize>(
                 let mut new_program = program_seed.clone();
                 new_program.code.append(BfInstruction::Right);
                 new_program.jump_table.push(-1); // -1 indicates non-loop instruction
-                let run_res = run_program_fragment_without_states(&new_program, target_output);
+                let run_res =
+                    interpreter.run_target_without_states(&new_program, target_output, overflow);
                 if let Some(working_program) = handle_run_result(
                     run_res,
                     new_program,
@@ -206,7 +258,8 @@ fn find_program<const MAX_TAPE_SIZE: usize>(
                 let mut new_program = program_seed.clone();
                 new_program.code.append(BfInstruction::Inc);
                 new_program.jump_table.push(-1); // -1 indicates non-loop instruction
-                let run_res = run_program_fragment_without_states(&new_program, target_output);
+                let run_res =
+                    interpreter.run_target_without_states(&new_program, target_output, overflow);
                 if let Some(working_program) = handle_run_result(
                     run_res,
                     new_program,
@@ -223,7 +276,8 @@ fn find_program<const MAX_TAPE_SIZE: usize>(
                 let mut new_program = program_seed.clone();
                 new_program.code.append(BfInstruction::Dec);
                 new_program.jump_table.push(-1); // -1 indicates non-loop instruction
-                let run_res = run_program_fragment_without_states(&new_program, target_output);
+                let run_res =
+                    interpreter.run_target_without_states(&new_program, target_output, overflow);
                 if let Some(working_program) = handle_run_result(
                     run_res,
                     new_program,
@@ -234,74 +288,34 @@ fn find_program<const MAX_TAPE_SIZE: usize>(
                 }
             }
         }
-        current_program_writing_head.flush();
-
-        println!(
-            "Finished processing all programs of size {}. Max steps reached: {}",
+        current_program_writing_head.flush()?;
+        // the previous layer has been fully expanded, so its seeds are no longer needed
+        drop(current_program_reading_head);
+        let _ = fs::remove_file(seed_file_path(
+            seed_dir,
+            tape_size,
             current_program_size - 1,
-            get_max_steps_reached()
-        );
+        ));
 
-        if current_program_size == 16 {
-            return Err("Reached maximum program size of 16 without finding a solution.");
+        if let Some(progress) = progress {
+            progress(current_program_size - 1);
         }
-
-        println!(
-            "Advanced to next layer of program size of {}.",
-            current_program_size
-        );
-
-        //debug write all of the current programs to file and their jump table, code, and continue state to a file
-        // let file_name = format!("program_{}.bf", program_seeds.iter().last().unwrap().code.size());
-        // let mut file = std::fs::File::create(file_name).expect("Could not create file");
-        // for program_seed in program_seeds.iter() {
-        //     let code_str = program_seed.code.to_string();
-        //     let jump_table_strs = program_seed.jump_table.iter()
-        //         .map(|x| x.to_string())
-        //         .collect::<Vec<String>>();
-        //     let continue_state_str = if let Some(real_continue_state) = &program_seed.continue_state { format!(
-        //         "Tape: {:?}, Tape Head: {}, pc: {}",
-        //         real_continue_state.0.tape,
-        //         real_continue_state.0.tape_head,
-        //         real_continue_state.1
-        //     )} else {
-        //         "No continue state".to_string()
-        //     };
-        //
-        //     //output the code with 2 spaces in between each instruction
-        //     //outupt the jump table under it aligned with the code
-        //     //finally output the continue state
-        //     for char in code_str.chars() {
-        //         if char != ' ' {
-        //             file.write_all(format!("{}  ", char).as_bytes()).expect("Could not write to file");
-        //         }
-        //     }
-        //     file.write_all(b"\n").expect("Could not write to file");
-        //     //make sure that each jump table entry is 3 characters wide
-        //     for jump in jump_table_strs {
-        //         file.write_all(format!("{:>3}", jump).as_bytes()).expect("Could not write to file");
-        //     }
-        //     file.write_all(b"\n").expect("Could not write to file");
-        //     file.write_all(continue_state_str.as_bytes()).expect("Could not write to file");
-        //     file.write_all(b"\n").expect("Could not write to file");
-        // }
-        //debug print the number of programs
     }
 }
 
-fn handle_run_result<const MAX_TAPE_SIZE: usize>(
-    run_res: BfRunResult<MAX_TAPE_SIZE>,
-    mut new_program: RunningProgramInfo<MAX_TAPE_SIZE>,
-    new_programs: &mut DiskSeedWriter<MAX_TAPE_SIZE>,
-    found_states: &mut HashSet<(ProgramState<MAX_TAPE_SIZE>, usize)>,
-) -> Option<Vec<BfInstruction>> {
+fn handle_run_result<C: Cell>(
+    run_res: BfRunResult<C>,
+    mut new_program: RunningProgramInfo<C>,
+    new_programs: &mut DiskSeedWriter<C>,
+    found_states: &mut HashSet<(ProgramState<C>, usize)>,
+) -> Option<CompressedBF> {
     match run_res {
         BfRunResult::IncompleteLoopSuccess(continue_state) => {
             new_program.continue_state = continue_state;
             new_programs.append(new_program.clone());
             None
         }
-        BfRunResult::Success => Some(new_program.code.to_vec()),
+        BfRunResult::Success => Some(new_program.code),
         BfRunResult::IncompleteOutputSuccess(end_state) => {
             if found_states
                 .contains(&(end_state.program_state.clone(), end_state.resume_output_ind))
@@ -309,7 +323,6 @@ fn handle_run_result<const MAX_TAPE_SIZE: usize>(
                 return None; // Skip already found state
             } else {
                 found_states.insert((end_state.program_state.clone(), end_state.resume_output_ind));
-                // println!("total found states: {}", found_states.len());
             }
             new_program.continue_state = end_state;
             new_programs.append(new_program.clone());
@@ -319,82 +332,123 @@ fn handle_run_result<const MAX_TAPE_SIZE: usize>(
     }
 }
 
-pub struct DiskSeedWriter<const MAX_TAPE_SIZE: usize> {
-    sender: Option<Sender<RunningProgramInfo<MAX_TAPE_SIZE>>>,
-    handle: Option<JoinHandle<()>>,
+pub struct DiskSeedWriter<C: Cell = u8> {
+    sender: Option<Sender<RunningProgramInfo<C>>>,
+    handle: Option<JoinHandle<io::Result<()>>>,
     file: Arc<Mutex<BufWriter<File>>>,
+    path: PathBuf,
+    tape_size: usize,
     program_size: usize,
 }
 
-impl<const MAX_TAPE_SIZE: usize> DiskSeedWriter<MAX_TAPE_SIZE> {
-    pub fn new(program_size: usize) -> Self {
-        let file_path = format!("program_{}_seeds_{}.bin", MAX_TAPE_SIZE, program_size);
-        let file = match OpenOptions::new()
+//tapes of at most 256 cells only need a single byte to store the head, which keeps the seed files of the search small
+fn tape_head_width(tape_size: usize) -> usize {
+    if tape_size <= 256 {
+        1
+    } else {
+        std::mem::size_of::<usize>()
+    }
+}
+
+fn encode_tape_head(tape_head: usize, tape_size: usize) -> Vec<u8> {
+    if tape_head_width(tape_size) == 1 {
+        vec![tape_head as u8]
+    } else {
+        tape_head.to_ne_bytes().to_vec()
+    }
+}
+
+fn decode_tape_head(bytes: &[u8]) -> usize {
+    match bytes {
+        [byte] => *byte as usize,
+        _ => usize::from_ne_bytes(bytes.try_into().unwrap()),
+    }
+}
+
+fn seed_file_path(seed_dir: &Path, tape_size: usize, program_size: usize) -> PathBuf {
+    seed_dir.join(format!("program_{}_seeds_{}.bin", tape_size, program_size))
+}
+
+fn write_seed<C: Cell>(
+    file: &mut impl Write,
+    program: &RunningProgramInfo<C>,
+    tape_size: usize,
+) -> io::Result<()> {
+    // write code
+    let code_bytes = program
+        .code
+        .to_vec()
+        .iter()
+        .map(|b| (*b).to_u8())
+        .collect::<Vec<u8>>();
+    file.write_all(&code_bytes)?;
+
+    // write jump table
+    let jump_table_bytes = program
+        .jump_table
+        .iter()
+        .flat_map(|&x| x.to_ne_bytes())
+        .collect::<Vec<u8>>();
+    file.write_all(&jump_table_bytes)?;
+
+    let mut tape_bytes = Vec::with_capacity(tape_size * C::BYTES);
+    for cell in &program.continue_state.program_state.tape {
+        cell.write_ne_bytes(&mut tape_bytes);
+    }
+    file.write_all(&tape_bytes)?;
+    file.write_all(&encode_tape_head(
+        program.continue_state.program_state.tape_head,
+        tape_size,
+    ))?;
+    file.write_all(&program.continue_state.resume_pc.to_ne_bytes())?;
+    file.write_all(&program.continue_state.resume_output_ind.to_ne_bytes())?;
+
+    // write paren count
+    file.write_all(&program.current_paren_count.to_ne_bytes())
+}
+
+impl<C: Cell> DiskSeedWriter<C> {
+    pub fn new(seed_dir: &Path, tape_size: usize, program_size: usize) -> Result<Self, SeedError> {
+        let path = seed_file_path(seed_dir, tape_size, program_size);
+        let io_error = |source| SeedError::Io {
+            path: path.clone(),
+            source,
+        };
+        let file = OpenOptions::new()
             .write(true)
             .create(true)
             .truncate(true)
-            .open(file_path)
-        {
-            std::result::Result::Ok(file) => file,
-            std::result::Result::Err(err) => {
-                panic!("Could not open file for writing: {}", err);
-            }
-        };
+            .open(&path)
+            .map_err(io_error)?;
 
         let mut file = BufWriter::with_capacity(1_000_000_000, file);
-        file.write_all(&program_size.to_ne_bytes()).unwrap();
+        file.write_all(&program_size.to_ne_bytes())
+            .map_err(io_error)?;
 
         let file = Arc::new(Mutex::new(file));
-        let (sender, receiver) = mpsc::channel::<RunningProgramInfo<MAX_TAPE_SIZE>>();
+        let (sender, receiver) = mpsc::channel::<RunningProgramInfo<C>>();
         let file_clone = Arc::clone(&file);
 
+        // the worker stops at the first failed write, later sends fail quietly and the error is reported by flush
         let handle = thread::spawn(move || {
             for program in receiver {
                 let mut file = file_clone.lock().unwrap();
-
-                // write code
-                let code_bytes = program
-                    .code
-                    .to_vec()
-                    .iter()
-                    .map(|b| (*b).to_u8())
-                    .collect::<Vec<u8>>();
-                file.write_all(&code_bytes)
-                    .expect("Could not write program code");
-
-                // write jump table
-                let jump_table_bytes = program
-                    .jump_table
-                    .iter()
-                    .flat_map(|&x| x.to_ne_bytes())
-                    .collect::<Vec<u8>>();
-                file.write_all(&jump_table_bytes)
-                    .expect("Could not write jump table");
-
-                file.write_all(&program.continue_state.program_state.tape)
-                    .expect("Could not write tape");
-                file.write_all(&[program.continue_state.program_state.tape_head])
-                    .expect("Could not write tape head");
-                file.write_all(&program.continue_state.resume_pc.to_ne_bytes())
-                    .expect("Could not write pc");
-                file.write_all(&program.continue_state.resume_output_ind.to_ne_bytes())
-                    .expect("Could not write output index");
-
-                // write paren count
-                file.write_all(&program.current_paren_count.to_ne_bytes())
-                    .expect("Could not write paren count");
+                write_seed(&mut *file, &program, tape_size)?;
             }
+            Ok(())
         });
 
-        DiskSeedWriter {
+        Ok(DiskSeedWriter {
             sender: Some(sender),
             handle: Some(handle),
             file,
+            path,
+            tape_size,
             program_size,
-        }
+        })
     }
 
-    pub fn append(&mut self, program: RunningProgramInfo<MAX_TAPE_SIZE>) {
+    pub fn append(&mut self, program: RunningProgramInfo<C>) {
         if program.code.size() != self.program_size {
             panic!(
                 "Program size mismatch: {} != {}",
@@ -402,71 +456,136 @@ impl<const MAX_TAPE_SIZE: usize> DiskSeedWriter<MAX_TAPE_SIZE> {
                 self.program_size
             );
         }
+        if program.continue_state.program_state.tape.len() != self.tape_size {
+            panic!(
+                "Tape size mismatch: {} != {}",
+                program.continue_state.program_state.tape.len(),
+                self.tape_size
+            );
+        }
 
         if let Some(sender) = &self.sender {
-            sender
-                .send(program)
-                .expect("Failed to send program to worker thread");
+            let _ = sender.send(program);
         }
     }
 
-    pub fn flush(&mut self) {
+    pub fn flush(&mut self) -> Result<(), SeedError> {
         // Drop sender so the worker thread knows there’s nothing more
         self.sender.take();
+        let io_error = |source| SeedError::Io {
+            path: self.path.clone(),
+            source,
+        };
         if let Some(handle) = self.handle.take() {
             handle
                 .join()
-                .expect("Failed to join worker thread of DiskSeedWriter");
+                .expect("Failed to join worker thread of DiskSeedWriter")
+                .map_err(io_error)?;
         }
 
         let mut file = self.file.lock().unwrap();
-        file.flush().expect("Failed to flush file");
+        file.flush().map_err(io_error)
+    }
+}
+
+impl<C: Cell> Drop for DiskSeedWriter<C> {
+    fn drop(&mut self) {
+        let _ = self.flush();
     }
 }
 
-pub struct DiskSeedReader {
+pub struct DiskSeedReader<C: Cell = u8> {
     file: BufReader<File>,
+    path: PathBuf,
+    tape_size: usize,
     program_size: usize,
+    cell: PhantomData<C>,
 }
 
-impl DiskSeedReader {
-    pub fn new(program_size: usize) -> Self {
-        //make sure the file exists
-        let file_path = format!("program_seeds_{}.bin", program_size);
+impl<C: Cell> DiskSeedReader<C> {
+    pub fn new(seed_dir: &Path, tape_size: usize, program_size: usize) -> Result<Self, SeedError> {
+        let path = seed_file_path(seed_dir, tape_size, program_size);
         let file = OpenOptions::new()
             .read(true)
-            .open(file_path)
-            .expect("Could not open file for reading");
-        let mut file = BufReader::with_capacity(1_000_000_000, file);
+            .open(&path)
+            .map_err(|source| SeedError::Io {
+                path: path.clone(),
+                source,
+            })?;
+        let file = BufReader::with_capacity(1_000_000_000, file);
+
+        let mut reader = DiskSeedReader {
+            file,
+            path,
+            tape_size,
+            program_size,
+            cell: PhantomData,
+        };
 
         let mut size_bytes = [0u8; usize::to_ne_bytes(0).len()];
-        file.read_exact(&mut size_bytes)
-            .expect("Could not read program size from file");
+        reader.read_bytes(&mut size_bytes)?;
         let read_program_size = usize::from_ne_bytes(size_bytes);
         if program_size != read_program_size {
-            panic!(
-                "Program size does not match expected size: {} != {}",
-                program_size, read_program_size
-            );
+            return Err(SeedError::ProgramSizeMismatch {
+                path: reader.path,
+                expected: program_size,
+                found: read_program_size,
+            });
         }
 
-        DiskSeedReader { file, program_size }
+        Ok(reader)
+    }
+
+    // a file that ends part way through a seed was cut short, so running out of bytes here is corruption
+    fn read_bytes(&mut self, buf: &mut [u8]) -> Result<(), SeedError> {
+        self.file.read_exact(buf).map_err(|source| {
+            if source.kind() == io::ErrorKind::UnexpectedEof {
+                SeedError::Corrupt {
+                    path: self.path.clone(),
+                }
+            } else {
+                SeedError::Io {
+                    path: self.path.clone(),
+                    source,
+                }
+            }
+        })
+    }
+
+    fn read_usize(&mut self) -> Result<usize, SeedError> {
+        let mut bytes = [0u8; std::mem::size_of::<usize>()];
+        self.read_bytes(&mut bytes)?;
+        Ok(usize::from_ne_bytes(bytes))
     }
 
-    pub fn read_seed<const MAX_TAPE_SIZE: usize>(&mut self) -> Option<RunningProgramInfo<MAX_TAPE_SIZE>> {
+    /// Reads the next seed, or `None` once every seed in the file has been read.
+    pub fn read_seed(&mut self) -> Result<Option<RunningProgramInfo<C>>, SeedError> {
+        let at_end = self
+            .file
+            .fill_buf()
+            .map_err(|source| SeedError::Io {
+                path: self.path.clone(),
+                source,
+            })?
+            .is_empty();
+        if at_end {
+            return Ok(None);
+        }
+
         let mut code = CompressedBF::new(self.program_size, self.program_size + 1);
         let mut jump_table = Vec::with_capacity(self.program_size + 1);
 
         // Read program code
         let mut code_bytes = vec![0u8; self.program_size];
-        if self.file.read_exact(&mut code_bytes).is_err() {
-            return None; // End of file or read error
-        }
+        self.read_bytes(&mut code_bytes)?;
         for (i, byte) in code_bytes.iter().enumerate() {
-            if let Some(instruction) = BfInstruction::from_u8(*byte) {
-                code.set(i, instruction);
-            } else {
-                return None; // Invalid instruction
+            match BfInstruction::from_u8(*byte) {
+                Some(instruction) => code.set(i, instruction),
+                None => {
+                    return Err(SeedError::Corrupt {
+                        path: self.path.clone(),
+                    });
+                }
             }
         }
 
@@ -474,9 +593,7 @@ impl DiskSeedReader {
         let jump_table_size = self.program_size;
         //read jump_table_size * sizeof(i64) bytes
         let mut jump_table_bytes = vec![0u8; jump_table_size * std::mem::size_of::<i64>()];
-        if self.file.read_exact(&mut jump_table_bytes).is_err() {
-            return None; // End of file or read error
-        }
+        self.read_bytes(&mut jump_table_bytes)?;
         for i in 0..jump_table_size {
             let start = i * std::mem::size_of::<i64>();
             let end = start + std::mem::size_of::<i64>();
@@ -484,50 +601,226 @@ impl DiskSeedReader {
             jump_table.push(jump_value);
         }
 
-        //read the MAX_TAPE_SIZE bytes of tape
-        let mut tape = [0u8; MAX_TAPE_SIZE];
-        if self.file.read_exact(&mut tape).is_err() {
-            return None; // End of file or read error
-        }
+        //read the tape_size cells of tape
+        let mut tape_bytes = vec![0u8; self.tape_size * C::BYTES];
+        self.read_bytes(&mut tape_bytes)?;
+        let tape: Tape<C> = tape_bytes
+            .chunks_exact(C::BYTES)
+            .map(C::read_ne_bytes)
+            .collect();
         //read the tape head
-        let mut tape_head_bytes = [0u8; 1];
-        if self.file.read_exact(&mut tape_head_bytes).is_err() {
-            return None; // End of file or read error
-        }
-        let tape_head = tape_head_bytes[0];
-
-        //read the program counter
-        let mut pc_bytes = [0u8; std::mem::size_of::<usize>()];
-        if self.file.read_exact(&mut pc_bytes).is_err() {
-            return None; // End of file or read error
-        }
-        let pc = usize::from_ne_bytes(pc_bytes);
+        let mut tape_head_bytes = [0u8; std::mem::size_of::<usize>()];
+        let tape_head_bytes = &mut tape_head_bytes[..tape_head_width(self.tape_size)];
+        self.read_bytes(tape_head_bytes)?;
+        let tape_head = decode_tape_head(tape_head_bytes);
 
-        // Read the output index
-        let mut output_index_bytes = [0u8; std::mem::size_of::<usize>()];
-        if self.file.read_exact(&mut output_index_bytes).is_err() {
-            return None; // End of file or read error
-        }
-        let output_index = usize::from_ne_bytes(output_index_bytes);
+        let pc = self.read_usize()?;
+        let output_index = self.read_usize()?;
 
         let continue_state = ContinueState {
-            program_state: ProgramState { tape, tape_head },
+            program_state: ProgramState {
+                tape,
+                tape_head,
+                origin: 0,
+            },
             resume_pc: pc,
             resume_output_ind: output_index,
         };
 
-        // Read paren count
-        let mut paren_count_bytes = [0u8; std::mem::size_of::<usize>()];
-        if self.file.read_exact(&mut paren_count_bytes).is_err() {
-            return None; // End of file or read error
-        }
-        let current_paren_count = usize::from_ne_bytes(paren_count_bytes);
+        let current_paren_count = self.read_usize()?;
 
-        Some(RunningProgramInfo {
+        Ok(Some(RunningProgramInfo {
             code,
             jump_table,
             continue_state,
             current_paren_count,
-        })
+        }))
+    }
+}
+
+// --- UNIT TESTS ---
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ParseError;
+    const TAPE_SIZE: usize = 8;
+
+    fn options(max_program_size: usize) -> SearchOptions {
+        SearchOptions {
+            tape_size: TAPE_SIZE,
+            max_program_size,
+            ..SearchOptions::default()
+        }
+    }
+
+    /// Finds the obvious shortest program for a tiny target.
+    #[test]
+    fn test_find_short_program() {
+        let program = find_program(&[1, 2], "", &options(6)).unwrap();
+        assert_eq!(program.to_string(), "+.+.");
+    }
+
+    /// A starting program that already prints the target is returned as is.
+    #[test]
+    fn test_starting_program_is_solution() {
+        let program = find_program(&[2], "++.", &options(6)).unwrap();
+        assert_eq!(program.to_string(), "++.");
+    }
+
+    /// Hitting the size cap reports an error instead of searching forever.
+    #[test]
+    fn test_size_cap() {
+        let result = find_program(&[0, 5], "", &options(3));
+        assert!(matches!(
+            result.unwrap_err(),
+            SearchError::MaxSizeReached {
+                max_program_size: 3
+            }
+        ));
+    }
+
+    /// Searches on tapes too large to be stored inline still find programs.
+    #[test]
+    fn test_large_tape() {
+        let program = find_program(
+            &[1, 2],
+            "",
+            &SearchOptions {
+                tape_size: 300,
+                ..options(6)
+            },
+        )
+        .unwrap();
+        assert_eq!(program.to_string(), "+.+.");
+    }
+
+    /// Starting programs with unbalanced loops are rejected.
+    #[test]
+    fn test_unbalanced_starting_program() {
+        let result = find_program(&[1], "[", &options(6));
+        assert!(matches!(
+            result.unwrap_err(),
+            SearchError::StartingProgram(ParseError::UnmatchedLoopStart { instruction: 0, .. })
+        ));
+    }
+
+    /// Searches on 16 and 32 bit cells store their wider tapes in the seed files and still find programs.
+    #[test]
+    fn test_wide_cells() {
+        let wide = SearchOptions {
+            cell_width: CellWidth::U16,
+            ..options(6)
+        };
+        let program = find_program(&[1, 2], "", &wide).unwrap();
+        assert_eq!(program.to_string(), "+.+.");
+
+        // kept below the size of the first loop that can run, which takes 2^32 steps to wrap
+        let wide = SearchOptions {
+            cell_width: CellWidth::U32,
+            ..options(3)
+        };
+        let program = find_program(&[2], "", &wide).unwrap();
+        assert_eq!(program.to_string(), "++.");
+    }
+
+    /// Candidates can only use wrapping when the overflow mode allows it.
+    #[test]
+    fn test_overflow_modes() {
+        let program = find_program(&[255], "", &options(4)).unwrap();
+        assert_eq!(program.to_string(), "-.");
+
+        for overflow in [OverflowMode::Saturate, OverflowMode::Error] {
+            let strict = SearchOptions {
+                overflow,
+                ..options(4)
+            };
+            let result = find_program(&[255], "", &strict);
+            assert!(matches!(
+                result.unwrap_err(),
+                SearchError::MaxSizeReached { .. }
+            ));
+        }
+    }
+
+    static LAST_LAYER: AtomicUsize = AtomicUsize::new(0);
+
+    /// The progress callback hears of every layer the search finished, in order.
+    #[test]
+    fn test_progress() {
+        fn record(size: usize) {
+            assert_eq!(LAST_LAYER.fetch_add(1, Ordering::Relaxed), size);
+        }
+        let with_progress = SearchOptions {
+            progress: Some(record),
+            ..options(6)
+        };
+        let program = find_program(&[1, 2], "", &with_progress).unwrap();
+        assert_eq!(program.to_string(), "+.+.");
+        //the program of size 4 was found while extending the layer of size 3, after the layers of sizes 0 to 2
+        assert_eq!(LAST_LAYER.load(Ordering::Relaxed), 3);
+    }
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "brainfuck_seed_test_{}_{}",
+            name,
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A seed written to disk reads back unchanged, followed by a clean end of file.
+    #[test]
+    fn test_seed_round_trip() {
+        let dir = scratch_dir("round_trip");
+        let mut program = crate::util::preprocess_input_with_cells::<u16>("+[>+<-]", 4).unwrap();
+        program.continue_state.program_state.tape[1] = 300;
+        program.continue_state.program_state.tape_head = 2;
+        program.continue_state.resume_pc = 5;
+
+        let mut writer = DiskSeedWriter::<u16>::new(&dir, 4, 7).unwrap();
+        writer.append(program.clone());
+        writer.flush().unwrap();
+
+        let mut reader = DiskSeedReader::<u16>::new(&dir, 4, 7).unwrap();
+        let seed = reader.read_seed().unwrap().unwrap();
+        assert_eq!(seed.code, program.code);
+        assert_eq!(seed.jump_table, program.jump_table);
+        assert_eq!(seed.continue_state, program.continue_state);
+        assert!(reader.read_seed().unwrap().is_none());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// Missing, mismatched and truncated seed files are reported as errors instead of panicking.
+    #[test]
+    fn test_bad_seed_files() {
+        let dir = scratch_dir("bad_files");
+        assert!(matches!(
+            DiskSeedReader::<u8>::new(&dir, 4, 3),
+            Err(SeedError::Io { .. })
+        ));
+
+        let program = crate::util::preprocess_input("+++", 4).unwrap();
+        let mut writer = DiskSeedWriter::<u8>::new(&dir, 4, 3).unwrap();
+        writer.append(program);
+        writer.flush().unwrap();
+        let path = seed_file_path(&dir, 4, 3);
+        let bytes = fs::read(&path).unwrap();
+
+        fs::write(seed_file_path(&dir, 4, 2), &bytes).unwrap();
+        assert!(matches!(
+            DiskSeedReader::<u8>::new(&dir, 4, 2),
+            Err(SeedError::ProgramSizeMismatch {
+                expected: 2,
+                found: 3,
+                ..
+            })
+        ));
+
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        let mut reader = DiskSeedReader::<u8>::new(&dir, 4, 3).unwrap();
+        assert!(matches!(reader.read_seed(), Err(SeedError::Corrupt { .. })));
+        let _ = fs::remove_dir_all(&dir);
     }
 }
@@ -0,0 +1,131 @@
+use std::fmt;
+
+use crate::data::{BfInstruction, CompressedBF};
+
+/// Where an instruction sits in the source text it was parsed from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceSpan {
+    /// Byte offset of the instruction's character.
+    pub offset: usize,
+    /// Line number, starting at 1.
+    pub line: usize,
+    /// Column in characters, starting at 1.
+    pub column: usize,
+}
+
+impl fmt::Display for SourceSpan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+/// Maps every instruction index of a parsed program back to its place in the source.
+///
+/// Characters that are not Brainfuck commands are comments, so instruction `i` is usually not at byte `i`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    spans: Vec<SourceSpan>,
+}
+
+impl SourceMap {
+    pub fn from_source(source: &str) -> SourceMap {
+        parse(source).1
+    }
+
+    /// Span of the instruction at `instruction`, or `None` past the end of the program.
+    pub fn span(&self, instruction: usize) -> Option<SourceSpan> {
+        self.spans.get(instruction).copied()
+    }
+
+    /// Number of instructions in the program.
+    pub fn len(&self) -> usize {
+        self.spans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spans.is_empty()
+    }
+
+    /// Index of the first instruction at or after `line` and `column`, or the number of instructions when there is none.
+    pub fn instruction_from(&self, line: usize, column: usize) -> usize {
+        self.spans
+            .partition_point(|span| (span.line, span.column) < (line, column))
+    }
+}
+
+/// Parses `source` into its instructions together with where each one came from.
+pub(crate) fn parse(source: &str) -> (CompressedBF, SourceMap) {
+    let mut code = CompressedBF::new(0, 0);
+    let mut spans = Vec::new();
+    let mut line = 1;
+    let mut column = 1;
+    for (offset, c) in source.char_indices() {
+        if let Some(instruction) = BfInstruction::from_char(c) {
+            code.append(instruction);
+            spans.push(SourceSpan {
+                offset,
+                line,
+                column,
+            });
+        }
+        if c == '\n' {
+            line += 1;
+            column = 1;
+        } else {
+            column += 1;
+        }
+    }
+    (code, SourceMap { spans })
+}
+
+// --- UNIT TESTS ---
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Comments and line breaks are skipped but still counted in the spans.
+    #[test]
+    fn test_spans_skip_comments() {
+        let (code, map) = parse("add + one\n  [-]é.");
+        assert_eq!(code.to_string(), "+[-].");
+        assert_eq!(map.len(), 5);
+        assert_eq!(
+            map.span(0),
+            Some(SourceSpan {
+                offset: 4,
+                line: 1,
+                column: 5
+            })
+        );
+        assert_eq!(
+            map.span(1),
+            Some(SourceSpan {
+                offset: 12,
+                line: 2,
+                column: 3
+            })
+        );
+        // the two byte character before `.` counts as one column
+        assert_eq!(
+            map.span(4),
+            Some(SourceSpan {
+                offset: 17,
+                line: 2,
+                column: 7
+            })
+        );
+        assert_eq!(map.span(5), None);
+    }
+
+    /// Positions between instructions belong to the next one.
+    #[test]
+    fn test_instruction_from() {
+        let map = SourceMap::from_source("+ +\n\n[-]");
+        assert_eq!(map.instruction_from(1, 1), 0);
+        assert_eq!(map.instruction_from(1, 2), 1);
+        assert_eq!(map.instruction_from(2, 1), 2);
+        assert_eq!(map.instruction_from(3, 3), 4);
+        assert_eq!(map.instruction_from(3, 4), 5);
+        assert_eq!(map.instruction_from(9, 1), 5);
+    }
+}
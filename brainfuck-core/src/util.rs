@@ -1,60 +1,94 @@
 //convience functions to hide implemtation details better
 
 use crate::{
+    cell::Cell,
     data::{BfInstruction, CompressedBF},
+    error::{ParseError, PreprocessError},
     run::{ContinueState, ProgramState, RunningProgramInfo},
+    source::{SourceSpan, parse},
 };
 
-// TODO: Do actual error types instead of hamfisted &'static str
-pub fn preprocess_input<const MAX_TAPE_SIZE: usize>(input: &str) -> Result<RunningProgramInfo<MAX_TAPE_SIZE>, &'static str> {
-    let program_code = CompressedBF::from_string(input);
+pub fn preprocess_input(input: &str, tape_size: usize) -> Result<RunningProgramInfo, PreprocessError> {
+    preprocess_input_with_cells(input, tape_size)
+}
+
+/// Same as [`preprocess_input`], for a tape of `C` cells instead of bytes.
+pub fn preprocess_input_with_cells<C: Cell>(
+    input: &str,
+    tape_size: usize,
+) -> Result<RunningProgramInfo<C>, PreprocessError> {
+    if tape_size == 0 {
+        return Err(PreprocessError::ZeroTapeSize);
+    }
+    Ok(parse_for_tape(input, tape_size)?)
+}
+
+/// Parses `input` for tools that look at a program without running it, like [`preprocess_input`] with a tape of one cell.
+pub fn parse_program(input: &str) -> Result<RunningProgramInfo, ParseError> {
+    parse_for_tape(input, 1)
+}
+
+fn parse_for_tape<C: Cell>(
+    input: &str,
+    tape_size: usize,
+) -> Result<RunningProgramInfo<C>, ParseError> {
+    let (program_code, source_map) = parse(input);
 
     let continue_state = ContinueState {
         resume_pc: 0,
         resume_output_ind: 0,
-        program_state: ProgramState {
-            tape: [0; MAX_TAPE_SIZE],
-            tape_head: 0,
-        },
+        program_state: ProgramState::new(tape_size),
     };
 
-    let mut jump_table = Vec::with_capacity(program_code.size());
+    let jump_table = jump_table(&program_code, |i| source_map.span(i).unwrap())?;
+
+    Ok(RunningProgramInfo {
+        code: program_code,
+        current_paren_count: 0,
+        jump_table,
+        continue_state,
+    })
+}
 
-    let mut current_paren_count = 0;
+/// The jump table of `program_code`: one past the index of the matching bracket for `[` and `]`, and -1 for every other instruction.
+///
+/// `span(i)` is where instruction `i` is in the source, for the error about an unmatched bracket.
+pub(crate) fn jump_table(
+    program_code: &CompressedBF,
+    span: impl Fn(usize) -> SourceSpan,
+) -> Result<Vec<i64>, ParseError> {
+    let mut jump_table = Vec::with_capacity(program_code.size());
 
     for (i, instruction) in program_code.iter().enumerate() {
         match instruction {
-            BfInstruction::LoopStart => {
-                jump_table.push(-2);
-                current_paren_count += 1;
-            }
+            BfInstruction::LoopStart => jump_table.push(-2),
             BfInstruction::LoopEnd => {
                 //find the last -2 in the jump table and set it to the current index + 1 and append the index of the loop start + 1
                 if let Some(loop_start_index) = jump_table.iter().rposition(|&x| x == -2) {
                     jump_table[loop_start_index] = i as i64 + 1; // set the loop start to the current index + 1
                     jump_table.push((loop_start_index + 1) as i64); // append the index of the loop start + 1
-                    current_paren_count -= 1;
                 } else {
-                    return Err("Loop end without matching loop start.");
+                    return Err(ParseError::UnmatchedLoopEnd {
+                        instruction: i,
+                        span: span(i),
+                    });
                 }
             }
             _ => jump_table.push(-1), // -1 indicates non-loop instruction
         }
     }
 
-    if current_paren_count != 0 {
-        return Err("Unmatched loop in the input.");
+    if let Some(loop_start_index) = jump_table.iter().rposition(|&x| x == -2) {
+        return Err(ParseError::UnmatchedLoopStart {
+            instruction: loop_start_index,
+            span: span(loop_start_index),
+        });
     }
 
-    Ok(RunningProgramInfo {
-        code: program_code,
-        current_paren_count,
-        jump_table,
-        continue_state,
-    })
+    Ok(jump_table)
 }
 
-// pub fn run_program(input: RunningProgramInfo<30_000>) {
+// pub fn run_program(input: RunningProgramInfo) {
 //     let internal_res = run_program_fragment_no_target(input.code, );
 // }
 
@@ -62,12 +96,13 @@ pub fn preprocess_input<const MAX_TAPE_SIZE: usize>(input: &str) -> Result<Runni
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{data::CompressedBF, source::SourceSpan};
     const TAPE_SIZE: usize = 30000;
 
     /// Tests preprocessing an empty Brainfuck program.
     #[test]
     fn test_empty_input() {
-        let result = preprocess_input::<TAPE_SIZE>("");
+        let result = preprocess_input("", TAPE_SIZE);
         assert!(result.is_ok());
         let info = result.unwrap();
         assert_eq!(info.code.size(), 0);
@@ -79,7 +114,7 @@ mod tests {
     #[test]
     fn test_no_loops() {
         let input = "+-<>,.";
-        let result = preprocess_input::<TAPE_SIZE>(input);
+        let result = preprocess_input(input, TAPE_SIZE);
         assert!(result.is_ok());
         let info = result.unwrap();
         
@@ -97,7 +132,7 @@ mod tests {
     #[test]
     fn test_simple_loop() {
         let input = "+[]"; // 3 instructions
-        let result = preprocess_input::<TAPE_SIZE>(input);
+        let result = preprocess_input(input, TAPE_SIZE);
         assert!(result.is_ok());
         let info = result.unwrap();
 
@@ -111,7 +146,7 @@ mod tests {
     #[test]
     fn test_nested_loops() {
         let input = "[[]]"; // 4 instructions
-        let result = preprocess_input::<TAPE_SIZE>(input);
+        let result = preprocess_input(input, TAPE_SIZE);
         assert!(result.is_ok());
         let info = result.unwrap();
         
@@ -124,47 +159,87 @@ mod tests {
     #[test]
     fn test_unmatched_loop_start() {
         let input = "[.";
-        let result = preprocess_input::<TAPE_SIZE>(input);
+        let result = preprocess_input(input, TAPE_SIZE);
         assert!(result.is_err());
-        assert_eq!(result.unwrap_err(), "Unmatched loop in the input.");
+        assert_eq!(
+            result.unwrap_err(),
+            PreprocessError::Parse(ParseError::UnmatchedLoopStart {
+                instruction: 0,
+                span: SourceSpan { offset: 0, line: 1, column: 1 }
+            })
+        );
     }
 
     /// Tests for a loop end bracket ']' without a matching start.
     #[test]
     fn test_unmatched_loop_end() {
         let input = ".]";
-        let result = preprocess_input::<TAPE_SIZE>(input);
+        let result = preprocess_input(input, TAPE_SIZE);
         assert!(result.is_err());
-        assert_eq!(result.unwrap_err(), "Loop end without matching loop start.");
+        assert_eq!(
+            result.unwrap_err(),
+            PreprocessError::Parse(ParseError::UnmatchedLoopEnd {
+                instruction: 1,
+                span: SourceSpan { offset: 1, line: 1, column: 2 }
+            })
+        );
     }
 
     /// Tests for mismatched brackets where ']' appears before '['.
     #[test]
     fn test_mismatched_loops() {
         let input = "][.";
-        let result = preprocess_input::<TAPE_SIZE>(input);
+        let result = preprocess_input(input, TAPE_SIZE);
         assert!(result.is_err());
-        assert_eq!(result.unwrap_err(), "Loop end without matching loop start.");
+        assert_eq!(
+            result.unwrap_err(),
+            PreprocessError::Parse(ParseError::UnmatchedLoopEnd {
+                instruction: 0,
+                span: SourceSpan { offset: 0, line: 1, column: 1 }
+            })
+        );
     }
     
+    /// Bracket errors point at the line and column of the bracket, past comments and line breaks.
+    #[test]
+    fn test_bracket_error_location() {
+        let input = "add one +\n  loop [-\n  ] ]";
+        let result = preprocess_input(input, TAPE_SIZE);
+        assert_eq!(
+            result.unwrap_err(),
+            PreprocessError::Parse(ParseError::UnmatchedLoopEnd {
+                instruction: 4,
+                span: SourceSpan { offset: 24, line: 3, column: 5 }
+            })
+        );
+    }
+
     /// Verifies that the initial state of the program is set correctly.
     #[test]
     fn test_initial_continue_state() {
-        let result = preprocess_input::<TAPE_SIZE>("+").unwrap();
+        let result = preprocess_input("+", TAPE_SIZE).unwrap();
         let state = result.continue_state;
 
         assert_eq!(state.resume_pc, 0);
         assert_eq!(state.resume_output_ind, 0);
         assert_eq!(state.program_state.tape_head, 0);
         // Ensure the tape is initialized to all zeros.
+        assert_eq!(state.program_state.tape.len(), TAPE_SIZE);
         assert!(state.program_state.tape.iter().all(|&x| x == 0));
     }
 
+    /// A tape needs at least one cell for the head to sit on.
+    #[test]
+    fn test_zero_tape_size() {
+        let result = preprocess_input("+", 0);
+        assert_eq!(result.unwrap_err(), PreprocessError::ZeroTapeSize);
+    }
+
     /// Verifies that the generated jump table is the correct size
     #[test]
     fn test_jump_table_size() {
         let input = "+[]";
-        let result = preprocess_input::<TAPE_SIZE>(input);
+        let result = preprocess_input(input, TAPE_SIZE);
         assert!(result.is_ok());
         let info = result.unwrap();
 
@@ -176,7 +251,7 @@ mod tests {
     #[test]
     fn test_jump_table_values() {
         let input = ">++++++++[<+++++++++>-]<.>++++[<+++++++>-]<+.+++++++..+++.>>++++++[<+++++++>-]<++.------------.>++++++[<+++++++++>-]<+.<.+++.------.--------.>>>++++[<++++++++>-]<+.";
-        let result = preprocess_input::<TAPE_SIZE>(input);
+        let result = preprocess_input(input, TAPE_SIZE);
         assert!(result.is_ok());
         let info = result.unwrap();
 
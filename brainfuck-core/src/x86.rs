@@ -0,0 +1,404 @@
+//! An x86-64 encoder for the ops of a [`CompiledProgram`](crate::CompiledProgram), shared by the JIT and the ELF and assembly code generators.
+
+use std::fmt::{self, Write};
+
+/// Where a jump in the native code goes.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Label {
+    /// The start of the op with this index, or the end of the program.
+    Op(usize),
+    /// Out of line code for the op with this index when the tape is too small for it.
+    Bail(usize),
+    /// Stops at the op with this index with the status a callback returned.
+    Status(usize),
+    /// A routine or some data that the backend places itself.
+    Named(&'static str),
+}
+
+impl fmt::Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Label::Op(ip) => write!(f, ".Lop{}", ip),
+            Label::Bail(ip) => write!(f, ".Lslow{}", ip),
+            Label::Status(ip) => write!(f, ".Lstatus{}", ip),
+            Label::Named(name) => f.write_str(name),
+        }
+    }
+}
+
+/// A short jump forward, pointed at its target with [`Assembler::land`].
+pub(crate) struct Forward {
+    field: usize,
+    local: usize,
+}
+
+/// A position that jumps further on go back to, made with [`Assembler::mark`].
+#[derive(Clone, Copy)]
+pub(crate) struct Mark {
+    position: usize,
+    local: usize,
+}
+
+/// The cell some cells away from the head as an operand in the listing, `[rbx + r12*width + offset*width]`.
+#[derive(Clone, Copy)]
+pub(crate) struct CellOperand {
+    width: usize,
+    offset: i32,
+    // whether the operand says its size, like `byte ptr`
+    sized: bool,
+}
+
+impl fmt::Display for CellOperand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.sized {
+            let size = match self.width {
+                1 => "byte",
+                2 => "word",
+                _ => "dword",
+            };
+            write!(f, "{} ptr ", size)?;
+        }
+        f.write_str("[rbx + r12")?;
+        if self.width > 1 {
+            write!(f, "*{}", self.width)?;
+        }
+        let displacement = self.offset as i64 * self.width as i64;
+        match displacement {
+            0 => {}
+            displacement if displacement < 0 => write!(f, " - {}", displacement.unsigned_abs())?,
+            displacement => write!(f, " + {}", displacement)?,
+        }
+        f.write_str("]")
+    }
+}
+
+/// Emits x86-64 machine code for the ops of a program.
+///
+/// The code keeps the tape in `rbx`, the head in `r12` and the tape length in `r13`, all callee saved, so calls into Rust keep them.
+/// The other registers are up to the backend.
+///
+/// An assembler made with [`Assembler::with_listing`] also writes the code as text for the GNU assembler in Intel syntax, one line for every instruction emitted through [`Assembler::ins`], [`Assembler::jump`] and the emitters built on them.
+pub(crate) struct Assembler {
+    pub(crate) code: Vec<u8>,
+    // size of a cell in bytes
+    width: usize,
+    // positions of rel32 fields that still have to point at their label
+    pub(crate) fixups: Vec<(usize, Label)>,
+    // the code as assembly text, `None` when nobody reads it
+    listing: Option<String>,
+    // number of local labels of short jumps and marks so far
+    locals: usize,
+}
+
+impl Assembler {
+    /// An assembler for cells of `width` bytes.
+    pub(crate) fn new(width: usize) -> Self {
+        Assembler {
+            code: Vec::new(),
+            width,
+            fixups: Vec::new(),
+            listing: None,
+            locals: 0,
+        }
+    }
+
+    /// An assembler for cells of `width` bytes that also writes the listing.
+    pub(crate) fn with_listing(width: usize) -> Self {
+        Assembler {
+            listing: Some(String::new()),
+            ..Assembler::new(width)
+        }
+    }
+
+    /// The listing written so far, empty for an assembler made without one.
+    pub(crate) fn into_listing(self) -> String {
+        self.listing.unwrap_or_default()
+    }
+
+    /// Writes `line` to the listing as it is, for labels, comments and directives.
+    pub(crate) fn line(&mut self, line: fmt::Arguments) {
+        if let Some(listing) = &mut self.listing {
+            let _ = writeln!(listing, "{}", line);
+        }
+    }
+
+    /// Writes the comment `text` in the listing, indented like the instructions.
+    pub(crate) fn comment(&mut self, text: fmt::Arguments) {
+        self.line(format_args!("    # {}", text));
+    }
+
+    pub(crate) fn label(&mut self, label: Label) {
+        self.line(format_args!("{}:", label));
+    }
+
+    /// Emits the instruction `text`, whose encoding starts with `bytes` and goes on with the next [`Assembler::emit`] calls.
+    pub(crate) fn ins(&mut self, text: fmt::Arguments, bytes: &[u8]) {
+        self.line(format_args!("    {}", text));
+        self.emit(bytes);
+    }
+
+    /// Points every rel32 field at the position `target` gives for its label, `None` if a label has no position or is out of reach.
+    pub(crate) fn resolve(&mut self, target: impl Fn(Label) -> Option<usize>) -> Option<()> {
+        for &(field, label) in &self.fixups {
+            let rel = i32::try_from(target(label)? as i64 - (field as i64 + 4)).ok()?;
+            self.code[field..field + 4].copy_from_slice(&rel.to_le_bytes());
+        }
+        Some(())
+    }
+
+    /// Size of a cell in bytes.
+    pub(crate) fn width(&self) -> usize {
+        self.width
+    }
+
+    pub(crate) fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    pub(crate) fn emit_i32(&mut self, value: i32) {
+        self.emit(&value.to_le_bytes());
+    }
+
+    //emits an instruction on the cell `offset` cells from the head, [rbx + r12 * width + offset * width]
+    pub(crate) fn cell(&mut self, prefix16: bool, rex: u8, opcode: &[u8], reg: u8, offset: i32) {
+        if prefix16 {
+            self.emit(&[0x66]);
+        }
+        // REX.X selects r12 as the index
+        self.emit(&[0x42 | rex]);
+        self.emit(opcode);
+        let scale = self.width.trailing_zeros() as u8;
+        let mode = if offset == 0 { 0b00 } else { 0b10 };
+        self.emit(&[
+            mode << 6 | reg << 3 | 0b100,
+            scale << 6 | 0b100 << 3 | 0b011,
+        ]);
+        if offset != 0 {
+            self.emit_i32(offset * self.width as i32);
+        }
+    }
+
+    /// The cell `offset` cells from the head as it is written in the listing.
+    pub(crate) fn cell_operand(&self, offset: i32) -> CellOperand {
+        CellOperand {
+            width: self.width,
+            offset,
+            sized: true,
+        }
+    }
+
+    /// The address of the cell `offset` cells from the head as it is written in the listing, without its size.
+    pub(crate) fn cell_address(&self, offset: i32) -> CellOperand {
+        CellOperand {
+            sized: false,
+            ..self.cell_operand(offset)
+        }
+    }
+
+    //emits an instruction with a rel32 operand that points at `label`
+    pub(crate) fn jump(&mut self, opcode: &[u8], label: Label) {
+        match opcode {
+            [0x48, 0x8D, 0x35] => self.line(format_args!("    lea rsi, [rip + {}]", label)),
+            [0x48, 0x8D, 0x3D] => self.line(format_args!("    lea rdi, [rip + {}]", label)),
+            _ => self.line(format_args!("    {} {}", branch(opcode), label)),
+        }
+        self.emit(opcode);
+        self.fixups.push((self.code.len(), label));
+        self.emit_i32(0);
+    }
+
+    /// Emits the short jump `opcode` to a position further on, which [`Assembler::land`] sets.
+    pub(crate) fn short(&mut self, opcode: u8) -> Forward {
+        let local = self.next_local();
+        self.line(format_args!("    {} .L{}", branch(&[opcode]), local));
+        self.emit(&[opcode, 0]);
+        Forward {
+            field: self.code.len() - 1,
+            local,
+        }
+    }
+
+    /// Points the short jump `forward` at the current position.
+    pub(crate) fn land(&mut self, forward: Forward) {
+        self.line(format_args!(".L{}:", forward.local));
+        self.code[forward.field] = u8::try_from(self.code.len() - (forward.field + 1))
+            .ok()
+            .filter(|&rel| rel < 0x80)
+            .expect("short jumps go less than 128 bytes");
+    }
+
+    /// The current position, for jumps back to it.
+    pub(crate) fn mark(&mut self) -> Mark {
+        let local = self.next_local();
+        self.line(format_args!(".L{}:", local));
+        Mark {
+            position: self.code.len(),
+            local,
+        }
+    }
+
+    /// Emits the jump `opcode` back to `mark`, with a rel8 operand for the short jumps `0x70..=0x7F` and `0xEB` and a rel32 one for the others.
+    pub(crate) fn jump_back(&mut self, opcode: &[u8], mark: Mark) {
+        self.line(format_args!("    {} .L{}", branch(opcode), mark.local));
+        self.emit(opcode);
+        let short = matches!(opcode, [0x70..=0x7F] | [0xEB]);
+        let end = self.code.len() + if short { 1 } else { 4 };
+        let back = mark.position as i64 - end as i64;
+        if short {
+            let back = i8::try_from(back).expect("short jumps go less than 128 bytes");
+            self.emit(&back.to_le_bytes());
+        } else {
+            self.emit_i32(back as i32);
+        }
+    }
+
+    fn next_local(&mut self) -> usize {
+        self.locals += 1;
+        self.locals
+    }
+
+    pub(crate) fn compare_zero(&mut self) {
+        let cell = self.cell_operand(0);
+        self.line(format_args!("    cmp {}, 0", cell));
+        match self.width {
+            1 => self.cell(false, 0, &[0x80], 7, 0),
+            2 => self.cell(true, 0, &[0x83], 7, 0),
+            _ => self.cell(false, 0, &[0x83], 7, 0),
+        }
+        self.emit(&[0]);
+    }
+
+    pub(crate) fn add(&mut self, amount: i64) {
+        let cell = self.cell_operand(0);
+        // the amount the cell changes by, as the nearest positive or negative number
+        let signed = match self.width {
+            1 => amount as i8 as i64,
+            2 => amount as i16 as i64,
+            _ => amount as i32 as i64,
+        };
+        if signed != 0 {
+            self.line(format_args!("    add {}, {}", cell, signed));
+        }
+        match self.width {
+            1 if amount as u8 != 0 => {
+                self.cell(false, 0, &[0x80], 0, 0);
+                self.emit(&[amount as u8]);
+            }
+            2 if amount as u16 != 0 => {
+                self.cell(true, 0, &[0x81], 0, 0);
+                self.emit(&(amount as u16).to_le_bytes());
+            }
+            4 if amount as u32 != 0 => {
+                self.cell(false, 0, &[0x81], 0, 0);
+                self.emit(&(amount as u32).to_le_bytes());
+            }
+            _ => {}
+        }
+    }
+
+    pub(crate) fn set_zero(&mut self) {
+        let cell = self.cell_operand(0);
+        self.line(format_args!("    mov {}, 0", cell));
+        match self.width {
+            1 => {
+                self.cell(false, 0, &[0xC6], 0, 0);
+                self.emit(&[0]);
+            }
+            2 => {
+                self.cell(true, 0, &[0xC7], 0, 0);
+                self.emit(&[0; 2]);
+            }
+            _ => {
+                self.cell(false, 0, &[0xC7], 0, 0);
+                self.emit(&[0; 4]);
+            }
+        }
+    }
+
+    //jumps to `bail` unless the head can move `distance` cells without leaving the tape, leaving the moved head in rax when moving right
+    pub(crate) fn check_move(&mut self, distance: i32, bail: Label) {
+        if distance > 0 {
+            self.ins(
+                format_args!("lea rax, [r12 + {}]", distance),
+                &[0x49, 0x8D, 0x84, 0x24],
+            );
+            self.emit_i32(distance);
+            self.ins(format_args!("cmp rax, r13"), &[0x4C, 0x39, 0xE8]);
+            self.jump(&[0x0F, 0x83], bail);
+        } else if distance < 0 {
+            self.ins(format_args!("cmp r12, {}", -distance), &[0x49, 0x81, 0xFC]);
+            self.emit_i32(-distance);
+            self.jump(&[0x0F, 0x82], bail);
+        }
+    }
+
+    pub(crate) fn move_head(&mut self, distance: i32, bail: Label) {
+        self.check_move(distance, bail);
+        if distance > 0 {
+            self.ins(format_args!("mov r12, rax"), &[0x49, 0x89, 0xC4]);
+        } else if distance < 0 {
+            self.ins(format_args!("sub r12, {}", -distance), &[0x49, 0x81, 0xEC]);
+            self.emit_i32(-distance);
+        }
+    }
+
+    pub(crate) fn mul_move(&mut self, targets: &[(i32, i32)], reach: (i32, i32), ip: usize) {
+        self.compare_zero();
+        self.jump(&[0x0F, 0x84], Label::Op(ip + 1));
+        self.check_move(reach.0, Label::Bail(ip));
+        self.check_move(reach.1, Label::Bail(ip));
+        self.load_cell();
+        for &(offset, factor) in targets {
+            self.ins(format_args!("imul ecx, eax, {}", factor), &[0x69, 0xC8]);
+            self.emit_i32(factor);
+            let cell = self.cell_operand(offset);
+            let ecx = ["cl", "cx", "ecx"][self.width.trailing_zeros() as usize];
+            self.line(format_args!("    add {}, {}", cell, ecx));
+            match self.width {
+                1 => self.cell(false, 0, &[0x00], 1, offset),
+                2 => self.cell(true, 0, &[0x01], 1, offset),
+                _ => self.cell(false, 0, &[0x01], 1, offset),
+            }
+        }
+        self.set_zero();
+    }
+
+    pub(crate) fn scan(&mut self, stride: i32, ip: usize) {
+        let top = self.mark();
+        self.compare_zero();
+        self.jump(&[0x0F, 0x84], Label::Op(ip + 1));
+        self.move_head(stride, Label::Bail(ip));
+        self.jump_back(&[0xE9], top);
+    }
+
+    /// eax = the current cell
+    pub(crate) fn load_cell(&mut self) {
+        let cell = self.cell_operand(0);
+        match self.width {
+            1 | 2 => self.line(format_args!("    movzx eax, {}", cell)),
+            _ => self.line(format_args!("    mov eax, {}", cell)),
+        }
+        match self.width {
+            1 => self.cell(false, 0, &[0x0F, 0xB6], 0, 0),
+            2 => self.cell(false, 0, &[0x0F, 0xB7], 0, 0),
+            _ => self.cell(false, 0, &[0x8B], 0, 0),
+        }
+    }
+}
+
+//the mnemonic of the jump or call `opcode`, in its short or its rel32 form
+fn branch(opcode: &[u8]) -> &'static str {
+    match opcode {
+        [0xE8] => "call",
+        [0xE9] | [0xEB] => "jmp",
+        [0x72] | [0x0F, 0x82] => "jb",
+        [0x73] | [0x0F, 0x83] => "jae",
+        [0x74] | [0x0F, 0x84] => "je",
+        [0x75] | [0x0F, 0x85] => "jne",
+        [0x76] | [0x0F, 0x86] => "jbe",
+        [0x77] | [0x0F, 0x87] => "ja",
+        [0x79] | [0x0F, 0x89] => "jns",
+        [0x7E] | [0x0F, 0x8E] => "jle",
+        opcode => unreachable!("{:02X?} is not a jump", opcode),
+    }
+}
@@ -0,0 +1,854 @@
+use crate::{
+    debug::{describe_watchpoint, watchpoint},
+    jsonrpc::{read_message, write_message},
+};
+use brainfuck_core::{
+    Budget, Cell, DebugStop, Debugger, RunError, RunOptions, SourceMap, StopPoint, WatchEvent,
+    Watchpoint, util::preprocess_input_with_cells,
+};
+use serde_json::{Value, json};
+use std::{
+    collections::VecDeque,
+    fs,
+    io::{self, BufReader, Read, Write},
+    path::Path,
+    sync::mpsc::{self, Receiver, TryRecvError},
+    thread,
+};
+
+//most instructions a run goes before it looks for a `pause`
+const CHUNK: u64 = 1 << 16;
+
+//the program is the only thread, and it has one stack frame
+const THREAD_ID: u64 = 1;
+
+//variables references of the scopes
+const TAPE: u64 = 1;
+const MACHINE: u64 = 2;
+
+/// Serves the Debug Adapter Protocol with requests read from `input` and responses and events written to `out`, until the client disconnects or `input` ends.
+///
+/// Programs are parsed for a tape of `tape_size` cells and run with `options`, except for its budget.
+pub(crate) fn serve<C: Cell>(
+    input: impl Read + Send + 'static,
+    out: &mut impl Write,
+    tape_size: usize,
+    options: &RunOptions,
+) -> io::Result<()> {
+    let (sender, requests) = mpsc::channel();
+    //requests are read on their own thread, so that `pause` can reach a running program
+    thread::spawn(move || {
+        let mut input = BufReader::new(input);
+        loop {
+            let message = read_message(&mut input).transpose();
+            let Some(message) = message else { break };
+            let failed = message.is_err();
+            if sender.send(message).is_err() || failed {
+                break;
+            }
+        }
+    });
+    let mut adapter = Adapter::<C, _> {
+        out,
+        seq: 0,
+        tape_size,
+        options: *options,
+        lines_start_at1: true,
+        columns_start_at1: true,
+        launched: None,
+        pending: VecDeque::new(),
+    };
+    loop {
+        let request = match adapter.pending.pop_front() {
+            Some(request) => request,
+            None => match requests.recv() {
+                Ok(request) => request?,
+                Err(_) => return Ok(()),
+            },
+        };
+        if !adapter.handle(&request, &requests)? {
+            return Ok(());
+        }
+    }
+}
+
+//how far the program goes when it resumes
+#[derive(Clone, Copy)]
+enum Motion {
+    Continue,
+    StepIn,
+    Next,
+    StepOut,
+    StepBack,
+    ReverseContinue,
+}
+
+//the program being debugged after a `launch`
+struct Launched<C> {
+    path: String,
+    source_map: SourceMap,
+    debugger: Debugger<C>,
+    stop_on_entry: bool,
+    //bytes of output already sent
+    shown: usize,
+    failed: bool,
+}
+
+struct Adapter<'a, C, W> {
+    out: &'a mut W,
+    seq: u64,
+    tape_size: usize,
+    options: RunOptions,
+    lines_start_at1: bool,
+    columns_start_at1: bool,
+    launched: Option<Launched<C>>,
+    //requests that came in while the program ran
+    pending: VecDeque<Value>,
+}
+
+impl<C: Cell, W: Write> Adapter<'_, C, W> {
+    //handles one request, returning false once the client disconnected
+    fn handle(
+        &mut self,
+        request: &Value,
+        requests: &Receiver<io::Result<Value>>,
+    ) -> io::Result<bool> {
+        if request["type"] != "request" {
+            return Ok(true);
+        }
+        let arguments = &request["arguments"];
+        let command = request["command"].as_str().unwrap_or_default();
+        match command {
+            "initialize" => {
+                self.lines_start_at1 = arguments["linesStartAt1"].as_bool().unwrap_or(true);
+                self.columns_start_at1 = arguments["columnsStartAt1"].as_bool().unwrap_or(true);
+                self.respond(
+                    request,
+                    Ok(json!({
+                        "supportsConfigurationDoneRequest": true,
+                        "supportsStepBack": true,
+                        "supportsTerminateRequest": true,
+                        "supportsDataBreakpoints": true,
+                    })),
+                )?;
+            }
+            "launch" => {
+                let launched = self.launch(arguments);
+                let ok = launched.is_ok();
+                self.respond(request, launched.map(|()| json!({})))?;
+                if ok {
+                    self.event("initialized", json!({}))?;
+                }
+            }
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(json!({})))?;
+                if command == "terminate" {
+                    self.event("terminated", json!({}))?;
+                }
+                return Ok(false);
+            }
+            "threads" => self.respond(
+                request,
+                Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "program" }] })),
+            )?,
+            "pause" => self.respond(request, Ok(json!({})))?,
+            _ if self.launched.is_none() => {
+                self.respond(request, Err("no program was launched".to_string()))?
+            }
+            "setBreakpoints" => {
+                let body = self.set_breakpoints(arguments);
+                self.respond(request, Ok(body))?;
+            }
+            "dataBreakpointInfo" => {
+                let body = data_breakpoint_info(arguments);
+                self.respond(request, Ok(body))?;
+            }
+            "setDataBreakpoints" => {
+                let body = self.set_data_breakpoints(arguments);
+                self.respond(request, Ok(body))?;
+            }
+            "configurationDone" => {
+                self.respond(request, Ok(json!({})))?;
+                let launched = self.launched();
+                if launched.stop_on_entry {
+                    self.stopped("entry", None)?;
+                } else if launched.debugger.breakpoints().next() == Some(0) {
+                    self.stopped("breakpoint", Some(0))?;
+                } else {
+                    return self.resume(Motion::Continue, requests);
+                }
+            }
+            "stackTrace" => {
+                let body = self.stack_trace();
+                self.respond(request, Ok(body))?;
+            }
+            "scopes" => {
+                let launched = self.launched();
+                let body = json!({ "scopes": [
+                    {
+                        "name": "Tape",
+                        "variablesReference": TAPE,
+                        "indexedVariables": launched.debugger.tape().len(),
+                        "expensive": false,
+                    },
+                    {
+                        "name": "Machine",
+                        "variablesReference": MACHINE,
+                        "namedVariables": 4,
+                        "expensive": false,
+                    },
+                ]});
+                self.respond(request, Ok(body))?;
+            }
+            "variables" => {
+                let body = self.variables(arguments);
+                self.respond(request, Ok(body))?;
+            }
+            "continue" | "next" | "stepIn" | "stepOut" | "stepBack" | "reverseContinue" => {
+                let motion = match command {
+                    "continue" => Motion::Continue,
+                    "next" => Motion::Next,
+                    "stepIn" => Motion::StepIn,
+                    "stepOut" => Motion::StepOut,
+                    "stepBack" => Motion::StepBack,
+                    _ => Motion::ReverseContinue,
+                };
+                let body = match motion {
+                    Motion::Continue => json!({ "allThreadsContinued": true }),
+                    _ => json!({}),
+                };
+                self.respond(request, Ok(body))?;
+                return self.resume(motion, requests);
+            }
+            _ => self.respond(request, Err(format!("`{}` is not supported", command)))?,
+        }
+        Ok(true)
+    }
+
+    fn launch(&mut self, arguments: &Value) -> Result<(), String> {
+        let Some(path) = arguments["program"].as_str() else {
+            return Err("`program` must be the path to a Brainfuck file".to_string());
+        };
+        let source =
+            fs::read_to_string(path).map_err(|e| format!("Error opening {}: {}", path, e))?;
+        let program =
+            preprocess_input_with_cells::<C>(&source, self.tape_size).map_err(|e| e.to_string())?;
+        let mut debugger = Debugger::new(program, &self.options);
+        if let Some(input) = arguments["input"].as_str() {
+            debugger.push_input(input.as_bytes());
+        }
+        self.launched = Some(Launched {
+            path: path.to_string(),
+            source_map: SourceMap::from_source(&source),
+            debugger,
+            stop_on_entry: arguments["stopOnEntry"].as_bool().unwrap_or(false),
+            shown: 0,
+            failed: false,
+        });
+        Ok(())
+    }
+
+    //the launched program, which every request but the first few comes after
+    fn launched(&mut self) -> &mut Launched<C> {
+        self.launched.as_mut().expect("a program was launched")
+    }
+
+    //replaces all breakpoints, putting each on the first instruction at or after its line and column
+    fn set_breakpoints(&mut self, arguments: &Value) -> Value {
+        let (lines_start_at1, columns_start_at1) = (self.lines_start_at1, self.columns_start_at1);
+        let launched = self.launched();
+        let pcs: Vec<usize> = launched.debugger.breakpoints().collect();
+        for pc in pcs {
+            launched.debugger.remove_breakpoint(pc);
+        }
+        let same_file = arguments["source"]["path"]
+            .as_str()
+            .is_some_and(|path| Path::new(path) == Path::new(&launched.path));
+        let breakpoints = arguments["breakpoints"]
+            .as_array()
+            .map_or(&[][..], Vec::as_slice);
+        let breakpoints: Vec<Value> = breakpoints
+            .iter()
+            .map(|breakpoint| {
+                let line = breakpoint["line"].as_u64().unwrap_or(0) as usize
+                    + usize::from(!lines_start_at1);
+                let column = breakpoint["column"].as_u64().map_or(1, |column| {
+                    column as usize + usize::from(!columns_start_at1)
+                });
+                let map = &launched.source_map;
+                let pc = Some(map.instruction_from(line, column)).filter(|&pc| pc < map.len());
+                match pc.filter(|_| same_file) {
+                    Some(pc) => {
+                        launched.debugger.add_breakpoint(pc);
+                        let span = map.span(pc).expect("pc is in the program");
+                        json!({
+                            "id": pc,
+                            "verified": true,
+                            "line": span.line - usize::from(!lines_start_at1),
+                            "column": span.column - usize::from(!columns_start_at1),
+                        })
+                    }
+                    None if !same_file => json!({
+                        "verified": false,
+                        "message": format!("only {} is being debugged", launched.path),
+                    }),
+                    None => json!({
+                        "verified": false,
+                        "message": "there are no instructions from here to the end of the file",
+                    }),
+                }
+            })
+            .collect();
+        json!({ "breakpoints": breakpoints })
+    }
+
+    //replaces all watchpoints with the ones the data breakpoints name
+    fn set_data_breakpoints(&mut self, arguments: &Value) -> Value {
+        let launched = self.launched();
+        let watchpoints: Vec<Watchpoint> = launched.debugger.watchpoints().collect();
+        for watched in watchpoints {
+            launched.debugger.remove_watchpoint(watched);
+        }
+        let breakpoints = arguments["breakpoints"]
+            .as_array()
+            .map_or(&[][..], Vec::as_slice);
+        let breakpoints: Vec<Value> = breakpoints
+            .iter()
+            .map(
+                |breakpoint| match breakpoint["dataId"].as_str().and_then(watchpoint) {
+                    Some(watched) => {
+                        launched.debugger.add_watchpoint(watched);
+                        json!({ "verified": true })
+                    }
+                    None => json!({
+                        "verified": false,
+                        "message": "only cells, the tape head and the output can be watched",
+                    }),
+                },
+            )
+            .collect();
+        json!({ "breakpoints": breakpoints })
+    }
+
+    fn stack_trace(&mut self) -> Value {
+        let (lines_start_at1, columns_start_at1) = (self.lines_start_at1, self.columns_start_at1);
+        let launched = self.launched();
+        let pc = launched.debugger.pc();
+        let source = json!({
+            "name": Path::new(&launched.path).file_name().map(|name| name.to_string_lossy()),
+            "path": launched.path,
+        });
+        let frame = match (
+            launched.source_map.span(pc),
+            launched.debugger.program().get(pc),
+        ) {
+            (Some(span), Some(instruction)) => json!({
+                "id": 0,
+                "name": format!("instruction {} `{}`", pc, instruction),
+                "source": source,
+                "line": span.line - usize::from(!lines_start_at1),
+                "column": span.column - usize::from(!columns_start_at1),
+            }),
+            _ => json!({
+                "id": 0,
+                "name": "end of the program",
+                "line": 0,
+                "column": 0,
+            }),
+        };
+        json!({ "stackFrames": [frame], "totalFrames": 1 })
+    }
+
+    fn variables(&mut self, arguments: &Value) -> Value {
+        let launched = self.launched();
+        let debugger = &launched.debugger;
+        let variables: Vec<Value> = match arguments["variablesReference"].as_u64() {
+            Some(TAPE) => {
+                let start = arguments["start"].as_u64().unwrap_or(0) as usize;
+                let count = arguments["count"]
+                    .as_u64()
+                    .map_or(debugger.tape().len(), |count| count as usize);
+                debugger
+                    .tape()
+                    .iter()
+                    .enumerate()
+                    .skip(start)
+                    .take(count)
+                    .map(|(index, value)| {
+                        json!({
+                            "name": (index as isize - debugger.origin() as isize).to_string(),
+                            "value": format!("{:?}", value),
+                            "variablesReference": 0,
+                        })
+                    })
+                    .collect()
+            }
+            Some(MACHINE) => [
+                ("instruction", debugger.pc().to_string()),
+                ("tape head", debugger.head_position().to_string()),
+                ("steps", debugger.steps().to_string()),
+                (
+                    "output",
+                    format!("\"{}\"", debugger.output().escape_ascii()),
+                ),
+            ]
+            .into_iter()
+            .map(|(name, value)| json!({ "name": name, "value": value, "variablesReference": 0 }))
+            .collect(),
+            _ => Vec::new(),
+        };
+        json!({ "variables": variables })
+    }
+
+    //runs the program for `motion`, then reports where it stopped
+    fn resume(
+        &mut self,
+        motion: Motion,
+        requests: &Receiver<io::Result<Value>>,
+    ) -> io::Result<bool> {
+        if self.launched().failed {
+            return self.exit(1);
+        }
+        // where `next` and `stepOut` end, found before the first chunk as the ones after it can start in an inner loop
+        let debugger = &self.launched().debugger;
+        let target = match motion {
+            Motion::Next => debugger.loop_end(debugger.pc()),
+            Motion::StepOut => debugger
+                .enclosing_loop()
+                .and_then(|start| debugger.loop_end(start)),
+            _ => None,
+        };
+        let budget = Budget::steps(CHUNK);
+        let mut first = true;
+        let stop = loop {
+            let debugger = &mut self.launched().debugger;
+            let stop = match (motion, target) {
+                (Motion::StepIn, _) => debugger.step(),
+                (Motion::Next, _) if first => debugger.step_over(budget),
+                (Motion::StepOut, _) if first => debugger.step_out(budget),
+                (Motion::StepBack, _) => debugger.step_back(),
+                (Motion::ReverseContinue, _) => debugger.run_back(budget),
+                (_, Some(target)) => debugger.run_to(target, budget),
+                (_, None) => debugger.run(budget),
+            };
+            first = false;
+            if stop != DebugStop::Paused {
+                break stop;
+            }
+            match requests.try_recv() {
+                Ok(Ok(request)) if request["command"] == "pause" => {
+                    self.respond(&request, Ok(json!({})))?;
+                    return self.stopped("pause", None).map(|()| true);
+                }
+                Ok(Ok(request)) => {
+                    let disconnect = matches!(
+                        request["command"].as_str(),
+                        Some("disconnect" | "terminate")
+                    );
+                    self.pending.push_back(request);
+                    if disconnect {
+                        return Ok(true);
+                    }
+                }
+                Ok(Err(e)) => return Err(e),
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => return Ok(true),
+            }
+        };
+        self.report(stop)
+    }
+
+    fn report(&mut self, stop: DebugStop<C>) -> io::Result<bool> {
+        let launched = self.launched();
+        let output = launched.debugger.output()[launched.shown..].to_vec();
+        launched.shown = launched.debugger.output().len();
+        if !output.is_empty() {
+            self.event(
+                "output",
+                json!({ "category": "stdout", "output": String::from_utf8_lossy(&output) }),
+            )?;
+        }
+        match stop {
+            DebugStop::Stepped | DebugStop::Paused => self.stopped("step", None)?,
+            DebugStop::Start => self.stopped("entry", None)?,
+            DebugStop::Breakpoint(pc) => self.stopped("breakpoint", Some(pc))?,
+            DebugStop::Watchpoint(event) => {
+                let description = match event {
+                    WatchEvent::Cell { position, old, new } => {
+                        format!("cell {} changed from {:?} to {:?}", position, old, new)
+                    }
+                    WatchEvent::Head { old, new } => {
+                        format!("tape head moved from {} to {}", old, new)
+                    }
+                    WatchEvent::Output(byte) => format!("printed byte {}", byte),
+                };
+                self.event(
+                    "stopped",
+                    json!({
+                        "reason": "data breakpoint",
+                        "description": description,
+                        "threadId": THREAD_ID,
+                        "allThreadsStopped": true,
+                    }),
+                )?;
+            }
+            DebugStop::Finished | DebugStop::WaitingForInput => return self.exit(0),
+            DebugStop::Failed(kind) => {
+                let launched = self.launched();
+                launched.failed = true;
+                let debugger = &launched.debugger;
+                let stop = StopPoint {
+                    pc: debugger.pc(),
+                    tape_head: debugger.head_position(),
+                    cell: debugger.cell(),
+                };
+                let text = RunError { kind, stop }.to_string();
+                self.event(
+                    "output",
+                    json!({ "category": "stderr", "output": format!("Error: {}\n", text) }),
+                )?;
+                self.event(
+                    "stopped",
+                    json!({
+                        "reason": "exception",
+                        "description": "the program failed",
+                        "text": text,
+                        "threadId": THREAD_ID,
+                        "allThreadsStopped": true,
+                    }),
+                )?;
+            }
+        }
+        Ok(true)
+    }
+
+    fn stopped(&mut self, reason: &str, breakpoint: Option<usize>) -> io::Result<()> {
+        let mut body = json!({
+            "reason": reason,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+        });
+        if let Some(pc) = breakpoint {
+            body["hitBreakpointIds"] = json!([pc]);
+        }
+        self.event("stopped", body)
+    }
+
+    fn exit(&mut self, code: i32) -> io::Result<bool> {
+        self.event("exited", json!({ "exitCode": code }))?;
+        self.event("terminated", json!({}))?;
+        Ok(true)
+    }
+
+    fn respond(&mut self, request: &Value, body: Result<Value, String>) -> io::Result<()> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": body.is_ok(),
+        });
+        match body {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = message.into(),
+        }
+        self.send(response)
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = self.seq.into();
+        write_message(self.out, &message)
+    }
+}
+
+//the data breakpoint on a variable of the Tape or Machine scope, whose id is what `watch` takes in the line debugger
+fn data_breakpoint_info(arguments: &Value) -> Value {
+    let name = arguments["name"].as_str().unwrap_or_default();
+    let id = match (arguments["variablesReference"].as_u64(), name) {
+        (Some(TAPE), position) => Some(position),
+        (Some(MACHINE), "tape head") => Some("head"),
+        (Some(MACHINE), "output") => Some("output"),
+        _ => None,
+    };
+    match id.and_then(watchpoint) {
+        Some(watched) => json!({
+            "dataId": id,
+            "description": format!("changes of {}", describe_watchpoint(watched)),
+            "accessTypes": ["write"],
+        }),
+        None => json!({
+            "dataId": null,
+            "description": format!("`{}` can not be watched", name),
+        }),
+    }
+}
+
+// --- UNIT TESTS ---
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    //writes `source` to a file of its own and runs `requests` on it, returning everything the adapter sent
+    fn session(name: &str, source: &str, requests: &[Value]) -> Vec<Value> {
+        let path = std::env::temp_dir().join(format!("bf-dap-{}-{}.bf", std::process::id(), name));
+        fs::write(&path, source).unwrap();
+        let mut input = Vec::new();
+        for (seq, request) in requests.iter().enumerate() {
+            let mut request = request.clone();
+            request["seq"] = (seq + 1).into();
+            request["type"] = "request".into();
+            let path = path.to_str().unwrap();
+            if request["command"] == "launch" && request["arguments"]["program"].is_null() {
+                request["arguments"]["program"] = path.into();
+            }
+            if request["command"] == "setBreakpoints" {
+                request["arguments"]["source"] = json!({ "path": path });
+            }
+            write_message(&mut input, &request).unwrap();
+        }
+        let mut out = Vec::new();
+        serve::<u8>(Cursor::new(input), &mut out, 16, &RunOptions::default()).unwrap();
+        fs::remove_file(&path).unwrap();
+        let mut out = Cursor::new(out);
+        let mut messages = Vec::new();
+        while let Some(message) = read_message(&mut out).unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    fn response<'a>(messages: &'a [Value], command: &str) -> &'a Value {
+        messages
+            .iter()
+            .find(|message| message["type"] == "response" && message["command"] == command)
+            .unwrap()
+    }
+
+    fn events<'a>(messages: &'a [Value], event: &str) -> Vec<&'a Value> {
+        messages
+            .iter()
+            .filter(|message| message["type"] == "event" && message["event"] == event)
+            .map(|message| &message["body"])
+            .collect()
+    }
+
+    /// Breakpoints move to the next instruction and stop the program there, where the tape and the position can be looked at.
+    #[test]
+    fn test_breakpoints_and_variables() {
+        let messages = session(
+            "breakpoints",
+            "++ two\n\n>+[-]<.",
+            &[
+                json!({ "command": "initialize", "arguments": { "adapterID": "brainfuck" } }),
+                json!({ "command": "launch", "arguments": {} }),
+                json!({ "command": "setBreakpoints", "arguments": {
+                    "breakpoints": [{ "line": 2 }, { "line": 3, "column": 4 }, { "line": 9 }],
+                } }),
+                json!({ "command": "configurationDone" }),
+                json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+                json!({ "command": "variables", "arguments": {
+                    "variablesReference": TAPE, "start": 0, "count": 2,
+                } }),
+                json!({ "command": "evaluate", "arguments": { "expression": "x" } }),
+                json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+                json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+                json!({ "command": "disconnect" }),
+            ],
+        );
+        assert_eq!(
+            response(&messages, "initialize")["body"]["supportsStepBack"],
+            true
+        );
+        assert_eq!(events(&messages, "initialized").len(), 1);
+        let breakpoints = &response(&messages, "setBreakpoints")["body"]["breakpoints"];
+        assert_eq!(
+            breakpoints[0],
+            json!({ "id": 2, "verified": true, "line": 3, "column": 1 })
+        );
+        assert_eq!(breakpoints[1]["id"], 5);
+        assert_eq!(breakpoints[2]["verified"], false);
+
+        let stopped = events(&messages, "stopped");
+        assert_eq!(stopped[0]["reason"], "breakpoint");
+        assert_eq!(stopped[0]["hitBreakpointIds"], json!([2]));
+        let frame = &response(&messages, "stackTrace")["body"]["stackFrames"][0];
+        assert_eq!(frame["name"], "instruction 2 `>`");
+        assert_eq!((&frame["line"], &frame["column"]), (&json!(3), &json!(1)));
+        let variables = &response(&messages, "variables")["body"]["variables"];
+        assert_eq!(variables[0]["name"], "0");
+        assert_eq!(variables[0]["value"], "2");
+        assert_eq!(variables[1]["value"], "0");
+        assert_eq!(response(&messages, "evaluate")["success"], false);
+
+        // the loop runs once, so the breakpoint inside it is hit once
+        assert_eq!(stopped[1]["hitBreakpointIds"], json!([5]));
+        assert_eq!(stopped.len(), 2);
+        assert_eq!(events(&messages, "output")[0]["output"], "\u{2}");
+        assert_eq!(events(&messages, "exited")[0]["exitCode"], 0);
+        assert_eq!(events(&messages, "terminated").len(), 1);
+    }
+
+    /// Stepping goes over and out of loops and back, and a failure stops the program before it exits.
+    #[test]
+    fn test_stepping_and_failures() {
+        let columns = |messages: &[Value]| -> Vec<Value> {
+            messages
+                .iter()
+                .filter(|message| message["command"] == "stackTrace")
+                .map(|message| message["body"]["stackFrames"][0]["column"].clone())
+                .collect()
+        };
+        let stack_trace = json!({ "command": "stackTrace", "arguments": { "threadId": 1 } });
+        let messages = session(
+            "stepping",
+            "+[->+<]<",
+            &[
+                json!({ "command": "initialize", "arguments": { "columnsStartAt1": false } }),
+                json!({ "command": "launch", "arguments": { "stopOnEntry": true } }),
+                json!({ "command": "configurationDone" }),
+                json!({ "command": "stepIn", "arguments": { "threadId": 1 } }),
+                stack_trace.clone(),
+                json!({ "command": "next", "arguments": { "threadId": 1 } }),
+                stack_trace.clone(),
+                json!({ "command": "stepBack", "arguments": { "threadId": 1 } }),
+                stack_trace.clone(),
+                json!({ "command": "stepOut", "arguments": { "threadId": 1 } }),
+                stack_trace.clone(),
+                json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+                json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+            ],
+        );
+        assert_eq!(columns(&messages), [json!(1), json!(7), json!(6), json!(7)]);
+        let stopped = events(&messages, "stopped");
+        let reasons: Vec<_> = stopped.iter().map(|body| &body["reason"]).collect();
+        assert_eq!(
+            reasons,
+            ["entry", "step", "step", "step", "step", "exception"]
+        );
+        assert!(
+            stopped[5]["text"]
+                .as_str()
+                .unwrap()
+                .ends_with("at instruction 7, tape head 0, cell value 0")
+        );
+        assert_eq!(events(&messages, "output")[0]["category"], "stderr");
+        assert_eq!(events(&messages, "exited")[0]["exitCode"], 1);
+    }
+
+    /// Requests that need a program fail until one is launched.
+    #[test]
+    fn test_requests_before_launch() {
+        let messages = session(
+            "before-launch",
+            "+",
+            &[
+                json!({ "command": "threads" }),
+                json!({ "command": "next", "arguments": { "threadId": 1 } }),
+                json!({ "command": "launch", "arguments": { "program": "/nowhere/at/all.bf" } }),
+                json!({ "command": "launch", "arguments": {} }),
+            ],
+        );
+        assert_eq!(
+            response(&messages, "threads")["body"]["threads"][0]["id"],
+            THREAD_ID
+        );
+        assert_eq!(
+            response(&messages, "next")["message"],
+            "no program was launched"
+        );
+        let launches: Vec<_> = messages
+            .iter()
+            .filter(|message| message["command"] == "launch")
+            .collect();
+        assert_eq!(launches[0]["success"], false);
+        assert!(
+            launches[0]["message"]
+                .as_str()
+                .unwrap()
+                .starts_with("Error opening /nowhere/at/all.bf")
+        );
+        assert_eq!(launches[1]["success"], true);
+        assert_eq!(events(&messages, "initialized").len(), 1);
+    }
+
+    /// A program that never ends still stops for `pause`.
+    #[test]
+    fn test_pause() {
+        let messages = session(
+            "pause",
+            "+[]",
+            &[
+                json!({ "command": "initialize", "arguments": {} }),
+                json!({ "command": "launch", "arguments": {} }),
+                json!({ "command": "configurationDone" }),
+                json!({ "command": "pause", "arguments": { "threadId": 1 } }),
+                json!({ "command": "disconnect" }),
+            ],
+        );
+        assert_eq!(events(&messages, "stopped")[0]["reason"], "pause");
+        assert_eq!(response(&messages, "disconnect")["success"], true);
+    }
+
+    /// Data breakpoints on the variables watch the cells, the tape head and the output, and stop the program right after they change.
+    #[test]
+    fn test_data_breakpoints() {
+        let info = |reference: u64, name: &str| {
+            json!({ "command": "dataBreakpointInfo", "arguments": {
+                "variablesReference": reference, "name": name,
+            } })
+        };
+        let messages = session(
+            "data-breakpoints",
+            "++>+<[->+<]>.",
+            &[
+                json!({ "command": "initialize", "arguments": {} }),
+                json!({ "command": "launch", "arguments": {} }),
+                info(TAPE, "1"),
+                info(MACHINE, "output"),
+                info(MACHINE, "steps"),
+                json!({ "command": "setDataBreakpoints", "arguments": {
+                    "breakpoints": [{ "dataId": "1" }, { "dataId": "output" }, { "dataId": "steps" }],
+                } }),
+                json!({ "command": "configurationDone" }),
+                json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+                json!({ "command": "setDataBreakpoints", "arguments": { "breakpoints": [] } }),
+                json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+            ],
+        );
+        assert_eq!(
+            response(&messages, "initialize")["body"]["supportsDataBreakpoints"],
+            true
+        );
+        let infos: Vec<_> = messages
+            .iter()
+            .filter(|message| message["command"] == "dataBreakpointInfo")
+            .map(|message| &message["body"])
+            .collect();
+        assert_eq!(infos[0]["dataId"], "1");
+        assert_eq!(infos[0]["description"], "changes of cell 1");
+        assert_eq!(infos[1]["dataId"], "output");
+        assert_eq!(infos[2]["dataId"], Value::Null);
+        let breakpoints = &response(&messages, "setDataBreakpoints")["body"]["breakpoints"];
+        assert_eq!(breakpoints[0]["verified"], true);
+        assert_eq!(breakpoints[1]["verified"], true);
+        assert_eq!(breakpoints[2]["verified"], false);
+
+        let stopped = events(&messages, "stopped");
+        let descriptions: Vec<_> = stopped.iter().map(|body| &body["description"]).collect();
+        assert_eq!(
+            descriptions,
+            ["cell 1 changed from 0 to 1", "cell 1 changed from 1 to 2"]
+        );
+        assert!(
+            stopped
+                .iter()
+                .all(|body| body["reason"] == "data breakpoint")
+        );
+        assert_eq!(events(&messages, "exited")[0]["exitCode"], 0);
+    }
+}
@@ -0,0 +1,418 @@
+use brainfuck_core::{
+    Cell, DebugStop, Debugger, RunError, RunOptions, RunningProgramInfo, SourceMap, StopPoint,
+    WatchEvent, Watchpoint,
+};
+use std::io::{self, BufRead, Write};
+
+const HELP: &str = "\
+break <idx>       stop before the instruction with index <idx>
+delete <idx>      remove the breakpoint at <idx>
+step [n]          run the next instruction, or the next n
+next              run the next instruction, or the whole loop when it is a `[`
+continue          run until a breakpoint, a watchpoint, the end of the program or --max-steps instructions
+tape <from> <to>  print the cells from <from> to <to>, counted from the cell the program started on
+watch <cell>      stop when the cell at <cell> changes; `watch head` and `watch output` watch the head and the output
+unwatch <cell>    remove a watchpoint
+output            print everything the program printed
+input <text>      give the program <text> and a newline to read
+eof               tell the program there is no more input
+restart           start the program over, keeping breakpoints, watchpoints and input
+quit              leave the debugger
+";
+
+/// A debugging session on one program, driven by commands one line at a time.
+pub(crate) struct Session<'a, C> {
+    source: &'a str,
+    program: RunningProgramInfo<C>,
+    options: RunOptions,
+    source_map: SourceMap,
+    debugger: Debugger<C>,
+    // everything given to the program, so that `restart` can give it again
+    input: Vec<u8>,
+    // whether input is typed at the prompt, and whether `eof` ended it
+    typed: bool,
+    ended: bool,
+    // bytes of output already shown
+    shown: usize,
+}
+
+impl<'a, C: Cell> Session<'a, C> {
+    /// Starts on `program`, parsed from `source`, with `data` as all of its input or `None` to type input at the prompt.
+    ///
+    /// `continue` and `next` run at most as far as the budget of `options` goes.
+    pub(crate) fn new(
+        source: &'a str,
+        program: RunningProgramInfo<C>,
+        options: &RunOptions,
+        data: Option<Vec<u8>>,
+    ) -> Self {
+        let typed = data.is_none();
+        let debugger = Debugger::new(program.clone(), options);
+        let mut session = Session {
+            source,
+            program,
+            options: *options,
+            source_map: SourceMap::from_source(source),
+            debugger,
+            input: data.unwrap_or_default(),
+            typed,
+            ended: false,
+            shown: 0,
+        };
+        session.debugger.push_input(&session.input);
+        session.debugger.wait_for_input(typed);
+        session
+    }
+
+    /// Reads commands from `commands` until `quit` or the end of the commands, writing what they show to `out`.
+    pub(crate) fn run(&mut self, commands: impl BufRead, out: &mut impl Write) -> io::Result<()> {
+        writeln!(
+            out,
+            "{} instructions, type `help` for the commands",
+            self.debugger.program().size()
+        )?;
+        self.location(out)?;
+        let mut lines = commands.lines();
+        loop {
+            write!(out, "(bf) ")?;
+            out.flush()?;
+            let Some(line) = lines.next().transpose()? else {
+                writeln!(out)?;
+                return Ok(());
+            };
+            let mut words = line.split_whitespace();
+            let Some(command) = words.next() else {
+                continue;
+            };
+            let argument = words.next();
+            match (command, argument) {
+                ("quit" | "q", _) => return Ok(()),
+                ("help" | "h", _) => write!(out, "{}", HELP)?,
+                ("break" | "b", Some(argument)) => match self.instruction(argument) {
+                    Ok(pc) => {
+                        self.debugger.add_breakpoint(pc);
+                        writeln!(out, "breakpoint at {}", self.describe(pc))?;
+                    }
+                    Err(e) => writeln!(out, "{}", e)?,
+                },
+                ("delete" | "d", Some(argument)) => match argument.parse() {
+                    Ok(pc) if self.debugger.remove_breakpoint(pc) => {
+                        writeln!(out, "deleted the breakpoint at instruction {}", pc)?
+                    }
+                    _ => writeln!(out, "there is no breakpoint at {}", argument)?,
+                },
+                ("step" | "s", argument) => {
+                    let count = match argument.map(str::parse::<u64>) {
+                        None => 1,
+                        Some(Ok(count)) if count > 0 => count,
+                        _ => {
+                            writeln!(out, "`step` takes a number of instructions")?;
+                            continue;
+                        }
+                    };
+                    let mut stop = DebugStop::Stepped;
+                    for _ in 0..count {
+                        stop = self.debugger.step();
+                        if stop != DebugStop::Stepped {
+                            break;
+                        }
+                    }
+                    self.report(stop, out)?;
+                }
+                ("next" | "n", _) => {
+                    let stop = self.debugger.step_over(self.options.budget);
+                    self.report(stop, out)?;
+                }
+                ("continue" | "c", _) => {
+                    let stop = self.debugger.run(self.options.budget);
+                    self.report(stop, out)?;
+                }
+                ("tape" | "t", Some(from)) => match (from.parse(), words.next().map(str::parse)) {
+                    (Ok(from), Some(Ok(to))) if from <= to => self.tape(from, to, out)?,
+                    _ => writeln!(out, "`tape` takes the first and last cell to print")?,
+                },
+                ("watch" | "w", Some(argument)) => match watchpoint(argument) {
+                    Some(watchpoint) => {
+                        self.debugger.add_watchpoint(watchpoint);
+                        writeln!(out, "watching {}", describe_watchpoint(watchpoint))?;
+                    }
+                    None => writeln!(out, "`watch` takes a cell, `head` or `output`")?,
+                },
+                ("unwatch", Some(argument)) => match watchpoint(argument) {
+                    Some(watchpoint) if self.debugger.remove_watchpoint(watchpoint) => {
+                        writeln!(out, "stopped watching {}", describe_watchpoint(watchpoint))?
+                    }
+                    _ => writeln!(out, "{} is not watched", argument)?,
+                },
+                ("output" | "o", _) => {
+                    writeln!(out, "output: \"{}\"", self.debugger.output().escape_ascii())?;
+                    self.shown = self.debugger.output().len();
+                }
+                ("input" | "i", _) => {
+                    // everything after the command, spaces included
+                    let text = line.trim_start()[command.len()..]
+                        .strip_prefix(' ')
+                        .unwrap_or_default();
+                    self.give(format!("{}\n", text).as_bytes(), out)?;
+                }
+                ("eof", _) => {
+                    self.ended = true;
+                    self.debugger.wait_for_input(false);
+                    writeln!(out, "the program gets no more input")?;
+                }
+                ("restart" | "r", _) => {
+                    self.restart();
+                    writeln!(out, "restarted")?;
+                    self.location(out)?;
+                }
+                _ => writeln!(
+                    out,
+                    "unknown command `{}`, type `help` for the commands",
+                    line.trim()
+                )?,
+            }
+        }
+    }
+
+    fn restart(&mut self) {
+        let mut debugger = Debugger::new(self.program.clone(), &self.options);
+        for pc in self.debugger.breakpoints() {
+            debugger.add_breakpoint(pc);
+        }
+        for watchpoint in self.debugger.watchpoints() {
+            debugger.add_watchpoint(watchpoint);
+        }
+        debugger.push_input(&self.input);
+        debugger.wait_for_input(self.typed && !self.ended);
+        self.debugger = debugger;
+        self.shown = 0;
+    }
+
+    fn give(&mut self, input: &[u8], out: &mut impl Write) -> io::Result<()> {
+        if !self.typed {
+            return writeln!(
+                out,
+                "the program reads its input from --data or --stdin-file"
+            );
+        }
+        if self.ended {
+            return writeln!(out, "the input already ended, `restart` to give it again");
+        }
+        self.input.extend_from_slice(input);
+        self.debugger.push_input(input);
+        writeln!(out, "gave the program {} bytes", input.len())
+    }
+
+    //the index of the instruction in `argument`
+    fn instruction(&self, argument: &str) -> Result<usize, String> {
+        match argument.parse() {
+            Ok(pc) if pc < self.debugger.program().size() => Ok(pc),
+            Ok(_) => Err(format!(
+                "the program only has {} instructions",
+                self.debugger.program().size()
+            )),
+            Err(_) => Err(format!("`{}` is not an instruction index", argument)),
+        }
+    }
+
+    //instruction `pc`, where it is in the source and what it is
+    fn describe(&self, pc: usize) -> String {
+        match (self.source_map.span(pc), self.debugger.program().get(pc)) {
+            (Some(span), Some(instruction)) => {
+                format!("instruction {} `{}` at {}", pc, instruction, span)
+            }
+            _ => format!("instruction {}, the end of the program", pc),
+        }
+    }
+
+    //where the program is, with the source line and a caret under the next instruction
+    fn location(&self, out: &mut impl Write) -> io::Result<()> {
+        let pc = self.debugger.pc();
+        writeln!(
+            out,
+            "at {}, tape head {}, cell value {:?}",
+            self.describe(pc),
+            self.debugger.head_position(),
+            self.debugger.cell()
+        )?;
+        if let Some(span) = self.source_map.span(pc) {
+            write!(out, "{}", crate::excerpt(self.source, span))?;
+        }
+        Ok(())
+    }
+
+    fn report(&mut self, stop: DebugStop<C>, out: &mut impl Write) -> io::Result<()> {
+        let output = &self.debugger.output()[self.shown..];
+        if !output.is_empty() {
+            writeln!(out, "printed \"{}\"", output.escape_ascii())?;
+            self.shown = self.debugger.output().len();
+        }
+        match stop {
+            DebugStop::Stepped | DebugStop::Start => {}
+            DebugStop::Breakpoint(pc) => writeln!(out, "breakpoint at instruction {}", pc)?,
+            DebugStop::Watchpoint(WatchEvent::Cell { position, old, new }) => {
+                writeln!(out, "cell {} changed from {:?} to {:?}", position, old, new)?
+            }
+            DebugStop::Watchpoint(WatchEvent::Head { old, new }) => {
+                writeln!(out, "tape head moved from {} to {}", old, new)?
+            }
+            DebugStop::Watchpoint(WatchEvent::Output(byte)) => {
+                writeln!(out, "printed byte {}", byte)?
+            }
+            DebugStop::Paused => writeln!(
+                out,
+                "paused after {} steps, the most --max-steps allows at a time",
+                self.options.budget.max_steps.unwrap_or_default()
+            )?,
+            DebugStop::Finished => {
+                return writeln!(
+                    out,
+                    "the program finished after {} steps",
+                    self.debugger.steps()
+                );
+            }
+            DebugStop::Failed(kind) => {
+                let stop = StopPoint {
+                    pc: self.debugger.pc(),
+                    tape_head: self.debugger.head_position(),
+                    cell: self.debugger.cell(),
+                };
+                writeln!(out, "the program failed: {}", RunError { kind, stop })?;
+                if let Some(span) = self.source_map.span(stop.pc) {
+                    write!(out, "{}", crate::excerpt(self.source, span))?;
+                }
+                return Ok(());
+            }
+            DebugStop::WaitingForInput => writeln!(
+                out,
+                "the program is waiting for input, give it with `input <text>` or end it with `eof`"
+            )?,
+        }
+        self.location(out)
+    }
+
+    fn tape(&self, from: isize, to: isize, out: &mut impl Write) -> io::Result<()> {
+        for position in from..=to {
+            let marker = if position == self.debugger.head_position() {
+                "  <- head"
+            } else {
+                ""
+            };
+            match self.debugger.cell_at(position) {
+                Some(value) => writeln!(out, "{:>6}: {:?}{}", position, value, marker)?,
+                None => writeln!(out, "{:>6}: not on the tape", position)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn watchpoint(argument: &str) -> Option<Watchpoint> {
+    match argument {
+        "head" => Some(Watchpoint::Head),
+        "output" => Some(Watchpoint::Output),
+        cell => cell.parse().ok().map(Watchpoint::Cell),
+    }
+}
+
+pub(crate) fn describe_watchpoint(watchpoint: Watchpoint) -> String {
+    match watchpoint {
+        Watchpoint::Cell(position) => format!("cell {}", position),
+        Watchpoint::Head => "the tape head".to_string(),
+        Watchpoint::Output => "the output".to_string(),
+    }
+}
+
+// --- UNIT TESTS ---
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use brainfuck_core::{Budget, util::preprocess_input_with_cells};
+
+    fn session(source: &str, commands: &str, data: Option<&[u8]>, options: RunOptions) -> String {
+        let program = preprocess_input_with_cells::<u8>(source, 16).unwrap();
+        let mut session = Session::new(source, program, &options, data.map(<[u8]>::to_vec));
+        let mut out = Vec::new();
+        session.run(commands.as_bytes(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_breakpoints_and_tape() {
+        let out = session(
+            "++>+++[-]<.",
+            "break 6\ncontinue\ntape 0 2\nnext\nstep 2\ncontinue\noutput\n",
+            Some(b""),
+            RunOptions::default(),
+        );
+        assert!(out.starts_with("11 instructions, type `help` for the commands\n"));
+        assert!(out.contains("breakpoint at instruction 6 `[` at line 1, column 7\n"));
+        assert!(out.contains("breakpoint at instruction 6\nat instruction 6 `[` at line 1, column 7, tape head 1, cell value 3\n"));
+        assert!(out.contains("     0: 2\n     1: 3  <- head\n     2: 0\n"));
+        assert!(out.contains("at instruction 9 `<`"));
+        assert!(out.contains("printed \"\\x02\"\n"));
+        assert!(out.contains("the program finished after"));
+        assert!(out.ends_with("output: \"\\x02\"\n(bf) \n"));
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let out = session(
+            "+>++<-",
+            "watch 1\ncontinue\nwatch head\nunwatch 1\ncontinue\nunwatch 5\nwatch nowhere\nq\n",
+            Some(b""),
+            RunOptions::default(),
+        );
+        assert!(out.contains("watching cell 1\n"));
+        assert!(out.contains("cell 1 changed from 0 to 1\n"));
+        assert!(out.contains("stopped watching cell 1\n"));
+        assert!(out.contains("tape head moved from 1 to 0\n"));
+        assert!(out.contains("5 is not watched\n"));
+        assert!(out.contains("`watch` takes a cell, `head` or `output`\n"));
+    }
+
+    #[test]
+    fn test_typed_input_and_restart() {
+        let out = session(
+            ",.,.",
+            "continue\ninput a b\ncontinue\neof\ncontinue\nrestart\ncontinue\ninput c\n",
+            None,
+            RunOptions::default(),
+        );
+        assert!(out.contains("the program is waiting for input"));
+        assert!(out.contains("gave the program 4 bytes\n"));
+        assert!(out.contains("printed \"a \"\n"));
+        assert!(out.contains("the program gets no more input\n"));
+        assert!(out.contains("the program finished after 4 steps\n"));
+        //after a restart the same input is given again, and it has ended
+        let restarted = &out[out.find("restarted\n").unwrap()..];
+        assert!(restarted.contains("printed \"a \"\nthe program finished after 4 steps\n"));
+        assert!(out.contains("the input already ended, `restart` to give it again\n"));
+
+        let out = session(",.", "input x\n", Some(b"y"), RunOptions::default());
+        assert!(out.contains("the program reads its input from --data or --stdin-file\n"));
+    }
+
+    #[test]
+    fn test_failures_and_bad_commands() {
+        let out = session(
+            "<",
+            "c\nbreak 7\nbreak x\nstep 0\ntape 3 1\nfly\n",
+            Some(b""),
+            RunOptions::default(),
+        );
+        assert!(out.contains("the program failed: "));
+        assert!(out.contains("the program only has 1 instructions\n"));
+        assert!(out.contains("`x` is not an instruction index\n"));
+        assert!(out.contains("`step` takes a number of instructions\n"));
+        assert!(out.contains("`tape` takes the first and last cell to print\n"));
+        assert!(out.contains("unknown command `fly`, type `help` for the commands\n"));
+
+        let options = RunOptions {
+            budget: Budget::steps(100),
+            ..RunOptions::default()
+        };
+        let out = session("+[]", "continue\n", Some(b""), options);
+        assert!(out.contains("paused after 100 steps"));
+    }
+}
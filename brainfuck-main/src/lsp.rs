@@ -0,0 +1,506 @@
+use crate::jsonrpc::{read_message, write_message};
+use brainfuck_core::{
+    CellEffect, NetEffect, ParseError, RunningProgramInfo, SourceMap, SourceSpan, format_source,
+    util::parse_program,
+};
+use serde_json::{Value, json};
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Write},
+    ops::Range,
+};
+
+//JSON-RPC error codes
+const METHOD_NOT_FOUND: i64 = -32601;
+const REQUEST_FAILED: i64 = -32803;
+
+//longest piece of code a hover shows
+const HOVER_CODE: usize = 40;
+
+/// Serves the Language Server Protocol with messages read from `input` and written to `out`, until the `exit` notification or the end of `input`.
+pub(crate) fn serve(mut input: impl BufRead, out: &mut impl Write) -> io::Result<()> {
+    let mut documents = HashMap::new();
+    while let Some(message) = read_message(&mut input)? {
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = &message["params"];
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        match method {
+            "exit" => return Ok(()),
+            "textDocument/didOpen" | "textDocument/didChange" => {
+                //documents are synced in full, so the last change has all of the text
+                let text = match method {
+                    "textDocument/didOpen" => &params["textDocument"]["text"],
+                    _ => params["contentChanges"]
+                        .as_array()
+                        .and_then(|changes| changes.last())
+                        .map_or(&Value::Null, |change| &change["text"]),
+                };
+                let document = Document::new(text.as_str().unwrap_or_default().to_string());
+                publish_diagnostics(out, uri, document.diagnostics())?;
+                documents.insert(uri.to_string(), document);
+                continue;
+            }
+            "textDocument/didClose" => {
+                documents.remove(uri);
+                publish_diagnostics(out, uri, Vec::new())?;
+                continue;
+            }
+            _ => {}
+        }
+        //other notifications, like `initialized`, need nothing
+        if message["id"].is_null() {
+            continue;
+        }
+        let result = match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "hoverProvider": true,
+                    "documentHighlightProvider": true,
+                    "documentFormattingProvider": true,
+                },
+                "serverInfo": { "name": "brainfuck-main" },
+            })),
+            "shutdown" => Ok(Value::Null),
+            "textDocument/documentHighlight" | "textDocument/hover" | "textDocument/formatting" => {
+                match documents.get(uri) {
+                    Some(document) if method == "textDocument/documentHighlight" => {
+                        Ok(document.highlights(&params["position"]))
+                    }
+                    Some(document) if method == "textDocument/hover" => Ok(document.hover(params)),
+                    Some(document) => document.format(&params["options"]),
+                    None => Err((REQUEST_FAILED, format!("{} is not open", uri))),
+                }
+            }
+            _ => Err((METHOD_NOT_FOUND, format!("`{}` is not supported", method))),
+        };
+        let mut response = json!({ "jsonrpc": "2.0", "id": message["id"] });
+        match result {
+            Ok(result) => response["result"] = result,
+            Err((code, message)) => response["error"] = json!({ "code": code, "message": message }),
+        }
+        write_message(out, &response)?;
+    }
+    Ok(())
+}
+
+fn publish_diagnostics(out: &mut impl Write, uri: &str, diagnostics: Vec<Value>) -> io::Result<()> {
+    let notification = json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": { "uri": uri, "diagnostics": diagnostics },
+    });
+    write_message(out, &notification)
+}
+
+//an open document, parsed again on every change
+struct Document {
+    text: String,
+    source_map: SourceMap,
+    program: Result<RunningProgramInfo, ParseError>,
+}
+
+impl Document {
+    fn new(text: String) -> Self {
+        Document {
+            source_map: SourceMap::from_source(&text),
+            program: parse_program(&text),
+            text,
+        }
+    }
+
+    fn diagnostics(&self) -> Vec<Value> {
+        let Err(e) = &self.program else {
+            return Vec::new();
+        };
+        let message = match e {
+            ParseError::UnmatchedLoopStart { .. } => "this loop is never closed",
+            ParseError::UnmatchedLoopEnd { .. } => "this `]` has no matching `[`",
+        };
+        let span = e.span();
+        let range = self.range(span, span);
+        vec![json!({
+            "range": range,
+            "severity": 1,
+            "source": "brainfuck",
+            "message": message,
+        })]
+    }
+
+    //both brackets of the loop at or right before `position`
+    fn highlights(&self, position: &Value) -> Value {
+        let Ok(program) = &self.program else {
+            return Value::Null;
+        };
+        let (line, column) = self.line_column(position);
+        let brackets = [column, column.saturating_sub(1)]
+            .into_iter()
+            .filter_map(|column| self.instruction_at(line, column))
+            .find_map(|pc| Some((pc, program.matching_bracket(pc)?)));
+        match brackets {
+            Some((pc, other)) => json!(
+                [pc, other]
+                    .into_iter()
+                    .map(|pc| {
+                        let span = self.source_map.span(pc).expect("pc is in the program");
+                        json!({ "range": self.range(span, span), "kind": 1 })
+                    })
+                    .collect::<Vec<_>>()
+            ),
+            None => Value::Null,
+        }
+    }
+
+    //the net effect of the selection in `params`, or at a position of the loop or the run of instructions there
+    fn hover(&self, params: &Value) -> Value {
+        let Ok(program) = &self.program else {
+            return Value::Null;
+        };
+        //the selection is an extension some clients send, either as `range` or in place of `position`
+        let selection = [&params["range"], &params["position"]]
+            .into_iter()
+            .find(|range| range["start"].is_object());
+        let instructions = match selection {
+            Some(range) => {
+                let (line, column) = self.line_column(&range["start"]);
+                let start = self.source_map.instruction_from(line, column);
+                let (line, column) = self.line_column(&range["end"]);
+                start..self.source_map.instruction_from(line, column).max(start)
+            }
+            None => {
+                let (line, column) = self.line_column(&params["position"]);
+                let Some(pc) = [column, column.saturating_sub(1)]
+                    .into_iter()
+                    .find_map(|column| self.instruction_at(line, column))
+                else {
+                    return Value::Null;
+                };
+                match program.matching_bracket(pc) {
+                    Some(other) => pc.min(other)..pc.max(other) + 1,
+                    None => self.run_around(pc, program),
+                }
+            }
+        };
+        if instructions.is_empty() {
+            return Value::Null;
+        }
+        let code: String = instructions
+            .clone()
+            .map(|pc| self.instruction_char(pc))
+            .collect();
+        let code = match code.char_indices().nth(HOVER_CODE) {
+            Some((end, _)) => format!("{}…", &code[..end]),
+            None => code,
+        };
+        let value = format!(
+            "`{}`\n\n{}",
+            code,
+            describe(program.net_effect(instructions.clone()))
+        );
+        let first = self
+            .source_map
+            .span(instructions.start)
+            .expect("pc is in the program");
+        let last = self
+            .source_map
+            .span(instructions.end - 1)
+            .expect("pc is in the program");
+        json!({
+            "contents": { "kind": "markdown", "value": value },
+            "range": self.range(first, last),
+        })
+    }
+
+    //the instructions around `pc` that follow one another without brackets or anything else between them
+    fn run_around(&self, pc: usize, program: &RunningProgramInfo) -> Range<usize> {
+        let joined = |pc: usize| {
+            let (a, b) = (self.source_map.span(pc), self.source_map.span(pc + 1));
+            matches!((a, b), (Some(a), Some(b)) if a.offset + 1 == b.offset)
+                && program.matching_bracket(pc).is_none()
+                && program.matching_bracket(pc + 1).is_none()
+        };
+        let mut start = pc;
+        while start > 0 && joined(start - 1) {
+            start -= 1;
+        }
+        let mut end = pc;
+        while joined(end) {
+            end += 1;
+        }
+        start..end + 1
+    }
+
+    fn format(&self, options: &Value) -> Result<Value, (i64, String)> {
+        let indent = match options["insertSpaces"].as_bool() {
+            Some(false) => "\t".to_string(),
+            _ => " ".repeat(options["tabSize"].as_u64().unwrap_or(4) as usize),
+        };
+        let formatted = format_source(&self.text, &indent)
+            .map_err(|e| (REQUEST_FAILED, format!("can not format: {}", e)))?;
+        if formatted == self.text {
+            return Ok(json!([]));
+        }
+        let last_line = self.text.split('\n').count() - 1;
+        let last_column = self.text.split('\n').next_back().unwrap_or_default();
+        Ok(json!([{
+            "range": {
+                "start": { "line": 0, "character": 0 },
+                "end": {
+                    "line": last_line,
+                    "character": last_column.encode_utf16().count(),
+                },
+            },
+            "newText": formatted,
+        }]))
+    }
+
+    fn instruction_char(&self, pc: usize) -> char {
+        let span = self.source_map.span(pc).expect("pc is in the program");
+        self.text[span.offset..]
+            .chars()
+            .next()
+            .expect("an instruction is one character")
+    }
+
+    //the instruction exactly at `line` and `column`
+    fn instruction_at(&self, line: usize, column: usize) -> Option<usize> {
+        let pc = self.source_map.instruction_from(line, column);
+        let span = self.source_map.span(pc)?;
+        (span.line == line && span.column == column).then_some(pc)
+    }
+
+    //the line and column counted from 1 in characters of an LSP position, which counts from 0 in UTF-16 code units
+    fn line_column(&self, position: &Value) -> (usize, usize) {
+        let line = position["line"].as_u64().unwrap_or(0) as usize;
+        let character = position["character"].as_u64().unwrap_or(0) as usize;
+        let text = self.text.split('\n').nth(line).unwrap_or_default();
+        let mut units = 0;
+        let column = text
+            .chars()
+            .take_while(|c| {
+                units += c.len_utf16();
+                units <= character
+            })
+            .count();
+        (line + 1, column + 1)
+    }
+
+    //the LSP position of `line` and `column`
+    fn position(&self, line: usize, column: usize) -> Value {
+        let text = self.text.split('\n').nth(line - 1).unwrap_or_default();
+        let character: usize = text.chars().take(column - 1).map(char::len_utf16).sum();
+        json!({ "line": line - 1, "character": character })
+    }
+
+    //the range from the instruction at `first` to the one at `last`, both included
+    fn range(&self, first: SourceSpan, last: SourceSpan) -> Value {
+        json!({
+            "start": self.position(first.line, first.column),
+            "end": self.position(last.line, last.column + 1),
+        })
+    }
+}
+
+fn describe(effect: Option<NetEffect>) -> String {
+    let Some(effect) = effect else {
+        return "Where the head ends up depends on the tape, as a loop here moves the head or is only partly selected.".to_string();
+    };
+    let mut description = match effect.movement {
+        0 => "The head ends where it started.".to_string(),
+        1 => "The head moves 1 cell to the right.".to_string(),
+        -1 => "The head moves 1 cell to the left.".to_string(),
+        distance if distance > 0 => format!("The head moves {} cells to the right.", distance),
+        distance => format!("The head moves {} cells to the left.", -distance),
+    };
+    for (offset, cell) in effect.cells {
+        let change = match cell {
+            CellEffect::Add(amount) if amount > 0 => format!("adds {}", amount),
+            CellEffect::Add(amount) => format!("subtracts {}", -amount),
+            CellEffect::Set(value) => format!("sets it to {}", value),
+            CellEffect::Unknown => "changes it depending on the tape or the input".to_string(),
+        };
+        description.push_str(&format!("\n- cell {:+}: {}", offset, change));
+    }
+    description
+}
+
+// --- UNIT TESTS ---
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const URI: &str = "file:///tmp/test.bf";
+
+    //runs `messages` through the server, numbering the requests, and returns everything it sent
+    fn session(messages: &[Value]) -> Vec<Value> {
+        let mut input = Vec::new();
+        for (id, message) in messages.iter().enumerate() {
+            let mut message = message.clone();
+            message["jsonrpc"] = "2.0".into();
+            if !message["method"]
+                .as_str()
+                .unwrap()
+                .starts_with("textDocument/did")
+            {
+                message["id"] = id.into();
+            }
+            if message["params"]["textDocument"].is_object() {
+                message["params"]["textDocument"]["uri"] = URI.into();
+            }
+            write_message(&mut input, &message).unwrap();
+        }
+        let mut out = Vec::new();
+        serve(Cursor::new(input), &mut out).unwrap();
+        let mut out = Cursor::new(out);
+        let mut messages = Vec::new();
+        while let Some(message) = read_message(&mut out).unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    fn open(text: &str) -> Value {
+        json!({
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "languageId": "brainfuck", "version": 1, "text": text } },
+        })
+    }
+
+    fn request(method: &str, params: Value) -> Value {
+        let mut params = params;
+        params["textDocument"] = json!({});
+        json!({ "method": method, "params": params })
+    }
+
+    fn at(line: u64, character: u64) -> Value {
+        json!({ "line": line, "character": character })
+    }
+
+    /// Mismatched brackets are reported where they are, and the report goes away once they match.
+    #[test]
+    fn test_diagnostics() {
+        let messages = session(&[
+            json!({ "method": "initialize", "params": { "capabilities": {} } }),
+            open("+\n ]"),
+            json!({
+                "method": "textDocument/didChange",
+                "params": {
+                    "textDocument": { "version": 2 },
+                    "contentChanges": [{ "text": "[\n ]" }],
+                },
+            }),
+            json!({ "method": "shutdown" }),
+            json!({ "method": "exit" }),
+            json!({ "method": "initialize" }),
+        ]);
+        assert_eq!(messages[0]["result"]["capabilities"]["hoverProvider"], true);
+        let diagnostics = &messages[1]["params"]["diagnostics"];
+        assert_eq!(messages[1]["params"]["uri"], URI);
+        assert_eq!(diagnostics[0]["message"], "this `]` has no matching `[`");
+        assert_eq!(
+            diagnostics[0]["range"],
+            json!({ "start": at(1, 1), "end": at(1, 2) })
+        );
+        assert_eq!(messages[2]["params"]["diagnostics"], json!([]));
+        assert_eq!(messages[3]["result"], Value::Null);
+        // nothing is answered after `exit`
+        assert_eq!(messages.len(), 4);
+    }
+
+    /// The bracket under or right before the cursor is highlighted with its match.
+    #[test]
+    fn test_highlights() {
+        let highlight = |position| {
+            request(
+                "textDocument/documentHighlight",
+                json!({ "position": position }),
+            )
+        };
+        let messages = session(&[
+            open("é+[->\n[-]<]"),
+            highlight(at(0, 2)),
+            highlight(at(1, 5)),
+            highlight(at(0, 4)),
+        ]);
+        let ranges = |message: &Value| -> Vec<Value> {
+            message["result"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|highlight| highlight["range"]["start"].clone())
+                .collect()
+        };
+        assert_eq!(ranges(&messages[1]), [at(0, 2), at(1, 4)]);
+        assert_eq!(ranges(&messages[2]), [at(1, 4), at(0, 2)]);
+        assert_eq!(messages[3]["result"], Value::Null);
+    }
+
+    /// Hovers show what a loop, a run of instructions or a selection does.
+    #[test]
+    fn test_hover() {
+        let hover = |params| request("textDocument/hover", params);
+        let messages = session(&[
+            open("++ [->+<] >>, end\n[>]"),
+            hover(json!({ "position": at(0, 3) })),
+            hover(json!({ "position": at(0, 1) })),
+            hover(
+                json!({ "position": at(0, 1), "range": { "start": at(0, 0), "end": at(0, 13) } }),
+            ),
+            hover(json!({ "position": { "start": at(0, 0), "end": at(1, 3) } })),
+            hover(json!({ "position": at(0, 16) })),
+        ]);
+        let value = |message: &Value| message["result"]["contents"]["value"].clone();
+        assert_eq!(
+            value(&messages[1]),
+            "`[->+<]`\n\nThe head ends where it started.\n- cell +0: sets it to 0\n- cell +1: changes it depending on the tape or the input"
+        );
+        assert_eq!(
+            messages[1]["result"]["range"],
+            json!({ "start": at(0, 3), "end": at(0, 9) })
+        );
+        assert_eq!(
+            value(&messages[2]),
+            "`++`\n\nThe head ends where it started.\n- cell +0: adds 2"
+        );
+        assert_eq!(
+            value(&messages[3]),
+            "`++[->+<]>>,`\n\nThe head moves 2 cells to the right.\n- cell +0: sets it to 0\n- cell +1: changes it depending on the tape or the input\n- cell +2: changes it depending on the tape or the input"
+        );
+        assert!(
+            value(&messages[4])
+                .as_str()
+                .unwrap()
+                .starts_with("`++[->+<]>>,[>]`\n\nWhere the head ends up depends on the tape")
+        );
+        assert_eq!(messages[5]["result"], Value::Null);
+    }
+
+    /// Formatting replaces the whole document, and fails while the brackets do not match.
+    #[test]
+    fn test_formatting() {
+        let format = |options| request("textDocument/formatting", json!({ "options": options }));
+        let messages = session(&[
+            open("+[\n-]  \n"),
+            format(json!({ "tabSize": 2, "insertSpaces": true })),
+            format(json!({ "tabSize": 4, "insertSpaces": false })),
+            open("+[\n-\n"),
+            format(json!({ "tabSize": 2, "insertSpaces": true })),
+            request("textDocument/definition", json!({ "position": at(0, 0) })),
+        ]);
+        assert_eq!(
+            messages[1]["result"],
+            json!([{
+                "range": { "start": at(0, 0), "end": at(2, 0) },
+                "newText": "+[\n  -]\n",
+            }])
+        );
+        assert_eq!(messages[2]["result"][0]["newText"], "+[\n\t-]\n");
+        assert_eq!(messages[4]["error"]["code"], REQUEST_FAILED);
+        assert_eq!(
+            messages[4]["error"]["message"],
+            "can not format: loop start at line 1, column 2 is never closed"
+        );
+        assert_eq!(messages[5]["error"]["code"], METHOD_NOT_FOUND);
+    }
+}
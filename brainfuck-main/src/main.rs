@@ -1,8 +1,25 @@
-use brainfuck_core::{run_program_fragment_no_target, util::preprocess_input};
-use clap::{Args, Parser, Subcommand, ValueEnum};
-use std::fs;
+mod dap;
+mod debug;
+mod jsonrpc;
+mod lsp;
+
+use brainfuck_core::{
+    BfRunResult, Budget, Cell, CellWidth, CodegenOptions, Engine, EofBehavior, Interpreter,
+    OverflowMode, RunError, RunOptions, SearchOptions, SourceMap, SourceSpan, StopPoint,
+    TapePolicy, find_program, generate_asm, generate_c, generate_elf, generate_rust,
+    generate_rust_module,
+    util::{parse_program, preprocess_input_with_cells},
+};
+use clap::{Args, Parser, Subcommand, ValueEnum, builder::RangedU64ValueParser};
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    process::ExitCode,
+    time::{Duration, Instant},
+};
 
 use brainfuck_tui::{App, CrosstermTerminal, run_app};
+use debug::Session;
 
 /// CLI for processing and searching inputs
 #[derive(Parser)]
@@ -17,12 +34,44 @@ enum Commands {
     /// Run input through the Brainfuck interpreter.
     ///
     /// This uses all optimizations including jump tables, therefore requiring a full preprocessing pass of the input.
-    /// Default memory size is 30,000 cells, does not automatically resize, and throws errors if the program attempts to move pointer out of bounds in either direction.
+    /// Default memory size is 30,000 cells (see `--tape-size`), does not automatically resize, and throws errors if the program attempts to move pointer out of bounds in either direction.
+    /// Use `--tape-mode` to let the tape grow on demand instead, up to `--max-cells` cells.
+    /// The program's `,` reads from stdin unless `--data` or `--stdin-file` is given, and its output is written to stdout as raw bytes.
+    /// Long running programs are much faster with `--engine optimized`, which prints the same output and stops at the same instruction, but holds output back until it is sure the program is not in an infinite loop.
+    /// `--engine jit` compiles the program to native code and is faster still, but only on x86-64 Unix with `--overflow wrap` and without `--max-steps` or `--timeout`; other runs use the optimized engine, with a warning.
+    ///
+    /// Exit codes: 0 success, 1 the program or its input could not be read or the output could not be written, 3 tape head moved left of the tape, 4 out of tape memory, 5 infinite loop, 6 input ran out, 7 cell overflow, 8 unclosed loop, 10 stopped by `--max-steps` or `--timeout`.
     Run(RunArgs),
 
-    /// Search input for a pattern
+    /// Search for the shortest Brainfuck program that prints the target bytes.
+    ///
+    /// Programs are enumerated breadth first by length, so the first program found is the shortest one.
+    /// The search runs on a 32 cell tape by default and gives up once programs grow past the maximum size.
     Search(SearchArgs),
 
+    /// Compile a program to source code in another language, or straight to a Linux executable.
+    ///
+    /// The generated program takes the same tape, cell and EOF options as `run` and behaves the same: it prints the same bytes and fails with the same message and exit code, except that it does not detect infinite loops.
+    Compile(CompileArgs),
+
+    /// Step through a program in a line debugger that reads commands from stdin.
+    ///
+    /// The commands are `break <idx>`, `step`, `next`, `continue`, `tape <from> <to>`, `watch <cell>`, `output` and `restart`, type `help` in the debugger for all of them.
+    /// Instructions are counted from 0 and cells from the one the program starts on, like in the errors of `run`.
+    /// As stdin carries the commands, the program's `,` reads from `--data` or `--stdin-file`, or else from text given with the `input` command.
+    Debug(DebugArgs),
+
+    /// Serve the Debug Adapter Protocol over stdin and stdout, for debugging in editors.
+    ///
+    /// The `launch` request takes the path of the file to debug as `program`, the program's input as `input` and `stopOnEntry`.
+    /// Breakpoints go on the first instruction at or after their line and column, the tape shows up as the variables of the `Tape` scope.
+    Dap(DapArgs),
+
+    /// Serve the Language Server Protocol over stdin and stdout, for editing in editors.
+    ///
+    /// It reports brackets that do not match, highlights the bracket matching the one at the cursor, formats documents and shows on hover how a loop, a run of instructions or a selection moves the head and changes the cells.
+    Lsp,
+
     /// launch TUI
     Tui,
 }
@@ -36,6 +85,238 @@ struct RunArgs {
     /// Path to input file
     #[arg(short, long, required_unless_present = "input")]
     file: Option<String>,
+
+    #[command(flatten)]
+    machine: MachineArgs,
+
+    /// Read the program's input from this file instead of stdin
+    #[arg(long, conflicts_with = "data")]
+    stdin_file: Option<String>,
+
+    /// Use this string as the program's input instead of stdin
+    #[arg(long)]
+    data: Option<String>,
+
+    /// How to execute the program
+    #[arg(long, value_enum, default_value_t = RunEngine::Reference)]
+    engine: RunEngine,
+
+    /// Stop the program after this many instructions
+    #[arg(long)]
+    max_steps: Option<u64>,
+
+    /// Stop the program after this many seconds
+    #[arg(long)]
+    timeout: Option<f64>,
+
+    /// Flush every output byte immediately and only read input when `,` asks for it, for programs used from a terminal
+    #[arg(long)]
+    interactive: bool,
+}
+
+/// The tape and cells a program runs on, shared by `run` and `compile`.
+#[derive(Args)]
+struct MachineArgs {
+    /// Number of cells on the tape, or the initial number of cells for a growable tape
+    #[arg(long, default_value_t = 30_000, value_parser = at_least_one_cell())]
+    tape_size: usize,
+
+    /// What happens when the pointer moves off the end of the tape
+    #[arg(long, value_enum, default_value_t = TapeMode::Fixed)]
+    tape_mode: TapeMode,
+
+    /// Most cells a growable tape may hold before the run fails as out of memory
+    #[arg(long, default_value_t = 1 << 26)]
+    max_cells: usize,
+
+    /// Number of bits in each cell
+    #[arg(long, value_enum, default_value_t = CellBits::Eight)]
+    cell_width: CellBits,
+
+    /// What happens when a cell is incremented past its largest value or decremented below 0
+    #[arg(long, value_enum, default_value_t = Overflow::Wrap)]
+    overflow: Overflow,
+
+    /// What `,` stores in the cell once the input has run out
+    #[arg(long, value_enum, default_value_t = Eof::Error)]
+    eof: Eof,
+}
+
+#[derive(Args)]
+struct DebugArgs {
+    /// Input string
+    #[arg(short, long, required_unless_present = "file")]
+    input: Option<String>,
+
+    /// Path to input file
+    #[arg(short, long, required_unless_present = "input")]
+    file: Option<String>,
+
+    #[command(flatten)]
+    machine: MachineArgs,
+
+    /// Read the program's input from this file instead of the `input` command
+    #[arg(long, conflicts_with = "data")]
+    stdin_file: Option<String>,
+
+    /// Use this string as the program's input instead of the `input` command
+    #[arg(long)]
+    data: Option<String>,
+
+    /// Most instructions `continue` and `next` run before they pause, for programs that might not end
+    #[arg(long)]
+    max_steps: Option<u64>,
+}
+
+#[derive(Args)]
+struct DapArgs {
+    #[command(flatten)]
+    machine: MachineArgs,
+}
+
+#[derive(Args)]
+struct CompileArgs {
+    /// Input string
+    #[arg(short, long, required_unless_present = "file")]
+    input: Option<String>,
+
+    /// Path to input file
+    #[arg(short, long, required_unless_present = "input")]
+    file: Option<String>,
+
+    /// Language to compile to
+    #[arg(long, value_enum)]
+    target: CompileTarget,
+
+    /// Write the generated code to this file instead of stdout
+    #[arg(short, long)]
+    output: Option<String>,
+
+    #[command(flatten)]
+    machine: MachineArgs,
+}
+
+#[derive(Clone, ValueEnum, Debug, Copy)]
+enum CompileTarget {
+    /// A self-contained C file
+    C,
+    /// A self-contained `main.rs`
+    Rust,
+    /// A Rust module with a `run` function that reads from an `impl Read` and writes to an `impl Write`
+    RustModule,
+    /// A static Linux x86-64 executable that needs no C library or toolchain
+    Elf,
+    /// Annotated x86-64 assembly for the GNU assembler, in Intel syntax, for Linux
+    Asm,
+}
+
+#[derive(Clone, ValueEnum, Debug, Copy)]
+enum RunEngine {
+    /// Run one instruction at a time and stop programs that loop forever
+    Reference,
+    /// Compile the program first, much faster for long running programs but holds output back while it makes sure the program is not looping forever
+    Optimized,
+    /// Compile the program to native code, the fastest; runs with `--max-steps`, `--timeout`, an `--overflow` other than `wrap` or on other machines than x86-64 Unix use `optimized`
+    Jit,
+}
+
+impl From<RunEngine> for Engine {
+    fn from(engine: RunEngine) -> Self {
+        match engine {
+            RunEngine::Reference => Engine::Reference,
+            RunEngine::Optimized => Engine::Optimized,
+            RunEngine::Jit => Engine::Jit,
+        }
+    }
+}
+
+#[derive(Clone, ValueEnum, Debug, Copy)]
+enum Eof {
+    /// Stop the program with an error
+    Error,
+    /// Set the cell to 0
+    Zero,
+    /// Set the cell to its largest value, 255 (-1) for 8-bit cells
+    Max,
+    /// Leave the cell as it is
+    Unchanged,
+}
+
+impl From<Eof> for EofBehavior {
+    fn from(eof: Eof) -> Self {
+        match eof {
+            Eof::Error => EofBehavior::Error,
+            Eof::Zero => EofBehavior::Zero,
+            Eof::Max => EofBehavior::Max,
+            Eof::Unchanged => EofBehavior::Unchanged,
+        }
+    }
+}
+
+#[derive(Clone, ValueEnum, Debug, Copy)]
+enum CellBits {
+    #[value(name = "8")]
+    Eight,
+    #[value(name = "16")]
+    Sixteen,
+    #[value(name = "32")]
+    ThirtyTwo,
+}
+
+impl From<CellBits> for CellWidth {
+    fn from(bits: CellBits) -> Self {
+        match bits {
+            CellBits::Eight => CellWidth::U8,
+            CellBits::Sixteen => CellWidth::U16,
+            CellBits::ThirtyTwo => CellWidth::U32,
+        }
+    }
+}
+
+#[derive(Clone, ValueEnum, Debug, Copy)]
+enum Overflow {
+    /// Wrap around to the other end of the cell's range
+    Wrap,
+    /// Stay at the largest or smallest value
+    Saturate,
+    /// Stop the program with an error
+    Error,
+}
+
+impl From<Overflow> for OverflowMode {
+    fn from(overflow: Overflow) -> Self {
+        match overflow {
+            Overflow::Wrap => OverflowMode::Wrap,
+            Overflow::Saturate => OverflowMode::Saturate,
+            Overflow::Error => OverflowMode::Error,
+        }
+    }
+}
+
+#[derive(Clone, ValueEnum, Debug, Copy)]
+enum TapeMode {
+    /// The tape never resizes, moving off either end is an error
+    Fixed,
+    /// The tape grows to the right, moving left of the first cell is an error
+    Growable,
+    /// The tape grows in both directions
+    Bidirectional,
+}
+
+impl TapeMode {
+    fn to_policy(self, max_cells: usize) -> TapePolicy {
+        match self {
+            TapeMode::Fixed => TapePolicy::Fixed,
+            TapeMode::Growable => TapePolicy::Growable {
+                grow_left: false,
+                max_cells,
+            },
+            TapeMode::Bidirectional => TapePolicy::Growable {
+                grow_left: true,
+                max_cells,
+            },
+        }
+    }
 }
 
 #[derive(Args)]
@@ -49,73 +330,486 @@ struct SearchArgs {
     file: Option<String>,
 
     /// Input format
-    #[arg(short, long, value_enum)]
+    #[arg(long, value_enum, default_value_t = InputFormat::Txt)]
     format: InputFormat,
 
-    /// Enable multithreaded search
-    #[arg(long)]
+    /// No longer supported, the search runs on one thread
+    #[arg(long, hide = true)]
     multithread: bool,
+
+    /// Number of cells on the tape of every candidate program, tapes up to 32 cells are fastest
+    #[arg(long, default_value_t = 32, value_parser = at_least_one_cell())]
+    tape_size: usize,
+
+    /// Number of bits in each cell, wider cells make loops that wrap around much slower to search
+    #[arg(long, value_enum, default_value_t = CellBits::Eight)]
+    cell_width: CellBits,
+
+    /// What happens when a cell is incremented past its largest value or decremented below 0
+    #[arg(long, value_enum, default_value_t = Overflow::Wrap)]
+    overflow: Overflow,
 }
 
 #[derive(Clone, ValueEnum, Debug, Copy)]
 enum InputFormat {
+    /// The target is taken byte for byte
+    Txt,
+    /// No longer supported, give the target as text
+    #[value(hide = true)]
     Json,
+    /// No longer supported, give the target as text
+    #[value(hide = true)]
     Xml,
-    Txt,
 }
 
-fn main() {
+fn main() -> ExitCode {
     let cli = Cli::parse();
 
     match cli.command {
         Commands::Run(args) => {
-            let input = match args.input {
-                Some(s) => s,
-                None => fs::read_to_string(args.file.expect("Expected file"))
-                    .expect("Failed to read file"),
+            let input = match read_source(args.input, args.file) {
+                Ok(input) => input,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    return ExitCode::FAILURE;
+                }
+            };
+            let deadline = match args.timeout.map(Duration::try_from_secs_f64) {
+                Some(Ok(timeout)) => Some(Instant::now() + timeout),
+                Some(Err(e)) => {
+                    eprintln!("Error: invalid timeout: {}", e);
+                    return ExitCode::FAILURE;
+                }
+                None => None,
+            };
+            let options = RunOptions {
+                tape: args.machine.tape_mode.to_policy(args.machine.max_cells),
+                overflow: args.machine.overflow.into(),
+                eof: args.machine.eof.into(),
+                budget: Budget {
+                    max_steps: args.max_steps,
+                    deadline,
+                },
+                engine: args.engine.into(),
             };
-            run_code(&input);
+            if options.engine == Engine::Jit && !options.runs_natively() {
+                eprintln!(
+                    "Warning: --engine jit needs x86-64 Unix, --overflow wrap and no --max-steps or --timeout, using the optimized engine"
+                );
+            }
+            let data: Box<dyn Read> = match (args.data, args.stdin_file) {
+                (Some(data), _) => Box::new(io::Cursor::new(data.into_bytes())),
+                (None, Some(path)) => match File::open(&path) {
+                    Ok(file) => Box::new(BufReader::new(file)),
+                    Err(e) => {
+                        eprintln!("Error opening {}: {}", path, e);
+                        return ExitCode::FAILURE;
+                    }
+                },
+                (None, None) => Box::new(io::stdin().lock()),
+            };
+            let io = ProgramIo {
+                data,
+                interactive: args.interactive,
+            };
+            let tape_size = args.machine.tape_size;
+            match args.machine.cell_width.into() {
+                CellWidth::U8 => run_code::<u8>(&input, tape_size, &options, io),
+                CellWidth::U16 => run_code::<u16>(&input, tape_size, &options, io),
+                CellWidth::U32 => run_code::<u32>(&input, tape_size, &options, io),
+            }
+        }
+        Commands::Compile(args) => {
+            let input = match read_source(args.input, args.file) {
+                Ok(input) => input,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    return ExitCode::FAILURE;
+                }
+            };
+            let options = CodegenOptions {
+                tape_size: args.machine.tape_size,
+                cell_width: args.machine.cell_width.into(),
+                tape: args.machine.tape_mode.to_policy(args.machine.max_cells),
+                overflow: args.machine.overflow.into(),
+                eof: args.machine.eof.into(),
+            };
+            compile_handler(&input, args.target, args.output.as_deref(), &options)
+        }
+        Commands::Debug(args) => {
+            let input = match read_source(args.input, args.file) {
+                Ok(input) => input,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    return ExitCode::FAILURE;
+                }
+            };
+            let options = RunOptions {
+                tape: args.machine.tape_mode.to_policy(args.machine.max_cells),
+                overflow: args.machine.overflow.into(),
+                eof: args.machine.eof.into(),
+                budget: args.max_steps.map_or(Budget::default(), Budget::steps),
+                ..RunOptions::default()
+            };
+            let data = match (args.data, args.stdin_file) {
+                (Some(data), _) => Some(data.into_bytes()),
+                (None, Some(path)) => match fs::read(&path) {
+                    Ok(data) => Some(data),
+                    Err(e) => {
+                        eprintln!("Error opening {}: {}", path, e);
+                        return ExitCode::FAILURE;
+                    }
+                },
+                (None, None) => None,
+            };
+            let tape_size = args.machine.tape_size;
+            match args.machine.cell_width.into() {
+                CellWidth::U8 => debug_code::<u8>(&input, tape_size, &options, data),
+                CellWidth::U16 => debug_code::<u16>(&input, tape_size, &options, data),
+                CellWidth::U32 => debug_code::<u32>(&input, tape_size, &options, data),
+            }
         }
+        Commands::Dap(args) => {
+            let options = RunOptions {
+                tape: args.machine.tape_mode.to_policy(args.machine.max_cells),
+                overflow: args.machine.overflow.into(),
+                eof: args.machine.eof.into(),
+                ..RunOptions::default()
+            };
+            let tape_size = args.machine.tape_size;
+            let served = match args.machine.cell_width.into() {
+                CellWidth::U8 => {
+                    dap::serve::<u8>(io::stdin(), &mut io::stdout(), tape_size, &options)
+                }
+                CellWidth::U16 => {
+                    dap::serve::<u16>(io::stdin(), &mut io::stdout(), tape_size, &options)
+                }
+                CellWidth::U32 => {
+                    dap::serve::<u32>(io::stdin(), &mut io::stdout(), tape_size, &options)
+                }
+            };
+            match served {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        Commands::Lsp => match lsp::serve(io::stdin().lock(), &mut io::stdout().lock()) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                ExitCode::FAILURE
+            }
+        },
         Commands::Search(args) => {
-            let input = match args.target {
-                Some(s) => s,
-                None => fs::read_to_string(args.file.expect("Expected file"))
-                    .expect("Failed to read file"),
+            let input = match read_source(args.target, args.file) {
+                Ok(input) => input,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    return ExitCode::FAILURE;
+                }
             };
-            search_handler(&input, args.format, args.multithread);
+            if args.multithread {
+                eprintln!(
+                    "Error: --multithread is no longer supported, the search runs on one thread"
+                );
+                return ExitCode::FAILURE;
+            }
+            let options = SearchOptions {
+                tape_size: args.tape_size,
+                cell_width: args.cell_width.into(),
+                overflow: args.overflow.into(),
+                progress: Some(report_layer),
+                ..SearchOptions::default()
+            };
+            search_handler(&input, args.format, &options)
         }
         Commands::Tui => {
-            let mut terminal = CrosstermTerminal::new().expect("Failed to create terminal");
+            let mut terminal = match CrosstermTerminal::new() {
+                Ok(terminal) => terminal,
+                Err(e) => {
+                    eprintln!("Error: could not set up the terminal: {}", e);
+                    return ExitCode::FAILURE;
+                }
+            };
             let mut app = App::new();
-            run_app(&mut terminal, &mut app).expect("Failed to run TUI app");
-            terminal.try_close().expect("Failed to close terminal");
+            let result = run_app(&mut terminal, &mut app);
+            // the terminal has to be restored before anything is printed
+            let closed = terminal.try_close();
+            match (result, closed) {
+                (Err(e), _) => {
+                    eprintln!("Error: the TUI stopped: {}", e);
+                    ExitCode::FAILURE
+                }
+                (Ok(_), Err(e)) => {
+                    eprintln!("Error: could not restore the terminal: {}", e);
+                    ExitCode::FAILURE
+                }
+                (Ok(_), Ok(())) => ExitCode::SUCCESS,
+            }
         }
     }
 }
 
-fn run_code(input: &str) {
-    let preprocessed_code = preprocess_input::<30_000>(input);
+/// The text given inline, or else the contents of `file`.
+fn read_source(inline: Option<String>, file: Option<String>) -> Result<String, String> {
+    match (inline, file) {
+        (Some(s), _) => Ok(s),
+        (None, Some(path)) => {
+            fs::read_to_string(&path).map_err(|e| format!("could not read {}: {}", path, e))
+        }
+        (None, None) => Err("no input given".to_string()),
+    }
+}
+
+/// Where a running program reads `,` from, and how its output is written.
+struct ProgramIo {
+    data: Box<dyn Read>,
+    interactive: bool,
+}
+
+fn run_code<C: Cell>(
+    input: &str,
+    tape_size: usize,
+    options: &RunOptions,
+    io: ProgramIo,
+) -> ExitCode {
+    let preprocessed_code = preprocess_input_with_cells::<C>(input, tape_size);
     match preprocessed_code {
         Ok(running_program_info) => {
-            run_program_fragment_no_target(
+            let ProgramIo {
+                mut data,
+                interactive,
+            } = io;
+            // the writer is shared by both closures, so a prompt is flushed before `,` blocks on input
+            let stdout = std::cell::RefCell::new(BufWriter::new(io::stdout().lock()));
+            let run = Interpreter::new().try_run_with_stop(
                 &running_program_info,
-                || None,
+                options,
+                || {
+                    if interactive {
+                        stdout
+                            .borrow_mut()
+                            .flush()
+                            .map_err(|e| ("writing output", e))?;
+                    }
+                    let mut byte = [0u8];
+                    loop {
+                        match data.read(&mut byte) {
+                            Ok(0) => return Ok(None),
+                            Ok(_) => return Ok(Some(byte[0])),
+                            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                            Err(e) => return Err(("reading input", e)),
+                        }
+                    }
+                },
                 |output| {
-                    print!("{}", output as char);
+                    let mut stdout = stdout.borrow_mut();
+                    stdout
+                        .write_all(&[output])
+                        .map_err(|e| ("writing output", e))?;
+                    if interactive {
+                        stdout.flush().map_err(|e| ("writing output", e))?;
+                    }
+                    Ok(())
                 },
             );
+            let flushed = stdout
+                .into_inner()
+                .flush()
+                .map_err(|e| ("writing output", e));
+            let (result, stop) = match run.and_then(|run| flushed.map(|()| run)) {
+                Ok(run) => run,
+                Err((action, e)) => {
+                    eprintln!("Error: {} failed: {}", action, e);
+                    return ExitCode::FAILURE;
+                }
+            };
+            report_run_result(input, &result, &stop)
         }
         Err(e) => {
-            eprintln!("Error preprocessing input: {}", e);
+            eprintln!("Error: {}", e);
+            if let Some(span) = e.span() {
+                eprint!("{}", excerpt(input, span));
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn debug_code<C: Cell>(
+    input: &str,
+    tape_size: usize,
+    options: &RunOptions,
+    data: Option<Vec<u8>>,
+) -> ExitCode {
+    let program = match preprocess_input_with_cells::<C>(input, tape_size) {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            if let Some(span) = e.span() {
+                eprint!("{}", excerpt(input, span));
+            }
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut session = Session::new(input, program, options, data);
+    match session.run(io::stdin().lock(), &mut io::stdout().lock()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// The source line holding `span` with a caret under it, in the style of compiler diagnostics.
+fn excerpt(source: &str, span: SourceSpan) -> String {
+    let line = source.lines().nth(span.line - 1).unwrap_or_default();
+    let number = span.line.to_string();
+    // copy tabs from the line so the caret lines up however wide the terminal draws them
+    let padding: String = line
+        .chars()
+        .take(span.column - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let gutter = " ".repeat(number.len());
+    format!(
+        "{} |\n{} | {}\n{} | {}^\n",
+        gutter, number, line, gutter, padding
+    )
+}
+
+/// Prints a diagnostic for a failed run to stderr and picks the exit code for it, keeping one code per `BfRunResult` variant.
+fn report_run_result<C: Cell>(
+    input: &str,
+    result: &BfRunResult<C>,
+    stop: &StopPoint<C>,
+) -> ExitCode {
+    if let BfRunResult::Paused(_) = result {
+        eprintln!(
+            "Error: stopped at instruction {}, tape head {}, cell value {:?} before the program finished",
+            stop.pc, stop.tape_head, stop.cell
+        );
+        if let Some(span) = SourceMap::from_source(input).span(stop.pc) {
+            eprint!("{}", excerpt(input, span));
         }
+        return ExitCode::from(10);
+    }
+    let Some(kind) = result.error_kind() else {
+        return ExitCode::SUCCESS;
+    };
+    eprintln!("Error: {}", RunError { kind, stop: *stop });
+    if let Some(span) = SourceMap::from_source(input).span(stop.pc) {
+        eprint!("{}", excerpt(input, span));
     }
+    ExitCode::from(kind.exit_code())
+}
+
+fn compile_handler(
+    input: &str,
+    target: CompileTarget,
+    output: Option<&str>,
+    options: &CodegenOptions,
+) -> ExitCode {
+    // parse the source itself, so that errors point into it
+    let program = match parse_program(input) {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            eprint!("{}", excerpt(input, e.span()));
+            return ExitCode::FAILURE;
+        }
+    };
+    let program = program.code();
+    let generated = match target {
+        CompileTarget::C => generate_c(program, options).map(String::into_bytes),
+        CompileTarget::Rust => generate_rust(program, options).map(String::into_bytes),
+        CompileTarget::RustModule => generate_rust_module(program, options).map(String::into_bytes),
+        CompileTarget::Elf => generate_elf(program, options),
+        CompileTarget::Asm => generate_asm(program, options).map(String::into_bytes),
+    };
+    let code = match generated {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let written = match output {
+        Some(path) => fs::write(path, code)
+            .and_then(|()| match target {
+                CompileTarget::Elf => make_executable(path),
+                _ => Ok(()),
+            })
+            .map_err(|e| format!("could not write {}: {}", path, e)),
+        None => io::stdout().write_all(&code).map_err(|e| e.to_string()),
+    };
+    match written {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+//lets everyone who can read the file run it, as a linker would
+#[cfg(unix)]
+fn make_executable(path: &str) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_mode(permissions.mode() | (permissions.mode() & 0o444) >> 2);
+    fs::set_permissions(path, permissions)
+}
+
+#[cfg(not(unix))]
+fn make_executable(_path: &str) -> io::Result<()> {
+    Ok(())
+}
+
+fn search_handler(input: &str, format: InputFormat, options: &SearchOptions) -> ExitCode {
+    let target = match parse_target(input, format) {
+        Ok(target) => target,
+        Err(e) => {
+            eprintln!("Error parsing search target: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match find_program(&target, "", options) {
+        Ok(program) => {
+            println!("{}", program);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Search failed: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+//reports the progress of a search on stderr, keeping stdout for the program it finds
+fn report_layer(size: usize) {
+    eprintln!("Finished processing all programs of size {}.", size);
+    eprintln!("Advanced to next layer of program size of {}.", size + 1);
+}
+
+//`--tape-size` is rejected when it is 0, as the head needs a cell to start on
+fn at_least_one_cell() -> RangedU64ValueParser<usize> {
+    RangedU64ValueParser::new().range(1..)
 }
 
-fn search_handler(input: &str, format: InputFormat, multithread: bool) {
-    println!(
-        "Searching in format {:?} with multithread: {}",
-        format, multithread
-    );
-    println!("Input:\n{}", input);
-    // Your actual logic here
+fn parse_target(input: &str, format: InputFormat) -> Result<Vec<u8>, String> {
+    let name = match format {
+        InputFormat::Txt => return Ok(input.as_bytes().to_vec()),
+        InputFormat::Json => "json",
+        InputFormat::Xml => "xml",
+    };
+    Err(format!(
+        "the {} format is no longer supported, give the target as text with --format txt",
+        name
+    ))
 }
@@ -364,6 +364,8 @@ impl App {
         self.scroll_state.set_offset(current_scroll);
     }
 
+    // guards on the key arms would let unhandled keys fall through to the catch-all arms below them
+    #[allow(clippy::collapsible_if, clippy::collapsible_match)]
     fn handle_event(&mut self, ev: CEvent) -> bool {
         if let CEvent::Key(key) = ev {
             if key.kind == KeyEventKind::Press {
@@ -11,7 +11,7 @@ use ratatui::{
 };
 
 pub trait RawTerminal {
-    fn draw<T>(&mut self, render_callback: T) -> io::Result<CompletedFrame>
+    fn draw<T>(&mut self, render_callback: T) -> io::Result<CompletedFrame<'_>>
     where
         T: FnOnce(&mut Frame);
 }
@@ -51,12 +51,13 @@ impl CrosstermTerminal {
 
 impl Drop for CrosstermTerminal {
     fn drop(&mut self) {
-        self.try_close().unwrap();
+        // errors can't be returned from drop, callers that care use try_close themselves
+        let _ = self.try_close();
     }
 }
 
 impl RawTerminal for CrosstermTerminal {
-    fn draw<T>(&mut self, render_callback: T) -> io::Result<CompletedFrame>
+    fn draw<T>(&mut self, render_callback: T) -> io::Result<CompletedFrame<'_>>
     where
         T: FnOnce(&mut Frame),
     {